@@ -1,18 +1,39 @@
 use std::env;
-use std::path::PathBuf;
+use std::io::{IsTerminal, Read};
+use std::path::{Path, PathBuf};
 
 use anyhow::Context;
 use camino::Utf8PathBuf;
 use clap::{Parser, Subcommand};
-use git2::{ObjectType, Repository};
-use gitpatcher::apply_patches::bulk::BulkPatchApply;
-use gitpatcher::apply_patches::EmailMessage;
-use gitpatcher::regenerate_patches::PatchFileSet;
+use git2::{Commit, Repository};
+use gitpatcher::apply_patches::bulk::{BulkPatchApply, ExcludePatterns};
+use gitpatcher::apply_patches::dedup::find_duplicates;
+use gitpatcher::apply_patches::drift::check_series_drift;
+use gitpatcher::apply_patches::email::{
+    check_trailing_garbage, DeltaDesc, InvalidEmailMessage, PatchApplyError,
+};
+use gitpatcher::apply_patches::{ApplyOptions, AuthorMap, CleanupMode, EmailMessage};
+use gitpatcher::format_patches::{expected_patch_name, FormatOptions, PatchFormatter};
+use gitpatcher::regenerate_patches::{PatchFileSet, RegenerateOptions};
+use gitpatcher::resolve::{fetch_missing_remote_ref, resolve_commitish};
 use slog::{Drain, Logger};
+use time::OffsetDateTime;
+
+#[cfg(feature = "watch")]
+mod watch;
 
 #[derive(Parser, Debug)]
 #[clap(name = "gitpatcher", about = "A patching system based on git", version = env!("VERGEN_GIT_DESCRIBE"))]
 struct GitPatcher {
+    /// The number of hex digits used to show commit ids in the logs
+    #[clap(long, global = true)]
+    abbrev: Option<usize>,
+    /// When to color the log messages by their level
+    #[clap(long, global = true, value_enum, default_value_t = ColorChoice::Auto)]
+    color: ColorChoice,
+    /// How the log records are rendered
+    #[clap(long, global = true, value_enum, default_value_t = LogFormat::Compact)]
+    log_format: LogFormat,
     #[clap(subcommand)]
     subcommand: PatchSubcommand,
 }
@@ -24,6 +45,17 @@ enum PatchSubcommand {
     ApplyAllPatches(ApplyAllPatches),
     /// Regenerate a set of patched files by comparing a patched repo to an upstream reference
     RegeneratePatches(RegeneratePatchOpts),
+    /// Check which patches no longer match an upstream reference, without applying them
+    CheckDrift(CheckDriftOpts),
+    /// List the patches in a patch directory, with their subjects and authors
+    List(ListOpts),
+    /// Report patches that make the same change as another patch in the directory
+    Dedup(DedupOpts),
+    /// Report patch files whose names don't match the subjects they contain
+    LintNames(LintNamesOpts),
+    /// Regenerate the patches whenever the branches of the patched repo change
+    #[cfg(feature = "watch")]
+    Watch(WatchOpts),
 }
 
 #[derive(Parser, Debug)]
@@ -35,6 +67,28 @@ struct ApplyPatchOpts {
     /// Defaults to current directory if nothing is specified
     #[clap(long = "target")]
     target_repo: Option<PathBuf>,
+    /// Apply the patch in a linked worktree of the target repository, given by name or path
+    #[clap(long)]
+    worktree: Option<PathBuf>,
+    /// Reject the patch if it is larger than the specified number of bytes
+    #[clap(long)]
+    max_patch_size: Option<u64>,
+    /// Reject the patch if anything besides the signature follows the diff
+    #[clap(long)]
+    strict_eof: bool,
+    /// Open conflicts in `$EDITOR` and commit the resolved files, like `git am --continue`
+    #[clap(long)]
+    interactive: bool,
+    /// If the patch doesn't apply, 3-way merge it with the files it was generated from
+    #[clap(long = "3way", short = '3', conflicts_with = "interactive")]
+    three_way: bool,
+    /// Print the files the patch would change, like `git apply --stat`, without applying it
+    #[clap(long, conflicts_with_all = ["interactive", "three_way"])]
+    stat: bool,
+    /// Commit as the configured user (`user.name` and `user.email`) instead of the author,
+    /// like `git am`
+    #[clap(long, conflicts_with = "stat")]
+    config_committer: bool,
 }
 
 #[derive(Parser, Debug)]
@@ -42,13 +96,58 @@ struct ApplyAllPatches {
     /// The upstream reference to reset to before applying patches
     #[clap(long)]
     upstream: Option<String>,
+    /// Reject any patch larger than the specified number of bytes
+    #[clap(long)]
+    max_patch_size: Option<u64>,
+    /// Reject any patch where anything besides the signature follows the diff
+    #[clap(long)]
+    strict_eof: bool,
+    /// Skip the patch files matching this glob, like `0003-*` (repeatable)
+    #[clap(long)]
+    exclude: Vec<String>,
+    /// Also apply files without an extension, if they start with a patch header
+    #[clap(long)]
+    sniff: bool,
+    /// Rewrite the authors of the patches using a CSV file,
+    /// with the columns `old name,old email,new name,new email`
+    #[clap(long)]
+    author_map: Option<PathBuf>,
+    /// Stage the combined changes of all the patches, without committing them
+    #[clap(long, visible_alias = "no-commit", conflicts_with_all = ["transactional", "interactive"])]
+    squash: bool,
+    /// Commit the patches in memory, checking out the result only once at the end
+    #[clap(long, conflicts_with = "interactive")]
+    in_memory: bool,
+    /// Only apply the first N patches, leaving the rest unapplied
+    #[clap(long, value_name = "N")]
+    count: Option<usize>,
+    /// Fetch the upstream reference if it is a missing remote-tracking ref
+    #[clap(long, requires = "upstream")]
+    fetch: bool,
+    /// Open conflicts in `$EDITOR` and commit the resolved files, like `git am --continue`
+    #[clap(long)]
+    interactive: bool,
+    /// If any patch fails, reset the target back to where it was before applying
+    #[clap(long)]
+    transactional: bool,
+    /// Commit the messages verbatim, keeping consecutive blank lines,
+    /// instead of cleaning them up like `commit.cleanup`
+    #[clap(long)]
+    verbatim: bool,
+    /// List the files that resetting to the upstream would change or remove,
+    /// without resetting or applying any patches
+    #[clap(long, requires = "upstream")]
+    dry_run: bool,
+    /// Apply the patches in a linked worktree of the target repository, given by name or path
+    #[clap(long)]
+    worktree: Option<PathBuf>,
     /// The target repository to apply patches too
     target_repo: PathBuf,
     /// The directory containing all the patch files
     patch_dir: PathBuf,
 }
 
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, Clone)]
 struct RegeneratePatchOpts {
     /// The repository containing the patched changes
     patched_repo: PathBuf,
@@ -56,30 +155,316 @@ struct RegeneratePatchOpts {
     upstream: String,
     /// The directory to place the generated patches in
     patch_dir: Utf8PathBuf,
+    /// Fetch the upstream reference if it is a missing remote-tracking ref
+    #[clap(long)]
+    fetch: bool,
+    /// Generate the patches against the merge-base of HEAD and upstream,
+    /// instead of the upstream commit itself
+    ///
+    /// Commits that are only in upstream are never part of the series,
+    /// but the first patch is diffed against the base,
+    /// so it would revert every upstream change that HEAD doesn't have yet.
+    /// Use this if the patched repo has diverged from upstream,
+    /// for example when upstream has moved on since it was last merged.
+    #[clap(long)]
+    merge_base: bool,
+    /// Mark the patches as version N of the series, with `[PATCH vN]` subjects
+    #[clap(long)]
+    reroll_count: Option<u32>,
+    /// Use this instead of `PATCH` in the brackets at the start of each subject,
+    /// overriding `format.subjectPrefix`
+    #[clap(long)]
+    subject_prefix: Option<String>,
+    /// Write a deleted and an added file that are at least N% similar as a rename
+    #[clap(long, value_name = "N", value_parser = clap::value_parser!(u16).range(0..=100))]
+    rename_threshold: Option<u16>,
+    /// Leave merge commits out of the series,
+    /// diffing every other commit against its first parent
+    #[clap(long)]
+    ignore_merges: bool,
+    /// Only include commits authored after this date,
+    /// like `2023-08-25`, `2023-08-25 06:13:20`, `@1692944000` or `2 weeks ago`
+    ///
+    /// Requires `--stdout`, since writing part of the series would drop the other patches.
+    #[clap(long, value_parser = parse_date, requires = "stdout")]
+    since: Option<OffsetDateTime>,
+    /// Only include commits authored before this date (requires `--stdout`)
+    #[clap(long, value_parser = parse_date, requires = "stdout")]
+    until: Option<OffsetDateTime>,
+    /// Write the patches to stdout as a single mbox, leaving the patch directory untouched
+    #[clap(long)]
+    stdout: bool,
+    /// Fail if the patched repo has uncommitted changes, instead of warning
+    #[clap(long)]
+    strict: bool,
+    /// Report how long each phase of regeneration takes
+    #[clap(long)]
+    timings: bool,
+    /// Leave the existing patch files matching this glob untouched, like `0003-*` (repeatable)
+    #[clap(long)]
+    exclude: Vec<String>,
+    /// Remove all the existing patches if there are no commits after upstream,
+    /// instead of failing
+    #[clap(long)]
+    allow_empty: bool,
+    /// The repository the patch directory is committed to,
+    /// instead of discovering it from the patch directory
+    #[clap(long)]
+    base_repo: Option<PathBuf>,
+}
+
+#[cfg(feature = "watch")]
+#[derive(Parser, Debug)]
+struct WatchOpts {
+    #[clap(flatten)]
+    regenerate: RegeneratePatchOpts,
+    /// Wait until the repo has been unchanged for this many milliseconds before regenerating
+    #[clap(long, default_value_t = 500)]
+    debounce_ms: u64,
+}
+
+#[derive(Parser, Debug)]
+struct CheckDriftOpts {
+    /// The repository containing the upstream reference
+    repo: PathBuf,
+    /// The upstream reference to check the patches against
+    upstream: String,
+    /// The directory containing all the patch files
+    patch_dir: PathBuf,
+}
+
+#[derive(Parser, Debug)]
+struct DedupOpts {
+    /// The directory containing all the patch files
+    patch_dir: PathBuf,
+}
+
+#[derive(Parser, Debug)]
+struct LintNamesOpts {
+    /// The directory containing all the patch files
+    patch_dir: PathBuf,
+    /// Rename the mismatched patches, instead of failing
+    #[clap(long)]
+    fix: bool,
+}
+
+#[derive(Parser, Debug)]
+struct ListOpts {
+    /// The directory containing all the patch files
+    patch_dir: Utf8PathBuf,
+    /// How to print the list of patches
+    #[clap(long, value_enum, default_value_t = ListFormat::Table)]
+    format: ListFormat,
+}
+
+#[derive(clap::ValueEnum, Copy, Clone, Debug, PartialEq, Eq)]
+enum ColorChoice {
+    /// Color the output if it is a terminal
+    Auto,
+    /// Always color the output
+    Always,
+    /// Never color the output
+    Never,
+}
+impl ColorChoice {
+    /// If the output should be colored, given whether it is a terminal
+    fn enabled(self, is_terminal: bool) -> bool {
+        match self {
+            ColorChoice::Auto => is_terminal,
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+        }
+    }
+}
+
+#[derive(clap::ValueEnum, Copy, Clone, Debug, PartialEq, Eq)]
+enum LogFormat {
+    /// Group the records under the context they share, like the patch being applied
+    Compact,
+    /// Print each record on a single line with all of its fields,
+    /// so the records of concurrent work never depend on each other
+    Full,
+}
+
+#[derive(clap::ValueEnum, Copy, Clone, Debug, PartialEq, Eq)]
+enum ListFormat {
+    /// A human readable table
+    Table,
+    /// A JSON array with an object for each patch
+    Json,
 }
 
 fn main() -> anyhow::Result<()> {
     let opt: GitPatcher = GitPatcher::parse();
-    let plain = slog_term::PlainSyncDecorator::new(std::io::stdout());
-    let logger = Logger::root(
-        std::sync::Mutex::new(slog_term::CompactFormat::new(plain).build()).fuse(),
-        slog::o!(),
+    if let Some(len) = opt.abbrev {
+        gitpatcher::set_abbrev_len(len);
+    }
+    // Keep the logs out of the way when the patches are written to stdout
+    let log_to_stderr = matches!(
+        opt.subcommand,
+        PatchSubcommand::RegeneratePatches(RegeneratePatchOpts { stdout: true, .. })
+    );
+    // JSON is meant to be read by other programs, which don't expect escape codes
+    let json = matches!(
+        opt.subcommand,
+        PatchSubcommand::List(ListOpts {
+            format: ListFormat::Json,
+            ..
+        })
     );
+    let is_terminal = if log_to_stderr {
+        std::io::stderr().is_terminal()
+    } else {
+        std::io::stdout().is_terminal()
+    };
+    let logger = if !json && opt.color.enabled(is_terminal) {
+        let decorator = slog_term::TermDecorator::new().force_color();
+        let decorator = if log_to_stderr {
+            decorator.stderr()
+        } else {
+            decorator.stdout()
+        };
+        term_logger(decorator.build(), opt.log_format)
+    } else if log_to_stderr {
+        plain_logger(Box::new(std::io::stderr()), opt.log_format)
+    } else {
+        plain_logger(Box::new(std::io::stdout()), opt.log_format)
+    };
     match opt.subcommand {
         PatchSubcommand::ApplyPatch(opts) => apply_patch(opts),
         PatchSubcommand::RegeneratePatches(opts) => regenerate_patches(logger, opts),
         PatchSubcommand::ApplyAllPatches(opts) => apply_all_patches(logger, opts),
+        PatchSubcommand::CheckDrift(opts) => check_drift(opts),
+        PatchSubcommand::List(opts) => list_patches(opts),
+        PatchSubcommand::Dedup(opts) => dedup_patches(opts),
+        PatchSubcommand::LintNames(opts) => lint_names(opts),
+        #[cfg(feature = "watch")]
+        PatchSubcommand::Watch(opts) => watch_patches(logger, opts),
+    }
+}
+
+/// A logger that writes uncolored messages to the output
+fn plain_logger(output: Box<dyn std::io::Write + Send>, format: LogFormat) -> Logger {
+    term_logger(slog_term::PlainSyncDecorator::new(output), format)
+}
+
+/// A logger that renders the records in the specified format.
+///
+/// The drain is behind a mutex, so each record is written as a whole
+/// even when logging from multiple threads.
+fn term_logger<D: slog_term::Decorator + Send + 'static>(
+    decorator: D,
+    format: LogFormat,
+) -> Logger {
+    match format {
+        LogFormat::Compact => Logger::root(
+            std::sync::Mutex::new(slog_term::CompactFormat::new(decorator).build()).fuse(),
+            slog::o!(),
+        ),
+        LogFormat::Full => Logger::root(
+            std::sync::Mutex::new(slog_term::FullFormat::new(decorator).build()).fuse(),
+            slog::o!(),
+        ),
     }
 }
 
+/// Open the repository at the specified path,
+/// giving an actionable hint if that fails.
+fn open_repo(path: &Path, require_worktree: bool) -> anyhow::Result<Repository> {
+    let repo = match Repository::open(path) {
+        Ok(repo) => repo,
+        Err(cause) if !path.exists() => {
+            return Err(cause).with_context(|| format!("Path does not exist: {}", path.display()))
+        }
+        Err(cause) => {
+            let hint = match Repository::discover(path) {
+                Ok(parent) => {
+                    let root = parent.workdir().unwrap_or(parent.path());
+                    format!(" (the nearest repository is {})", root.display())
+                }
+                Err(_) => String::new(),
+            };
+            return Err(cause)
+                .with_context(|| format!("Not a git repository: {}{hint}", path.display()));
+        }
+    };
+    if require_worktree && repo.is_bare() {
+        anyhow::bail!(
+            "Expected a worktree, but {} is a bare repository",
+            path.display()
+        );
+    }
+    Ok(repo)
+}
+
+/// Open a linked worktree of the repository, given either its name or its path.
+///
+/// The returned repository uses the index, `HEAD` and files of that worktree,
+/// leaving every other checkout alone.
+fn open_worktree(repo: &Repository, worktree: &Path) -> anyhow::Result<Repository> {
+    let requested = std::fs::canonicalize(worktree).ok();
+    for name in repo.worktrees()?.iter().flatten() {
+        let linked = repo.find_worktree(name)?;
+        if worktree == Path::new(name)
+            || (requested.is_some() && std::fs::canonicalize(linked.path()).ok() == requested)
+        {
+            return Repository::open_from_worktree(&linked)
+                .with_context(|| format!("Unable to open worktree {name:?}"));
+        }
+    }
+    anyhow::bail!(
+        "{} is not a linked worktree of {}",
+        worktree.display(),
+        repo.path().display()
+    )
+}
+
 fn apply_all_patches(logger: Logger, opts: ApplyAllPatches) -> anyhow::Result<()> {
-    let target = Repository::open(&opts.target_repo).with_context(|| {
-        format!(
-            "Unable to access target repo: {}",
-            opts.target_repo.display()
-        )
-    })?;
-    let bulk_apply = BulkPatchApply::new(&logger, &target, opts.patch_dir);
+    let mut target = open_repo(&opts.target_repo, opts.worktree.is_none())
+        .context("Unable to access target repo")?;
+    if let Some(ref worktree) = opts.worktree {
+        target = open_worktree(&target, worktree).context("Unable to access worktree")?;
+    }
+    let mut bulk_apply = BulkPatchApply::new(&logger, &target, opts.patch_dir);
+    bulk_apply.load_config().context("Invalid git config")?;
+    bulk_apply.set_max_patch_size(opts.max_patch_size);
+    bulk_apply.set_strict_eof(opts.strict_eof);
+    bulk_apply.set_exclude(ExcludePatterns::new(&opts.exclude).context("Invalid --exclude glob")?);
+    bulk_apply.set_sniff(opts.sniff);
+    bulk_apply.set_squash(opts.squash);
+    bulk_apply.set_in_memory(opts.in_memory);
+    bulk_apply.set_count(opts.count);
+    if let Some(ref path) = opts.author_map {
+        bulk_apply.set_author_map(AuthorMap::load(path)?);
+    }
+    bulk_apply.set_fetch_upstream(opts.fetch);
+    bulk_apply.set_transactional(opts.transactional);
+    if opts.verbatim {
+        bulk_apply.set_message_cleanup(CleanupMode::None);
+    }
+    if opts.interactive {
+        let workdir = target.workdir().expect("Worktree required").to_path_buf();
+        let editor = editor();
+        bulk_apply.set_conflict_handler(Box::new(move |name, rejected| {
+            eprintln!("Conflicts applying {name}");
+            resolve_conflicts(&workdir, rejected, &editor)
+        }));
+    }
+    if let (true, Some(upstream)) = (opts.dry_run, &opts.upstream) {
+        let preview = bulk_apply
+            .reset_upstream_preview(upstream)
+            .context("Failed to preview reset to upstream")?;
+        if preview.is_empty() {
+            println!("Resetting to {upstream:?} would not change any files");
+        }
+        for path in &preview.changed {
+            println!("Would reset {}", path.display());
+        }
+        for path in &preview.removed_untracked {
+            println!("Would remove {}", path.display());
+        }
+        return Ok(());
+    }
     if let Some(ref upstream) = opts.upstream {
         bulk_apply.reset_upstream(upstream).with_context(|| {
             format!(
@@ -94,48 +479,988 @@ fn apply_all_patches(logger: Logger, opts: ApplyAllPatches) -> anyhow::Result<()
     Ok(())
 }
 
+/// Read a file, failing if its size exceeds the limit (if any).
+///
+/// Reading stops one byte after the limit, in case the file grows
+/// (or isn't a regular file), so a huge file is never buffered in memory.
+fn read_limited(path: &Path, limit: Option<u64>) -> anyhow::Result<Vec<u8>> {
+    let file = std::fs::File::open(path).context("Unable to read patch")?;
+    if let Some(limit) = limit {
+        let size = file.metadata().context("Unable to read patch")?.len();
+        if size > limit {
+            return Err(InvalidEmailMessage::PatchTooLarge { size, limit }.into());
+        }
+    }
+    let mut contents = Vec::new();
+    file.take(limit.map_or(u64::MAX, |limit| limit.saturating_add(1)))
+        .read_to_end(&mut contents)
+        .context("Unable to read patch")?;
+    Ok(contents)
+}
+
 fn apply_patch(opts: ApplyPatchOpts) -> anyhow::Result<()> {
+    let contents = read_limited(&opts.patch_file, opts.max_patch_size)?;
+    let message = EmailMessage::parse_bytes_limited(&contents, opts.max_patch_size)
+        .context("Error parsing patch")?;
+    if opts.strict_eof {
+        // Already known to be valid UTF-8
+        check_trailing_garbage(std::str::from_utf8(&contents)?).context("Error parsing patch")?;
+    }
+    if opts.stat {
+        print!("{}", render_stat(&message)?);
+        return Ok(());
+    }
     let target_repo = match opts.target_repo {
         Some(location) => location,
         None => env::current_dir().context("Unable to detect current dir")?,
     };
-    let target_repo = Repository::open(&target_repo)
-        .with_context(|| format!("Unable to access target repo {}", target_repo.display(),))?;
-    let message = std::fs::read_to_string(&opts.patch_file).context("Unable to read patch")?;
-    let message = EmailMessage::parse(&message).context("Error parsing patch")?;
-    message
-        .apply_commit(&target_repo)
-        .context("Unable to apply patch")?;
+    let mut target_repo =
+        open_repo(&target_repo, opts.worktree.is_none()).context("Unable to access target repo")?;
+    if let Some(ref worktree) = opts.worktree {
+        target_repo = open_worktree(&target_repo, worktree).context("Unable to access worktree")?;
+    }
+    let config = target_repo.config()?;
+    let mut apply_opts = ApplyOptions::from_config(&config).context("Invalid git config")?;
+    if opts.config_committer {
+        let committer = ApplyOptions::config_committer(&config).context("Invalid git config")?;
+        apply_opts.committer =
+            Some(committer.context("Missing `user.name` or `user.email` for the committer")?);
+    }
+    if opts.interactive {
+        apply_interactive(&target_repo, &message, apply_opts, &editor())?;
+    } else {
+        apply_opts.three_way |= opts.three_way;
+        message
+            .apply_commit_with(&target_repo, &apply_opts)
+            .context("Unable to apply patch")?;
+    }
     println!("Applied: {}", opts.patch_file.display());
     Ok(())
 }
 
+/// Render the changes of a patch as a diffstat, like `git apply --stat`.
+fn render_stat(message: &EmailMessage) -> anyhow::Result<String> {
+    let stats = message.stats().context("Unable to compute diffstat")?;
+    let buf = stats
+        .to_buf(git2::DiffStatsFormat::FULL, 80)
+        .context("Unable to format diffstat")?;
+    Ok(String::from_utf8_lossy(&buf).into_owned())
+}
+
+/// The editor used to resolve conflicts, with the same precedence as git
+fn editor() -> String {
+    ["GIT_EDITOR", "VISUAL", "EDITOR"]
+        .iter()
+        .find_map(|var| env::var(var).ok().filter(|editor| !editor.is_empty()))
+        .unwrap_or_else(|| "vi".into())
+}
+
+/// Apply the patch, letting the user resolve any conflicts in their editor.
+fn apply_interactive(
+    target: &Repository,
+    email: &EmailMessage,
+    apply_opts: ApplyOptions,
+    editor: &str,
+) -> anyhow::Result<()> {
+    let apply_opts = ApplyOptions {
+        leave_conflicts_in_worktree: true,
+        three_way: false,
+        ..apply_opts
+    };
+    match email.apply_commit_with(target, &apply_opts) {
+        Ok(()) => Ok(()),
+        Err(PatchApplyError::LeftInWorktree { rejected }) => {
+            let workdir = target.workdir().context("Expected a worktree")?;
+            if !resolve_conflicts(workdir, &rejected, editor).context("Unable to run editor")? {
+                anyhow::bail!("Editor failed, conflicts are left in the worktree");
+            }
+            email
+                .commit_resolved(target, &apply_opts)
+                .context("Unable to commit resolved conflicts")
+        }
+        Err(cause) => Err(cause).context("Unable to apply patch"),
+    }
+}
+
+/// Open the conflicted files in the editor, and wait for the user to resolve them.
+///
+/// Any `.rej` files are removed afterwards.
+/// Returns `true` if the editor exited successfully.
+fn resolve_conflicts(
+    workdir: &Path,
+    rejected: &[DeltaDesc],
+    editor: &str,
+) -> std::io::Result<bool> {
+    let mut files = Vec::new();
+    let mut reject_files = Vec::new();
+    for delta in rejected {
+        if let Some(path) = delta.new_path().or(delta.old_path()) {
+            files.push(workdir.join(path));
+            let reject_file = workdir.join(format!("{path}.rej"));
+            if reject_file.exists() {
+                files.push(reject_file.clone());
+                reject_files.push(reject_file);
+            }
+        }
+    }
+    eprintln!("Resolve the conflicts in:");
+    for file in &files {
+        eprintln!("  {}", file.display());
+    }
+    // Run through the shell, so the editor can include arguments (like git does)
+    let status = std::process::Command::new("sh")
+        .arg("-c")
+        .arg(format!("{editor} \"$@\""))
+        .arg(editor)
+        .args(&files)
+        .current_dir(workdir)
+        .status()?;
+    for reject_file in reject_files {
+        std::fs::remove_file(reject_file)?;
+    }
+    Ok(status.success())
+}
+
+fn check_drift(opts: CheckDriftOpts) -> anyhow::Result<()> {
+    let repo = open_repo(&opts.repo, false).context("Unable to access repo")?;
+    let upstream = resolve_commitish(&repo, &opts.upstream)
+        .with_context(|| format!("Unable to resolve upstream ref {:?}", opts.upstream))?;
+    let (patch_files, patches) = read_patch_dir(&opts.patch_dir)?;
+    let statuses = check_series_drift(&repo, &upstream.tree()?, &patches)
+        .context("Failed to check patches")?;
+    for (path, status) in patch_files.iter().zip(statuses) {
+        println!("{}: {status}", file_name(path).display());
+    }
+    Ok(())
+}
+
+fn dedup_patches(opts: DedupOpts) -> anyhow::Result<()> {
+    let (patch_files, patches) = read_patch_dir(&opts.patch_dir)?;
+    let duplicates = find_duplicates(&patches).context("Failed to compare patches")?;
+    if duplicates.is_empty() {
+        println!("No duplicate patches");
+    }
+    for group in duplicates {
+        let names = group
+            .iter()
+            .map(|&index| file_name(&patch_files[index]).display().to_string())
+            .collect::<Vec<_>>();
+        println!("Same changes: {}", names.join(", "));
+    }
+    Ok(())
+}
+
+fn lint_names(opts: LintNamesOpts) -> anyhow::Result<()> {
+    let (patch_files, patches) = read_patch_dir(&opts.patch_dir)?;
+    let mut mismatched = 0;
+    for (path, patch) in patch_files.iter().zip(&patches) {
+        let name = file_name(path).to_string_lossy();
+        let expected = expected_patch_name(&name, patch)
+            .with_context(|| format!("Invalid subject in {name}"))?;
+        let Some(expected) = expected.filter(|expected| *expected != name) else {
+            continue;
+        };
+        if opts.fix {
+            let new_path = path.with_file_name(&expected);
+            if new_path.exists() {
+                anyhow::bail!("Unable to rename {name}, {expected} already exists");
+            }
+            std::fs::rename(path, &new_path)
+                .with_context(|| format!("Unable to rename {name} to {expected}"))?;
+            println!("Renamed {name} to {expected}");
+        } else {
+            println!("{name}: expected {expected}");
+            mismatched += 1;
+        }
+    }
+    if mismatched > 0 {
+        anyhow::bail!(
+            "{mismatched} patch names don't match their subjects, use --fix to rename them"
+        );
+    }
+    Ok(())
+}
+
+/// Read and parse every `.patch` file in the directory, sorted by path.
+fn read_patch_dir(patch_dir: &Path) -> anyhow::Result<(Vec<PathBuf>, Vec<EmailMessage>)> {
+    let mut patch_files = std::fs::read_dir(patch_dir)
+        .and_then(|entries| {
+            entries
+                .map(|entry| entry.map(|entry| entry.path()))
+                .collect::<Result<Vec<_>, _>>()
+        })
+        .with_context(|| format!("Unable to read patch dir {}", patch_dir.display()))?;
+    patch_files.retain(|path| path.extension() == Some("patch".as_ref()));
+    patch_files.sort();
+    let patches = patch_files
+        .iter()
+        .map(|path| {
+            let contents = std::fs::read(path)
+                .with_context(|| format!("Unable to read {}", path.display()))?;
+            EmailMessage::parse_bytes(&contents)
+                .with_context(|| format!("Error parsing {}", path.display()))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    Ok((patch_files, patches))
+}
+
+fn file_name(path: &Path) -> &Path {
+    Path::new(path.file_name().unwrap_or(path.as_os_str()))
+}
+
 fn regenerate_patches(logger: Logger, opts: RegeneratePatchOpts) -> anyhow::Result<()> {
-    let patched_repo = Repository::open(&opts.patched_repo).with_context(|| {
-        format!(
-            "Unable to access patched repo: {}",
-            opts.patched_repo.display()
-        )
-    })?;
-    let upstream_obj = patched_repo
-        .resolve_reference_from_short_name(&opts.upstream)
-        .and_then(|reference| reference.peel(ObjectType::Any))
+    let patched_repo =
+        open_repo(&opts.patched_repo, false).context("Unable to access patched repo")?;
+    if opts.fetch {
+        fetch_missing_remote_ref(&patched_repo, &opts.upstream)
+            .with_context(|| format!("Unable to fetch upstream ref {:?}", opts.upstream))?;
+    }
+    let mut upstream_commit = resolve_commitish(&patched_repo, &opts.upstream)
         .with_context(|| format!("Unable to resolve upstream ref {:?}", opts.upstream))?;
-    let base_repo =
-        Repository::discover(&opts.patch_dir).context("Unable to discover repo for patch dir")?;
+    if opts.merge_base {
+        upstream_commit = merge_base_with_head(&patched_repo, &upstream_commit)?;
+    }
+    let mut format_opts =
+        FormatOptions::from_config(&patched_repo.config()?).context("Invalid git config")?;
+    format_opts.reroll_count = opts.reroll_count;
+    if let Some(prefix) = &opts.subject_prefix {
+        format_opts.subject_prefix = prefix.clone();
+    }
+    format_opts.ignore_merges = opts.ignore_merges;
+    format_opts.rename_threshold = opts.rename_threshold;
+    format_opts.since = opts.since;
+    format_opts.until = opts.until;
+    if opts.stdout {
+        let mut formatter = PatchFormatter::new(
+            logger,
+            opts.patch_dir,
+            &patched_repo,
+            upstream_commit,
+            format_opts,
+        )?;
+        formatter
+            .generate_mbox(&mut std::io::stdout().lock())
+            .context("Failed to format patches")?;
+        return Ok(());
+    }
+    let base_repo = open_base_repo(
+        opts.patch_dir.as_std_path(),
+        opts.base_repo.as_deref(),
+        &patched_repo,
+    )?;
     let mut patches =
         PatchFileSet::load(&base_repo, &opts.patch_dir).context("Unable to load patches")?;
-    let upstream_commit = upstream_obj.as_commit().with_context(|| {
-        format!("Upstream ref must be either a tree or a commit: {upstream_obj:?}")
-    })?;
+    let regenerate_opts = RegenerateOptions {
+        format_opts,
+        strict: opts.strict,
+        timings: opts.timings,
+        exclude: ExcludePatterns::new(&opts.exclude).context("Invalid --exclude glob")?,
+        allow_empty: opts.allow_empty,
+        ..Default::default()
+    };
     ::gitpatcher::regenerate_patches::regenerate_patches(
-        upstream_commit,
+        &upstream_commit,
         &mut patches,
         &patched_repo,
         logger.clone(),
-        Default::default(),
+        regenerate_opts,
     )
     .context("Failed to regenerate patches")?;
     println!("Success!");
     Ok(())
 }
+
+/// The merge-base of `HEAD` and the upstream commit, used as the base of the patches.
+fn merge_base_with_head<'repo>(
+    repo: &'repo Repository,
+    upstream: &Commit<'repo>,
+) -> anyhow::Result<Commit<'repo>> {
+    let head = repo.head()?.peel_to_commit()?;
+    let merge_base = repo
+        .merge_base(head.id(), upstream.id())
+        .with_context(|| format!("HEAD has no common history with upstream {}", upstream.id()))?;
+    Ok(repo.find_commit(merge_base)?)
+}
+
+/// Regenerate the patches, then regenerate them again whenever HEAD or a branch moves.
+///
+/// Failures are reported without stopping the watch,
+/// since the repo is often in the middle of being changed.
+#[cfg(feature = "watch")]
+fn watch_patches(logger: Logger, opts: WatchOpts) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        !opts.regenerate.stdout,
+        "Can't write the patches to stdout when watching"
+    );
+    let git_dir = open_repo(&opts.regenerate.patched_repo, false)
+        .context("Unable to access patched repo")?
+        .path()
+        .to_path_buf();
+    let regenerate = || {
+        if let Err(e) = regenerate_patches(logger.clone(), opts.regenerate.clone()) {
+            eprintln!("{e:#}");
+        }
+    };
+    regenerate();
+    println!("Watching {} for changes", git_dir.display());
+    watch::watch_refs(
+        &git_dir,
+        std::time::Duration::from_millis(opts.debounce_ms),
+        || {
+            regenerate();
+            std::ops::ControlFlow::Continue(())
+        },
+    )
+}
+
+/// Open the repository that the patch directory is committed to,
+/// either the explicit `base_repo` or the one discovered from the patch directory.
+///
+/// Discovery walks up from the patch directory, so it can find the patched repo
+/// (or some enclosing repo) if the patch directory isn't in a repository of its own.
+/// Since the patches are staged in this repo, a discovered repo is only trusted
+/// if it contains the patched repo, like a project checkout with the patches alongside it.
+fn open_base_repo(
+    patch_dir: &Path,
+    base_repo: Option<&Path>,
+    patched_repo: &Repository,
+) -> anyhow::Result<Repository> {
+    let repo = match base_repo {
+        Some(path) => open_repo(path, true).context("Unable to access base repo")?,
+        None => Repository::discover(patch_dir).context("Unable to discover repo for patch dir")?,
+    };
+    let Some(workdir) = repo.workdir() else {
+        anyhow::bail!(
+            "Expected a worktree, but {} is a bare repository",
+            repo.path().display()
+        );
+    };
+    let canonical_patch_dir = patch_dir
+        .canonicalize()
+        .with_context(|| format!("Unable to access patch dir {}", patch_dir.display()))?;
+    if !canonical_patch_dir.starts_with(workdir.canonicalize()?) {
+        anyhow::bail!(
+            "Patch dir {} is not inside the base repo {}",
+            patch_dir.display(),
+            workdir.display()
+        );
+    }
+    // Storing the patches alongside the code is supported, but has to be asked for explicitly,
+    // since it's usually a mistake (like passing the patched repo in place of the patch dir)
+    if base_repo.is_none() && repo.path().canonicalize()? == patched_repo.path().canonicalize()? {
+        anyhow::bail!(
+            "Patch dir {} is inside the patched repo, use --base-repo to specify where to stage the patches \
+            (pass the patched repo itself to keep the patches alongside the code)",
+            patch_dir.display()
+        );
+    }
+    if base_repo.is_none() {
+        let patched_workdir = patched_repo.workdir().unwrap_or(patched_repo.path());
+        if !patched_workdir
+            .canonicalize()?
+            .starts_with(workdir.canonicalize()?)
+        {
+            anyhow::bail!(
+                "Patch dir {} is inside the repo {}, which doesn't contain the patched repo {}, \
+                use --base-repo to confirm where to stage the patches",
+                patch_dir.display(),
+                workdir.display(),
+                patched_workdir.display()
+            );
+        }
+    }
+    Ok(repo)
+}
+
+/// Parse a date in one of the formats git accepts for `--since`.
+///
+/// Dates without a timezone are in UTC.
+fn parse_date(s: &str) -> anyhow::Result<OffsetDateTime> {
+    use time::format_description::well_known::{Rfc2822, Rfc3339};
+    use time::macros::format_description;
+    use time::{Date, Duration, PrimitiveDateTime};
+    let s = s.trim();
+    if let Some(timestamp) = s.strip_prefix('@') {
+        return Ok(OffsetDateTime::from_unix_timestamp(timestamp.parse()?)?);
+    }
+    if let Some(relative) = s.strip_suffix(" ago") {
+        let (count, unit) = relative
+            .split_once(' ')
+            .with_context(|| format!("Invalid relative date: {s:?}"))?;
+        let count: i32 = count.parse()?;
+        let unit = match unit.strip_suffix('s').unwrap_or(unit) {
+            "second" => Duration::SECOND,
+            "minute" => Duration::MINUTE,
+            "hour" => Duration::HOUR,
+            "day" => Duration::DAY,
+            "week" => Duration::WEEK,
+            "month" => Duration::days(30),
+            "year" => Duration::days(365),
+            _ => anyhow::bail!("Unknown unit {unit:?} in relative date {s:?}"),
+        };
+        return Ok(OffsetDateTime::now_utc() - unit * count);
+    }
+    if let Ok(date) =
+        OffsetDateTime::parse(s, &Rfc3339).or_else(|_| OffsetDateTime::parse(s, &Rfc2822))
+    {
+        return Ok(date);
+    }
+    if let Ok(date) = PrimitiveDateTime::parse(
+        s,
+        format_description!("[year]-[month]-[day] [hour]:[minute]:[second]"),
+    ) {
+        return Ok(date.assume_utc());
+    }
+    let date = Date::parse(s, format_description!("[year]-[month]-[day]"))
+        .with_context(|| format!("Unsupported date format: {s:?}"))?;
+    Ok(date.midnight().assume_utc())
+}
+
+fn list_patches(opts: ListOpts) -> anyhow::Result<()> {
+    let base_repo =
+        Repository::discover(&opts.patch_dir).context("Unable to discover repo for patch dir")?;
+    let patches =
+        PatchFileSet::load(&base_repo, &opts.patch_dir).context("Unable to load patches")?;
+    print!("{}", render_patch_list(&patches, opts.format)?);
+    Ok(())
+}
+
+/// A summary of a single patch, as printed by the `list` subcommand
+#[derive(serde::Serialize)]
+struct PatchListing {
+    index: usize,
+    name: String,
+    subject: String,
+    author: String,
+    files_changed: usize,
+    insertions: usize,
+    deletions: usize,
+    binary: bool,
+}
+
+fn render_patch_list(patches: &PatchFileSet, format: ListFormat) -> anyhow::Result<String> {
+    use std::fmt::Write;
+    let listings = patches
+        .patches()
+        .iter()
+        .map(|patch| {
+            let path = patch.path();
+            let contents = patch
+                .read()
+                .with_context(|| format!("Unable to read {path}"))?;
+            let email = EmailMessage::parse_bytes(&contents)
+                .with_context(|| format!("Error parsing {path}"))?;
+            let stats = email.stats()?;
+            Ok(PatchListing {
+                index: patch.index(),
+                name: path.file_name().unwrap_or(path.as_str()).into(),
+                subject: email.summary().into(),
+                author: format!("{} <{}>", email.author_name(), email.author_email()),
+                files_changed: stats.files_changed(),
+                insertions: stats.insertions(),
+                deletions: stats.deletions(),
+                binary: patch
+                    .touches_binary()
+                    .with_context(|| format!("Unable to read {path}"))?,
+            })
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let mut out = String::new();
+    match format {
+        ListFormat::Table => {
+            let name_width = listings.iter().map(|l| l.name.len()).max().unwrap_or(0);
+            let author_width = listings.iter().map(|l| l.author.len()).max().unwrap_or(0);
+            for l in &listings {
+                let files = if l.files_changed == 1 {
+                    "file"
+                } else {
+                    "files"
+                };
+                let stats = format!(
+                    "{} {files} +{} -{}",
+                    l.files_changed, l.insertions, l.deletions
+                );
+                let binary = if l.binary { " (binary)" } else { "" };
+                writeln!(
+                    out,
+                    "{:>4}  {:name_width$}  {stats:<18}  {:author_width$}  {}{binary}",
+                    l.index, l.name, l.author, l.subject
+                )?;
+            }
+        }
+        ListFormat::Json => {
+            out = serde_json::to_string_pretty(&listings)?;
+            out.push('\n');
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        apply_interactive, lint_names, merge_base_with_head, open_base_repo, open_repo,
+        open_worktree, parse_date, plain_logger, read_limited, render_patch_list, render_stat,
+        ColorChoice, LintNamesOpts, ListFormat, LogFormat,
+    };
+    use gitpatcher::apply_patches::{ApplyOptions, EmailMessage};
+
+    /// Shares the logged bytes with the test
+    #[derive(Clone, Default)]
+    struct Output(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+    impl std::io::Write for Output {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+    impl Output {
+        fn logged(&self) -> String {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+        }
+    }
+
+    fn open_err(path: &std::path::Path, require_worktree: bool) -> String {
+        match open_repo(path, require_worktree) {
+            Ok(_) => panic!("Expected error opening {}", path.display()),
+            Err(e) => format!("{e:#}"),
+        }
+    }
+
+    #[test]
+    fn parse_dates() {
+        let expected = 1_692_921_600;
+        for date in [
+            "2023-08-25",
+            "2023-08-25 00:00:00",
+            "2023-08-25T00:00:00Z",
+            "Fri, 25 Aug 2023 00:00:00 +0000",
+            "@1692921600",
+        ] {
+            assert_eq!(
+                parse_date(date).unwrap().unix_timestamp(),
+                expected,
+                "{date}"
+            );
+        }
+        let now = time::OffsetDateTime::now_utc();
+        let elapsed: time::Duration = now - parse_date("2 weeks ago").unwrap();
+        assert!((elapsed - time::Duration::weeks(2)).abs() < time::Duration::MINUTE);
+        assert!(parse_date("next tuesday").is_err());
+    }
+
+    #[test]
+    fn read_limited_patch() {
+        let dir: std::path::PathBuf = testdir::testdir!();
+        let path = dir.join("huge.patch");
+        std::fs::write(&path, "x".repeat(1000)).unwrap();
+        let err = read_limited(&path, Some(10)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Patch is 1000 bytes, exceeding the limit of 10 bytes"
+        );
+        assert_eq!(read_limited(&path, Some(1000)).unwrap().len(), 1000);
+        assert_eq!(read_limited(&path, None).unwrap().len(), 1000);
+    }
+
+    #[test]
+    fn date_filters_require_stdout() {
+        use clap::Parser;
+        let parse = |extra: &[&str]| {
+            let args = [
+                "gitpatcher",
+                "regenerate-patches",
+                "patched",
+                "upstream",
+                "patches",
+            ];
+            super::GitPatcher::try_parse_from(args.iter().chain(extra))
+        };
+        // Writing only part of the series to the patch dir would drop the other patches
+        assert!(parse(&["--since", "2023-08-25"]).is_err());
+        assert!(parse(&["--until", "2023-08-25"]).is_err());
+        assert!(parse(&["--since", "2023-08-25", "--stdout"]).is_ok());
+    }
+
+    #[test]
+    fn open_repo_hints() {
+        let dir: std::path::PathBuf = testdir::testdir!();
+        let missing = dir.join("missing");
+        assert!(open_err(&missing, true).starts_with("Path does not exist"));
+
+        let not_repo = dir.join("not_repo");
+        std::fs::create_dir(&not_repo).unwrap();
+        assert!(open_err(&not_repo, true).starts_with("Not a git repository"));
+
+        let worktree = dir.join("worktree");
+        git2::Repository::init(&worktree).unwrap();
+        let nested = worktree.join("nested");
+        std::fs::create_dir(&nested).unwrap();
+        let err = open_err(&nested, true);
+        assert!(
+            err.contains(&format!("the nearest repository is {}", worktree.display())),
+            "{err}"
+        );
+
+        let bare = dir.join("bare.git");
+        git2::Repository::init_bare(&bare).unwrap();
+        assert!(open_err(&bare, true).contains("is a bare repository"));
+        assert!(open_repo(&bare, false).is_ok());
+    }
+
+    #[test]
+    fn color_choice() {
+        assert!(ColorChoice::Auto.enabled(true));
+        assert!(!ColorChoice::Auto.enabled(false));
+        assert!(ColorChoice::Always.enabled(false));
+        assert!(!ColorChoice::Never.enabled(true));
+
+        let output = Output::default();
+        let logger = plain_logger(Box::new(output.clone()), LogFormat::Compact);
+        slog::error!(logger, "Failed");
+        slog::warn!(logger, "Careful");
+        drop(logger);
+        let logged = output.logged();
+        assert!(logged.contains("Careful"), "{logged}");
+        assert!(!logged.contains('\x1b'), "{logged:?}");
+    }
+
+    #[test]
+    fn concurrent_log_records() {
+        let output = Output::default();
+        let logger = plain_logger(Box::new(output.clone()), LogFormat::Full);
+        std::thread::scope(|scope| {
+            for thread in 0..8 {
+                let logger = logger.new(slog::o!("thread" => thread));
+                scope.spawn(move || {
+                    for patch_index in 0..50 {
+                        slog::info!(
+                            logger,
+                            "Generating patch";
+                            "patch_index" => patch_index,
+                            "patch_name" => format!("{thread}-{patch_index}"),
+                        );
+                    }
+                });
+            }
+        });
+        drop(logger);
+        let logged = output.logged();
+        let lines = logged.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 8 * 50, "{logged}");
+        for line in lines {
+            // Every line is a single record, with all of its own fields
+            assert_eq!(line.matches("Generating patch").count(), 1, "{line}");
+            let field = |key: &str| {
+                let start = line
+                    .find(&format!("{key}: "))
+                    .unwrap_or_else(|| panic!("{line}"));
+                line[start + key.len() + 2..]
+                    .split(',')
+                    .next()
+                    .unwrap()
+                    .to_string()
+            };
+            let expected_name = format!("{}-{}", field("thread"), field("patch_index"));
+            assert_eq!(field("patch_name"), expected_name, "{line}");
+        }
+    }
+
+    #[test]
+    fn base_repo_validation() {
+        let dir: std::path::PathBuf = testdir::testdir!();
+        let root = git2::Repository::init(dir.join("root")).unwrap();
+        let patched = git2::Repository::init(dir.join("root/patched")).unwrap();
+        let error = |patch_dir: &std::path::Path, base_repo: Option<&std::path::Path>| {
+            match open_base_repo(patch_dir, base_repo, &patched) {
+                Ok(repo) => panic!("Expected error, but found {}", repo.path().display()),
+                Err(e) => format!("{e:#}"),
+            }
+        };
+
+        // A patch dir nested inside the patched repo
+        let nested = dir.join("root/patched/patches");
+        std::fs::create_dir(&nested).unwrap();
+        let err = error(&nested, None);
+        assert!(err.contains("is inside the patched repo"), "{err}");
+        let err = error(&nested, Some(&dir.join("other")));
+        assert!(err.contains("Unable to access base repo"), "{err}");
+        // Unless the single-repo layout is explicitly requested
+        let repo = open_base_repo(&nested, Some(&dir.join("root/patched")), &patched).unwrap();
+        assert_eq!(
+            repo.path().canonicalize().unwrap(),
+            patched.path().canonicalize().unwrap()
+        );
+
+        let patch_dir = dir.join("root/patches");
+        std::fs::create_dir(&patch_dir).unwrap();
+        for base_repo in [None, Some(dir.join("root"))] {
+            let repo = open_base_repo(&patch_dir, base_repo.as_deref(), &patched).unwrap();
+            assert_eq!(
+                repo.path().canonicalize().unwrap(),
+                root.path().canonicalize().unwrap()
+            );
+        }
+
+        // A patch dir nested inside an unrelated parent repo
+        let parent = git2::Repository::init(dir.join("parent")).unwrap();
+        let unrelated = dir.join("parent/project/patches");
+        std::fs::create_dir_all(&unrelated).unwrap();
+        let err = error(&unrelated, None);
+        assert!(err.contains("doesn't contain the patched repo"), "{err}");
+        let err = error(&unrelated, Some(&dir.join("root")));
+        assert!(err.contains("is not inside the base repo"), "{err}");
+        let repo = open_base_repo(&unrelated, Some(&dir.join("parent")), &patched).unwrap();
+        assert_eq!(
+            repo.path().canonicalize().unwrap(),
+            parent.path().canonicalize().unwrap()
+        );
+    }
+
+    /// Commit a single file on top of HEAD
+    fn commit_file(
+        repo: &git2::Repository,
+        name: &str,
+        contents: &str,
+        message: &str,
+    ) -> git2::Oid {
+        std::fs::write(repo.workdir().unwrap().join(name), contents).unwrap();
+        let mut index = repo.index().unwrap();
+        index.add_path(std::path::Path::new(name)).unwrap();
+        index.write().unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let sig = git2::Signature::now("dummy", "dummy@dumb.gov").unwrap();
+        let parent = repo.head().ok().map(|head| head.peel_to_commit().unwrap());
+        let parents = parent.iter().collect::<Vec<_>>();
+        repo.commit(Some("HEAD"), &sig, &sig, message, &tree, &parents)
+            .unwrap()
+    }
+
+    /// Format the single commit on top of `base` into a patch
+    fn format_patch(
+        dir: &std::path::Path,
+        source: &git2::Repository,
+        base: git2::Oid,
+    ) -> EmailMessage {
+        use gitpatcher::format_patches::{FormatOptions, PatchFormatter};
+        let out_dir = camino::Utf8PathBuf::try_from(dir.join("patches")).unwrap();
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let mut formatter = PatchFormatter::new(
+            logger,
+            out_dir,
+            source,
+            source.find_commit(base).unwrap(),
+            FormatOptions::default(),
+        )
+        .unwrap();
+        formatter.generate_all().unwrap();
+        let patch = std::fs::read_to_string(&formatter.generated_patches()[0].path).unwrap();
+        EmailMessage::parse(&patch).unwrap()
+    }
+
+    #[test]
+    fn render_diffstat() {
+        let dir: std::path::PathBuf = testdir::testdir!();
+        let repo = git2::Repository::init(dir.join("repo")).unwrap();
+        commit_file(&repo, "a.txt", "a\nb\nc\n", "Initial");
+        let base = commit_file(&repo, "other.txt", "x\n", "Add other");
+        std::fs::write(dir.join("repo/a.txt"), "a\nB\nc\nd\n").unwrap();
+        std::fs::write(dir.join("repo/new.txt"), "new\n").unwrap();
+        let mut index = repo.index().unwrap();
+        index.add_path(std::path::Path::new("a.txt")).unwrap();
+        index.add_path(std::path::Path::new("new.txt")).unwrap();
+        index
+            .remove_path(std::path::Path::new("other.txt"))
+            .unwrap();
+        index.write().unwrap();
+        std::fs::remove_file(dir.join("repo/other.txt")).unwrap();
+        commit_file(&repo, "a.txt", "a\nB\nc\nd\n", "Change files");
+        let message = format_patch(&dir, &repo, base);
+        assert_eq!(
+            render_stat(&message).unwrap(),
+            " a.txt     | 3 ++-\n new.txt   | 1 +\n other.txt | 1 -\n \
+             3 files changed, 3 insertions(+), 2 deletions(-)\n"
+        );
+    }
+
+    #[test]
+    fn merge_base_of_diverged_history() {
+        let dir: std::path::PathBuf = testdir::testdir!();
+        let repo = git2::Repository::init(dir.join("repo")).unwrap();
+        let base = commit_file(&repo, "a.txt", "a\n", "Initial");
+        let upstream = commit_file(&repo, "upstream.txt", "upstream\n", "Upstream only");
+        let base_commit = repo.find_commit(base).unwrap();
+        repo.reset(base_commit.as_object(), git2::ResetType::Hard, None)
+            .unwrap();
+        commit_file(&repo, "a.txt", "fork\n", "Fork change");
+
+        let upstream = repo.find_commit(upstream).unwrap();
+        let merge_base = merge_base_with_head(&repo, &upstream).unwrap();
+        assert_eq!(merge_base.id(), base);
+        let email = format_patch(&dir.join("merge-base"), &repo, merge_base.id());
+        assert_eq!(email.summary(), "Fork change");
+        let diff = email.unified_diff().unwrap();
+        assert!(!diff.contains("upstream.txt"), "{diff}");
+        // Against upstream itself, the patch reverts the upstream-only commit
+        let email = format_patch(&dir.join("upstream"), &repo, upstream.id());
+        let diff = email.unified_diff().unwrap();
+        assert!(diff.contains("-upstream"), "{diff}");
+    }
+
+    #[test]
+    fn lint_mismatched_names() {
+        let dir: std::path::PathBuf = testdir::testdir!();
+        let source = git2::Repository::init(dir.join("source")).unwrap();
+        let base = commit_file(&source, "file.txt", "a\n", "Initial");
+        commit_file(&source, "file.txt", "b\n", "Something else");
+        format_patch(&dir, &source, base);
+        let patch_dir = dir.join("patches");
+        let lint = |fix| {
+            lint_names(LintNamesOpts {
+                patch_dir: patch_dir.clone(),
+                fix,
+            })
+        };
+        lint(false).unwrap();
+
+        std::fs::rename(
+            patch_dir.join("0001-Something-else.patch"),
+            patch_dir.join("0001-Fix-foo.patch"),
+        )
+        .unwrap();
+        let err = lint(false).unwrap_err();
+        assert!(
+            err.to_string().contains("1 patch names don't match"),
+            "{err}"
+        );
+        lint(true).unwrap();
+        assert!(patch_dir.join("0001-Something-else.patch").exists());
+        assert!(!patch_dir.join("0001-Fix-foo.patch").exists());
+        lint(false).unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn interactive_conflict_resolution() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir: std::path::PathBuf = testdir::testdir!();
+        let source = git2::Repository::init(dir.join("source")).unwrap();
+        let base = commit_file(&source, "file.txt", "a\nb\nc\n", "Initial");
+        commit_file(&source, "file.txt", "a\nB\nc\n", "Change b");
+        let email = format_patch(&dir, &source, base);
+
+        let target = git2::Repository::init(dir.join("target")).unwrap();
+        commit_file(&target, "file.txt", "a\nX\nc\n", "Diverged");
+        let editor = dir.join("editor.sh");
+        std::fs::write(
+            &editor,
+            "#!/bin/sh\nprintf 'a\\nresolved\\nc\\n' > \"$1\"\n",
+        )
+        .unwrap();
+        std::fs::set_permissions(&editor, std::fs::Permissions::from_mode(0o755)).unwrap();
+        apply_interactive(
+            &target,
+            &email,
+            ApplyOptions::default(),
+            editor.to_str().unwrap(),
+        )
+        .unwrap();
+
+        let head = target.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(head.summary(), Some("Change b"));
+        let entry = head.tree().unwrap().get_path("file.txt".as_ref()).unwrap();
+        let blob = target.find_blob(entry.id()).unwrap();
+        assert_eq!(blob.content(), b"a\nresolved\nc\n");
+        assert!(!target.workdir().unwrap().join("file.txt.rej").exists());
+    }
+
+    #[test]
+    fn apply_to_linked_worktree() {
+        let dir: std::path::PathBuf = testdir::testdir!();
+        let repo = git2::Repository::init(dir.join("main")).unwrap();
+        let base = commit_file(&repo, "file.txt", "a\nb\nc\n", "Initial");
+        commit_file(&repo, "file.txt", "a\nB\nc\n", "Change b");
+        let email = format_patch(&dir, &repo, base);
+        repo.reset(
+            &repo.find_object(base, None).unwrap(),
+            git2::ResetType::Hard,
+            None,
+        )
+        .unwrap();
+
+        let linked_path = dir.join("linked");
+        repo.worktree("linked", &linked_path, None).unwrap();
+        let by_name = open_worktree(&repo, "linked".as_ref()).unwrap();
+        assert_eq!(
+            by_name.workdir().unwrap().canonicalize().unwrap(),
+            linked_path.canonicalize().unwrap()
+        );
+        let linked = open_worktree(&repo, &linked_path).unwrap();
+        email.apply_commit(&linked).unwrap();
+
+        let linked_head = linked.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(linked_head.summary(), Some("Change b"));
+        assert_eq!(
+            std::fs::read_to_string(linked_path.join("file.txt")).unwrap(),
+            "a\nB\nc\n"
+        );
+        // The main checkout is untouched
+        assert_eq!(repo.head().unwrap().peel_to_commit().unwrap().id(), base);
+        assert_eq!(
+            std::fs::read_to_string(repo.workdir().unwrap().join("file.txt")).unwrap(),
+            "a\nb\nc\n"
+        );
+
+        let other = git2::Repository::init(dir.join("other")).unwrap();
+        assert!(open_worktree(&other, &linked_path).is_err());
+    }
+
+    #[test]
+    fn list_patches() {
+        use gitpatcher::regenerate_patches::PatchFileSet;
+        let dir: std::path::PathBuf = testdir::testdir!();
+        let repo = git2::Repository::init(dir.join("repo")).unwrap();
+        let base = commit_file(&repo, "file.txt", "a\n", "Initial");
+        commit_file(&repo, "file.txt", "b\n", "First \"change\"");
+        commit_file(&repo, "other.txt", "c\nd\n", "Second change");
+        // Store the patches alongside the code, where `list` discovers the repo
+        let repo_dir = dir.join("repo");
+        format_patch(&repo_dir, &repo, base);
+        let patch_dir = camino::Utf8PathBuf::try_from(repo_dir.join("patches")).unwrap();
+        let patches = PatchFileSet::load(&repo, &patch_dir).unwrap();
+
+        let table = render_patch_list(&patches, ListFormat::Table).unwrap();
+        let lines = table.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2, "{table}");
+        assert!(
+            lines[0].starts_with("   1  0001-First-change.patch"),
+            "{table}"
+        );
+        assert!(lines[0].ends_with("dummy <dummy@dumb.gov>  First \"change\""));
+        assert!(lines[0].contains("1 file +1 -1"), "{table}");
+        assert!(lines[1].contains("1 file +2 -0"), "{table}");
+        assert!(lines[1].ends_with("Second change"));
+        assert!(!table.contains("(binary)"), "{table}");
+
+        let json = render_patch_list(&patches, ListFormat::Json).unwrap();
+        let listings: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(
+            listings[0],
+            serde_json::json!({
+                "index": 1,
+                "name": "0001-First-change.patch",
+                "subject": "First \"change\"",
+                "author": "dummy <dummy@dumb.gov>",
+                "files_changed": 1,
+                "insertions": 1,
+                "deletions": 1,
+                "binary": false,
+            })
+        );
+        assert_eq!(listings[1]["subject"], "Second change");
+        assert_eq!(listings.as_array().unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,144 @@
+//! Watch the patched repository, regenerating the patches whenever its refs change.
+use std::ops::ControlFlow;
+use std::path::Path;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::Duration;
+
+use anyhow::Context;
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+
+/// If a changed path in the git directory means `HEAD` or a branch may have moved.
+///
+/// Lock files are ignored, since git renames them into place once the update is done.
+pub fn is_ref_change(git_dir: &Path, path: &Path) -> bool {
+    let Ok(relative) = path.strip_prefix(git_dir) else {
+        return false;
+    };
+    if relative.extension().is_some_and(|ext| ext == "lock") {
+        return false;
+    }
+    relative == Path::new("HEAD")
+        || relative == Path::new("packed-refs")
+        || relative.starts_with("refs")
+}
+
+/// Watch the git directory, calling `on_change` once the refs stop changing
+/// for the `debounce` period.
+///
+/// Runs until `on_change` breaks, or the watcher stops.
+pub fn watch_refs(
+    git_dir: &Path,
+    debounce: Duration,
+    mut on_change: impl FnMut() -> ControlFlow<()>,
+) -> anyhow::Result<()> {
+    // Events are reported with the canonical path
+    let git_dir = git_dir
+        .canonicalize()
+        .with_context(|| format!("Unable to access {}", git_dir.display()))?;
+    let (sender, receiver) = std::sync::mpsc::channel();
+    let mut watcher = notify::recommended_watcher(sender).context("Unable to create watcher")?;
+    watcher
+        .watch(&git_dir, RecursiveMode::Recursive)
+        .with_context(|| format!("Unable to watch {}", git_dir.display()))?;
+    while wait_for_change(&receiver, &git_dir, debounce)? {
+        if on_change().is_break() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Block until the refs change, then until no further changes arrive for the `debounce` period.
+///
+/// This avoids regenerating once per commit of a rebase or `git am`.
+/// Returns `false` if the watcher stopped.
+fn wait_for_change(
+    receiver: &Receiver<notify::Result<Event>>,
+    git_dir: &Path,
+    debounce: Duration,
+) -> anyhow::Result<bool> {
+    let is_relevant = |event: notify::Result<Event>| -> anyhow::Result<bool> {
+        let event = event.context("Failed to watch repo")?;
+        Ok(!matches!(event.kind, EventKind::Access(_))
+            && event.paths.iter().any(|path| is_ref_change(git_dir, path)))
+    };
+    loop {
+        let Ok(event) = receiver.recv() else {
+            return Ok(false);
+        };
+        if is_relevant(event)? {
+            break;
+        }
+    }
+    loop {
+        match receiver.recv_timeout(debounce) {
+            Ok(event) => {
+                is_relevant(event)?;
+            }
+            Err(RecvTimeoutError::Timeout) => return Ok(true),
+            Err(RecvTimeoutError::Disconnected) => return Ok(false),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{is_ref_change, watch_refs};
+    use std::ops::ControlFlow;
+    use std::path::Path;
+    use std::sync::mpsc::RecvTimeoutError;
+    use std::time::Duration;
+
+    #[test]
+    fn ref_changes() {
+        let git_dir = Path::new("/repo/.git");
+        assert!(is_ref_change(git_dir, &git_dir.join("HEAD")));
+        assert!(is_ref_change(git_dir, &git_dir.join("packed-refs")));
+        assert!(is_ref_change(git_dir, &git_dir.join("refs/heads/main")));
+        assert!(!is_ref_change(
+            git_dir,
+            &git_dir.join("refs/heads/main.lock")
+        ));
+        assert!(!is_ref_change(git_dir, &git_dir.join("index")));
+        assert!(!is_ref_change(git_dir, &git_dir.join("objects/ab/cdef")));
+        assert!(!is_ref_change(git_dir, Path::new("/repo/HEAD")));
+    }
+
+    #[test]
+    fn regenerate_on_commit() {
+        let dir: std::path::PathBuf = testdir::testdir!();
+        let repo = git2::Repository::init(&dir).unwrap();
+        let git_dir = repo.path().to_path_buf();
+        let (sender, receiver) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let res = watch_refs(&git_dir, Duration::from_millis(100), || {
+                ControlFlow::Break(())
+            });
+            let _ = sender.send(res);
+        });
+        let sig = git2::Signature::now("dummy", "dummy@dumb.gov").unwrap();
+        let tree = repo
+            .find_tree(repo.index().unwrap().write_tree().unwrap())
+            .unwrap();
+        // The watcher may not have started yet, so keep committing until it sees a change
+        for attempt in 0..20 {
+            let parent = repo.head().ok().map(|head| head.peel_to_commit().unwrap());
+            let message = format!("Commit {attempt}");
+            repo.commit(
+                Some("HEAD"),
+                &sig,
+                &sig,
+                &message,
+                &tree,
+                &Vec::from_iter(&parent),
+            )
+            .unwrap();
+            match receiver.recv_timeout(Duration::from_millis(500)) {
+                Ok(res) => return res.unwrap(),
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => panic!("Watcher stopped without a result"),
+            }
+        }
+        panic!("Watcher didn't report a change after 20 commits");
+    }
+}
@@ -0,0 +1,25 @@
+//! Feed arbitrary bytes to the patch parser, which must never panic.
+//!
+//! The corpus is seeded with patches covering renames, mode changes,
+//! binary files and missing trailing newlines.
+//! libgit2 leaks memory on some malformed headers, so run it without leak detection:
+//! `ASAN_OPTIONS=detect_leaks=0 cargo +nightly fuzz run parse_email`
+#![no_main]
+use gitpatcher::apply_patches::email::check_trailing_garbage;
+use gitpatcher::apply_patches::EmailMessage;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(msg) = EmailMessage::parse_bytes(data) {
+        // These walk the diff that libgit2 parsed
+        let _ = msg.deltas();
+        let _ = msg.stats();
+        let _ = msg.content_id();
+        if let Ok(text) = msg.to_patch_string() {
+            let _ = EmailMessage::parse(&text);
+        }
+    }
+    if let Ok(text) = std::str::from_utf8(data) {
+        let _ = check_trailing_garbage(text);
+    }
+});
@@ -1,4 +1,26 @@
+#[cfg(feature = "git")]
+pub mod author_map;
+#[cfg(feature = "git")]
 pub mod bulk;
+#[cfg(feature = "git")]
+pub mod dedup;
+#[cfg(feature = "git")]
+pub mod drift;
 pub mod email;
+#[cfg(feature = "git")]
+pub mod hooks;
+#[cfg(feature = "git")]
+mod hunks;
+#[cfg(feature = "git")]
+mod options;
 
+#[cfg(feature = "git")]
+pub use self::author_map::AuthorMap;
 pub use self::email::EmailMessage;
+#[cfg(feature = "git")]
+pub use self::hunks::{Candidate, OffsetResolver};
+#[cfg(feature = "git")]
+pub use self::options::{
+    ApplyEngine, ApplyOptions, BlobWriter, CleanupMode, IgnoredPathCheck, InvalidApplyOptions,
+    WhitespaceAction,
+};
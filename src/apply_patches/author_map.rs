@@ -0,0 +1,144 @@
+//! Rewrite the authors of applied patches to different identities.
+//!
+//! Used to implement the `--author-map` option of the CLI.
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// Maps the authors of patches to new identities, before they are committed.
+///
+/// Unlike a `.mailmap` (see [ApplyOptions::use_mailmap](super::ApplyOptions::use_mailmap)),
+/// which only canonicalizes the identities in the target repository,
+/// this can replace any identity, like the authors of a series imported from elsewhere.
+///
+/// The map is written as CSV, with the columns `old name,old email,new name,new email`.
+/// Blank lines and lines starting with `#` are ignored.
+/// An empty old name matches any author with that email.
+/// Emails are compared case-insensitively, like a `.mailmap`.
+/// Quoting isn't supported, so the names and emails can't contain commas.
+#[derive(Debug, Clone, Default)]
+pub struct AuthorMap {
+    /// The new identities for each lowercase old email, along with the old name they require
+    entries: HashMap<String, Vec<(Option<String>, Identity)>>,
+}
+/// The name and email of an author
+#[derive(Debug, Clone, Eq, PartialEq)]
+struct Identity {
+    name: String,
+    email: String,
+}
+impl AuthorMap {
+    /// Parse the mapping from a CSV file.
+    pub fn load(path: &Path) -> Result<Self, InvalidAuthorMap> {
+        let text = std::fs::read_to_string(path).map_err(|cause| InvalidAuthorMap::Io {
+            path: path.into(),
+            cause,
+        })?;
+        Self::parse(&text)
+    }
+    /// Parse the mapping from the contents of a CSV file.
+    pub fn parse(text: &str) -> Result<Self, InvalidAuthorMap> {
+        let mut map = AuthorMap::default();
+        for (line_idx, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let columns = line.split(',').map(str::trim).collect::<Vec<_>>();
+            let [old_name, old_email, new_name, new_email] = columns[..] else {
+                return Err(InvalidAuthorMap::InvalidLine {
+                    line: line_idx + 1,
+                    content: line.into(),
+                });
+            };
+            if old_email.is_empty() || new_name.is_empty() || new_email.is_empty() {
+                return Err(InvalidAuthorMap::InvalidLine {
+                    line: line_idx + 1,
+                    content: line.into(),
+                });
+            }
+            map.insert(
+                (!old_name.is_empty()).then_some(old_name),
+                old_email,
+                new_name,
+                new_email,
+            );
+        }
+        Ok(map)
+    }
+    /// Map the author with the specified email (and optionally name) to a new identity.
+    ///
+    /// An entry for the exact name takes precedence over one matching any name.
+    pub fn insert(&mut self, old_name: Option<&str>, old_email: &str, name: &str, email: &str) {
+        let entries = self.entries.entry(old_email.to_lowercase()).or_default();
+        let old_name = old_name.map(String::from);
+        entries.retain(|(existing, _)| *existing != old_name);
+        entries.push((
+            old_name,
+            Identity {
+                name: name.into(),
+                email: email.into(),
+            },
+        ));
+    }
+    /// The new name and email of the author, or `None` if they aren't mapped.
+    pub fn resolve(&self, name: &str, email: &str) -> Option<(&str, &str)> {
+        let entries = self.entries.get(&email.to_lowercase())?;
+        let exact = entries
+            .iter()
+            .find(|(old_name, _)| old_name.as_deref() == Some(name));
+        let (_, identity) =
+            exact.or_else(|| entries.iter().find(|(old_name, _)| old_name.is_none()))?;
+        Some((&identity.name, &identity.email))
+    }
+    /// If there are no entries in the map
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// Indicates that an [AuthorMap] couldn't be loaded.
+#[derive(Debug, thiserror::Error)]
+pub enum InvalidAuthorMap {
+    #[error("Unable to read author map {}: {cause}", path.display())]
+    Io {
+        path: PathBuf,
+        #[source]
+        cause: std::io::Error,
+    },
+    #[error("Expected `old name,old email,new name,new email` on line {line}: {content:?}")]
+    InvalidLine { line: usize, content: String },
+}
+
+#[cfg(test)]
+mod test {
+    use super::AuthorMap;
+
+    #[test]
+    fn resolve() {
+        let map = AuthorMap::parse(
+            "\
+# old name,old email,new name,new email
+Upstream Dev, dev@upstream.org, Jane Doe, jane@example.com
+,DEV@upstream.org,Other,other@example.com
+
+,bot@upstream.org,CI Bot,ci@example.com
+",
+        )
+        .unwrap();
+        assert_eq!(
+            map.resolve("Upstream Dev", "dev@upstream.org"),
+            Some(("Jane Doe", "jane@example.com"))
+        );
+        assert_eq!(
+            map.resolve("Someone Else", "Dev@Upstream.org"),
+            Some(("Other", "other@example.com"))
+        );
+        assert_eq!(
+            map.resolve("Bot", "bot@upstream.org"),
+            Some(("CI Bot", "ci@example.com"))
+        );
+        assert_eq!(map.resolve("Upstream Dev", "jane@example.com"), None);
+        assert!(AuthorMap::parse("a,b,c\n").is_err());
+        assert!(AuthorMap::parse("a,,c,d\n").is_err());
+    }
+}
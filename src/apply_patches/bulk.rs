@@ -1,21 +1,83 @@
 //! Apply an entire set of patches in bulk.
 //!
 //! Used to implement the the `apply-all-patches` command in the CLI.
-use std::ffi::OsStr;
-use std::io;
+use std::borrow::Cow;
+use std::io::{self, BufRead, BufReader, Read};
 use std::path::PathBuf;
 
 use git2::build::CheckoutBuilder;
-use git2::{ObjectType, Repository, ResetType};
+use git2::{Oid, Repository, ResetType};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
 
-use super::email::EmailMessage;
+use super::email::{
+    check_patch_size, is_patch_header, line_of_offset, DeltaDesc, EmailMessage, PatchApplyError,
+};
+use super::{ApplyOptions, AuthorMap, CleanupMode, WhitespaceAction};
+use crate::compression::{read_patch_file, strip_patch_extension};
+use crate::format_patches::decode_message;
+use crate::resolve::{
+    fetch_missing_remote_ref, resolve_commitish, FetchRemoteError, ResolveCommitishError,
+};
 use crate::utils;
+use crate::utils::log::ShortId;
 
 pub struct BulkPatchApply<'repo> {
     logger: slog::Logger,
     target_repo: &'repo Repository,
     patch_dir: PathBuf,
+    max_patch_size: Option<u64>,
+    fetch_upstream: bool,
+    conflict_handler: Option<ConflictHandler>,
+    transactional: bool,
+    strict_eof: bool,
+    message_cleanup: CleanupMode,
+    three_way: bool,
+    whitespace: WhitespaceAction,
+    exclude: ExcludePatterns,
+    author_map: Option<AuthorMap>,
+    squash: bool,
+    in_memory: bool,
+    sniff: bool,
+    count: Option<usize>,
 }
+
+/// Globs matching the file names of patches to skip,
+/// like a known-broken patch that shouldn't be moved out of the patch directory.
+///
+/// The globs use the same syntax as `.gitignore`, like `0003-*` or `*-wip.patch`.
+#[derive(Clone)]
+pub struct ExcludePatterns {
+    matcher: Gitignore,
+}
+impl ExcludePatterns {
+    pub fn new<S: AsRef<str>>(globs: impl IntoIterator<Item = S>) -> Result<Self, ignore::Error> {
+        let mut builder = GitignoreBuilder::new("");
+        for glob in globs {
+            builder.add_line(None, glob.as_ref())?;
+        }
+        Ok(ExcludePatterns {
+            matcher: builder.build()?,
+        })
+    }
+    /// If the patch file with the specified name should be skipped
+    pub fn matches(&self, file_name: &str) -> bool {
+        self.matcher.matched(file_name, false).is_ignore()
+    }
+}
+impl Default for ExcludePatterns {
+    fn default() -> Self {
+        ExcludePatterns {
+            matcher: Gitignore::empty(),
+        }
+    }
+}
+
+/// A function that resolves conflicts left in the worktree by a patch.
+///
+/// It receives the name of the patch and the deltas that couldn't be applied,
+/// and returns `true` if the conflicts were resolved.
+/// See [BulkPatchApply::set_conflict_handler].
+pub type ConflictHandler = Box<dyn FnMut(&str, &[DeltaDesc]) -> io::Result<bool>>;
 impl<'repo> BulkPatchApply<'repo> {
     pub fn new(logger: &slog::Logger, target_repo: &'repo Repository, patch_dir: PathBuf) -> Self {
         use utils::log::LogPathValue;
@@ -27,25 +89,154 @@ impl<'repo> BulkPatchApply<'repo> {
             logger,
             target_repo,
             patch_dir,
+            max_patch_size: None,
+            fetch_upstream: false,
+            conflict_handler: None,
+            transactional: false,
+            strict_eof: false,
+            message_cleanup: CleanupMode::default(),
+            three_way: false,
+            whitespace: WhitespaceAction::NoWarn,
+            exclude: ExcludePatterns::default(),
+            author_map: None,
+            squash: false,
+            in_memory: false,
+            sniff: false,
+            count: None,
         }
     }
+    /// Reject any patch file larger than the specified number of bytes.
+    ///
+    /// The size is checked before the patch is read,
+    /// which guards against exhausting memory with untrusted patches.
+    /// By default, patches can be any size.
+    pub fn set_max_patch_size(&mut self, limit: Option<u64>) {
+        self.max_patch_size = limit;
+    }
+    /// Reject patches with unexpected content after their last hunk,
+    /// like the footer of a mailing list.
+    ///
+    /// See [EmailMessage::parse_strict].
+    pub fn set_strict_eof(&mut self, strict: bool) {
+        self.strict_eof = strict;
+    }
+    /// Clean up the commit messages of the patches before committing them.
+    ///
+    /// The default is the same as [ApplyOptions::message_cleanup],
+    /// so use [CleanupMode::None] to keep the exact blank lines of the messages
+    /// when applying a series and regenerating it.
+    pub fn set_message_cleanup(&mut self, mode: CleanupMode) {
+        self.message_cleanup = mode;
+    }
+    /// Fall back to a 3-way merge when a patch doesn't apply.
+    ///
+    /// See [ApplyOptions::three_way].
+    pub fn set_three_way(&mut self, three_way: bool) {
+        self.three_way = three_way;
+    }
+    /// What to do with trailing whitespace in the lines added by the patches.
+    ///
+    /// See [ApplyOptions::whitespace].
+    pub fn set_whitespace(&mut self, action: WhitespaceAction) {
+        self.whitespace = action;
+    }
+    /// Use the settings from the config of the target repository,
+    /// like `git am` and `apply-patch` do.
+    ///
+    /// This reads the keys that [ApplyOptions::from_config] supports.
+    pub fn load_config(&mut self) -> Result<(), git2::Error> {
+        let opts = ApplyOptions::from_config(&self.target_repo.config()?)?;
+        self.message_cleanup = opts.message_cleanup;
+        self.three_way = opts.three_way;
+        self.whitespace = opts.whitespace;
+        Ok(())
+    }
+    /// Skip the patch files that match the patterns in [BulkPatchApply::apply_all].
+    ///
+    /// A warning is logged for each skipped patch,
+    /// since the patches after it may depend on its changes.
+    pub fn set_exclude(&mut self, exclude: ExcludePatterns) {
+        self.exclude = exclude;
+    }
+    /// Rewrite the authors of the patches before committing them.
+    ///
+    /// See [ApplyOptions::author_map].
+    pub fn set_author_map(&mut self, author_map: AuthorMap) {
+        self.author_map = Some(author_map);
+    }
+    /// Fetch the upstream reference in [BulkPatchApply::reset_upstream]
+    /// if it is a remote-tracking reference that is missing locally.
+    pub fn set_fetch_upstream(&mut self, fetch: bool) {
+        self.fetch_upstream = fetch;
+    }
+    /// Handle conflicts by leaving them in the worktree and calling the specified function.
+    ///
+    /// If the handler resolves the conflicts,
+    /// the resolved worktree is committed and the remaining patches are applied.
+    /// See [ApplyOptions::leave_conflicts_in_worktree] and [EmailMessage::commit_resolved].
+    pub fn set_conflict_handler(&mut self, handler: ConflictHandler) {
+        self.conflict_handler = Some(handler);
+    }
+    /// Stage the combined changes of all the patches, without committing any of them.
+    ///
+    /// Each patch is applied in memory on top of the previous ones,
+    /// then the cumulative result is checked out into the index and worktree,
+    /// leaving `HEAD` untouched.
+    /// If a patch fails to apply (including conflicts with an earlier patch),
+    /// nothing is changed, so the [conflict handler](BulkPatchApply::set_conflict_handler) isn't used.
+    /// Local changes to the affected files also make the checkout fail, instead of being overwritten.
+    pub fn set_squash(&mut self, squash: bool) {
+        self.squash = squash;
+    }
+    /// Include files without an extension if they look like patches.
+    ///
+    /// Patches saved from an email client often lack the `.patch` extension,
+    /// so the first line of each extensionless file is checked for the
+    /// `From <sha> Mon Sep 17 00:00:00 2001` header that starts every patch.
+    /// The whole file name is used as the name of the patch.
+    pub fn set_sniff(&mut self, sniff: bool) {
+        self.sniff = sniff;
+    }
+    /// Commit the patches in memory, checking out the result only once at the end.
+    ///
+    /// Normally each patch is committed on top of `HEAD` and then checked out,
+    /// round-tripping through the index and worktree for every patch.
+    /// Instead, each patch is applied to the tree of the previous commit,
+    /// which is much faster for a long series.
+    /// If a patch fails, the patches before it are still checked out (unless the apply is
+    /// [transactional](BulkPatchApply::set_transactional)),
+    /// but conflicts are never left in the worktree, so the
+    /// [conflict handler](BulkPatchApply::set_conflict_handler) isn't used.
+    pub fn set_in_memory(&mut self, in_memory: bool) {
+        self.in_memory = in_memory;
+    }
+    /// Only apply the first `count` patches, leaving the rest unapplied.
+    ///
+    /// The patches are counted in sorted order, after skipping any
+    /// [excluded](BulkPatchApply::set_exclude) ones, and the first unapplied patch is logged.
+    /// This is useful to find which patch of a series breaks the build, like `git bisect`.
+    /// By default, all the patches are applied.
+    pub fn set_count(&mut self, count: Option<usize>) {
+        self.count = count;
+    }
+    /// Apply the patches as a single transaction.
+    ///
+    /// If any patch fails, `HEAD` and the worktree are reset back to
+    /// the commit they pointed at before applying the first patch,
+    /// so either all the patches are applied or none of them are.
+    pub fn set_transactional(&mut self, transactional: bool) {
+        self.transactional = transactional;
+    }
     /// Reset the target repository to the specified upstream reference.
     ///
     /// This should be done _before_ applying the patches.
     /// It is used to implement the `--upstream` option for the command line.
     pub fn reset_upstream(&self, upstream_name: &str) -> Result<(), ResetUpstreamError> {
-        let obj = self
-            .target_repo
-            .resolve_reference_from_short_name(upstream_name)
-            .and_then(|reference| reference.peel(ObjectType::Any))
-            .map_err(|cause| ResetUpstreamError::InvalidReference {
-                upstream_name: upstream_name.into(),
-                cause,
-            })?;
+        let commit = self.resolve_upstream(upstream_name)?;
         let mut checkout = CheckoutBuilder::new();
         checkout.remove_untracked(true);
         self.target_repo
-            .reset(&obj, ResetType::Hard, Some(&mut checkout))
+            .reset(commit.as_object(), ResetType::Hard, Some(&mut checkout))
             .map_err(|cause| ResetUpstreamError::FailedReset {
                 upstream_name: upstream_name.into(),
                 cause,
@@ -56,8 +247,63 @@ impl<'repo> BulkPatchApply<'repo> {
         );
         Ok(())
     }
+    /// Determine what [BulkPatchApply::reset_upstream] would discard, without resetting anything.
+    ///
+    /// The upstream reference is still fetched if [BulkPatchApply::set_fetch_upstream] is enabled,
+    /// since that doesn't touch the worktree.
+    pub fn reset_upstream_preview(
+        &self,
+        upstream_name: &str,
+    ) -> Result<ResetPreview, ResetUpstreamError> {
+        let commit = self.resolve_upstream(upstream_name)?;
+        let failed_preview = |cause| ResetUpstreamError::FailedPreview {
+            upstream_name: upstream_name.into(),
+            cause,
+        };
+        let tree = commit.tree().map_err(failed_preview)?;
+        let diff = self
+            .target_repo
+            .diff_tree_to_workdir_with_index(Some(&tree), None)
+            .map_err(failed_preview)?;
+        let changed = diff
+            .deltas()
+            .filter_map(|delta| delta.new_file().path().or(delta.old_file().path()))
+            .map(PathBuf::from)
+            .collect::<Vec<_>>();
+        let mut status_opts = git2::StatusOptions::new();
+        status_opts
+            .include_untracked(true)
+            .recurse_untracked_dirs(true);
+        let statuses = self
+            .target_repo
+            .statuses(Some(&mut status_opts))
+            .map_err(failed_preview)?;
+        let removed_untracked = statuses
+            .iter()
+            .filter(|entry| entry.status().is_wt_new())
+            .filter_map(|entry| entry.path().map(PathBuf::from))
+            .filter(|path| !changed.contains(path))
+            .collect();
+        Ok(ResetPreview {
+            changed,
+            removed_untracked,
+        })
+    }
+    fn resolve_upstream(
+        &self,
+        upstream_name: &str,
+    ) -> Result<git2::Commit<'repo>, ResetUpstreamError> {
+        if self.fetch_upstream && fetch_missing_remote_ref(self.target_repo, upstream_name)? {
+            slog::info!(self.logger, "Fetched upstream"; "upstream" => upstream_name);
+        }
+        resolve_commitish(self.target_repo, upstream_name).map_err(|cause| {
+            ResetUpstreamError::InvalidReference {
+                upstream_name: upstream_name.into(),
+                cause,
+            }
+        })
+    }
     /// Apply all the patches in the directory.
-    // TODO: Consider splitting into multiple functions?
     pub fn apply_all(self) -> Result<(), BulkApplyError> {
         let entries = std::fs::read_dir(&self.patch_dir).map_err(|cause| {
             BulkApplyError::ErrorAccessPatchDir {
@@ -65,16 +311,6 @@ impl<'repo> BulkPatchApply<'repo> {
                 patch_dir: self.patch_dir.clone(),
             }
         })?;
-        /*
-         * TODO: Avoid buffering all these patches in-memory
-         *
-         * Buffering the metadata is fine, but we don't want to do the whole thing.
-         */
-        struct BufferedPatch {
-            patch_name: String,
-            patch_file: PathBuf,
-            email: EmailMessage,
-        }
         let mut patch_files = Vec::new();
         for entry in entries {
             let entry = entry.map_err(|cause| BulkApplyError::ErrorAccessPatchDir {
@@ -82,8 +318,10 @@ impl<'repo> BulkPatchApply<'repo> {
                 patch_dir: self.patch_dir.clone(),
             })?;
             let full_patch_path = entry.path();
-            // Implicitly skip directory entries that do not end with '.patch'
-            if full_patch_path.extension() != Some(OsStr::new("patch")) {
+            // Implicitly skip directory entries that do not end with '.patch' (or a compressed variant)
+            if strip_patch_extension(&entry.file_name().to_string_lossy()).is_none()
+                && !self.sniff_patch(&entry)?
+            {
                 slog::debug!(
                     self.logger,
                     "Skipping non-patch directory entry";
@@ -97,60 +335,317 @@ impl<'repo> BulkPatchApply<'repo> {
                 .map_err(|invalid_file_name| BulkApplyError::PatchNameInvalidUtf8 {
                     raw_entry: PathBuf::from(invalid_file_name),
                 })?;
-            let patch_name = file_name
-                .strip_suffix(".patch")
-                .unwrap_or_else(|| panic!("Patch file doesn't end with `.patch`: {file_name:?}"));
-            let patch_file_contents =
-                String::from_utf8(std::fs::read(&full_patch_path).map_err(|cause| {
-                    BulkApplyError::FailedReadPatch {
-                        cause,
-                        patch_file: full_patch_path.clone(),
-                    }
-                })?)
-                .map_err(|cause| BulkApplyError::PatchContentsInvalidUtf8 {
+            let patch_name = strip_patch_extension(&file_name).unwrap_or(&file_name);
+            if self.exclude.matches(&file_name) {
+                slog::warn!(
+                    self.logger,
+                    "Skipping excluded patch {}, later patches may fail to apply without it",
+                    file_name
+                );
+                continue;
+            }
+            patch_files.push((patch_name.to_string(), full_patch_path));
+        }
+        /*
+         * TODO: Special handling for patch numbering?
+         *
+         * Maybe the implicit handling by the sort function is enough.
+         */
+        patch_files.sort_by(|(first, _), (second, _)| first.cmp(second));
+        self.apply_files(patch_files)
+    }
+    /// Check if an extensionless directory entry looks like a patch,
+    /// if [sniffing](BulkPatchApply::set_sniff) is enabled.
+    fn sniff_patch(&self, entry: &std::fs::DirEntry) -> Result<bool, BulkApplyError> {
+        let path = entry.path();
+        if !self.sniff || path.extension().is_some() {
+            return Ok(false);
+        }
+        let failed = |cause| BulkApplyError::FailedReadPatch {
+            cause,
+            patch_file: path.clone(),
+        };
+        if !entry.file_type().map_err(failed)?.is_file() {
+            return Ok(false);
+        }
+        let file = std::fs::File::open(&path).map_err(failed)?;
+        let mut first_line = Vec::new();
+        // The header is only 64 bytes, so don't read too much of non-patches
+        BufReader::new(file.take(128))
+            .read_until(b'\n', &mut first_line)
+            .map_err(failed)?;
+        let matches = is_patch_header(&first_line);
+        if matches {
+            slog::debug!(
+                self.logger,
+                "Including extensionless patch";
+                "full_path" => path.display(),
+            );
+        }
+        Ok(matches)
+    }
+    /// Apply the specified patch files in order,
+    /// given the name of each patch (without the `.patch` extension) and its path.
+    pub(crate) fn apply_files(
+        mut self,
+        mut patch_files: Vec<(String, PathBuf)>,
+    ) -> Result<(), BulkApplyError> {
+        if let Some(count) = self.count {
+            if let Some((next_name, _)) = patch_files.get(count) {
+                slog::info!(
+                    self.logger,
+                    "Stopping after {} of {} patches, before {}",
+                    count,
+                    patch_files.len(),
+                    next_name
+                );
+                patch_files.truncate(count);
+            }
+        }
+        /*
+         * TODO: Avoid buffering all these patches in-memory
+         *
+         * Buffering the metadata is fine, but we don't want to do the whole thing.
+         */
+        struct BufferedPatch {
+            patch_name: String,
+            patch_file: PathBuf,
+            email: EmailMessage,
+        }
+        let mut patches = Vec::with_capacity(patch_files.len());
+        for (patch_name, full_patch_path) in patch_files {
+            let patch_size = std::fs::metadata(&full_patch_path)
+                .map_err(|cause| BulkApplyError::FailedReadPatch {
                     cause,
                     patch_file: full_patch_path.clone(),
-                })?;
-            let email = EmailMessage::parse(&patch_file_contents).map_err(|cause| {
+                })?
+                .len();
+            check_patch_size(patch_size, self.max_patch_size).map_err(|cause| {
                 BulkApplyError::FailedParsePatch {
                     patch_file: full_patch_path.clone(),
                     cause,
                 }
             })?;
-            patch_files.push(BufferedPatch {
+            let patch_file_contents = read_patch_file(&full_patch_path, self.max_patch_size)
+                .map_err(|cause| BulkApplyError::FailedReadPatch {
+                    cause,
+                    patch_file: full_patch_path.clone(),
+                })?;
+            // Compressed patches can be much larger than their files
+            check_patch_size(patch_file_contents.len() as u64, self.max_patch_size).map_err(
+                |cause| BulkApplyError::FailedParsePatch {
+                    patch_file: full_patch_path.clone(),
+                    cause,
+                },
+            )?;
+            let decoded = match decode_message(&patch_file_contents) {
+                Cow::Owned(decoded) => Some(decoded),
+                Cow::Borrowed(_) => None,
+            };
+            let patch_file_contents = decoded.unwrap_or(patch_file_contents);
+            let patch_file_contents = String::from_utf8(patch_file_contents).map_err(|cause| {
+                let offset = cause.utf8_error().valid_up_to();
+                BulkApplyError::PatchContentsInvalidUtf8 {
+                    offset,
+                    line: line_of_offset(cause.as_bytes(), offset),
+                    cause,
+                    patch_file: full_patch_path.clone(),
+                }
+            })?;
+            let email = match self.strict_eof {
+                true => EmailMessage::parse_strict(&patch_file_contents),
+                false => EmailMessage::parse(&patch_file_contents),
+            }
+            .map_err(|cause| BulkApplyError::FailedParsePatch {
+                patch_file: full_patch_path.clone(),
+                cause,
+            })?;
+            patches.push(BufferedPatch {
                 email,
                 patch_file: full_patch_path,
-                patch_name: patch_name.into(),
+                patch_name,
             });
         }
-        /*
-         * TODO: Special handling for patch numbering?
-         *
-         * Maybe the implicit handling by the sort function is enough.
-         */
-        patch_files.sort_by(|first, second| first.patch_name.cmp(&second.patch_name));
-        for patch in &patch_files {
+        if self.squash {
+            let mut tree = self
+                .target_repo
+                .head()
+                .and_then(|head| head.peel_to_tree())
+                .map_err(|cause| BulkApplyError::MissingStartCommit { cause })?;
+            for (patch_index, patch) in patches.iter().enumerate() {
+                slog::info!(
+                    self.logger,
+                    "Squashing patch";
+                    "patch_index" => patch_index + 1,
+                    "patch_name" => &patch.patch_name,
+                    "patch_file" => patch.patch_file.display()
+                );
+                tree = patch
+                    .email
+                    .build_tree(self.target_repo, &tree, &self.apply_options())
+                    .map_err(|cause| BulkApplyError::FailedApplyPatch {
+                        name: patch.patch_name.clone(),
+                        cause,
+                    })?;
+            }
+            self.target_repo
+                .checkout_tree(tree.as_object(), None)
+                .map_err(|cause| BulkApplyError::FailedCheckoutSquashed { cause })?;
+            slog::info!(
+                self.logger,
+                "Successfully staged {} squashed patches!",
+                patches.len()
+            );
+            return Ok(());
+        }
+        let start = match self.transactional {
+            true => Some(
+                self.target_repo
+                    .head()
+                    .and_then(|head| head.peel_to_commit())
+                    .map_err(|cause| BulkApplyError::MissingStartCommit { cause })?
+                    .id(),
+            ),
+            false => None,
+        };
+        if self.in_memory {
+            let mut commit = self
+                .target_repo
+                .head()
+                .and_then(|head| head.peel_to_commit())
+                .map_err(|cause| BulkApplyError::MissingStartCommit { cause })?;
+            let opts = self.apply_options();
+            let mut failure = None;
+            for (patch_index, patch) in patches.iter().enumerate() {
+                slog::info!(
+                    self.logger,
+                    "Applying patch";
+                    "patch_index" => patch_index + 1,
+                    "patch_name" => &patch.patch_name,
+                    "patch_file" => patch.patch_file.display()
+                );
+                match patch.email.commit_onto(self.target_repo, &commit, &opts) {
+                    Ok(next) => commit = next,
+                    Err(cause) => {
+                        failure = Some(BulkApplyError::FailedApplyPatch {
+                            name: patch.patch_name.clone(),
+                            cause,
+                        });
+                        break;
+                    }
+                }
+            }
+            // A failed transaction leaves `HEAD` at the start commit
+            if failure.is_none() || start.is_none() {
+                self.checkout_commit(&commit)
+                    .map_err(|cause| BulkApplyError::FailedCheckoutCommits { cause })?;
+            }
+            if let Some(error) = failure {
+                return Err(error);
+            }
+            slog::info!(
+                self.logger,
+                "Successfully applied {} patches!",
+                patches.len()
+            );
+            return Ok(());
+        }
+        for (patch_index, patch) in patches.iter().enumerate() {
             slog::info!(
                 self.logger,
                 "Applying patch";
+                "patch_index" => patch_index + 1,
                 "patch_name" => &patch.patch_name,
                 "patch_file" => patch.patch_file.display()
             );
-            patch
-                .email
-                .apply_commit(self.target_repo)
-                .map_err(|cause| BulkApplyError::FailedApplyPatch {
-                    cause,
-                    name: patch.patch_name.clone(),
-                })?;
+            if let Err(cause) = self.apply_patch(&patch.patch_name, &patch.email) {
+                return Err(match start {
+                    Some(start) => self.rollback(start, cause),
+                    None => cause,
+                });
+            }
         }
         slog::info!(
             self.logger,
             "Successfully applied {} patches!",
-            patch_files.len()
+            patches.len()
         );
         Ok(())
     }
+    fn apply_patch(&mut self, name: &str, email: &EmailMessage) -> Result<(), BulkApplyError> {
+        let failed = |cause| BulkApplyError::FailedApplyPatch {
+            cause,
+            name: name.into(),
+        };
+        let opts = ApplyOptions {
+            leave_conflicts_in_worktree: self.conflict_handler.is_some(),
+            ..self.apply_options()
+        };
+        match (
+            email.apply_commit_with(self.target_repo, &opts),
+            &mut self.conflict_handler,
+        ) {
+            (Ok(()), _) => Ok(()),
+            (Err(PatchApplyError::LeftInWorktree { rejected }), Some(handler)) => {
+                let resolved = handler(name, &rejected).map_err(|cause| {
+                    BulkApplyError::ConflictHandlerFailed {
+                        name: name.into(),
+                        cause,
+                    }
+                })?;
+                if !resolved {
+                    return Err(failed(PatchApplyError::LeftInWorktree { rejected }));
+                }
+                email
+                    .commit_resolved(self.target_repo, &opts)
+                    .map_err(failed)
+            }
+            (Err(cause), _) => Err(failed(cause)),
+        }
+    }
+    /// The options used to apply each patch, however they are committed.
+    fn apply_options(&self) -> ApplyOptions {
+        ApplyOptions {
+            message_cleanup: self.message_cleanup,
+            three_way: self.three_way,
+            whitespace: self.whitespace,
+            author_map: self.author_map.clone(),
+            ..Default::default()
+        }
+    }
+    /// Point `HEAD` at a commit created in memory, then check it out.
+    fn checkout_commit(&self, commit: &git2::Commit) -> Result<(), git2::Error> {
+        let mut head = self.target_repo.head()?;
+        if head.target() == Some(commit.id()) {
+            return Ok(());
+        }
+        head.set_target(commit.id(), "gitpatcher: apply patches in memory")?;
+        self.target_repo
+            .reset(commit.as_object(), ResetType::Hard, None)
+    }
+    /// Reset `HEAD` and the worktree back to the start commit after an error.
+    fn rollback(&self, start: Oid, error: BulkApplyError) -> BulkApplyError {
+        slog::warn!(
+            self.logger, "Rolling back after failure";
+            "start" => %ShortId(start),
+        );
+        let res = self.target_repo.head().and_then(|mut head| {
+            head.set_target(start, "gitpatcher: rollback failed apply")?;
+            let commit = self.target_repo.find_commit(start)?;
+            let mut checkout = CheckoutBuilder::new();
+            checkout.force();
+            self.target_repo
+                .reset(commit.as_object(), ResetType::Hard, Some(&mut checkout))
+        });
+        match res {
+            Ok(()) => error,
+            Err(cause) => BulkApplyError::RollbackFailed {
+                start,
+                original: Box::new(error),
+                cause,
+            },
+        }
+    }
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -170,9 +665,16 @@ pub enum BulkApplyError {
         #[source]
         cause: io::Error,
     },
-    #[error("Patch contents are not valid UTF8: {}", patch_file.display())]
+    #[error(
+        "Patch contents are not valid UTF8 at byte {offset} (line {line}): {}",
+        patch_file.display()
+    )]
     PatchContentsInvalidUtf8 {
         patch_file: PathBuf,
+        /// The offset of the first invalid byte, starting from zero
+        offset: usize,
+        /// The line containing the first invalid byte, starting from one
+        line: usize,
         #[source]
         cause: std::string::FromUtf8Error,
     },
@@ -182,6 +684,34 @@ pub enum BulkApplyError {
         #[source]
         cause: super::email::InvalidEmailMessage,
     },
+    #[error("Unable to determine the starting commit for a transactional or squashed apply")]
+    MissingStartCommit {
+        #[source]
+        cause: git2::Error,
+    },
+    #[error("Failed to check out the squashed patches")]
+    FailedCheckoutSquashed {
+        #[source]
+        cause: git2::Error,
+    },
+    #[error("Failed to check out the patches applied in memory")]
+    FailedCheckoutCommits {
+        #[source]
+        cause: git2::Error,
+    },
+    #[error("Failed to roll back to {start} after error: {original}")]
+    RollbackFailed {
+        start: git2::Oid,
+        original: Box<BulkApplyError>,
+        #[source]
+        cause: git2::Error,
+    },
+    #[error("Failed to resolve conflicts in patch: {name:?}")]
+    ConflictHandlerFailed {
+        name: String,
+        #[source]
+        cause: io::Error,
+    },
     #[error("Failed to apply patch: {name:?}")]
     FailedApplyPatch {
         name: String,
@@ -190,6 +720,27 @@ pub enum BulkApplyError {
     },
 }
 
+/// The changes that [BulkPatchApply::reset_upstream] would make to the target repository.
+///
+/// Returned by [BulkPatchApply::reset_upstream_preview].
+/// All paths are relative to the root of the worktree.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ResetPreview {
+    /// Files that differ from the upstream reference,
+    /// including local modifications that would be lost.
+    pub changed: Vec<PathBuf>,
+    /// Untracked files that would be deleted.
+    ///
+    /// Ignored files are left alone by the reset, and aren't included.
+    pub removed_untracked: Vec<PathBuf>,
+}
+impl ResetPreview {
+    /// If the reset wouldn't change anything.
+    pub fn is_empty(&self) -> bool {
+        self.changed.is_empty() && self.removed_untracked.is_empty()
+    }
+}
+
 /// An error that occurs in [BulkPatchApply::reset_upstream].
 ///
 /// This is seperated from the main error type,
@@ -199,6 +750,14 @@ pub enum BulkApplyError {
 pub enum ResetUpstreamError {
     #[error("Unable to resolve reference: {upstream_name:?}")]
     InvalidReference {
+        upstream_name: String,
+        #[source]
+        cause: ResolveCommitishError,
+    },
+    #[error(transparent)]
+    FailedFetch(#[from] FetchRemoteError),
+    #[error("Failed to determine the changes from resetting to {upstream_name:?}")]
+    FailedPreview {
         upstream_name: String,
         #[source]
         cause: git2::Error,
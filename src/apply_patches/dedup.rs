@@ -0,0 +1,51 @@
+//! Find patches in a series that make the same change.
+//!
+//! Used to implement the `dedup` command in the CLI.
+use std::collections::HashMap;
+
+use git2::{ObjectType, Oid};
+
+use super::email::{EmailMessage, EmailSerializeError};
+
+impl EmailMessage {
+    /// A hash of the changes made by this patch, ignoring whitespace and line numbers.
+    ///
+    /// Like `git patch-id`, patches that make the same change have the same id,
+    /// even if their hunks are at different offsets or their `index` lines differ.
+    /// The commit message and author aren't included.
+    pub fn content_id(&self) -> Result<Oid, EmailSerializeError> {
+        let diff = self.unified_diff()?;
+        let mut normalized = Vec::with_capacity(diff.len());
+        for line in diff.lines() {
+            if line.starts_with("index ") {
+                continue;
+            }
+            let line = if line.starts_with("@@ ") { "@@" } else { line };
+            normalized.extend(line.bytes().filter(|b| !b.is_ascii_whitespace()));
+            normalized.push(b'\n');
+        }
+        Ok(Oid::hash_object(ObjectType::Blob, &normalized)?)
+    }
+}
+
+/// Group the patches of a series that make the same change,
+/// according to their [EmailMessage::content_id].
+///
+/// Each group contains the indices of two or more patches in ascending order,
+/// and the groups are ordered by their first patch.
+/// Patches without any duplicates aren't included.
+pub fn find_duplicates<'a>(
+    patches: impl IntoIterator<Item = &'a EmailMessage>,
+) -> Result<Vec<Vec<usize>>, EmailSerializeError> {
+    let mut groups = Vec::<Vec<usize>>::new();
+    let mut groups_by_id = HashMap::new();
+    for (index, patch) in patches.into_iter().enumerate() {
+        let group = *groups_by_id.entry(patch.content_id()?).or_insert_with(|| {
+            groups.push(Vec::new());
+            groups.len() - 1
+        });
+        groups[group].push(index);
+    }
+    groups.retain(|group| group.len() > 1);
+    Ok(groups)
+}
@@ -0,0 +1,147 @@
+//! Check whether patches still match an upstream tree, without applying them.
+//!
+//! Used to implement the `check-drift` command in the CLI.
+use std::fmt::{self, Display};
+
+use git2::{Delta as DeltaStatus, Repository, Tree};
+
+use super::email::{EmailMessage, PatchApplyError};
+use super::ApplyOptions;
+
+/// How well the hunks of a patch match the files they modify.
+///
+/// The variants are ordered from best to worst,
+/// so the status of an entire patch is the maximum of its hunks.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum DriftStatus {
+    /// Every hunk matches at the expected location
+    Clean,
+    /// Every hunk matches, but some only at a different offset
+    Fuzzy,
+    /// Some hunk doesn't match anywhere, or a file is missing (or already exists)
+    Conflicting,
+}
+impl Display for DriftStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            DriftStatus::Clean => "clean",
+            DriftStatus::Fuzzy => "fuzzy",
+            DriftStatus::Conflicting => "conflicting",
+        })
+    }
+}
+
+impl EmailMessage {
+    /// Check if the context of each hunk still matches the files in the specified tree.
+    ///
+    /// Nothing is written to the repository.
+    pub fn check_drift(
+        &self,
+        repo: &Repository,
+        tree: &Tree,
+    ) -> Result<DriftStatus, PatchApplyError> {
+        let diff = self.git_diff();
+        let mut status = DriftStatus::Clean;
+        for (delta_idx, delta) in diff.deltas().enumerate() {
+            if delta.status() == DeltaStatus::Added {
+                if find_entry(tree, delta.new_file())?.is_some() {
+                    return Ok(DriftStatus::Conflicting);
+                }
+                continue;
+            }
+            let Some(entry) = find_entry(tree, delta.old_file())? else {
+                return Ok(DriftStatus::Conflicting);
+            };
+            let Some(patch) = git2::Patch::from_diff(diff, delta_idx)? else {
+                // Binary deltas can't be inspected
+                continue;
+            };
+            let blob = repo.find_blob(entry.id())?;
+            let file_lines = blob
+                .content()
+                .split_inclusive(|&b| b == b'\n')
+                .collect::<Vec<_>>();
+            for hunk_idx in 0..patch.num_hunks() {
+                let (hunk, num_lines) = patch.hunk(hunk_idx)?;
+                let mut old_lines = Vec::new();
+                for line_idx in 0..num_lines {
+                    let line = patch.line_in_hunk(hunk_idx, line_idx)?;
+                    if matches!(line.origin(), ' ' | '-') {
+                        old_lines.push(line.content().to_vec());
+                    }
+                }
+                let expected_start = (hunk.old_start() as usize).saturating_sub(1);
+                status = status.max(match_hunk(&file_lines, &old_lines, expected_start));
+            }
+        }
+        Ok(status)
+    }
+}
+
+/// Find the tree entry for the specified file, or `None` if it doesn't exist.
+fn find_entry(
+    tree: &Tree,
+    file: git2::DiffFile,
+) -> Result<Option<git2::TreeEntry<'static>>, git2::Error> {
+    let Some(path) = file.path() else {
+        return Ok(None);
+    };
+    match tree.get_path(path) {
+        Ok(entry) => Ok(Some(entry.to_owned())),
+        Err(e) if e.code() == git2::ErrorCode::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Check where the old lines of a hunk occur in the file.
+fn match_hunk(file_lines: &[&[u8]], old_lines: &[Vec<u8>], expected_start: usize) -> DriftStatus {
+    let matches_at = |start: usize| {
+        file_lines.len() >= start + old_lines.len()
+            && file_lines[start..start + old_lines.len()]
+                .iter()
+                .zip(old_lines)
+                .all(|(actual, expected)| *actual == expected.as_slice())
+    };
+    if matches_at(expected_start) {
+        DriftStatus::Clean
+    } else if (0..=file_lines.len().saturating_sub(old_lines.len())).any(matches_at) {
+        DriftStatus::Fuzzy
+    } else {
+        DriftStatus::Conflicting
+    }
+}
+
+/// Check the drift of an entire series of patches against the specified tree.
+///
+/// Each patch that matches is applied before checking the next one,
+/// so later patches can depend on earlier ones.
+/// Conflicting patches are skipped.
+/// The patched blobs and trees are only written to an in-memory object database,
+/// so nothing is written to the repository.
+pub fn check_series_drift<'a>(
+    repo: &Repository,
+    base: &Tree,
+    patches: impl IntoIterator<Item = &'a EmailMessage>,
+) -> Result<Vec<DriftStatus>, PatchApplyError> {
+    /*
+     * New objects are written to the backend with the highest priority.
+     * The backend stays attached to the repository until it is closed,
+     * so use a separate handle to keep the caller's writes on disk.
+     */
+    let scratch = Repository::open(repo.path())?;
+    scratch.odb()?.add_new_mempack_backend(1000)?;
+    let mut tree = scratch.find_tree(base.id())?;
+    let mut result = Vec::new();
+    for patch in patches {
+        let mut status = patch.check_drift(&scratch, &tree)?;
+        if status != DriftStatus::Conflicting {
+            match patch.build_tree(&scratch, &tree, &ApplyOptions::default()) {
+                Ok(updated) => tree = updated,
+                Err(PatchApplyError::FailDelta { .. }) => status = DriftStatus::Conflicting,
+                Err(e) => return Err(e),
+            }
+        }
+        result.push(status);
+    }
+    Ok(result)
+}
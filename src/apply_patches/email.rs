@@ -1,22 +1,63 @@
+#[cfg(feature = "git")]
+use std::borrow::Cow;
+#[cfg(feature = "git")]
+use std::cell::{Cell, RefCell};
 use std::fmt::{self, Display};
+#[cfg(all(feature = "git", feature = "gzip"))]
+use std::io::Read;
 
+#[cfg(feature = "git")]
 use bstr::ByteSlice;
 use camino::{Utf8Path, Utf8PathBuf};
-use git2::build::TreeUpdateBuilder;
-use git2::{Delta as DeltaStatus, FileMode, Repository, ResetType, Signature};
+#[cfg(feature = "git")]
+use git2::build::{CheckoutBuilder, TreeUpdateBuilder};
+#[cfg(feature = "git")]
+pub use git2::Delta as DeltaStatus;
+#[cfg(feature = "git")]
+use git2::{ApplyLocation, DiffBinaryKind, FileMode, Repository, ResetType, Signature};
 use nom::bytes::complete::{tag, take_until, take_until1, take_while1, take_while_m_n};
 use nom::character::{is_digit, is_hex_digit};
-use nom::combinator::{all_consuming, opt, recognize, rest};
-use nom::{sequence::tuple, IResult};
+use nom::combinator::{all_consuming, opt, recognize, rest, verify};
+use nom::sequence::{terminated, tuple};
+use nom::IResult;
 use time::OffsetDateTime;
 
+#[cfg(feature = "git")]
+use super::hooks::{self, HookError};
+#[cfg(feature = "git")]
+use super::hunks::{self, Candidate, HunkSelector, OffsetResolver};
+#[cfg(feature = "git")]
+use super::options::InvalidApplyOptions;
+#[cfg(feature = "git")]
+use super::{ApplyEngine, ApplyOptions, IgnoredPathCheck, WhitespaceAction};
+#[cfg(feature = "git")]
+use crate::format_patches::LineEnding;
+use crate::format_patches::{decode_message, notes_section, CONTENT_TYPE_HEADER};
+#[cfg(feature = "git")]
+use crate::utils::log::ShortId;
+
 pub struct EmailMessage {
+    /// The commit id from the `From <sha>` line, unless it was all zeros
+    #[cfg(feature = "git")]
+    source_commit: Option<git2::Oid>,
     date: OffsetDateTime,
+    /// The bracketed prefix of the subject line, like `[PATCH]`, `[PATCH v2 1/3]` or `[my-project PATCH]`
+    subject_prefix: Option<String>,
     message_summary: String,
     message_tail: String,
     author_name: String,
     author_email: String,
+    /// The `encoding` header of the original commit, if its message wasn't UTF-8
+    message_encoding: Option<String>,
+    /// The `git notes` of the original commit, from the `Notes:` section after the message
+    notes: Option<String>,
+    /// The version of git from the `-- ` signature after the diff
+    generator_version: Option<String>,
+    #[cfg(feature = "git")]
     git_diff: git2::Diff<'static>,
+    /// The text of the diff, up to the signature
+    #[cfg(not(feature = "git"))]
+    diff_text: String,
 }
 
 fn parse_header_line(input: &[u8]) -> IResult<&[u8], &[u8]> {
@@ -27,18 +68,39 @@ fn parse_header_line(input: &[u8]) -> IResult<&[u8], &[u8]> {
     ))(input)?;
     Ok((input, sha))
 }
+/// If the line is the `From <sha> Mon Sep 17 00:00:00 2001` header that starts every patch
+#[cfg(feature = "git")]
+pub(crate) fn is_patch_header(line: &[u8]) -> bool {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    parse_header_line(line).is_ok_and(|(rest, _)| rest.is_empty())
+}
+/// Parse the notes from the part of the message after the `---` line,
+/// which is formatted by [notes_section].
+fn parse_notes_section(section: &str) -> Option<String> {
+    let mut lines = section.lines().skip(1).skip_while(|line| line.is_empty());
+    if lines.next()? != "Notes:" {
+        return None;
+    }
+    let mut notes = String::new();
+    for line in lines {
+        match line.strip_prefix("    ") {
+            Some(line) => notes.push_str(line),
+            None if line.is_empty() => {}
+            // The end of the indented notes, like the diffstat
+            None => break,
+        }
+        notes.push('\n');
+    }
+    notes.truncate(notes.trim_end().len());
+    notes.push('\n');
+    Some(notes)
+}
 struct AuthorInfo<T> {
     name: T,
     email: T,
 }
 impl<T> AuthorInfo<T> {
-    #[inline]
-    fn try_map<U, E>(self, mut func: impl FnMut(T) -> Result<U, E>) -> Result<AuthorInfo<U>, E> {
-        Ok(AuthorInfo {
-            name: func(self.name)?,
-            email: func(self.email)?,
-        })
-    }
     #[inline]
     fn map<U>(self, mut func: impl FnMut(T) -> U) -> AuthorInfo<U> {
         AuthorInfo {
@@ -69,9 +131,30 @@ fn parse_date_line(input: &[u8]) -> IResult<&[u8], &[u8]> {
     Ok((input, date))
 }
 
-fn parse_subject_line(input: &[u8]) -> IResult<&[u8], &[u8]> {
-    let (input, (_, _, subject)) = tuple((tag("Subject: "), opt(tag("[PATCH] ")), rest))(input)?;
-    Ok((input, subject))
+/// The optional `[PATCH]` prefix of a subject line, followed by the summary
+///
+/// Any bracketed prefix containing the word `PATCH` is recognized,
+/// including custom ones like `[my-project PATCH v2]`.
+/// Only that single prefix is stripped, so a summary that starts with its own tag
+/// (like `[PATCH] [sys] Declare ...`) keeps it,
+/// unlike `git am` without `-k`, which strips every bracketed prefix.
+type SubjectLine<'a> = (Option<&'a [u8]>, &'a [u8]);
+fn parse_subject_line(input: &[u8]) -> IResult<&[u8], SubjectLine<'_>> {
+    let (input, (_, prefix, subject)) = tuple((
+        tag("Subject: "),
+        opt(terminated(
+            recognize(tuple((
+                tag("["),
+                verify(take_until("]"), |inner: &[u8]| {
+                    inner.split(|&b| b == b' ').any(|word| word == b"PATCH")
+                }),
+                tag("]"),
+            ))),
+            tag(" "),
+        )),
+        rest,
+    ))(input)?;
+    Ok((input, (prefix, subject)))
 }
 
 fn parse_begin_diff_line(input: &[u8]) -> IResult<&[u8], (&[u8], &[u8])> {
@@ -80,6 +163,204 @@ fn parse_begin_diff_line(input: &[u8]) -> IResult<&[u8], (&[u8], &[u8])> {
     Ok((input, (file_a, file_b)))
 }
 
+/// Parse the path from a `--- a/<path>` or `+++ b/<path>` line,
+/// given the prefix of the line (including the `a/` or `b/`).
+///
+/// Returns `Some(None)` for `/dev/null`,
+/// which marks the missing side of an added or deleted file.
+#[cfg(not(feature = "git"))]
+fn parse_file_marker_line<'a>(line: &'a [u8], prefix: &str) -> Option<Option<&'a [u8]>> {
+    let marker = &prefix.as_bytes()[..4];
+    let path = line.strip_prefix(marker)?;
+    // Some tools follow the path with a tab and a timestamp
+    let path = path.split(|&b| b == b'\t').next().unwrap_or(path);
+    if path == b"/dev/null" {
+        Some(None)
+    } else {
+        path.strip_prefix(&prefix.as_bytes()[4..]).map(Some)
+    }
+}
+
+/// Prepare a patch to be parsed by libgit2, working around the headers it doesn't understand.
+///
+/// The `dissimilarity index` of a broken pair (`git diff -B`) is removed,
+/// since libgit2 rejects the `index` line that git writes after it.
+/// The dissimilarity doesn't matter when applying, because the diff is a complete rewrite.
+#[cfg(feature = "git")]
+fn prepare_libgit2_diff(msg: &str) -> Cow<'_, [u8]> {
+    let msg = strip_signature(msg);
+    if !msg.contains("\ndissimilarity index ") {
+        return Cow::Borrowed(msg.as_bytes());
+    }
+    Cow::Owned(
+        msg.split_inclusive('\n')
+            .filter(|line| !line.starts_with("dissimilarity index "))
+            .collect::<String>()
+            .into_bytes(),
+    )
+}
+
+/// Remove the `-- ` signature (with the version of git) from the end of a patch.
+///
+/// libgit2 only skips the signature after a hunk,
+/// and rejects it after a delta without any hunks (like a mode change).
+/// The signature is the last `-- ` (or `--`) line that is only followed by lines
+/// that can't be part of a hunk, since a removed `- ` line looks the same.
+fn strip_signature(msg: &str) -> &str {
+    let mut remaining = msg;
+    loop {
+        let trimmed = remaining.strip_suffix('\n').unwrap_or(remaining);
+        let (before, line) = match trimmed.rfind('\n') {
+            Some(index) => trimmed.split_at(index + 1),
+            None => ("", trimmed),
+        };
+        if line == "-- " || line == "--" {
+            return before;
+        } else if before.is_empty()
+            || line.starts_with([' ', '+', '-', '\\', '@'])
+            || line.starts_with("diff ")
+        {
+            return msg;
+        }
+        remaining = before;
+    }
+}
+
+/// Check that nothing follows the last hunk of a patch,
+/// except for the `-- ` signature with the version of git.
+///
+/// Trailing content like a stray mbox boundary or the footer of a mailing list
+/// is otherwise ignored by libgit2, or even mistaken for part of the last hunk.
+/// See [EmailMessage::parse_strict].
+pub fn check_trailing_garbage(msg: &str) -> Result<(), InvalidEmailMessage> {
+    #[derive(Copy, Clone, Eq, PartialEq)]
+    enum State {
+        /// The extended headers of a delta, before its first hunk
+        DeltaHeader,
+        /// Inside a hunk, with the remaining number of old and new lines
+        Hunk {
+            old: u64,
+            new: u64,
+        },
+        Binary,
+        Signature,
+        Version,
+    }
+    let mut lines = msg
+        .lines()
+        .map(|line| line.strip_suffix('\r').unwrap_or(line))
+        .collect::<Vec<_>>();
+    while lines.last().is_some_and(|line| line.trim().is_empty()) {
+        lines.pop();
+    }
+    // A missing diff is reported by the parser itself
+    let Some(start) = lines
+        .iter()
+        .position(|line| parse_begin_diff_line(line.as_bytes()).is_ok())
+    else {
+        return Ok(());
+    };
+    let mut state = State::DeltaHeader;
+    for (index, &line) in lines.iter().enumerate().skip(start) {
+        let garbage = || InvalidEmailMessage::TrailingGarbage {
+            line: index + 1,
+            content: line.into(),
+        };
+        match state {
+            State::Hunk { old, new } if old > 0 || new > 0 => {
+                // Some editors strip the trailing space of blank context lines
+                state = match line.as_bytes().first() {
+                    Some(b' ') | None if old > 0 && new > 0 => State::Hunk {
+                        old: old - 1,
+                        new: new - 1,
+                    },
+                    Some(b'-') if old > 0 => State::Hunk { old: old - 1, new },
+                    Some(b'+') if new > 0 => State::Hunk { old, new: new - 1 },
+                    Some(b'\\') => state,
+                    _ => return Err(garbage()),
+                };
+                continue;
+            }
+            State::Binary if line.is_empty() || is_binary_patch_line(line) => continue,
+            State::Signature => {
+                state = State::Version;
+                continue;
+            }
+            State::Version if !line.trim().is_empty() => return Err(garbage()),
+            _ => {}
+        }
+        if line.trim().is_empty() {
+            // Blank lines aren't garbage themselves, but whatever follows them may be
+        } else if line == "-- " || line == "--" {
+            state = State::Signature;
+        } else if parse_begin_diff_line(line.as_bytes()).is_ok() {
+            state = State::DeltaHeader;
+        } else if let Some((old, new)) = parse_hunk_line_counts(line) {
+            state = State::Hunk { old, new };
+        } else if line.starts_with('\\') && matches!(state, State::Hunk { .. }) {
+            // `\ No newline at end of file` after the last line of a hunk
+        } else if state == State::DeltaHeader && line == "GIT binary patch" {
+            state = State::Binary;
+        } else if state == State::DeltaHeader && is_extended_header_line(line) {
+        } else {
+            return Err(garbage());
+        }
+    }
+    Ok(())
+}
+
+/// Parse the number of old and new lines from a `@@ -a,b +c,d @@` hunk header.
+fn parse_hunk_line_counts(line: &str) -> Option<(u64, u64)> {
+    let (ranges, _) = line.strip_prefix("@@ -")?.split_once(" @@")?;
+    let (old, new) = ranges.split_once(" +")?;
+    // The count is omitted if it's one
+    let count = |range: &str| match range.split_once(',') {
+        Some((_, count)) => count.parse().ok(),
+        None => range.parse::<u64>().ok().map(|_| 1),
+    };
+    Some((count(old)?, count(new)?))
+}
+
+/// If the line is one of the extended headers that git writes between `diff --git` and the hunks
+fn is_extended_header_line(line: &str) -> bool {
+    const PREFIXES: &[&str] = &[
+        "index ",
+        "--- ",
+        "+++ ",
+        "old mode ",
+        "new mode ",
+        "deleted file mode ",
+        "new file mode ",
+        "similarity index ",
+        "dissimilarity index ",
+        "rename from ",
+        "rename to ",
+        "copy from ",
+        "copy to ",
+        "Binary files ",
+    ];
+    PREFIXES.iter().any(|prefix| line.starts_with(prefix))
+}
+
+/// If the line is part of a `GIT binary patch`,
+/// which is either the header of a block or a line of base85 data.
+fn is_binary_patch_line(line: &str) -> bool {
+    if line.starts_with("literal ") || line.starts_with("delta ") {
+        return true;
+    }
+    // The first character encodes the number of decoded bytes on the line
+    let decoded_len = match line.as_bytes().first() {
+        Some(&c @ b'A'..=b'Z') => usize::from(c - b'A') + 1,
+        Some(&c @ b'a'..=b'z') => usize::from(c - b'a') + 27,
+        _ => return false,
+    };
+    let data = &line[1..];
+    data.len() == decoded_len.div_ceil(4) * 5
+        && data
+            .bytes()
+            .all(|c| c.is_ascii_alphanumeric() || b"!#$%&()*+-;<=>?@^_`{|}~".contains(&c))
+}
+
 fn match_header_line<'a, T: 'a>(
     lines: &mut dyn Iterator<Item = &'a str>,
     expected: &'static str,
@@ -98,7 +379,7 @@ fn match_header_line<'a, T: 'a>(
                 actual: line.into(),
                 expected,
                 reason: nom::error::Error {
-                    input: std::str::from_utf8(err.input)?.into(),
+                    input: String::from_utf8_lossy(err.input).into_owned(),
                     code: err.code,
                 },
             })
@@ -106,34 +387,174 @@ fn match_header_line<'a, T: 'a>(
         Err(nom::Err::Incomplete(_)) => unreachable!(),
     }
 }
+/// The line containing the specified byte offset, starting from one
+pub(crate) fn line_of_offset(text: &[u8], offset: usize) -> usize {
+    text[..offset].iter().filter(|&&b| b == b'\n').count() + 1
+}
+/// Convert part of a header line back into a string.
+///
+/// The patch is already valid UTF8, and the parsers only split it at ASCII characters.
+fn part_str(part: &[u8]) -> &str {
+    std::str::from_utf8(part).expect("Header parsers should split at ASCII characters")
+}
+/// Check that a patch of the specified size doesn't exceed the limit (if any)
+pub(crate) fn check_patch_size(size: u64, limit: Option<u64>) -> Result<(), InvalidEmailMessage> {
+    match limit {
+        Some(limit) if size > limit => Err(InvalidEmailMessage::PatchTooLarge { size, limit }),
+        _ => Ok(()),
+    }
+}
 impl EmailMessage {
+    /// Parse a patch from raw bytes, which must be valid UTF8.
+    ///
+    /// The only exception is a commit message in another charset,
+    /// declared with a `Content-Type` header like `git format-patch` does.
+    pub fn parse_bytes(msg: &[u8]) -> Result<Self, InvalidEmailMessage> {
+        Self::parse_bytes_limited(msg, None)
+    }
+    /// Parse a patch from raw bytes,
+    /// rejecting it without parsing if it is larger than `max_size` bytes.
+    ///
+    /// Parsing a patch buffers the entire diff in memory,
+    /// so this guards against pathologically large patches from untrusted sources.
+    pub fn parse_bytes_limited(
+        msg: &[u8],
+        max_size: Option<u64>,
+    ) -> Result<Self, InvalidEmailMessage> {
+        check_patch_size(msg.len() as u64, max_size)?;
+        let decoded = decode_message(msg);
+        let msg =
+            std::str::from_utf8(&decoded).map_err(|cause| InvalidEmailMessage::InvalidUtf8 {
+                offset: cause.valid_up_to(),
+                line: line_of_offset(&decoded, cause.valid_up_to()),
+                cause,
+            })?;
+        Self::parse(msg)
+    }
+    /// Parse a patch, rejecting any content after the last hunk
+    /// except for the `-- ` signature with the version of git.
+    ///
+    /// See [check_trailing_garbage].
+    pub fn parse_strict(msg: &str) -> Result<Self, InvalidEmailMessage> {
+        check_trailing_garbage(msg)?;
+        Self::parse(msg)
+    }
     // TODO: Accept bstr?
     pub fn parse(msg: &str) -> Result<Self, InvalidEmailMessage> {
-        let git_diff = git2::Diff::from_buffer(msg.as_bytes())?;
+        // Some Windows editors insert a byte order mark at the start of the file
+        let msg = msg.strip_prefix('\u{FEFF}').unwrap_or(msg);
+        // Patch files may be written with CRLF line endings (see `LineEnding::CrLf`)
+        let normalized;
+        let msg = match msg.split_inclusive('\n').next() {
+            Some(first_line) if first_line.ends_with("\r\n") => {
+                normalized = msg.replace("\r\n", "\n");
+                normalized.as_str()
+            }
+            _ => msg,
+        };
+        /*
+         * Files with NUL bytes are always diffed as binary, so they can't appear in a patch.
+         * libgit2 also reads past the end of some lines that contain them.
+         */
+        if let Some(offset) = msg.find('\0') {
+            return Err(InvalidEmailMessage::NulByte { offset });
+        }
+        #[cfg(feature = "git")]
+        let git_diff = {
+            let diff_buffer = prepare_libgit2_diff(msg);
+            let git_diff = git2::Diff::from_buffer(&diff_buffer)?;
+            /*
+             * libgit2 silently drops some malformed sections (like a truncated `diff --git` line),
+             * which would otherwise go unnoticed until the applied result is wrong.
+             * Hunk lines always start with a prefix, so they never look like a header.
+             */
+            let headers = diff_buffer
+                .lines()
+                .filter(|line| line.starts_with(b"diff --git "))
+                .count();
+            let deltas = git_diff.deltas().len();
+            if headers != deltas {
+                return Err(InvalidEmailMessage::DeltaCountMismatch { headers, deltas });
+            }
+            git_diff
+        };
+        /*
+         * libgit2 leaves a path missing if the `diff --git` line doesn't match the other headers,
+         * and some of its functions (like `git_diff_get_stats`) crash on the missing path.
+         */
+        #[cfg(feature = "git")]
+        if let Some(delta) = git_diff.deltas().position(|delta| {
+            delta.old_file().path_bytes().is_none() || delta.new_file().path_bytes().is_none()
+        }) {
+            return Err(InvalidEmailMessage::MissingDeltaPath { delta });
+        }
 
         let mut lines = msg.lines().peekable();
-        match_header_line(&mut lines, "header", parse_header_line)?;
-        let author = match_header_line(&mut lines, "author", parse_author_line)?
-            .try_map(std::str::from_utf8)?
-            .map(String::from);
-        let date = std::str::from_utf8(match_header_line(&mut lines, "date", parse_date_line)?)?;
-        let message_summary = std::str::from_utf8(match_header_line(
-            &mut lines,
-            "subject",
-            parse_subject_line,
-        )?)?;
+        let from_sha = part_str(match_header_line(&mut lines, "header", parse_header_line)?);
+        // Abbreviated ids can't be turned into an Oid without a repository
+        #[cfg(feature = "git")]
+        let source_commit = match git2::Oid::from_str(from_sha) {
+            Ok(id) if from_sha.len() == 40 && !id.is_zero() => Some(id),
+            _ => None,
+        };
+        #[cfg(not(feature = "git"))]
+        let _ = from_sha;
+        // The author and date may be in either order (see `HeaderOrder`)
+        let (author, date) = if lines.peek().is_some_and(|line| line.starts_with("Date: ")) {
+            let date = match_header_line(&mut lines, "date", parse_date_line)?;
+            (
+                match_header_line(&mut lines, "author", parse_author_line)?,
+                date,
+            )
+        } else {
+            (
+                match_header_line(&mut lines, "author", parse_author_line)?,
+                match_header_line(&mut lines, "date", parse_date_line)?,
+            )
+        };
+        let author = author.map(part_str).map(String::from);
+        let date = part_str(date);
+        let (subject_prefix, message_summary) =
+            match_header_line(&mut lines, "subject", parse_subject_line)?;
+        let subject_prefix = subject_prefix.map(part_str).map(String::from);
+        let message_summary = part_str(message_summary);
         let mut message_summary = String::from(message_summary);
+        let mut message_encoding = None;
         loop {
             let line = lines.next().ok_or(InvalidEmailMessage::UnexpectedEof {
                 expected: "diff after subject",
             })?;
             if line.is_empty() {
                 break;
+            } else if let Some(label) = line.strip_prefix(CONTENT_TYPE_HEADER) {
+                match encoding_rs::Encoding::for_label(label.as_bytes()) {
+                    None => {
+                        return Err(InvalidEmailMessage::UnknownEncoding {
+                            label: label.into(),
+                        })
+                    }
+                    // The default, which doesn't need an `encoding` header
+                    Some(encoding) if encoding == encoding_rs::UTF_8 => {}
+                    Some(_) => message_encoding = Some(label.to_string()),
+                }
+            } else if line.starts_with("MIME-Version: ")
+                || line.starts_with("Content-Transfer-Encoding: ")
+            {
+                // The rest of the MIME headers written by `git format-patch`
+            } else if let Some(continuation) = line.strip_prefix([' ', '\t']) {
+                // Like git, unfold the subject by joining the lines with a single space
+                message_summary.truncate(message_summary.trim_end().len());
+                message_summary.push(' ');
+                message_summary.push_str(continuation.trim_start());
             } else {
-                // Breaking over newlines doesn't affect final result
+                // Without leading whitespace, the subject was broken mid-word
                 message_summary.push_str(line);
             }
         }
+        if message_summary.trim().is_empty() {
+            // git am refuses to create commits without a subject
+            return Err(InvalidEmailMessage::EmptySubject);
+        }
         /*
          * We already skipped a single line of whitespace
          * There could be several lines of `message_tail`,
@@ -162,21 +583,123 @@ impl EmailMessage {
         if trailing_message.ends_with('\n') {
             assert_eq!(trailing_message.pop(), Some('\n'));
         }
+        // Like `git am`, anything after a `---` line isn't part of the message
+        let mut notes = None;
+        let mut line_start = 0;
+        let separator = trailing_message.split_inclusive('\n').find_map(|line| {
+            let start = line_start;
+            line_start += line.len();
+            (line.trim_end() == "---").then_some(start)
+        });
+        if let Some(separator) = separator {
+            notes = parse_notes_section(&trailing_message[separator..]);
+            trailing_message.truncate(separator);
+            trailing_message.truncate(trailing_message.trim_end().len());
+        }
+        // Everything up to the `-- ` signature is part of the diff
+        #[cfg(not(feature = "git"))]
+        let diff_text = {
+            let mut diff_text = lines.fold(String::new(), |mut diff_text, line| {
+                diff_text.push_str(line);
+                diff_text.push('\n');
+                diff_text
+            });
+            // A removed `- ` line looks just like the signature
+            diff_text.truncate(strip_signature(&diff_text).len());
+            diff_text
+        };
+        let generator_version = msg[strip_signature(msg).len()..]
+            .lines()
+            .skip(1) // The `-- ` line itself
+            .map(str::trim)
+            .find(|line| !line.is_empty())
+            .map(String::from);
         let date = OffsetDateTime::parse(date, &time::format_description::well_known::Rfc2822)
             .map_err(|cause| InvalidEmailMessage::InvalidDate {
                 cause,
                 actual: date.into(),
             })?;
         Ok(EmailMessage {
+            #[cfg(feature = "git")]
+            source_commit,
+            #[cfg(feature = "git")]
             git_diff,
+            #[cfg(not(feature = "git"))]
+            diff_text,
             date,
+            subject_prefix,
             message_summary,
             message_tail: trailing_message,
             author_name: author.name,
             author_email: author.email,
+            message_encoding,
+            notes,
+            generator_version,
         })
     }
 
+    /// The id of the commit this patch was generated from,
+    /// as recorded in the `From <sha> Mon Sep 17 00:00:00 2001` line.
+    ///
+    /// This is `None` if the id is all zeros,
+    /// which git uses when the patch doesn't correspond to a commit.
+    #[inline]
+    #[cfg(feature = "git")]
+    pub fn source_commit(&self) -> Option<git2::Oid> {
+        self.source_commit
+    }
+
+    /// The summary line of the commit message, without the `[PATCH]` prefix.
+    #[inline]
+    pub fn summary(&self) -> &str {
+        &self.message_summary
+    }
+    /// The name of the author, from the `From:` header
+    #[inline]
+    pub fn author_name(&self) -> &str {
+        &self.author_name
+    }
+    /// The email of the author, from the `From:` header
+    #[inline]
+    pub fn author_email(&self) -> &str {
+        &self.author_email
+    }
+    /// Replace the author of the patch, before it is applied.
+    ///
+    /// This is simpler than an [AuthorMap](super::AuthorMap) for a one-off edit.
+    /// The new author is also written by [EmailMessage::to_patch_string].
+    pub fn set_author(&mut self, name: &str, email: &str) {
+        self.author_name = name.into();
+        self.author_email = email.into();
+    }
+    /// The author date, from the `Date:` header
+    #[inline]
+    pub fn date(&self) -> OffsetDateTime {
+        self.date
+    }
+    /// Replace the author date of the patch, before it is applied.
+    pub fn set_date(&mut self, date: OffsetDateTime) {
+        self.date = date;
+    }
+    /// The version of the tool that generated the patch,
+    /// from the `-- ` signature after the diff (like `2.40.0` or `libgit2 1.8.1`).
+    ///
+    /// This is `None` if the patch doesn't end with a signature.
+    /// The signature is never part of the diff,
+    /// and [EmailMessage::parse_strict] accepts it after the last hunk.
+    #[inline]
+    pub fn generator_version(&self) -> Option<&str> {
+        self.generator_version.as_deref()
+    }
+    /// The `git notes` of the original commit, if the patch included them.
+    ///
+    /// See [FormatOptions::include_notes](crate::format_patches::FormatOptions::include_notes).
+    #[inline]
+    pub fn notes(&self) -> Option<&str> {
+        self.notes.as_deref()
+    }
+
+    /// The commit message, without the `[PATCH]` prefix of the subject.
     pub fn full_message(&self) -> String {
         let mut message = self.message_summary.clone();
         if !self.message_tail.is_empty() {
@@ -186,8 +709,104 @@ impl EmailMessage {
         }
         message
     }
+
+    /// Serialize this message back into `git format-patch` text.
+    ///
+    /// The output uses the same canonical form as the patches
+    /// generated by [PatchFormatter](crate::format_patches::PatchFormatter),
+    /// so it can be parsed again with [EmailMessage::parse].
+    /// Unlike those patches, the message is always UTF-8,
+    /// even if the `Content-Type` header declares another charset.
+    pub fn to_patch_string(&self) -> Result<String, EmailSerializeError> {
+        use std::fmt::Write;
+        #[cfg(feature = "git")]
+        let from_sha = self.source_commit.unwrap_or_else(git2::Oid::zero);
+        #[cfg(not(feature = "git"))]
+        let from_sha = "0".repeat(40);
+        let mut result = String::new();
+        writeln!(result, "From {from_sha} Mon Sep 17 00:00:00 2001").unwrap();
+        writeln!(result, "From: {} <{}>", self.author_name, self.author_email).unwrap();
+        writeln!(
+            result,
+            "Date: {}",
+            self.date
+                .format(&time::format_description::well_known::Rfc2822)?
+        )
+        .unwrap();
+        writeln!(
+            result,
+            "Subject: {} {}",
+            self.subject_prefix.as_deref().unwrap_or("[PATCH]"),
+            self.message_summary
+        )
+        .unwrap();
+        if let Some(ref encoding) = self.message_encoding {
+            writeln!(result, "{CONTENT_TYPE_HEADER}{encoding}").unwrap();
+        }
+        result.push('\n');
+        if !self.message_tail.is_empty() {
+            result.push_str(&self.message_tail);
+            result.push('\n');
+        }
+        if let Some(ref notes) = self.notes {
+            result.push_str(&notes_section(notes));
+        }
+        result.push('\n');
+        result.push_str(&self.unified_diff()?);
+        Ok(result)
+    }
+
+    /// Check if two patches describe the same commit,
+    /// even if they weren't formatted identically.
+    ///
+    /// The author, date, message, notes and the text of the diff must match.
+    /// Runs of whitespace in the author and summary are considered equal,
+    /// since they come from (possibly reformatted) headers.
+    /// The source commit and the bracketed prefix of the subject are presentation details,
+    /// so they are ignored. This is mostly useful to check round-tripping a patch.
+    pub fn semantic_eq(&self, other: &EmailMessage) -> bool {
+        fn normalize(header: &str) -> impl Iterator<Item = &str> {
+            header.split_whitespace()
+        }
+        normalize(&self.author_name).eq(normalize(&other.author_name))
+            && normalize(&self.author_email).eq(normalize(&other.author_email))
+            && normalize(&self.message_summary).eq(normalize(&other.message_summary))
+            && self.date == other.date
+            && self.message_tail == other.message_tail
+            && self.message_encoding == other.message_encoding
+            && self.notes == other.notes
+            && matches!(
+                (self.unified_diff(), other.unified_diff()),
+                (Ok(ours), Ok(theirs)) if ours == theirs
+            )
+    }
+
+    /// The diff of this patch in unified format,
+    /// starting with the first `diff --git` line and without any email headers.
+    #[cfg(feature = "git")]
+    pub fn unified_diff(&self) -> Result<String, EmailSerializeError> {
+        let mut diff_text = Vec::new();
+        self.git_diff
+            .print(git2::DiffFormat::Patch, |_delta, _hunk, line| {
+                let origin = line.origin();
+                match origin {
+                    ' ' | '+' | '-' => diff_text.push(origin as u8),
+                    _ => {}
+                }
+                diff_text.extend_from_slice(line.content());
+                true
+            })?;
+        Ok(String::from_utf8(diff_text).map_err(|e| e.utf8_error())?)
+    }
+    /// The diff of this patch in unified format,
+    /// starting with the first `diff --git` line and without any email headers.
+    #[cfg(not(feature = "git"))]
+    pub fn unified_diff(&self) -> Result<String, EmailSerializeError> {
+        Ok(self.diff_text.clone())
+    }
 }
 
+#[cfg(feature = "git")]
 struct DeltaApplyContext<'repo, 'tree, 'builder> {
     repo: &'repo git2::Repository,
     delta_idx: usize,
@@ -195,24 +814,52 @@ struct DeltaApplyContext<'repo, 'tree, 'builder> {
     desc: DeltaDesc,
     orig_tree: &'tree git2::Tree<'repo>,
     result_tree: &'builder mut TreeUpdateBuilder,
+    write_blob: &'builder mut dyn FnMut(&[u8]) -> Result<git2::Oid, git2::Error>,
+    line_endings: Option<LineEnding>,
+    offset_resolver: Option<&'builder mut OffsetResolver>,
+    /// Record the position where each hunk was applied
+    applied_hunks: Option<&'builder mut Vec<Candidate>>,
+    /// Only apply the hunks accepted by the selector
+    hunk_selector: Option<HunkSelector<'builder>>,
+    whitespace: WhitespaceAction,
+    logger: &'builder slog::Logger,
 }
+#[cfg(feature = "git")]
 impl EmailMessage {
     fn apply_delta(&self, ctx: DeltaApplyContext) -> Result<(), DeltaApplyError> {
+        if let Some(selector) = ctx.hunk_selector {
+            // A delta without any hunks (like a mode change) is selected as a whole
+            let num_hunks = git2::Patch::from_diff(&self.git_diff, ctx.delta_idx)
+                .unexpected()?
+                .map_or(0, |patch| patch.num_hunks());
+            if !(0..num_hunks.max(1)).any(|hunk| selector(&ctx.desc, hunk)) {
+                return Ok(());
+            }
+        }
         match ctx.git_delta.status() {
             DeltaStatus::Deleted => {
-                assert!(ctx.git_delta.old_file().exists(), "Old file should exist");
+                // NOTE: libgit2 doesn't set `DiffFile::exists` for parsed diffs
+                assert!(
+                    ctx.git_delta.old_file().path().is_some(),
+                    "Old file should exist"
+                );
                 assert!(
-                    !ctx.git_delta.new_file().exists(),
+                    ctx.git_delta.new_file().id().is_zero(),
                     "New file should not exist"
                 );
                 let path = ctx.desc.old_path().expect("Old file should have path");
                 ctx.result_tree.remove(path.as_std_path().to_path_buf());
                 return Ok(());
             }
-            DeltaStatus::Added
-            | DeltaStatus::Modified
-            | DeltaStatus::Renamed
-            | DeltaStatus::Copied => {
+            DeltaStatus::Renamed => {
+                // The content is patched from the old path, then written to the new path
+                let path = ctx
+                    .desc
+                    .old_path()
+                    .expect("Renamed file should have old path");
+                ctx.result_tree.remove(path.as_std_path().to_path_buf());
+            }
+            DeltaStatus::Added | DeltaStatus::Modified | DeltaStatus::Copied => {
                 // fallthrough to generic handler
             }
             // unexpected status
@@ -227,98 +874,422 @@ impl EmailMessage {
                 })
             }
         }
-        let mut patch = git2::Patch::from_diff(&self.git_diff, ctx.delta_idx)
-            .unexpected()?
-            .ok_or_else(|| {
-                assert!(
-                    ctx.git_delta.old_file().is_binary() || ctx.git_delta.new_file().is_binary(),
-                    "Binary diff should be only reason for `None` ({git_delta:#?})",
-                    git_delta = &ctx.git_delta
-                );
-                DeltaApplyError::BinaryDelta
-            })?;
+        let patch = git2::Patch::from_diff(&self.git_diff, ctx.delta_idx).unexpected()?;
+        let old_mode = ctx.git_delta.old_file().mode();
+        let new_mode = ctx.git_delta.new_file().mode();
+        if ctx.git_delta.status() == DeltaStatus::Modified
+            && old_mode != new_mode
+            && patch.as_ref().is_none_or(|patch| patch.num_hunks() == 0)
+        {
+            // Only the mode changed, so reuse the existing blob
+            let original = self.find_original(&ctx)?;
+            ctx.result_tree.upsert(
+                ctx.desc.new_path().unwrap().as_std_path(),
+                original,
+                new_mode,
+            );
+            return Ok(());
+        }
+        if ctx.git_delta.flags().is_binary() {
+            return self.apply_binary_delta(ctx, new_mode);
+        }
+        let mut patch = patch.ok_or_else(|| {
+            assert!(
+                ctx.git_delta.old_file().is_binary() || ctx.git_delta.new_file().is_binary(),
+                "Binary diff should be only reason for `None` ({git_delta:#?})",
+                git_delta = &ctx.git_delta
+            );
+            DeltaApplyError::BinaryDelta
+        })?;
         let patch_buf = patch.to_buf().unexpected()?;
+        let patch_buf = match ctx.whitespace {
+            WhitespaceAction::NoWarn => Cow::Borrowed(patch_buf.as_bytes()),
+            action => {
+                let (lines, fixed) = strip_trailing_whitespace(patch_buf.as_bytes());
+                match action {
+                    _ if lines == 0 => Cow::Borrowed(patch_buf.as_bytes()),
+                    WhitespaceAction::Fix => Cow::Owned(fixed),
+                    WhitespaceAction::Error => {
+                        return Err(DeltaApplyError::TrailingWhitespace { lines })
+                    }
+                    _ => {
+                        slog::warn!(
+                            ctx.logger, "Patch adds trailing whitespace";
+                            "path" => %ctx.desc,
+                            "lines" => lines,
+                        );
+                        Cow::Borrowed(patch_buf.as_bytes())
+                    }
+                }
+            }
+        };
 
-        let diffy_patch = diffy::Patch::from_bytes(patch_buf.as_bytes())
+        let diffy_patch = diffy::Patch::from_bytes(&patch_buf)
             .map_err(|cause| DeltaApplyError::FailParseGitDelta { cause })?;
-        let existing: Option<(git2::TreeEntry, git2::Blob)> = match ctx
-            .git_delta
-            .old_file()
-            .path()
-            /*
-             * NOTE: Sometimes DeltaStatus::Added has an old_file (instead of None).
-             * We need to explicitly ignore that case,
-             * because otherwise the file will be missing.
-             */
-            .filter(|_| !matches!(ctx.git_delta.status(), DeltaStatus::Added))
-        {
+        // NOTE: Added files never have an old path, even if libgit2 reports one
+        let existing: Option<git2::Blob> = match ctx.desc.old_path() {
             None => None,
-            Some(old_path) => {
-                // Read bytes from the tree
-                let entry = ctx.orig_tree.get_path(old_path).map_err(|_cause| {
-                    DeltaApplyError::MissingOriginalFile {
-                        path: old_path.into(),
-                    }
-                })?;
-                let blob = ctx.repo.find_blob(entry.id()).unexpected()?;
-                assert_eq!(blob.id(), entry.id());
-                Some((entry, blob))
+            Some(_) => Some(ctx.repo.find_blob(self.find_original(&ctx)?).unexpected()?),
+        };
+        let existing_bytes: &[u8] = existing.as_ref().map_or(b"", |blob| blob.content());
+        /*
+         * A patch generated from LF-normalized contents doesn't match a CRLF file,
+         * so apply it to the normalized contents and then restore the CRLF line endings.
+         */
+        let normalize_crlf = is_mostly_crlf(existing_bytes) && !patch_buf.contains_str("\r\n");
+        let existing_bytes = match normalize_crlf {
+            true => Cow::Owned(existing_bytes.replace("\r\n", "\n")),
+            false => Cow::Borrowed(existing_bytes),
+        };
+        let mut patched_bytes = match (ctx.offset_resolver, ctx.applied_hunks, ctx.hunk_selector) {
+            (None, None, None) => diffy::apply_bytes(&existing_bytes, &diffy_patch)
+                .map_err(|cause| DeltaApplyError::FailApplyPatch { cause })?,
+            (resolver, applied_hunks, selector) => {
+                let (patched, positions) = hunks::apply_hunks(
+                    &existing_bytes,
+                    &diffy_patch,
+                    &ctx.desc,
+                    resolver,
+                    selector,
+                )?;
+                if let Some(applied_hunks) = applied_hunks {
+                    *applied_hunks = positions;
+                }
+                patched
+            }
+        };
+        let line_ending = match ctx.line_endings {
+            Some(forced) => Some(forced),
+            None if normalize_crlf => Some(LineEnding::CrLf),
+            None => None,
+        };
+        match line_ending {
+            Some(LineEnding::Lf) => patched_bytes = patched_bytes.replace("\r\n", "\n"),
+            Some(LineEnding::CrLf) => {
+                patched_bytes = patched_bytes.replace("\r\n", "\n").replace("\n", "\r\n")
             }
+            None => {}
+        }
+        let patched_oid = (ctx.write_blob)(&patched_bytes).unexpected()?;
+        let mode = match new_mode {
+            FileMode::BlobExecutable => FileMode::BlobExecutable,
+            _ => FileMode::Blob,
         };
-        let existing_bytes: &[u8] = existing.as_ref().map_or(b"", |(_, blob)| blob.content());
-        let patched_bytes = diffy::apply_bytes(existing_bytes, &diffy_patch)
-            .map_err(|cause| DeltaApplyError::FailApplyPatch { cause })?;
-        let patched_oid = ctx.repo.blob(&patched_bytes).unexpected()?;
         ctx.result_tree.upsert(
             ctx.desc.new_path().unwrap().as_std_path(),
             patched_oid,
-            FileMode::Blob,
+            mode,
         );
         Ok(())
     }
 
+    /// Find the blob of the original file in the tree.
+    fn find_original(&self, ctx: &DeltaApplyContext) -> Result<git2::Oid, DeltaApplyError> {
+        let path = ctx.desc.old_path().expect("Delta should have old path");
+        match ctx.orig_tree.get_path(path.as_std_path()) {
+            Ok(entry) => Ok(entry.id()),
+            Err(_) => Err(DeltaApplyError::MissingOriginalFile { path: path.into() }),
+        }
+    }
+
+    /// Apply a `GIT binary patch`, which must contain the full contents of the new file.
+    ///
+    /// Git uses a `literal` for added files, and whenever it is smaller than a `delta`
+    /// against the original contents, which isn't supported yet.
+    fn apply_binary_delta(
+        &self,
+        ctx: DeltaApplyContext,
+        new_mode: FileMode,
+    ) -> Result<(), DeltaApplyError> {
+        let delta_idx = Cell::new(0);
+        let mut binary_data = None;
+        self.git_diff
+            .foreach(
+                &mut |_delta, _progress| {
+                    delta_idx.set(delta_idx.get() + 1);
+                    true
+                },
+                Some(&mut |_delta, binary| {
+                    if delta_idx.get() == ctx.delta_idx + 1 {
+                        let new_file = binary.new_file();
+                        binary_data = Some((
+                            new_file.kind(),
+                            new_file.data().to_vec(),
+                            new_file.inflated_len(),
+                        ));
+                    }
+                    true
+                }),
+                None,
+                None,
+            )
+            .unexpected()?;
+        // `Binary files differ` doesn't include any data
+        let (DiffBinaryKind::Literal, deflated, inflated_len) =
+            binary_data.ok_or(DeltaApplyError::BinaryDelta)?
+        else {
+            return Err(DeltaApplyError::BinaryDelta);
+        };
+        let contents = inflate_binary_literal(&deflated, inflated_len)?;
+        /*
+         * The literal replaces the entire file, so check it was generated from the same contents.
+         * libgit2 pads abbreviated ids with zeros, so only compare the digits before them.
+         */
+        if ctx.git_delta.status() != DeltaStatus::Added {
+            let actual = self.find_original(&ctx)?;
+            let expected = ctx.git_delta.old_file().id();
+            if !actual
+                .to_string()
+                .starts_with(expected.to_string().trim_end_matches('0'))
+            {
+                return Err(DeltaApplyError::BinaryPreimageMismatch { expected, actual });
+            }
+        }
+        let oid = (ctx.write_blob)(&contents).unexpected()?;
+        let mode = match new_mode {
+            FileMode::BlobExecutable => FileMode::BlobExecutable,
+            _ => FileMode::Blob,
+        };
+        ctx.result_tree
+            .upsert(ctx.desc.new_path().unwrap().as_std_path(), oid, mode);
+        Ok(())
+    }
+
     /// Apply this email as a new commit against the repo
     pub fn apply_commit(&self, target: &Repository) -> Result<(), PatchApplyError> {
+        self.apply_commit_with(target, &ApplyOptions::default())
+    }
+
+    /// Apply this email as a new commit against the repo,
+    /// recording the commit it was formatted from like `git cherry-pick -x`.
+    ///
+    /// See [ApplyOptions::mark_cherry_pick].
+    pub fn apply_cherry_pick(&self, target: &Repository) -> Result<(), PatchApplyError> {
+        self.apply_commit_with(
+            target,
+            &ApplyOptions {
+                mark_cherry_pick: true,
+                ..Default::default()
+            },
+        )
+    }
+
+    /// Apply this email as a new commit against the repo,
+    /// using the specified options.
+    pub fn apply_commit_with(
+        &self,
+        target: &Repository,
+        opts: &ApplyOptions,
+    ) -> Result<(), PatchApplyError> {
+        opts.validate()?;
+        let mut message = self.commit_message(opts);
+        if opts.run_hooks {
+            message = hooks::run_applypatch_msg(target, &message).map_err(|cause| {
+                PatchApplyError::HookFailed {
+                    hook: "applypatch-msg",
+                    cause,
+                }
+            })?;
+        }
         let tree = target.index()?.write_tree_to(target)?;
         let tree = target.find_tree(tree)?;
-        let mut new_tree = TreeUpdateBuilder::new();
+        let updated_tree = match opts.engine {
+            ApplyEngine::Diffy => self.build_tree(target, &tree, opts)?,
+            ApplyEngine::Libgit2 => self.apply_with_libgit2(target)?,
+        };
+        trace_tree_change(&opts.logger(), self.summary(), target, &tree, &updated_tree)?;
+        if opts.run_hooks {
+            run_pre_applypatch(target, &tree, &updated_tree)?;
+        }
+        self.commit_tree(target, &updated_tree, opts, &message)?;
+        if opts.run_hooks {
+            if let Err(cause) = hooks::run_hook(target, "post-applypatch", &[]) {
+                slog::warn!(
+                    opts.logger(),
+                    "Failed to run post-applypatch hook: {}",
+                    cause
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Commit the conflicts resolved in the worktree,
+    /// after a previous attempt returned [PatchApplyError::LeftInWorktree].
+    ///
+    /// This is the equivalent of `git am --continue`.
+    /// Only the files touched by this patch are staged,
+    /// so any leftover `.rej` files should be removed first.
+    pub fn commit_resolved(
+        &self,
+        target: &Repository,
+        opts: &ApplyOptions,
+    ) -> Result<(), PatchApplyError> {
+        opts.validate()?;
+        let workdir = target.workdir().ok_or(PatchApplyError::BareRepository)?;
+        let mut index = target.index()?;
         for (delta_idx, git_delta) in self.git_diff.deltas().enumerate() {
-            let desc = DeltaDesc::from_git(Some(delta_idx), &git_delta)?;
-            self.apply_delta(DeltaApplyContext {
-                git_delta,
-                delta_idx,
-                orig_tree: &tree,
-                repo: target,
-                desc: desc.clone(),
-                result_tree: &mut new_tree,
-            })
-            .map_err(|cause| PatchApplyError::FailDelta {
-                cause,
-                delta: Box::new(desc.clone()),
-            })?
+            let desc = DeltaDesc::from_git(Some(delta_idx), &git_delta)?.rewrite_paths(opts)?;
+            for path in [desc.old_path(), desc.new_path()].into_iter().flatten() {
+                if workdir.join(path).exists() {
+                    index.add_path(path.as_std_path())?;
+                } else {
+                    index.remove_path(path.as_std_path())?;
+                }
+            }
         }
-        let updated_tree_oid = new_tree
-            .create_updated(target, &tree)
-            .map_err(|cause| PatchApplyError::FailBuildTree { cause })?;
-        let updated_tree = target.find_tree(updated_tree_oid).unexpected()?;
-        // target.apply(&self.git_diff, ApplyLocation::Both, None)?;
+        index.write()?;
+        let tree = target.find_tree(index.write_tree()?)?;
+        self.commit_tree(target, &tree, opts, &self.commit_message(opts))
+    }
+
+    /// The message of the commit created for this patch, according to the options.
+    fn commit_message(&self, opts: &ApplyOptions) -> String {
+        let mut message = self.full_message();
+        if let (true, Some(prefix)) = (opts.keep_subject_prefix, &self.subject_prefix) {
+            message.insert(0, ' ');
+            message.insert_str(0, prefix);
+        }
+        let mut message = opts.message_cleanup.cleanup(&message);
+        if let (true, Some(source)) = (opts.mark_cherry_pick, self.source_commit) {
+            // Like git, the trailer joins an existing trailer block instead of starting a new one
+            let last_paragraph = message.rsplit("\n\n").next().unwrap_or_default();
+            if message.contains("\n\n") && last_paragraph.lines().all(is_trailer_line) {
+                message.push('\n');
+            } else {
+                message.push_str("\n\n");
+            }
+            message.push_str(&format!("(cherry picked from commit {source})"));
+        }
+        message
+    }
+
+    /// Create a commit on `HEAD` with the specified tree,
+    /// using the author and message of this email.
+    fn commit_tree(
+        &self,
+        target: &Repository,
+        updated_tree: &git2::Tree,
+        opts: &ApplyOptions,
+        message: &str,
+    ) -> Result<(), PatchApplyError> {
+        // TODO: Handle detatched head/no commits
+        let head_commit = target.head()?.peel_to_commit()?;
+        let commit_id = self.create_commit(target, updated_tree, &head_commit, opts, message)?;
+        target
+            .head()?
+            .set_target(commit_id, &format!("commit: {}", self.message_summary))?;
+        let commit = target.find_commit(commit_id).unexpected()?;
+        target
+            .reset(commit.as_object(), ResetType::Hard, None)
+            .unexpected()?;
+        Ok(())
+    }
+
+    /// Apply this email on top of the specified commit,
+    /// returning the new commit without updating any references.
+    ///
+    /// Like [EmailMessage::apply_to_tree], this only writes objects to the repository,
+    /// so a series of patches can be committed without touching the index or worktree.
+    pub(crate) fn commit_onto<'repo>(
+        &self,
+        target: &'repo Repository,
+        parent: &git2::Commit<'repo>,
+        opts: &ApplyOptions,
+    ) -> Result<git2::Commit<'repo>, PatchApplyError> {
+        opts.validate()?;
+        let tree = parent.tree()?;
+        let updated_tree = self.build_tree(target, &tree, opts)?;
+        trace_tree_change(&opts.logger(), self.summary(), target, &tree, &updated_tree)?;
+        let message = self.commit_message(opts);
+        let commit_id = self.create_commit(target, &updated_tree, parent, opts, &message)?;
+        Ok(target.find_commit(commit_id).unexpected()?)
+    }
+
+    /// Write a commit with the specified tree and parent,
+    /// using the author and message of this email.
+    fn create_commit(
+        &self,
+        target: &Repository,
+        updated_tree: &git2::Tree,
+        parent: &git2::Commit,
+        opts: &ApplyOptions,
+        message: &str,
+    ) -> Result<git2::Oid, PatchApplyError> {
         let time = git2::Time::new(
             self.date.unix_timestamp(),
             // seconds -> minutes
             self.date.offset().whole_minutes() as i32,
         );
-        let author = Signature::new(&self.author_name, &self.author_email, &time)?;
-        // TODO: Handle detatched head/no commits
+        let (author_name, author_email) = opts
+            .author_map
+            .as_ref()
+            .and_then(|map| map.resolve(&self.author_name, &self.author_email))
+            .unwrap_or((&self.author_name, &self.author_email));
+        let mut author = Signature::new(author_name, author_email, &time)?;
+        if opts.use_mailmap {
+            author = target.mailmap()?.resolve_signature(&author)?;
+        }
+        let committer = match opts.committer {
+            Some(ref committer) if opts.committer_date_is_author_date => Signature::new(
+                committer.name_bytes().to_str_lossy().as_ref(),
+                committer.email_bytes().to_str_lossy().as_ref(),
+                &time,
+            )?,
+            Some(ref committer) => committer.clone(),
+            None => author.clone(),
+        };
+        let parents = vec![parent];
+        let commit_id = match self.message_encoding {
+            Some(ref encoding) => write_encoded_commit(
+                target,
+                &author,
+                &committer,
+                message,
+                encoding,
+                updated_tree,
+                &parents,
+            )?,
+            None => target.commit(None, &author, &committer, message, updated_tree, &parents)?,
+        };
+        if let (true, Some(notes)) = (opts.restore_notes, &self.notes) {
+            let force = true;
+            target.note(&committer, &committer, None, commit_id, notes, force)?;
+        }
+        if let (true, Some(source)) = (opts.verify_source_commit, self.source_commit) {
+            if source == commit_id {
+                slog::debug!(
+                    opts.logger(),
+                    "Reproduced the original commit {}",
+                    ShortId(source)
+                );
+            } else {
+                slog::warn!(
+                    opts.logger(), "Applied commit differs from the original";
+                    "original" => %ShortId(source),
+                    "applied" => %ShortId(commit_id),
+                );
+            }
+        }
+        Ok(commit_id)
+    }
+
+    /// Apply this email to the HEAD commit,
+    /// amending it instead of creating a new commit.
+    ///
+    /// The author, message and parents of the HEAD commit are preserved,
+    /// only its tree is updated.
+    pub fn apply_amending(&self, target: &Repository) -> Result<(), PatchApplyError> {
         let head_commit = target.head()?.peel_to_commit()?;
-        let parents = vec![&head_commit];
-        let message = self.full_message();
-        let commit_id = target.commit(
+        let updated_tree =
+            self.build_tree(target, &head_commit.tree()?, &ApplyOptions::default())?;
+        let commit_id = head_commit.amend(
             Some("HEAD"),
-            &author,
-            &author,
-            &message,
-            &updated_tree,
-            &parents,
+            /* author */ None,
+            /* committer */ None,
+            /* message_encoding */ None,
+            /* message */ None,
+            Some(&updated_tree),
         )?;
         let commit = target.find_commit(commit_id).unexpected()?;
         target
@@ -326,6 +1297,678 @@ impl EmailMessage {
             .unexpected()?;
         Ok(())
     }
+
+    /// Apply the diff of this email to the specified tree, returning the id of the updated tree.
+    ///
+    /// This only writes objects to the repository,
+    /// without touching the index, the worktree or `HEAD`.
+    pub fn apply_to_tree(
+        &self,
+        target: &Repository,
+        base: &git2::Tree,
+    ) -> Result<git2::Oid, PatchApplyError> {
+        Ok(self
+            .build_tree(target, base, &ApplyOptions::default())?
+            .id())
+    }
+
+    /// Apply only the hunks of this patch accepted by the `selector`,
+    /// like `git apply --index` after deleting the unwanted hunks from the patch.
+    ///
+    /// The selector receives each delta and the index of one of its hunks, starting from zero.
+    /// A delta is skipped entirely if none of its hunks are selected,
+    /// and a delta without any hunks (like a binary file or a mode change)
+    /// counts as a single hunk with index zero.
+    /// The hunks after a skipped hunk are found at an offset, just like moved context.
+    ///
+    /// The result is checked out into the index and worktree of the repo, without committing,
+    /// so the partial change can be reviewed (or amended into another commit) first.
+    /// This is useful for surgical history edits, like reverting part of a patch
+    /// by applying its reverse.
+    pub fn apply_selected_hunks(
+        &self,
+        repo: &Repository,
+        selector: impl Fn(&DeltaDesc, usize) -> bool,
+    ) -> Result<(), PatchApplyError> {
+        let head_tree = repo.head()?.peel_to_tree()?;
+        let updated_tree = self.build_tree_with(
+            repo,
+            &head_tree,
+            &ApplyOptions::default(),
+            &mut |data| repo.blob(data),
+            None,
+            Some(&selector),
+        )?;
+        repo.checkout_tree(updated_tree.as_object(), None)?;
+        Ok(())
+    }
+
+    /// Check which deltas of this patch would apply to the `HEAD` tree of the repo.
+    ///
+    /// Every delta is patched exactly like [EmailMessage::apply_commit] would,
+    /// but the patched blobs are only hashed and the updated tree is discarded,
+    /// so nothing is written to the repository.
+    /// Unlike applying, this continues past the first delta that fails.
+    /// The report also records where each hunk applied, like the
+    /// `Hunk #N succeeded at M (offset K lines)` output of `git apply`.
+    pub fn validate_against(&self, repo: &Repository) -> Result<ApplyReport, PatchApplyError> {
+        let tree = repo.head()?.peel_to_tree()?;
+        let mut discarded_tree = TreeUpdateBuilder::new();
+        let mut report = ApplyReport::default();
+        let logger = ApplyOptions::default().logger();
+        for (delta_idx, git_delta) in self.git_diff.deltas().enumerate() {
+            let desc = DeltaDesc::from_git(Some(delta_idx), &git_delta)?;
+            let mut hunks = Vec::new();
+            let res = self.apply_delta(DeltaApplyContext {
+                git_delta,
+                delta_idx,
+                orig_tree: &tree,
+                repo,
+                desc: desc.clone(),
+                result_tree: &mut discarded_tree,
+                write_blob: &mut |data| git2::Oid::hash_object(git2::ObjectType::Blob, data),
+                line_endings: None,
+                offset_resolver: None,
+                applied_hunks: Some(&mut hunks),
+                hunk_selector: None,
+                whitespace: WhitespaceAction::NoWarn,
+                logger: &logger,
+            });
+            match res {
+                Ok(()) => report.applied.push(AppliedDelta { delta: desc, hunks }),
+                Err(cause) => report.failed.push(FailedDelta { delta: desc, cause }),
+            }
+        }
+        Ok(report)
+    }
+
+    /// Reconstruct the files this patch was generated from, like `git am -3`.
+    ///
+    /// The tree contains the original blob of every modified, renamed or deleted file,
+    /// as recorded by the `index` lines of the patch, at its original path.
+    /// Added files have no original blob, so they are left out.
+    /// The blobs must already be in the repository, which is usually the case
+    /// if the patch was generated from an ancestor of the target (like a fork).
+    ///
+    /// The patch always applies cleanly to this tree,
+    /// so it can be 3-way merged into a tree that it no longer applies to directly.
+    pub fn build_fake_ancestor<'repo>(
+        &self,
+        repo: &'repo Repository,
+    ) -> Result<git2::Tree<'repo>, PatchApplyError> {
+        self.build_fake_ancestor_with(repo, &ApplyOptions::default())
+    }
+
+    /// Reconstruct the files this patch was generated from,
+    /// at the paths they are rewritten to by the options.
+    fn build_fake_ancestor_with<'repo>(
+        &self,
+        repo: &'repo Repository,
+        opts: &ApplyOptions,
+    ) -> Result<git2::Tree<'repo>, PatchApplyError> {
+        let mut builder = TreeUpdateBuilder::new();
+        for (delta_idx, git_delta) in self.git_diff.deltas().enumerate() {
+            if git_delta.status() == DeltaStatus::Added {
+                continue;
+            }
+            let desc = DeltaDesc::from_git(Some(delta_idx), &git_delta)?.rewrite_paths(opts)?;
+            let old_file = git_delta.old_file();
+            let blob = find_blob_by_prefix(repo, old_file.id()).map_err(|cause| {
+                PatchApplyError::MissingPreimage {
+                    delta: Box::new(desc.clone()),
+                    cause,
+                }
+            })?;
+            let mode = match old_file.mode() {
+                FileMode::BlobExecutable => FileMode::BlobExecutable,
+                _ => FileMode::Blob,
+            };
+            let path = desc.old_path().expect("Delta should have old path");
+            builder.upsert(path.as_std_path(), blob, mode);
+        }
+        let empty_tree = repo.find_tree(repo.treebuilder(None)?.write()?)?;
+        let tree_id = builder
+            .create_updated(repo, &empty_tree)
+            .map_err(|cause| PatchApplyError::FailBuildTree { cause })?;
+        Ok(repo.find_tree(tree_id)?)
+    }
+
+    /// The number of files changed, and lines inserted or deleted by this patch
+    pub fn stats(&self) -> Result<git2::DiffStats, git2::Error> {
+        self.git_diff.stats()
+    }
+
+    /// The parsed diff of this email
+    pub(crate) fn git_diff(&self) -> &git2::Diff<'static> {
+        &self.git_diff
+    }
+
+    /// Apply the diff of this email to the specified tree, returning the updated tree.
+    pub(crate) fn build_tree<'repo>(
+        &self,
+        target: &'repo Repository,
+        tree: &git2::Tree<'repo>,
+        opts: &ApplyOptions,
+    ) -> Result<git2::Tree<'repo>, PatchApplyError> {
+        let mut blob_writer = opts.blob_writer.as_ref().map(RefCell::borrow_mut);
+        let mut offset_resolver = opts.offset_resolver.as_ref().map(RefCell::borrow_mut);
+        let mut write_blob = |data: &[u8]| match blob_writer {
+            Some(ref mut writer) => writer(data),
+            None => target.blob(data),
+        };
+        match self.build_tree_with(
+            target,
+            tree,
+            opts,
+            &mut write_blob,
+            offset_resolver.as_deref_mut(),
+            None,
+        ) {
+            Err(cause @ PatchApplyError::FailDelta { .. }) if opts.three_way => self
+                .merge_with_fake_ancestor(
+                    target,
+                    tree,
+                    opts,
+                    &mut write_blob,
+                    offset_resolver.as_deref_mut(),
+                )
+                .map_err(|merge_cause| match merge_cause {
+                    // Without the original files, the reason the patch didn't apply is more useful
+                    PatchApplyError::MissingPreimage { .. } => cause,
+                    merge_cause => merge_cause,
+                }),
+            res => res,
+        }
+    }
+
+    /// Apply the patch to the files it was generated from,
+    /// then merge the result into the tree, like `git am -3`.
+    ///
+    /// See [ApplyOptions::three_way].
+    fn merge_with_fake_ancestor<'repo>(
+        &self,
+        target: &'repo Repository,
+        tree: &git2::Tree<'repo>,
+        opts: &ApplyOptions,
+        write_blob: &mut dyn FnMut(&[u8]) -> Result<git2::Oid, git2::Error>,
+        offset_resolver: Option<&mut OffsetResolver>,
+    ) -> Result<git2::Tree<'repo>, PatchApplyError> {
+        let ancestor = self.build_fake_ancestor_with(target, opts)?;
+        slog::debug!(
+            opts.logger(),
+            "Patch doesn't apply directly, falling back to a 3-way merge"
+        );
+        // The patch always applies cleanly to the files it was generated from
+        let theirs =
+            self.build_tree_with(target, &ancestor, opts, write_blob, offset_resolver, None)?;
+        let mut merged = target.merge_trees(&ancestor, tree, &theirs, None)?;
+        if merged.has_conflicts() {
+            let mut paths = Vec::new();
+            for conflict in merged.conflicts()? {
+                let conflict = conflict?;
+                let entry = conflict
+                    .our
+                    .or(conflict.their)
+                    .or(conflict.ancestor)
+                    .expect("Conflict should have an entry");
+                paths.push(Utf8PathBuf::from(
+                    String::from_utf8_lossy(&entry.path).into_owned(),
+                ));
+            }
+            return Err(PatchApplyError::MergeConflict { paths });
+        }
+        let merged_tree = merged.write_tree_to(target)?;
+        Ok(target.find_tree(merged_tree)?)
+    }
+
+    fn build_tree_with<'repo>(
+        &self,
+        target: &'repo Repository,
+        tree: &git2::Tree<'repo>,
+        opts: &ApplyOptions,
+        write_blob: &mut dyn FnMut(&[u8]) -> Result<git2::Oid, git2::Error>,
+        mut offset_resolver: Option<&mut OffsetResolver>,
+        hunk_selector: Option<HunkSelector>,
+    ) -> Result<git2::Tree<'repo>, PatchApplyError> {
+        let mut new_tree = TreeUpdateBuilder::new();
+        let mut failed_deltas = Vec::new();
+        let logger = opts.logger();
+        for (delta_idx, git_delta) in self.git_diff.deltas().enumerate() {
+            let desc = DeltaDesc::from_git(Some(delta_idx), &git_delta)?.rewrite_paths(opts)?;
+            if desc.delta_status == DeltaStatus::Added
+                && opts.check_ignored != IgnoredPathCheck::Off
+            {
+                let path = desc.new_path().expect("Added file should have path");
+                if target.status_should_ignore(path.as_std_path())? {
+                    if opts.check_ignored == IgnoredPathCheck::Error {
+                        return Err(PatchApplyError::AddsIgnoredFile { path: path.into() });
+                    }
+                    slog::warn!(
+                        logger, "Patch adds ignored file";
+                        "path" => path.as_str(),
+                    );
+                }
+            }
+            let res = self.apply_delta(DeltaApplyContext {
+                git_delta,
+                delta_idx,
+                orig_tree: tree,
+                repo: target,
+                desc: desc.clone(),
+                result_tree: &mut new_tree,
+                write_blob: &mut *write_blob,
+                line_endings: opts.line_endings,
+                offset_resolver: offset_resolver.as_deref_mut(),
+                applied_hunks: None,
+                hunk_selector,
+                whitespace: opts.whitespace,
+                logger: &logger,
+            });
+            match res {
+                Ok(()) => {}
+                Err(_) if opts.leave_conflicts_in_worktree => {
+                    failed_deltas.push(desc);
+                }
+                Err(cause) => {
+                    return Err(PatchApplyError::FailDelta {
+                        cause,
+                        delta: Box::new(desc),
+                    })
+                }
+            }
+        }
+        let updated_tree_oid = new_tree
+            .create_updated(target, tree)
+            .map_err(|cause| PatchApplyError::FailBuildTree { cause })?;
+        let updated_tree = target.find_tree(updated_tree_oid).unexpected()?;
+        if !failed_deltas.is_empty() {
+            return Err(self.leave_in_worktree(target, opts, &updated_tree, failed_deltas));
+        }
+        Ok(updated_tree)
+    }
+
+    /// Apply the diff to the worktree and index with libgit2, returning the updated tree.
+    ///
+    /// See [ApplyEngine::Libgit2].
+    fn apply_with_libgit2<'repo>(
+        &self,
+        target: &'repo Repository,
+    ) -> Result<git2::Tree<'repo>, PatchApplyError> {
+        if target.workdir().is_none() {
+            return Err(PatchApplyError::BareRepository);
+        }
+        // libgit2 doesn't report where it failed, so remember the last delta and hunk it reached
+        let current_delta = Cell::new(None::<usize>);
+        let current_hunk = Cell::new(None::<usize>);
+        let mut apply_opts = git2::ApplyOptions::new();
+        apply_opts.delta_callback(|_| {
+            current_delta.set(Some(current_delta.get().map_or(0, |index| index + 1)));
+            current_hunk.set(None);
+            true
+        });
+        apply_opts.hunk_callback(|_| {
+            current_hunk.set(Some(current_hunk.get().map_or(0, |index| index + 1)));
+            true
+        });
+        if let Err(cause) = target.apply(&self.git_diff, ApplyLocation::Both, Some(&mut apply_opts))
+        {
+            let Some(delta_idx) = current_delta.get() else {
+                return Err(cause.into());
+            };
+            let git_delta = self
+                .git_diff
+                .get_delta(delta_idx)
+                .expect("Failed delta should exist");
+            return Err(PatchApplyError::FailDelta {
+                delta: Box::new(DeltaDesc::from_git(Some(delta_idx), &git_delta)?),
+                cause: DeltaApplyError::Libgit2Apply {
+                    hunk: current_hunk.get(),
+                    cause,
+                },
+            });
+        }
+        let tree = target.index()?.write_tree()?;
+        Ok(target.find_tree(tree)?)
+    }
+}
+
+/// Find a blob from the possibly abbreviated id of an `index` line.
+///
+/// libgit2 pads abbreviated ids with zeros, so the trailing zeros are treated as unknown.
+/// Some of them may be real digits of the abbreviation though,
+/// so an ambiguous prefix is lengthened with them until it's unique.
+/// If that doesn't help, the ambiguity is reported instead of guessing.
+#[cfg(feature = "git")]
+fn find_blob_by_prefix(repo: &Repository, id: git2::Oid) -> Result<git2::Oid, git2::Error> {
+    let hex = id.to_string();
+    // A prefix must have at least four digits
+    let mut len = hex.trim_end_matches('0').len().max(4);
+    let mut ambiguous = None;
+    loop {
+        match repo.find_object_by_prefix(&hex[..len], Some(git2::ObjectType::Blob)) {
+            Ok(blob) => return Ok(blob.id()),
+            Err(e) if e.code() == git2::ErrorCode::Ambiguous && len < hex.len() => {
+                ambiguous.get_or_insert(e);
+                len += 1;
+            }
+            Err(e) => return Err(ambiguous.unwrap_or(e)),
+        }
+    }
+}
+
+/// Decode the zlib compressed contents of a `literal` binary patch.
+#[cfg(all(feature = "git", feature = "gzip"))]
+fn inflate_binary_literal(
+    deflated: &[u8],
+    inflated_len: usize,
+) -> Result<Vec<u8>, DeltaApplyError> {
+    /*
+     * The length comes from the patch, so don't trust it to preallocate the contents.
+     * Reading one byte more than expected detects data that is too long,
+     * and limits the inflated contents to what the (size limited) patch could contain.
+     */
+    let mut contents = Vec::new();
+    flate2::read::ZlibDecoder::new(deflated)
+        .take((inflated_len as u64).saturating_add(1))
+        .read_to_end(&mut contents)
+        .and_then(|len| match len == inflated_len {
+            true => Ok(contents),
+            false => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Expected {inflated_len} bytes, but decoded {len}"),
+            )),
+        })
+        .map_err(|cause| DeltaApplyError::CorruptBinaryPatch { cause })
+}
+
+#[cfg(all(feature = "git", not(feature = "gzip")))]
+fn inflate_binary_literal(
+    _deflated: &[u8],
+    _inflated_len: usize,
+) -> Result<Vec<u8>, DeltaApplyError> {
+    Err(DeltaApplyError::BinaryLiteralUnsupported)
+}
+
+/// Run the `pre-applypatch` hook, with the patched tree checked out in the worktree and index.
+///
+/// Uncommitted changes to the patched files are never overwritten.
+/// If the hook fails, the patched files are restored to the original tree.
+#[cfg(feature = "git")]
+fn run_pre_applypatch(
+    target: &Repository,
+    tree: &git2::Tree,
+    updated_tree: &git2::Tree,
+) -> Result<(), PatchApplyError> {
+    if hooks::find_hook(target, "pre-applypatch")
+        .map_err(|cause| PatchApplyError::HookFailed {
+            hook: "pre-applypatch",
+            cause,
+        })?
+        .is_none()
+    {
+        return Ok(());
+    }
+    if target.workdir().is_some() {
+        checkout_safely(target, updated_tree)?;
+        let mut index = target.index()?;
+        index.read_tree(updated_tree)?;
+        index.write()?;
+    }
+    if let Err(cause) = hooks::run_hook(target, "pre-applypatch", &[]) {
+        if target.workdir().is_some() {
+            /*
+             * The patched files didn't have any uncommitted changes before they were checked out,
+             * so only they are restored, leaving the rest of the worktree alone.
+             */
+            let diff = target.diff_tree_to_tree(Some(tree), Some(updated_tree), None)?;
+            let mut checkout = CheckoutBuilder::new();
+            checkout.force();
+            for delta in diff.deltas() {
+                for path in [delta.old_file().path(), delta.new_file().path()]
+                    .into_iter()
+                    .flatten()
+                {
+                    checkout.path(path);
+                }
+            }
+            if diff.deltas().len() > 0 {
+                target.checkout_tree(tree.as_object(), Some(&mut checkout))?;
+            }
+            let mut index = target.index()?;
+            index.read_tree(tree)?;
+            index.write()?;
+        }
+        return Err(PatchApplyError::HookFailed {
+            hook: "pre-applypatch",
+            cause,
+        });
+    }
+    Ok(())
+}
+
+/// Check out the tree into the worktree and index of the target,
+/// without overwriting any uncommitted changes to the files it modifies.
+#[cfg(feature = "git")]
+fn checkout_safely(target: &Repository, tree: &git2::Tree) -> Result<(), PatchApplyError> {
+    let mut conflicts = Vec::new();
+    let mut checkout = CheckoutBuilder::new();
+    checkout
+        .safe()
+        .notify_on(git2::CheckoutNotificationType::CONFLICT)
+        .notify(|_kind, path, _baseline, _target, _workdir| {
+            conflicts.extend(path.map(std::path::Path::to_path_buf));
+            true
+        });
+    let res = target.checkout_tree(tree.as_object(), Some(&mut checkout));
+    drop(checkout);
+    match res {
+        Ok(()) => Ok(()),
+        Err(_) if !conflicts.is_empty() => Err(PatchApplyError::DirtyWorktree {
+            paths: conflicts
+                .into_iter()
+                .map(Utf8PathBuf::try_from)
+                .collect::<Result<_, _>>()?,
+        }),
+        Err(cause) => Err(cause.into()),
+    }
+}
+
+/// Log the tree before and after applying a patch, at the trace level.
+///
+/// Comparing these tree ids against a known-good run
+/// shows exactly where a series of patches diverges.
+/// The changed entries are only computed if trace logging is enabled.
+#[cfg(feature = "git")]
+fn trace_tree_change(
+    logger: &slog::Logger,
+    summary: &str,
+    target: &Repository,
+    old_tree: &git2::Tree,
+    new_tree: &git2::Tree,
+) -> Result<(), git2::Error> {
+    use slog::Drain;
+    if !logger.is_trace_enabled() {
+        return Ok(());
+    }
+    slog::trace!(
+        logger,
+        "Applied patch: tree {} -> {}", ShortId(old_tree.id()), ShortId(new_tree.id());
+        "summary" => summary,
+    );
+    let diff = target.diff_tree_to_tree(Some(old_tree), Some(new_tree), None)?;
+    for delta in diff.deltas() {
+        let path = delta.new_file().path().or(delta.old_file().path());
+        slog::trace!(
+            logger,
+            "Changed tree entry: {} {} -> {}",
+            path.map_or_else(Default::default, |path| path.to_string_lossy()),
+            ShortId(delta.old_file().id()),
+            ShortId(delta.new_file().id());
+            "status" => ?delta.status(),
+        );
+    }
+    Ok(())
+}
+
+/// Check if the line of a commit message is a trailer,
+/// like `Signed-off-by: ...` or a previous `(cherry picked from commit ...)`.
+#[cfg(feature = "git")]
+fn is_trailer_line(line: &str) -> bool {
+    if line.starts_with("(cherry picked from commit ") {
+        return true;
+    }
+    line.split_once(": ").is_some_and(|(key, _)| {
+        !key.is_empty() && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    })
+}
+
+/// Check if most of the lines in the text end with CRLF instead of LF.
+#[cfg(feature = "git")]
+fn is_mostly_crlf(text: &[u8]) -> bool {
+    let crlf = text.find_iter("\r\n").count();
+    let lf = text.find_iter("\n").count() - crlf;
+    crlf > lf
+}
+
+/// Strip the trailing whitespace from the lines a patch adds,
+/// returning how many lines had any along with the fixed patch.
+///
+/// Carriage returns are kept, so CRLF lines are only stripped before the `\r`.
+#[cfg(feature = "git")]
+fn strip_trailing_whitespace(patch: &[u8]) -> (usize, Vec<u8>) {
+    let mut fixed = Vec::with_capacity(patch.len());
+    let mut lines = 0;
+    let mut in_hunk = false;
+    for line in patch.split_inclusive(|&b| b == b'\n') {
+        in_hunk |= line.starts_with(b"@@");
+        if !in_hunk || !line.starts_with(b"+") {
+            fixed.extend_from_slice(line);
+            continue;
+        }
+        let content = line.strip_suffix(b"\n").unwrap_or(line);
+        let content_end = content.strip_suffix(b"\r").unwrap_or(content).len();
+        let trimmed_end = line[..content_end]
+            .iter()
+            .rposition(|&b| b != b' ' && b != b'\t')
+            .map_or(0, |idx| idx + 1);
+        if trimmed_end < content_end {
+            lines += 1;
+        }
+        fixed.extend_from_slice(&line[..trimmed_end]);
+        fixed.extend_from_slice(&line[content_end..]);
+    }
+    (lines, fixed)
+}
+
+/// Write a commit whose message is stored in the specified `encoding`,
+/// returning its id without updating any references.
+///
+/// libgit2 can only create UTF-8 commits,
+/// so we insert the `encoding` header into the raw commit ourselves.
+#[cfg(feature = "git")]
+fn write_encoded_commit(
+    target: &Repository,
+    author: &Signature,
+    committer: &Signature,
+    message: &str,
+    encoding: &str,
+    tree: &git2::Tree,
+    parents: &[&git2::Commit],
+) -> Result<git2::Oid, PatchApplyError> {
+    let converter = encoding_rs::Encoding::for_label(encoding.as_bytes()).ok_or_else(|| {
+        PatchApplyError::UnencodableMessage {
+            encoding: encoding.into(),
+        }
+    })?;
+    let (encoded_message, _, had_errors) = converter.encode(message);
+    if had_errors {
+        return Err(PatchApplyError::UnencodableMessage {
+            encoding: encoding.into(),
+        });
+    }
+    let buffer = target.commit_create_buffer(author, committer, "", tree, parents)?;
+    let headers = buffer
+        .strip_suffix(b"\n")
+        .expect("Commit buffer should end with blank line");
+    let mut raw_commit = Vec::with_capacity(headers.len() + encoded_message.len() + 32);
+    raw_commit.extend_from_slice(headers);
+    raw_commit.extend_from_slice(format!("encoding {encoding}\n\n").as_bytes());
+    raw_commit.extend_from_slice(&encoded_message);
+    Ok(target.odb()?.write(git2::ObjectType::Commit, &raw_commit)?)
+}
+
+#[cfg(feature = "git")]
+impl EmailMessage {
+    /// Write the partially applied result into the worktree of the target,
+    /// falling back to libgit2 for the deltas that failed.
+    ///
+    /// Deltas that libgit2 can't apply either are written as `.rej` files,
+    /// like `git apply --reject`.
+    fn leave_in_worktree(
+        &self,
+        target: &Repository,
+        opts: &ApplyOptions,
+        partial_tree: &git2::Tree,
+        failed_deltas: Vec<DeltaDesc>,
+    ) -> PatchApplyError {
+        if let Err(cause) = checkout_safely(target, partial_tree) {
+            return cause;
+        }
+        let workdir = match target.workdir() {
+            Some(workdir) => workdir,
+            None => return PatchApplyError::BareRepository,
+        };
+        let mut rejected = Vec::new();
+        for desc in failed_deltas {
+            let delta_index = desc.delta_index.expect("Failed delta should have index");
+            let mut current_delta = 0;
+            let mut apply_opts = git2::ApplyOptions::new();
+            apply_opts.delta_callback(|_| {
+                let matches = current_delta == delta_index;
+                current_delta += 1;
+                matches
+            });
+            // libgit2 doesn't know about our path rewriting
+            if !opts.rewrites_paths()
+                && target
+                    .apply(
+                        &self.git_diff,
+                        ApplyLocation::WorkDir,
+                        Some(&mut apply_opts),
+                    )
+                    .is_ok()
+            {
+                continue;
+            }
+            let patch_text = match git2::Patch::from_diff(&self.git_diff, delta_index)
+                .and_then(|patch| patch.map(|mut patch| patch.to_buf()).transpose())
+            {
+                Ok(Some(buf)) => buf,
+                Ok(None) => {
+                    // Binary patches can't be rejected
+                    rejected.push(desc);
+                    continue;
+                }
+                Err(cause) => return cause.into(),
+            };
+            let path = desc
+                .new_path()
+                .or_else(|| desc.old_path())
+                .expect("Delta should have a path");
+            let reject_file = workdir.join(format!("{path}.rej"));
+            if let Some(parent) = reject_file.parent() {
+                // The directory might not exist if paths were rewritten
+                if let Err(cause) = std::fs::create_dir_all(parent) {
+                    return PatchApplyError::WriteReject { cause, reject_file };
+                }
+            }
+            if let Err(cause) = std::fs::write(&reject_file, patch_text.as_bytes()) {
+                return PatchApplyError::WriteReject { cause, reject_file };
+            }
+            rejected.push(desc);
+        }
+        PatchApplyError::LeftInWorktree { rejected }
+    }
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -345,12 +1988,52 @@ pub enum InvalidEmailMessage {
         #[source]
         cause: time::error::Parse,
     },
-    #[error("Invalid UTF8")]
+    /// The patch isn't valid UTF8,
+    /// like a commit message with a stray Latin-1 character.
+    #[error("Invalid UTF8 at byte {offset} (line {line})")]
+    InvalidUtf8 {
+        /// The offset of the first invalid byte, starting from zero
+        offset: usize,
+        /// The line containing the first invalid byte, starting from one
+        line: usize,
+        #[source]
+        cause: std::str::Utf8Error,
+    },
+    #[error("Patch has an empty subject")]
+    EmptySubject,
+    #[error("Unknown commit message encoding {label:?}")]
+    UnknownEncoding { label: String },
+    #[error("Patch is {size} bytes, exceeding the limit of {limit} bytes")]
+    PatchTooLarge { size: u64, limit: u64 },
+    #[error("Unexpected NUL byte at offset {offset}")]
+    NulByte { offset: usize },
+    #[cfg(feature = "git")]
+    #[error("Delta {delta} of the diff is missing a path")]
+    MissingDeltaPath { delta: usize },
+    /// Some of the `diff --git` sections of the patch were malformed,
+    /// so libgit2 parsed a different number of deltas.
+    #[cfg(feature = "git")]
+    #[error("Patch has {headers} `diff --git` sections, but libgit2 parsed {deltas} deltas")]
+    DeltaCountMismatch { headers: usize, deltas: usize },
+    #[error("Unexpected content after the diff on line {line}: {content:?}")]
+    TrailingGarbage { line: usize, content: String },
+    #[cfg(feature = "git")]
+    #[error("Internal git error: {0}")]
+    Git(#[from] git2::Error),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum EmailSerializeError {
+    #[error("Unable to format date: {0}")]
+    InvalidDate(#[from] time::error::Format),
+    #[error("Diff contains invalid UTF8")]
     InvalidUtf8(#[from] std::str::Utf8Error),
+    #[cfg(feature = "git")]
     #[error("Internal git error: {0}")]
     Git(#[from] git2::Error),
 }
 
+#[cfg(feature = "git")]
 #[derive(Debug, thiserror::Error)]
 pub enum PatchApplyError {
     #[error("Failed to apply delta {delta}")]
@@ -359,11 +2042,59 @@ pub enum PatchApplyError {
         #[source]
         cause: DeltaApplyError,
     },
+    /// The blob a delta was generated against isn't in the repository,
+    /// so the ancestor of the patch can't be reconstructed.
+    #[error("Unable to find the original blob of {delta}")]
+    MissingPreimage {
+        delta: Box<DeltaDesc>,
+        #[source]
+        cause: git2::Error,
+    },
+    /// The patch didn't apply directly, and the 3-way merge with the files
+    /// it was generated from conflicted (like a file that was modified and deleted).
+    ///
+    /// Only returned if [ApplyOptions::three_way] is set.
+    #[error("Patch conflicts with the target in {} files", paths.len())]
+    MergeConflict { paths: Vec<Utf8PathBuf> },
     #[error("Failed to construct updated tree")]
     FailBuildTree {
         #[source]
         cause: git2::Error,
     },
+    /// Some deltas failed to apply, and the partial result
+    /// was left in the worktree for manual resolution.
+    ///
+    /// Only returned if [ApplyOptions::leave_conflicts_in_worktree] is set.
+    #[error("Failed to apply {} deltas, partial changes left in worktree", rejected.len())]
+    LeftInWorktree { rejected: Vec<DeltaDesc> },
+    /// Writing the result to the worktree would overwrite uncommitted changes to these files.
+    #[error("Uncommitted changes to {} files would be overwritten", paths.len())]
+    DirtyWorktree { paths: Vec<Utf8PathBuf> },
+    #[error("Failed to write rejected hunks to {}", reject_file.display())]
+    WriteReject {
+        reject_file: std::path::PathBuf,
+        #[source]
+        cause: std::io::Error,
+    },
+    #[error("Target repository has no worktree")]
+    BareRepository,
+    #[error("The {hook} hook rejected the patch")]
+    HookFailed {
+        hook: &'static str,
+        #[source]
+        cause: HookError,
+    },
+    #[error("Patch adds file ignored by the target repository: {path}")]
+    AddsIgnoredFile { path: Utf8PathBuf },
+    #[error("Stripping {strip_components} components from `{path}` leaves an empty path")]
+    StripEmptyPath {
+        path: Utf8PathBuf,
+        strip_components: usize,
+    },
+    #[error(transparent)]
+    InvalidOptions(#[from] InvalidApplyOptions),
+    #[error("Commit message can't be represented in {encoding}")]
+    UnencodableMessage { encoding: String },
     #[error(transparent)]
     ForbiddenAbsolutePath(#[from] AbsolutePathError),
     #[error(transparent)]
@@ -377,6 +2108,7 @@ pub enum PatchApplyError {
         backtrace: std::backtrace::Backtrace,
     },
 }
+#[cfg(feature = "git")]
 impl From<BadPathError> for PatchApplyError {
     fn from(value: BadPathError) -> Self {
         match value {
@@ -386,6 +2118,51 @@ impl From<BadPathError> for PatchApplyError {
     }
 }
 
+/// Which deltas of a patch would apply, see [EmailMessage::validate_against].
+#[cfg(feature = "git")]
+#[derive(Debug, Default)]
+pub struct ApplyReport {
+    /// The deltas that would apply cleanly
+    pub applied: Vec<AppliedDelta>,
+    /// The deltas that would fail, in order
+    pub failed: Vec<FailedDelta>,
+}
+#[cfg(feature = "git")]
+impl ApplyReport {
+    /// If the entire patch would apply
+    #[inline]
+    pub fn is_clean(&self) -> bool {
+        self.failed.is_empty()
+    }
+}
+/// A delta that would apply, see [ApplyReport].
+#[cfg(feature = "git")]
+#[derive(Debug)]
+pub struct AppliedDelta {
+    pub delta: DeltaDesc,
+    /// The position where each hunk would be applied.
+    ///
+    /// A hunk with a nonzero [Candidate::offset] applied at a different line
+    /// than the one in its header, which may be the wrong place.
+    /// This is empty for deltas without any hunks, like binary files.
+    pub hunks: Vec<Candidate>,
+}
+#[cfg(feature = "git")]
+impl AppliedDelta {
+    /// If any hunk applied at a different line than the one in its header
+    pub fn has_offset(&self) -> bool {
+        self.hunks.iter().any(|hunk| hunk.offset != 0)
+    }
+}
+/// A delta that would fail to apply, see [ApplyReport].
+#[cfg(feature = "git")]
+#[derive(Debug)]
+pub struct FailedDelta {
+    pub delta: DeltaDesc,
+    pub cause: DeltaApplyError,
+}
+
+#[cfg(feature = "git")]
 #[derive(Debug, thiserror::Error)]
 pub enum DeltaApplyError {
     #[error("Deleting file failed, {cause}")]
@@ -397,8 +2174,24 @@ pub enum DeltaApplyError {
     MissingOriginalFile { path: std::path::PathBuf },
     #[error("Unexpected delta status")]
     UnexpectedDeltaStatus { status: DeltaStatus },
+    /// A binary delta without the full contents of the new file
     #[error("Unexpected binary delta")]
     BinaryDelta,
+    /// The original file differs from the one the binary patch was generated from,
+    /// so applying it would overwrite the changes.
+    #[error("Binary patch expects original blob {expected}, but found {actual}")]
+    BinaryPreimageMismatch {
+        expected: git2::Oid,
+        actual: git2::Oid,
+    },
+    /// Decoding binary patches requires the `gzip` feature
+    #[error("Binary patches require the `gzip` feature")]
+    BinaryLiteralUnsupported,
+    #[error("Corrupt binary patch, {cause}")]
+    CorruptBinaryPatch {
+        #[source]
+        cause: std::io::Error,
+    },
     #[error("Diffy failed to parse git delta, {cause}")]
     FailParseGitDelta {
         #[source]
@@ -409,6 +2202,30 @@ pub enum DeltaApplyError {
         #[source]
         cause: diffy::ApplyError,
     },
+    /// No position in the file matches the hunk with the specified index.
+    ///
+    /// Only returned when using an [ApplyOptions::offset_resolver].
+    #[error("No position matches hunk #{}", hunk + 1)]
+    HunkNotFound { hunk: usize },
+    /// The [ApplyOptions::offset_resolver] returned an out of range index
+    #[error("Chose candidate {choice} for hunk #{}, but there are only {candidates}", hunk + 1)]
+    InvalidOffsetChoice {
+        hunk: usize,
+        choice: usize,
+        candidates: usize,
+    },
+    /// Only returned by [ApplyEngine::Libgit2],
+    /// with the index of the hunk that was being applied (if any).
+    #[error("libgit2 failed to apply patch, {cause}")]
+    Libgit2Apply {
+        hunk: Option<usize>,
+        #[source]
+        cause: git2::Error,
+    },
+    /// The patch adds lines with trailing whitespace,
+    /// which is an error with [WhitespaceAction::Error].
+    #[error("Patch adds {lines} lines with trailing whitespace")]
+    TrailingWhitespace { lines: usize },
     #[error("Internal git error: {cause}")]
     UnexpectedGit {
         #[source]
@@ -418,11 +2235,13 @@ pub enum DeltaApplyError {
         backtrace: std::backtrace::Backtrace,
     },
 }
+#[cfg(feature = "git")]
 struct UnexpectedGitError {
     cause: git2::Error,
     #[cfg(feature = "backtrace")]
     backtrace: std::backtrace::Backtrace,
 }
+#[cfg(feature = "git")]
 impl From<UnexpectedGitError> for DeltaApplyError {
     #[inline]
     fn from(value: UnexpectedGitError) -> Self {
@@ -433,6 +2252,7 @@ impl From<UnexpectedGitError> for DeltaApplyError {
         }
     }
 }
+#[cfg(feature = "git")]
 impl From<UnexpectedGitError> for PatchApplyError {
     fn from(value: UnexpectedGitError) -> Self {
         PatchApplyError::UnexpectedGit {
@@ -442,10 +2262,12 @@ impl From<UnexpectedGitError> for PatchApplyError {
         }
     }
 }
+#[cfg(feature = "git")]
 trait IntoUnexpected {
     type Res: Sized;
     fn unexpected(self) -> Self::Res;
 }
+#[cfg(feature = "git")]
 impl IntoUnexpected for git2::Error {
     type Res = UnexpectedGitError;
     #[cold]
@@ -458,6 +2280,7 @@ impl IntoUnexpected for git2::Error {
         }
     }
 }
+#[cfg(feature = "git")]
 impl<T, E> IntoUnexpected for Result<T, E>
 where
     E: IntoUnexpected,
@@ -473,6 +2296,26 @@ where
     }
 }
 
+/// The kind of change made by a delta.
+///
+/// This mirrors [git2::Delta](https://docs.rs/git2/latest/git2/enum.Delta.html),
+/// which is used instead when the `git` feature is enabled.
+#[cfg(not(feature = "git"))]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum DeltaStatus {
+    Unmodified,
+    Added,
+    Deleted,
+    Modified,
+    Renamed,
+    Copied,
+    Ignored,
+    Untracked,
+    Typechange,
+    Unreadable,
+    Conflicted,
+}
+
 fn delta_status_name(status: DeltaStatus) -> &'static str {
     match status {
         DeltaStatus::Unmodified => "unmodified",
@@ -504,25 +2347,68 @@ pub struct DeltaDesc {
     delta_index: Option<usize>,
     old_file: DeltaFileDesc,
     new_file: DeltaFileDesc,
-    delta_status: git2::Delta,
+    delta_status: DeltaStatus,
 }
 
 impl DeltaDesc {
-    fn old_path(&self) -> Option<&Utf8Path> {
+    /// The path of the file before the change, if any
+    pub fn old_path(&self) -> Option<&Utf8Path> {
         self.old_file.path.as_deref()
     }
-    fn new_path(&self) -> Option<&Utf8Path> {
+    /// The path of the file after the change, if any
+    pub fn new_path(&self) -> Option<&Utf8Path> {
         self.new_file.path.as_deref()
     }
+    /// How the file is changed (added, deleted, modified, renamed, ...)
+    #[inline]
+    pub fn status(&self) -> DeltaStatus {
+        self.delta_status
+    }
+    /// If either side of the change is a binary file
+    #[inline]
+    pub fn is_binary(&self) -> bool {
+        self.old_file.binary || self.new_file.binary
+    }
+    /// The position of this delta in the patch, starting from zero
+    #[inline]
+    pub fn index(&self) -> Option<usize> {
+        self.delta_index
+    }
+    /// Rewrite the paths of this delta according to the specified options.
+    #[cfg(feature = "git")]
+    fn rewrite_paths(mut self, opts: &ApplyOptions) -> Result<Self, PatchApplyError> {
+        for file in [&mut self.old_file, &mut self.new_file] {
+            if let Some(ref mut path) = file.path {
+                *path = opts
+                    .rewrite_path(path)
+                    .ok_or_else(|| PatchApplyError::StripEmptyPath {
+                        path: path.clone(),
+                        strip_components: opts.strip_components,
+                    })?;
+            }
+        }
+        Ok(self)
+    }
+    #[cfg(feature = "git")]
     fn from_git(index: Option<usize>, git_delta: &git2::DiffDelta) -> Result<Self, BadPathError> {
-        let old_file = DeltaFileDesc::try_from(git_delta.old_file()).map_err(|mut err| {
+        let mut old_file = DeltaFileDesc::try_from(git_delta.old_file()).map_err(|mut err| {
             err.set_role("old_file");
             err
         })?;
-        let new_file = DeltaFileDesc::try_from(git_delta.new_file()).map_err(|mut err| {
+        let mut new_file = DeltaFileDesc::try_from(git_delta.new_file()).map_err(|mut err| {
             err.set_role("new_file");
             err
         })?;
+        /*
+         * libgit2 fills in the path of the missing side from the `diff --git` line,
+         * so an added file can have a (possibly bogus) old path and vice versa.
+         * Clear them, so nothing ever reads or stages the missing file.
+         */
+        match git_delta.status() {
+            DeltaStatus::Added => old_file.path = None,
+            DeltaStatus::Deleted => new_file.path = None,
+            _ => {}
+        }
         Ok(DeltaDesc {
             old_file,
             new_file,
@@ -531,6 +2417,97 @@ impl DeltaDesc {
         })
     }
 }
+#[cfg(feature = "git")]
+impl EmailMessage {
+    /// Describe the deltas of this patch, without applying anything.
+    ///
+    /// The paths are exactly as they appear in the patch,
+    /// before any [ApplyOptions::path_prefix] or [ApplyOptions::strip_components].
+    pub fn deltas(&self) -> Result<Vec<DeltaDesc>, BadPathError> {
+        self.git_diff
+            .deltas()
+            .enumerate()
+            .map(|(delta_idx, git_delta)| DeltaDesc::from_git(Some(delta_idx), &git_delta))
+            .collect()
+    }
+}
+#[cfg(not(feature = "git"))]
+impl EmailMessage {
+    /// Describe the deltas of this patch, without applying anything.
+    ///
+    /// Without the `git` feature the diff is never fully parsed,
+    /// so this only looks at the `diff --git` lines and the extended headers that follow them.
+    /// Quoted paths (containing special characters) aren't supported.
+    pub fn deltas(&self) -> Result<Vec<DeltaDesc>, BadPathError> {
+        fn file_desc(path: &[u8], role: &'static str) -> Result<DeltaFileDesc, BadPathError> {
+            let path = Utf8PathBuf::from(String::from_utf8_lossy(path).into_owned());
+            if path.is_absolute() {
+                let mut err = BadPathError::AbsolutePath(AbsolutePathError { path, role: None });
+                err.set_role(role);
+                return Err(err);
+            }
+            Ok(DeltaFileDesc {
+                path: Some(path),
+                binary: false,
+            })
+        }
+        let mut deltas: Vec<DeltaDesc> = Vec::new();
+        // If we are still in the extended headers, before the first hunk
+        let mut in_header = false;
+        for line in self.diff_text.lines() {
+            if let Ok((_, (old_path, new_path))) = parse_begin_diff_line(line.as_bytes()) {
+                deltas.push(DeltaDesc {
+                    delta_index: Some(deltas.len()),
+                    old_file: file_desc(old_path, "old_file")?,
+                    new_file: file_desc(new_path, "new_file")?,
+                    delta_status: DeltaStatus::Modified,
+                });
+                in_header = true;
+                continue;
+            }
+            let Some(delta) = deltas.last_mut() else {
+                continue;
+            };
+            if !in_header {
+                continue;
+            } else if line.starts_with("@@") {
+                in_header = false;
+            } else if let Some(old_path) = parse_file_marker_line(line.as_bytes(), "--- a/") {
+                match old_path {
+                    None => {
+                        delta.delta_status = DeltaStatus::Added;
+                        delta.old_file.path = None;
+                    }
+                    Some(path) => delta.old_file = file_desc(path, "old_file")?,
+                }
+            } else if let Some(new_path) = parse_file_marker_line(line.as_bytes(), "+++ b/") {
+                match new_path {
+                    None => {
+                        delta.delta_status = DeltaStatus::Deleted;
+                        delta.new_file.path = None;
+                    }
+                    Some(path) => delta.new_file = file_desc(path, "new_file")?,
+                }
+            } else if line.starts_with("new file mode ") {
+                delta.delta_status = DeltaStatus::Added;
+                delta.old_file.path = None;
+            } else if line.starts_with("deleted file mode ") {
+                delta.delta_status = DeltaStatus::Deleted;
+                delta.new_file.path = None;
+            } else if line.starts_with("rename from ") {
+                delta.delta_status = DeltaStatus::Renamed;
+            } else if line.starts_with("copy from ") {
+                delta.delta_status = DeltaStatus::Copied;
+            } else if line == "GIT binary patch"
+                || (line.starts_with("Binary files ") && line.ends_with(" differ"))
+            {
+                delta.old_file.binary = true;
+                delta.new_file.binary = true;
+            }
+        }
+        Ok(deltas)
+    }
+}
 impl Display for DeltaDesc {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self.delta_status {
@@ -557,6 +2534,7 @@ impl Display for DeltaDesc {
 #[derive(Debug, Clone)]
 pub struct DeltaFileDesc {
     path: Option<Utf8PathBuf>,
+    #[cfg(feature = "git")]
     _oid: git2::Oid,
     binary: bool,
 }
@@ -589,6 +2567,7 @@ impl BadPathError {
         }
     }
 }
+#[cfg(feature = "git")]
 impl<'a> TryFrom<git2::DiffFile<'a>> for DeltaFileDesc {
     type Error = BadPathError;
 
@@ -607,8 +2586,460 @@ impl<'a> TryFrom<git2::DiffFile<'a>> for DeltaFileDesc {
                     .map(Utf8PathBuf::try_from)
                     .transpose()?,
                 binary: git_file.is_binary(),
+                #[cfg(feature = "git")]
                 _oid: git_file.id(),
             }),
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{EmailMessage, InvalidEmailMessage};
+
+    const APPROX_PI_PATCH: &str = include_str!("../../tests/data/approx_pi.rs.patch");
+
+    #[cfg(feature = "git")]
+    fn diff_text(msg: &EmailMessage) -> String {
+        let mut text = String::new();
+        msg.git_diff
+            .print(git2::DiffFormat::Patch, |_delta, _hunk, line| {
+                text.push(line.origin());
+                text.push_str(std::str::from_utf8(line.content()).unwrap());
+                true
+            })
+            .unwrap();
+        text
+    }
+
+    #[test]
+    fn parse_with_bom() {
+        let with_bom = format!("\u{FEFF}{APPROX_PI_PATCH}");
+        let msg = EmailMessage::parse(&with_bom).unwrap();
+        assert_eq!(msg.message_summary, "Increase iterations of approx_pi");
+        assert_eq!(msg.author_name, "Techcable");
+    }
+
+    #[test]
+    fn semantic_eq() {
+        let msg = EmailMessage::parse(APPROX_PI_PATCH).unwrap();
+        let reformatted = APPROX_PI_PATCH
+            .replacen("From: Techcable <", "From:  Techcable  <", 1)
+            .replacen(
+                "Subject: [PATCH] Increase iterations",
+                "Subject: [PATCH]  Increase   iterations",
+                1,
+            );
+        assert_ne!(reformatted, APPROX_PI_PATCH);
+        let other = EmailMessage::parse(&reformatted).unwrap();
+        assert!(msg.semantic_eq(&other));
+        let round_tripped = EmailMessage::parse(&msg.to_patch_string().unwrap()).unwrap();
+        assert!(round_tripped.semantic_eq(&msg));
+
+        let changed = APPROX_PI_PATCH.replacen("Increase iterations", "Decrease iterations", 1);
+        assert!(!msg.semantic_eq(&EmailMessage::parse(&changed).unwrap()));
+        let changed = APPROX_PI_PATCH.replacen("0..21", "0..42", 1);
+        assert!(!msg.semantic_eq(&EmailMessage::parse(&changed).unwrap()));
+    }
+
+    #[test]
+    #[cfg(feature = "git")]
+    fn source_commit() {
+        assert_eq!(
+            EmailMessage::parse(APPROX_PI_PATCH)
+                .unwrap()
+                .source_commit(),
+            None
+        );
+        let id = "4d1b6c3a8e2f9b0c7d5e1a2b3c4d5e6f7a8b9c0d";
+        let patch = APPROX_PI_PATCH.replacen(&git2::Oid::zero().to_string(), id, 1);
+        let msg = EmailMessage::parse(&patch).unwrap();
+        assert_eq!(msg.source_commit(), Some(git2::Oid::from_str(id).unwrap()));
+        assert!(msg
+            .to_patch_string()
+            .unwrap()
+            .starts_with(&format!("From {id} ")));
+    }
+
+    #[test]
+    fn strip_signature() {
+        use super::strip_signature;
+        let diff = "diff --git a/a b/a\nold mode 100644\nnew mode 100755\n";
+        assert_eq!(
+            strip_signature(&format!("{diff}--\nlibgit2 1.8.1\n\n")),
+            diff
+        );
+        assert_eq!(strip_signature(&format!("{diff}-- \n2.41.0\n")), diff);
+        // A removed `- ` line isn't a signature
+        let hunk = "@@ -1,2 +1 @@\n-- item\n context\n";
+        assert_eq!(strip_signature(hunk), hunk);
+    }
+
+    #[test]
+    fn generator_version() {
+        assert_eq!(
+            EmailMessage::parse(APPROX_PI_PATCH)
+                .unwrap()
+                .generator_version(),
+            Some("fake")
+        );
+        let (diff, _) = APPROX_PI_PATCH.rsplit_once("--\n").unwrap();
+        let with_version = format!("{diff}-- \n2.40.0\n\n");
+        let msg = EmailMessage::parse_strict(&with_version).unwrap();
+        assert_eq!(msg.generator_version(), Some("2.40.0"));
+        let msg = EmailMessage::parse_strict(diff).unwrap();
+        assert_eq!(msg.generator_version(), None);
+    }
+
+    #[test]
+    fn invalid_utf8_position() {
+        // A Latin-1 `é` in the summary, on the fourth line
+        let offset = APPROX_PI_PATCH.find("Subject: ").unwrap() + "Subject: [PATCH] ".len();
+        let mut bytes = APPROX_PI_PATCH.as_bytes().to_vec();
+        bytes.insert(offset, 0xE9);
+        match EmailMessage::parse_bytes(&bytes) {
+            Err(InvalidEmailMessage::InvalidUtf8 {
+                offset: actual,
+                line,
+                ..
+            }) => {
+                assert_eq!(actual, offset);
+                assert_eq!(line, 4);
+            }
+            res => panic!("Expected invalid UTF8, got {:?}", res.err()),
+        }
+    }
+
+    /// Found by fuzzing, since these crashed libgit2
+    #[test]
+    fn reject_corrupt_diffs() {
+        let with_nul = APPROX_PI_PATCH.replacen("fn main", "fn\0main", 1);
+        assert!(matches!(
+            EmailMessage::parse(&with_nul),
+            Err(InvalidEmailMessage::NulByte { .. })
+        ));
+        #[cfg(feature = "git")]
+        {
+            // libgit2 can't split the `diff --git` line, and leaves the new path missing
+            let (headers, _) = APPROX_PI_PATCH.split_once("diff --git").unwrap();
+            let deleted = format!(
+                "{headers}diff --git a/some file.txt b/remove.txt\n\
+                deleted file mode 100644\n\
+                index ce8d8b6..0000000\n\
+                --- a/remove.txt\n\
+                +++ /dev/null\n\
+                @@ -1 +0,0 @@\n\
+                -Remove\n"
+            );
+            let res = EmailMessage::parse(&deleted);
+            assert!(
+                matches!(res, Err(InvalidEmailMessage::MissingDeltaPath { delta: 0 })),
+                "{:?}",
+                res.err()
+            );
+            // libgit2 ignores a truncated section at the end of the diff
+            let (diff, signature) = APPROX_PI_PATCH.rsplit_once("--\n").unwrap();
+            let truncated = format!("{diff}diff --git a/other.rs b/other.rs\n--\n{signature}");
+            let res = EmailMessage::parse(&truncated);
+            assert!(
+                matches!(
+                    res,
+                    Err(InvalidEmailMessage::DeltaCountMismatch {
+                        headers: 2,
+                        deltas: 1
+                    })
+                ),
+                "{:?}",
+                res.err()
+            );
+        }
+    }
+
+    #[test]
+    fn strict_eof() {
+        EmailMessage::parse_strict(APPROX_PI_PATCH).unwrap();
+        let with_footer = format!(
+            "{APPROX_PI_PATCH}\n_______________________________________________\nmailing-list\n"
+        );
+        assert!(EmailMessage::parse(&with_footer).is_ok());
+        match EmailMessage::parse_strict(&with_footer) {
+            Err(InvalidEmailMessage::TrailingGarbage { content, .. }) => {
+                assert_eq!(content, "_______________________________________________");
+            }
+            res => panic!("Expected trailing garbage, got {:?}", res.err()),
+        }
+        // Without a signature, the junk directly follows the last hunk
+        let (unsigned, _) = APPROX_PI_PATCH.split_once("\n--\n").unwrap();
+        EmailMessage::parse_strict(&format!("{unsigned}\n")).unwrap();
+        let mbox_boundary = format!("{unsigned}\n\nFrom 0000000000000000000000000000000000000000 Mon Sep 17 00:00:00 2001\n");
+        match EmailMessage::parse_strict(&mbox_boundary) {
+            Err(InvalidEmailMessage::TrailingGarbage { line, .. }) => {
+                assert_eq!(line, mbox_boundary.lines().count());
+            }
+            res => panic!("Expected trailing garbage, got {:?}", res.err()),
+        }
+        // Without a diffstat, like `git format-patch --no-stat`, the diff directly follows `---`
+        let (headers, rest) = with_footer.split_once("\n---\n").unwrap();
+        let (_stat, diff) = rest.split_once("\ndiff --git").unwrap();
+        let no_stat = format!("{headers}\n---\ndiff --git{diff}");
+        let (clean, _footer) = no_stat.split_once("\n_____").unwrap();
+        super::check_trailing_garbage(clean).unwrap();
+        match super::check_trailing_garbage(&no_stat) {
+            Err(InvalidEmailMessage::TrailingGarbage { content, .. }) => {
+                assert_eq!(content, "_______________________________________________");
+            }
+            res => panic!("Expected trailing garbage, got {:?}", res.err()),
+        }
+    }
+
+    #[test]
+    fn folded_subject() {
+        let subject = APPROX_PI_PATCH
+            .lines()
+            .find(|line| line.starts_with("Subject: "))
+            .unwrap();
+        let folded = "Subject: [PATCH] Increase the   \n\titerations of\n  approx_pi";
+        let patch = APPROX_PI_PATCH.replacen(subject, folded, 1);
+        // Matches `git mailinfo`
+        assert_eq!(
+            EmailMessage::parse(&patch).unwrap().message_summary,
+            "Increase the iterations of approx_pi"
+        );
+        let mid_word = "Subject: [PATCH] Increase iterations of appr\nox_pi";
+        let patch = APPROX_PI_PATCH.replacen(subject, mid_word, 1);
+        assert_eq!(
+            EmailMessage::parse(&patch).unwrap().message_summary,
+            "Increase iterations of approx_pi"
+        );
+    }
+
+    #[test]
+    fn mime_headers() {
+        let subject = APPROX_PI_PATCH
+            .lines()
+            .find(|line| line.starts_with("Subject: "))
+            .unwrap();
+        // Written by `git format-patch` for non-ASCII messages
+        let with_mime = |charset: &str| {
+            APPROX_PI_PATCH.replacen(
+                subject,
+                &format!(
+                    "{subject}\nMIME-Version: 1.0\nContent-Type: text/plain; charset={charset}\n\
+                    Content-Transfer-Encoding: 8bit"
+                ),
+                1,
+            )
+        };
+        let original = EmailMessage::parse(APPROX_PI_PATCH).unwrap();
+        let msg = EmailMessage::parse(&with_mime("UTF-8")).unwrap();
+        assert_eq!(msg.message_summary, original.message_summary);
+        assert_eq!(msg.message_encoding, None);
+        let msg = EmailMessage::parse(&with_mime("ISO-8859-1")).unwrap();
+        assert_eq!(msg.message_summary, original.message_summary);
+        assert_eq!(msg.message_encoding.as_deref(), Some("ISO-8859-1"));
+        assert!(matches!(
+            EmailMessage::parse(&with_mime("bogus")),
+            Err(InvalidEmailMessage::UnknownEncoding { .. })
+        ));
+    }
+
+    #[test]
+    fn bracketed_summary() {
+        let subject = APPROX_PI_PATCH
+            .lines()
+            .find(|line| line.starts_with("Subject: "))
+            .unwrap();
+        let patch = APPROX_PI_PATCH.replacen(subject, "Subject: [PATCH] [sys] Foo", 1);
+        let msg = EmailMessage::parse(&patch).unwrap();
+        assert_eq!(msg.subject_prefix.as_deref(), Some("[PATCH]"));
+        assert_eq!(msg.summary(), "[sys] Foo");
+        // Without a patch prefix, the tag is still part of the summary
+        let patch = APPROX_PI_PATCH.replacen(subject, "Subject: [sys] Foo", 1);
+        let msg = EmailMessage::parse(&patch).unwrap();
+        assert_eq!(msg.subject_prefix, None);
+        assert_eq!(msg.summary(), "[sys] Foo");
+    }
+
+    #[test]
+    fn empty_subject() {
+        let subject = APPROX_PI_PATCH
+            .lines()
+            .find(|line| line.starts_with("Subject: "))
+            .unwrap();
+        let patch = APPROX_PI_PATCH.replacen(subject, "Subject: ", 1);
+        assert!(matches!(
+            EmailMessage::parse(&patch),
+            Err(InvalidEmailMessage::EmptySubject)
+        ));
+    }
+
+    #[test]
+    #[cfg(feature = "git")]
+    fn round_trip_patch_string() {
+        let original = EmailMessage::parse(APPROX_PI_PATCH).unwrap();
+        let serialized = original.to_patch_string().unwrap();
+        let reparsed = EmailMessage::parse(&serialized).unwrap();
+        assert_eq!(original.author_name, reparsed.author_name);
+        assert_eq!(original.author_email, reparsed.author_email);
+        assert_eq!(original.date, reparsed.date);
+        assert_eq!(original.message_summary, reparsed.message_summary);
+        assert_eq!(original.message_tail, reparsed.message_tail);
+        assert_eq!(diff_text(&original), diff_text(&reparsed));
+        assert_eq!(serialized, reparsed.to_patch_string().unwrap());
+    }
+
+    #[test]
+    fn unified_diff() {
+        let msg = EmailMessage::parse(APPROX_PI_PATCH).unwrap();
+        let diff = msg.unified_diff().unwrap();
+        assert!(diff.starts_with("diff --git a/"), "{diff}");
+        let hunk_headers = APPROX_PI_PATCH
+            .lines()
+            .filter(|line| line.starts_with("@@"))
+            .collect::<Vec<_>>();
+        assert!(!hunk_headers.is_empty());
+        for header in hunk_headers {
+            assert!(diff.contains(header), "Missing {header:?}");
+        }
+        assert!(!diff.contains("Subject:"));
+    }
+
+    #[test]
+    #[cfg(not(feature = "git"))]
+    fn parse_delta_headers() {
+        use super::DeltaStatus;
+        const PATCH: &str = "\
+From 0000000000000000000000000000000000000000 Mon Sep 17 00:00:00 2001
+From: dummy <dummy@dumb.gov>
+Date: Fri, 25 Aug 2023 06:13:20 +0000
+Subject: [PATCH] Shuffle files
+
+
+diff --git a/added.txt b/added.txt
+new file mode 100644
+index 0000000..257cc56
+--- /dev/null
++++ b/added.txt
+@@ -0,0 +1 @@
++foo
+diff --git a/changed.txt b/changed.txt
+index 257cc56..5716ca5 100644
+--- a/changed.txt
++++ b/changed.txt
+@@ -1 +1 @@
+-foo
++bar
+diff --git a/removed.bin b/removed.bin
+deleted file mode 100644
+index 257cc56..0000000
+Binary files a/removed.bin and /dev/null differ
+-- 
+2.41.0
+";
+        let msg = EmailMessage::parse(PATCH).unwrap();
+        let deltas = msg.deltas().unwrap();
+        let summary = deltas
+            .iter()
+            .map(|delta| (delta.delta_status, delta.old_path(), delta.new_path()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            [
+                (DeltaStatus::Added, None, Some("added.txt".into())),
+                (
+                    DeltaStatus::Modified,
+                    Some("changed.txt".into()),
+                    Some("changed.txt".into())
+                ),
+                (DeltaStatus::Deleted, Some("removed.bin".into()), None),
+            ]
+        );
+        assert!(deltas[2].old_file.binary);
+        assert_eq!(deltas[1].to_string(), "modified changed.txt (#2)");
+        let diff = msg.unified_diff().unwrap();
+        assert!(diff.starts_with("diff --git a/added.txt"), "{diff}");
+        assert!(diff.ends_with(" differ\n"), "{diff}");
+    }
+
+    #[test]
+    #[cfg(not(feature = "git"))]
+    fn removed_line_like_signature() {
+        const PATCH: &str = "\
+From 0000000000000000000000000000000000000000 Mon Sep 17 00:00:00 2001
+From: dummy <dummy@dumb.gov>
+Date: Fri, 25 Aug 2023 06:13:20 +0000
+Subject: [PATCH] Remove empty item
+
+
+diff --git a/list.md b/list.md
+index 1b2c3d4..5e6f7a8 100644
+--- a/list.md
++++ b/list.md
+@@ -1,3 +1,2 @@
+ - first
+-- 
+ - last
+-- 
+2.41.0
+";
+        let msg = EmailMessage::parse(PATCH).unwrap();
+        let diff = msg.unified_diff().unwrap();
+        assert!(
+            diff.ends_with("@@ -1,3 +1,2 @@\n - first\n-- \n - last\n"),
+            "{diff}"
+        );
+    }
+
+    #[test]
+    #[cfg(not(feature = "git"))]
+    fn dev_null_markers() {
+        use super::{parse_file_marker_line, DeltaStatus};
+        assert_eq!(
+            parse_file_marker_line(b"--- /dev/null", "--- a/"),
+            Some(None)
+        );
+        assert_eq!(
+            parse_file_marker_line(b"+++ /dev/null\t2023-08-25 06:13:20", "+++ b/"),
+            Some(None)
+        );
+        assert_eq!(
+            parse_file_marker_line(b"+++ b/dev/null", "+++ b/"),
+            Some(Some(&b"dev/null"[..]))
+        );
+        // Without the extended headers, only the markers reveal the added and deleted files
+        const PATCH: &str = "\
+From 0000000000000000000000000000000000000000 Mon Sep 17 00:00:00 2001
+From: dummy <dummy@dumb.gov>
+Date: Fri, 25 Aug 2023 06:13:20 +0000
+Subject: [PATCH] Replace file
+
+
+diff --git a/added.txt b/added.txt
+--- /dev/null
++++ b/added.txt
+@@ -0,0 +1 @@
++foo
+diff --git a/removed.txt b/removed.txt
+--- a/removed.txt
++++ /dev/null
+@@ -1,2 +0,0 @@
+-bar
+--- /dev/null
+-- 
+2.41.0
+";
+        let msg = EmailMessage::parse(PATCH).unwrap();
+        let deltas = msg.deltas().unwrap();
+        let summary = deltas
+            .iter()
+            .map(|delta| (delta.delta_status, delta.old_path(), delta.new_path()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            [
+                (DeltaStatus::Added, None, Some("added.txt".into())),
+                (DeltaStatus::Deleted, Some("removed.txt".into()), None),
+            ]
+        );
+    }
+}
@@ -0,0 +1,102 @@
+//! Run the `git am` hooks of the target repository.
+//!
+//! See [ApplyOptions::run_hooks](super::ApplyOptions::run_hooks).
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus};
+
+use git2::Repository;
+
+use crate::utils::optional_config;
+
+/// Locate the specified hook in the repository, honoring `core.hooksPath`.
+///
+/// Returns `None` if the hook doesn't exist or isn't executable.
+pub fn find_hook(repo: &Repository, name: &str) -> Result<Option<PathBuf>, HookError> {
+    let hooks_dir = match optional_config(repo.config()?.get_path("core.hooksPath"))? {
+        Some(dir) if dir.is_absolute() => dir,
+        // Relative paths are resolved from where hooks are run
+        Some(dir) => hook_cwd(repo).join(dir),
+        None => common_dir(repo)?.join("hooks"),
+    };
+    let hook = hooks_dir.join(name);
+    let Ok(metadata) = hook.metadata() else {
+        return Ok(None);
+    };
+    #[cfg(unix)]
+    let executable = {
+        use std::os::unix::fs::PermissionsExt;
+        metadata.permissions().mode() & 0o111 != 0
+    };
+    #[cfg(not(unix))]
+    let executable = true;
+    Ok((metadata.is_file() && executable).then_some(hook))
+}
+
+/// Run the specified hook with the arguments, if it exists.
+///
+/// Fails if the hook exits unsuccessfully.
+pub(crate) fn run_hook(repo: &Repository, name: &str, args: &[&Path]) -> Result<(), HookError> {
+    let Some(hook) = find_hook(repo, name)? else {
+        return Ok(());
+    };
+    let status = Command::new(&hook)
+        .args(args)
+        .current_dir(hook_cwd(repo))
+        .status()
+        .map_err(|cause| HookError::FailedSpawn { hook, cause })?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(HookError::Rejected { status })
+    }
+}
+
+/// Run the `applypatch-msg` hook on the proposed commit message.
+///
+/// The hook can edit the message, so the updated message is returned.
+pub(crate) fn run_applypatch_msg(repo: &Repository, message: &str) -> Result<String, HookError> {
+    if find_hook(repo, "applypatch-msg")?.is_none() {
+        return Ok(message.into());
+    }
+    let message_file = repo.path().join("GITPATCHER_MSG");
+    // Like git, the file ends with a newline which isn't part of the message
+    std::fs::write(&message_file, format!("{message}\n"))?;
+    let res = run_hook(repo, "applypatch-msg", &[&message_file])
+        .and_then(|()| Ok(std::fs::read_to_string(&message_file)?));
+    let _ = std::fs::remove_file(&message_file);
+    let mut message = res?;
+    if message.ends_with('\n') {
+        message.pop();
+    }
+    Ok(message)
+}
+
+/// The directory that hooks are run in, which is the root of the worktree
+fn hook_cwd(repo: &Repository) -> &Path {
+    repo.workdir().unwrap_or(repo.path())
+}
+
+/// The git directory shared by all worktrees, which contains the default hooks directory
+fn common_dir(repo: &Repository) -> Result<PathBuf, HookError> {
+    if !repo.is_worktree() {
+        return Ok(repo.path().to_path_buf());
+    }
+    let common_dir = std::fs::read_to_string(repo.path().join("commondir"))?;
+    Ok(repo.path().join(common_dir.trim_end()))
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum HookError {
+    #[error("Hook exited with {status}")]
+    Rejected { status: ExitStatus },
+    #[error("Unable to run hook {}: {cause}", hook.display())]
+    FailedSpawn {
+        hook: PathBuf,
+        #[source]
+        cause: std::io::Error,
+    },
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Git(#[from] git2::Error),
+}
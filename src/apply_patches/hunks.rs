@@ -0,0 +1,123 @@
+//! Applying the hunks of a delta, keeping track of the position where each one applied.
+//!
+//! This mirrors the algorithm used by [diffy::apply_bytes],
+//! which applies each hunk at the matching position closest to the one in its header.
+use diffy::{Line, Patch};
+
+use super::email::{DeltaApplyError, DeltaDesc};
+
+/// A position where a hunk of a delta could be applied.
+///
+/// See [ApplyOptions::offset_resolver](super::ApplyOptions::offset_resolver)
+/// and [AppliedDelta::hunks](super::email::AppliedDelta::hunks).
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Candidate {
+    /// The index of the hunk in the delta, starting from zero
+    pub hunk: usize,
+    /// The line of the file where the hunk would start, starting from one.
+    ///
+    /// This is a line of the partially patched file,
+    /// so it already accounts for the lines added or removed by the previous hunks.
+    pub line: usize,
+    /// The number of lines between this position and the one in the hunk header
+    pub offset: isize,
+}
+
+/// A function that chooses where to apply a hunk,
+/// returning the index of one of the candidates.
+///
+/// See [ApplyOptions::offset_resolver](super::ApplyOptions::offset_resolver).
+pub type OffsetResolver = Box<dyn FnMut(&DeltaDesc, &[Candidate]) -> usize>;
+
+/// A function that decides whether to apply a hunk, given its delta and index.
+///
+/// See [EmailMessage::apply_selected_hunks](super::EmailMessage::apply_selected_hunks).
+pub(crate) type HunkSelector<'a> = &'a dyn Fn(&DeltaDesc, usize) -> bool;
+
+/// Apply the hunks of the patch to the base, returning the position each hunk was applied at.
+///
+/// The resolver is asked whenever a hunk matches at multiple positions.
+/// Without one, the closest position is used.
+/// If there is a selector, only the hunks it accepts are applied.
+pub(crate) fn apply_hunks(
+    base: &[u8],
+    patch: &Patch<'_, [u8]>,
+    desc: &DeltaDesc,
+    mut resolver: Option<&mut OffsetResolver>,
+    selector: Option<HunkSelector>,
+) -> Result<(Vec<u8>, Vec<Candidate>), DeltaApplyError> {
+    let mut applied = Vec::with_capacity(patch.hunks().len());
+    // Each line is paired with whether it was added by a previous hunk
+    let mut image: Vec<(&[u8], bool)> = base
+        .split_inclusive(|&b| b == b'\n')
+        .map(|line| (line, false))
+        .collect();
+    // The lines added by the skipped hunks, which the positions of later hunks include
+    let mut skipped_lines = 0isize;
+    for (hunk_idx, hunk) in patch.hunks().iter().enumerate() {
+        if selector.is_some_and(|selector| !selector(desc, hunk_idx)) {
+            skipped_lines += hunk.new_range().len() as isize - hunk.old_range().len() as isize;
+            continue;
+        }
+        let old_lines = hunk
+            .lines()
+            .iter()
+            .filter_map(|line| match *line {
+                Line::Context(line) | Line::Delete(line) => Some(line),
+                Line::Insert(_) => None,
+            })
+            .collect::<Vec<_>>();
+        let expected = (hunk.new_range().start().saturating_sub(1) as isize - skipped_lines)
+            .clamp(0, image.len() as isize) as usize;
+        // Closest first, preferring earlier positions, just like diffy
+        let mut positions = vec![expected];
+        for distance in 1..=expected.max(image.len() - expected) {
+            positions.extend(expected.checked_sub(distance));
+            positions.extend(Some(expected + distance).filter(|&pos| pos < image.len()));
+        }
+        let matching = positions
+            .into_iter()
+            .filter(|&pos| {
+                image.get(pos..pos + old_lines.len()).is_some_and(|lines| {
+                    lines
+                        .iter()
+                        .zip(&old_lines)
+                        .all(|(&(line, patched), &old)| !patched && line == old)
+                })
+            })
+            .map(|pos| Candidate {
+                hunk: hunk_idx,
+                line: pos + 1,
+                offset: pos as isize - expected as isize,
+            });
+        let candidates = match resolver {
+            Some(_) => matching.collect::<Vec<_>>(),
+            None => matching.take(1).collect(),
+        };
+        let choice = match (candidates.len(), &mut resolver) {
+            (0, _) => return Err(DeltaApplyError::HunkNotFound { hunk: hunk_idx }),
+            (1, _) | (_, None) => 0,
+            (_, Some(resolver)) => resolver(desc, &candidates),
+        };
+        let Some(chosen) = candidates.get(choice) else {
+            return Err(DeltaApplyError::InvalidOffsetChoice {
+                hunk: hunk_idx,
+                choice,
+                candidates: candidates.len(),
+            });
+        };
+        let start = chosen.line - 1;
+        let new_lines = hunk.lines().iter().filter_map(|line| match *line {
+            Line::Context(line) | Line::Insert(line) => Some((line, true)),
+            Line::Delete(_) => None,
+        });
+        image.splice(start..start + old_lines.len(), new_lines);
+        applied.push(*chosen);
+    }
+    let patched = image
+        .into_iter()
+        .flat_map(|(line, _)| line)
+        .copied()
+        .collect();
+    Ok((patched, applied))
+}
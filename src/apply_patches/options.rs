@@ -0,0 +1,443 @@
+//! Options controlling how patches are applied.
+use std::cell::RefCell;
+
+use camino::{Utf8Component, Utf8Path, Utf8PathBuf};
+use git2::Signature;
+use slog::Logger;
+
+use super::hunks::OffsetResolver;
+use super::AuthorMap;
+use crate::format_patches::LineEnding;
+use crate::utils::optional_config;
+
+/// Options for applying an [EmailMessage](super::EmailMessage) as a commit.
+#[derive(Default)]
+pub struct ApplyOptions {
+    /// How the commit message is cleaned up before committing.
+    ///
+    /// Mirrors the `--cleanup` option of `git commit`.
+    /// Formatting and parsing patches preserves the exact blank lines of the message,
+    /// so use [CleanupMode::None] to keep consecutive blank lines when round-tripping a series.
+    pub message_cleanup: CleanupMode,
+    /// If a delta fails to apply, leave the partial result in the worktree
+    /// instead of failing without any changes.
+    ///
+    /// This mirrors the behavior of `git am`, which lets the user
+    /// resolve the conflicts in their editor.
+    /// The failed deltas are retried with libgit2,
+    /// and anything that still fails is written as a `.rej` file.
+    /// No commit is created, and [PatchApplyError::LeftInWorktree] is returned.
+    ///
+    /// [PatchApplyError::LeftInWorktree]: super::email::PatchApplyError::LeftInWorktree
+    pub leave_conflicts_in_worktree: bool,
+    /// A directory to prepend to every path in the patch.
+    ///
+    /// This is equivalent to `git apply --directory=<prefix>`,
+    /// and is useful to apply a patch into a subtree of another repository.
+    /// The prefix must be relative and can not contain `..`.
+    pub path_prefix: Option<Utf8PathBuf>,
+    /// The number of leading path components to remove from each path in the patch.
+    ///
+    /// This is like `patch -p<n>`, except that the git `a/` and `b/` prefixes
+    /// are already removed. Stripping happens before the [ApplyOptions::path_prefix]
+    /// is added, and it is an error if a path has too few components.
+    pub strip_components: usize,
+    /// Check if the patch adds files that are ignored by the target repository.
+    ///
+    /// Adding ignored files is often a mistake,
+    /// like applying a patch meant for a build directory.
+    pub check_ignored: IgnoredPathCheck,
+    /// The logger used to report warnings.
+    ///
+    /// If this is `None`, warnings are discarded.
+    pub logger: Option<Logger>,
+    /// The committer of the new commits.
+    ///
+    /// If this is `None`, the author of the patch is also used as the committer.
+    pub committer: Option<Signature<'static>>,
+    /// Use the author date of the patch as the committer date,
+    /// even if a different [ApplyOptions::committer] is specified.
+    ///
+    /// This corresponds to `git am --committer-date-is-author-date`,
+    /// and is useful to reproduce the exact same commits when re-applying a series.
+    pub committer_date_is_author_date: bool,
+    /// Override how the patched blobs are written to the repository.
+    ///
+    /// By default, blobs are written with [Repository::blob](git2::Repository::blob).
+    /// This hook makes it possible to intercept blob creation,
+    /// for example to log or count the blobs in a series.
+    /// It is borrowed mutably while a patch is applied.
+    pub blob_writer: Option<RefCell<BlobWriter>>,
+    /// Keep the bracketed prefix of the subject (like `[PATCH v2]`)
+    /// at the start of the commit message.
+    ///
+    /// By default only that prefix is removed, like `git am --keep-non-patch`,
+    /// so other bracketed tags at the start of the summary (like `[RFC]`) are kept.
+    /// Plain `git am` would strip those too.
+    /// Enabling this option is like `git am --keep`, which keeps the whole subject.
+    /// Since [PatchFormatter](crate::format_patches::PatchFormatter) always adds
+    /// a single `[PATCH]` prefix
+    /// (or its [subject_prefix](crate::format_patches::FormatOptions::subject_prefix)),
+    /// formatting and then applying a commit preserves its message exactly
+    /// if this option is off.
+    pub keep_subject_prefix: bool,
+    /// Map the author of the patch to their canonical identity,
+    /// using the `.mailmap` of the target repository.
+    ///
+    /// This is useful to collapse the outdated names or emails of imported patches.
+    /// The mailmap is loaded like `git log --use-mailmap`,
+    /// so it also respects the `mailmap.file` and `mailmap.blob` settings.
+    pub use_mailmap: bool,
+    /// Replace the author of the patch with a different identity.
+    ///
+    /// This happens before the [ApplyOptions::use_mailmap] is applied.
+    /// The committer is still the author of the patch
+    /// (after mapping) unless [ApplyOptions::committer] is set.
+    pub author_map: Option<AuthorMap>,
+    /// Force the line endings of every patched file.
+    ///
+    /// If this is `None`, the line endings of each file are preserved.
+    /// A file that mostly uses CRLF is patched as if it used LF
+    /// when the patch itself doesn't contain any CRLF line endings,
+    /// which is common for patches generated from normalized contents.
+    pub line_endings: Option<LineEnding>,
+    /// Run the `applypatch-msg`, `pre-applypatch` and `post-applypatch` hooks
+    /// of the target repository, like `git am`.
+    ///
+    /// Hooks are found in `core.hooksPath`, or the `hooks` directory of the repository.
+    /// The `applypatch-msg` hook can edit the commit message in the file it receives,
+    /// and the `pre-applypatch` hook sees the patched files in the worktree and index.
+    /// If either of them fails, nothing is committed and the worktree is reset.
+    /// The result of `post-applypatch` is ignored.
+    pub run_hooks: bool,
+    /// The implementation used to patch the files.
+    pub engine: ApplyEngine,
+    /// Choose where to apply a hunk that matches at multiple positions in the file.
+    ///
+    /// By default, a hunk whose context doesn't match at the line in its header
+    /// is applied at the closest position that matches, like `git apply`.
+    /// The resolver is only invoked when there is more than one candidate,
+    /// which are ordered closest first, so returning zero keeps the default behavior.
+    /// This is useful for careful manual rebases of repetitive files.
+    /// It is borrowed mutably while a patch is applied.
+    pub offset_resolver: Option<RefCell<OffsetResolver>>,
+    /// Fall back to a 3-way merge if the patch doesn't apply directly, like `git am -3`.
+    ///
+    /// The files the patch was generated from are reconstructed from its `index` lines
+    /// (see [EmailMessage::build_fake_ancestor](super::EmailMessage::build_fake_ancestor)),
+    /// and the patched files are merged into the target with them as the merge base.
+    /// A conflicting merge (like a file the target modified or deleted)
+    /// fails with [PatchApplyError::MergeConflict].
+    /// If the original blobs aren't in the repository, the merge isn't possible,
+    /// so the error from applying the patch directly is returned.
+    /// The fallback isn't used when [leaving conflicts](ApplyOptions::leave_conflicts_in_worktree).
+    ///
+    /// [PatchApplyError::MergeConflict]: super::email::PatchApplyError::MergeConflict
+    pub three_way: bool,
+    /// Attach the `git notes` included in the patch to the new commit.
+    ///
+    /// The notes are written to the default notes ref,
+    /// replacing any existing notes for the commit.
+    /// Patches without notes are applied as usual.
+    /// See [FormatOptions::include_notes](crate::format_patches::FormatOptions::include_notes).
+    pub restore_notes: bool,
+    /// Append `(cherry picked from commit <sha>)` to the commit message, like `git cherry-pick -x`.
+    ///
+    /// The sha is the original commit from the `From <sha>` line of the patch.
+    /// Patches that don't correspond to a commit (whose sha is all zeros)
+    /// are committed without the trailer.
+    pub mark_cherry_pick: bool,
+    /// Warn if a new commit doesn't have the same id as the commit the patch was formatted from.
+    ///
+    /// Re-applying a series onto its original base reproduces the original commits
+    /// only if the author, committer, dates, message and tree all match.
+    /// Patches don't record the committer, so this works for commits
+    /// whose committer was their author, unless a different [ApplyOptions::committer] is set.
+    /// The warnings are reported to the [ApplyOptions::logger],
+    /// which makes it easy to confirm that a re-import was faithful.
+    /// Patches that don't correspond to a commit are never checked.
+    pub verify_source_commit: bool,
+    /// What to do with trailing whitespace in the lines added by the patch,
+    /// like `git apply --whitespace=<action>`.
+    ///
+    /// [ApplyOptions::from_config] respects `apply.whitespace`.
+    /// Unlike git, which warns by default, the lines are applied without checking them.
+    pub whitespace: WhitespaceAction,
+}
+
+/// A function that writes a blob and returns its id.
+///
+/// See [ApplyOptions::blob_writer].
+pub type BlobWriter = Box<dyn FnMut(&[u8]) -> Result<git2::Oid, git2::Error>>;
+impl ApplyOptions {
+    /// Use the settings from the git config (and environment) as the defaults,
+    /// like `git am` would.
+    ///
+    /// The precedence is the same as git: options set explicitly after this
+    /// override the environment, which overrides the config,
+    /// which overrides the built-in defaults.
+    ///
+    /// The [ApplyOptions::message_cleanup] comes from `commit.cleanup`,
+    /// [ApplyOptions::three_way] from `am.threeWay`,
+    /// and [ApplyOptions::whitespace] from `apply.whitespace`.
+    ///
+    /// The committer isn't taken from the config, so the author of the patch is still
+    /// the committer (which keeps re-applied commits identical to the originals).
+    /// Use [ApplyOptions::config_committer] to commit as the configured user instead.
+    pub fn from_config(config: &git2::Config) -> Result<Self, git2::Error> {
+        let mut opts = ApplyOptions::default();
+        if let Some(three_way) = optional_config(config.get_bool("am.threeWay"))? {
+            opts.three_way = three_way;
+        }
+        if let Some(action) = optional_config(config.get_string("apply.whitespace"))? {
+            opts.whitespace = match &*action {
+                "nowarn" => WhitespaceAction::NoWarn,
+                "warn" => WhitespaceAction::Warn,
+                "fix" | "strip" => WhitespaceAction::Fix,
+                "error" | "error-all" => WhitespaceAction::Error,
+                _ => {
+                    return Err(git2::Error::from_str(&format!(
+                        "Invalid apply.whitespace action: {action:?}"
+                    )))
+                }
+            };
+        }
+        if let Some(mode) = optional_config(config.get_string("commit.cleanup"))? {
+            opts.message_cleanup = match &*mode {
+                "default" => CleanupMode::default(),
+                // Scissors only differs from whitespace when editing the message
+                "whitespace" | "scissors" => CleanupMode::Whitespace,
+                "strip" => CleanupMode::Strip,
+                "verbatim" => CleanupMode::None,
+                _ => {
+                    return Err(git2::Error::from_str(&format!(
+                        "Invalid commit.cleanup mode: {mode:?}"
+                    )))
+                }
+            };
+        }
+        Ok(opts)
+    }
+    /// The committer configured like `git commit` would use it,
+    /// or `None` if the name or email is missing.
+    ///
+    /// This is taken from `GIT_COMMITTER_NAME` and `GIT_COMMITTER_EMAIL`,
+    /// falling back to `user.name` and `user.email`.
+    /// Use it as the [ApplyOptions::committer] to commit as the current user, like `git am`.
+    pub fn config_committer(
+        config: &git2::Config,
+    ) -> Result<Option<Signature<'static>>, git2::Error> {
+        let identity = |env_var: &str, key: &str| match std::env::var(env_var) {
+            Ok(value) if !value.is_empty() => Ok(Some(value)),
+            _ => optional_config(config.get_string(key)),
+        };
+        match (
+            identity("GIT_COMMITTER_NAME", "user.name")?,
+            identity("GIT_COMMITTER_EMAIL", "user.email")?,
+        ) {
+            (Some(name), Some(email)) => Ok(Some(Signature::now(&name, &email)?)),
+            _ => Ok(None),
+        }
+    }
+    /// The logger to use for warnings, discarding them if none is configured.
+    pub(crate) fn logger(&self) -> Logger {
+        self.logger
+            .clone()
+            .unwrap_or_else(|| Logger::root(slog::Discard, slog::o!()))
+    }
+    pub(crate) fn validate(&self) -> Result<(), InvalidApplyOptions> {
+        if let Some(ref prefix) = self.path_prefix {
+            if !prefix
+                .components()
+                .all(|component| matches!(component, Utf8Component::Normal(_)))
+            {
+                return Err(InvalidApplyOptions::InvalidPathPrefix {
+                    prefix: prefix.clone(),
+                });
+            }
+        }
+        if self.engine == ApplyEngine::Libgit2 {
+            let unsupported = if self.rewrites_paths() {
+                Some("path rewriting")
+            } else if self.line_endings.is_some() {
+                Some("forcing line endings")
+            } else if self.offset_resolver.is_some() {
+                Some("resolving offsets")
+            } else if self.three_way {
+                Some("3-way fallback")
+            } else if self.whitespace != WhitespaceAction::NoWarn {
+                Some("checking whitespace")
+            } else if self.check_ignored != IgnoredPathCheck::Off {
+                Some("checking ignored paths")
+            } else if self.leave_conflicts_in_worktree {
+                Some("leaving conflicts in the worktree")
+            } else {
+                None
+            };
+            if let Some(option) = unsupported {
+                return Err(InvalidApplyOptions::UnsupportedByEngine {
+                    engine: self.engine,
+                    option,
+                });
+            }
+        }
+        Ok(())
+    }
+    /// If paths from the patch are rewritten before being applied.
+    pub(crate) fn rewrites_paths(&self) -> bool {
+        self.path_prefix.is_some() || self.strip_components > 0
+    }
+    /// Rewrite a path from the patch into a path in the target repository.
+    ///
+    /// Returns `None` if stripping components would leave an empty path.
+    pub(crate) fn rewrite_path(&self, path: &Utf8Path) -> Option<Utf8PathBuf> {
+        let stripped = path
+            .components()
+            .skip(self.strip_components)
+            .collect::<Utf8PathBuf>();
+        if stripped.as_str().is_empty() {
+            return None;
+        }
+        Some(match self.path_prefix {
+            Some(ref prefix) => prefix.join(stripped),
+            None => stripped,
+        })
+    }
+}
+
+/// Indicates that the [ApplyOptions] are invalid.
+#[derive(Debug, thiserror::Error)]
+pub enum InvalidApplyOptions {
+    #[error("Path prefix must be relative and can't contain `..`: {prefix}")]
+    InvalidPathPrefix { prefix: Utf8PathBuf },
+    #[error("The {engine:?} engine doesn't support {option}")]
+    UnsupportedByEngine {
+        engine: ApplyEngine,
+        option: &'static str,
+    },
+}
+
+/// The implementation used to apply the diff of a patch.
+///
+/// See [ApplyOptions::engine].
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub enum ApplyEngine {
+    /// Patch each file in memory with `diffy`, then build the updated tree.
+    ///
+    /// This supports all the options, and never touches the worktree until the commit is created.
+    #[default]
+    Diffy,
+    /// Apply the diff to the worktree and index with libgit2 (`git_apply`).
+    ///
+    /// This is mostly useful to cross-check the results of [ApplyEngine::Diffy].
+    /// It requires a worktree that matches the index,
+    /// doesn't support rewriting paths, forcing [ApplyOptions::line_endings],
+    /// [checking ignored paths](ApplyOptions::check_ignored)
+    /// or [leaving conflicts](ApplyOptions::leave_conflicts_in_worktree),
+    /// and ignores the [ApplyOptions::blob_writer].
+    /// A conflict is reported as the delta (and hunk) where libgit2 gave up.
+    Libgit2,
+}
+
+/// What to do when a patch adds a file that is ignored by the target repository.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub enum IgnoredPathCheck {
+    /// Don't check for ignored files
+    #[default]
+    Off,
+    /// Log a warning for each ignored file
+    Warn,
+    /// Fail to apply the patch
+    Error,
+}
+
+/// What to do with whitespace errors in the lines added by a patch.
+///
+/// Only trailing whitespace (spaces and tabs before the end of the line) is detected.
+/// See [ApplyOptions::whitespace].
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub enum WhitespaceAction {
+    /// Apply the lines as they are, without checking them
+    #[default]
+    NoWarn,
+    /// Log a warning for each file with whitespace errors
+    Warn,
+    /// Strip the trailing whitespace from the added lines
+    Fix,
+    /// Fail to apply the patch
+    Error,
+}
+
+/// How to clean up a commit message before committing it.
+///
+/// This corresponds to `git commit --cleanup=<mode>`.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub enum CleanupMode {
+    /// Strip leading and trailing blank lines, trailing whitespace,
+    /// and collapse consecutive blank lines.
+    ///
+    /// Lines starting with `#` are considered legitimate content and kept.
+    #[default]
+    Whitespace,
+    /// Same as [CleanupMode::Whitespace], but also removes `#` comment lines.
+    Strip,
+    /// Use the message verbatim.
+    None,
+}
+impl CleanupMode {
+    /// Clean up the specified commit message according to this mode.
+    pub fn cleanup(self, message: &str) -> String {
+        let strip_comments = match self {
+            CleanupMode::None => return message.into(),
+            CleanupMode::Whitespace => false,
+            CleanupMode::Strip => true,
+        };
+        let mut result = String::with_capacity(message.len());
+        let mut pending_blank = false;
+        for line in message.lines() {
+            if strip_comments && line.starts_with('#') {
+                continue;
+            }
+            let line = line.trim_end();
+            if line.is_empty() {
+                // Only emit blank lines between non-blank content
+                pending_blank = !result.is_empty();
+                continue;
+            }
+            if pending_blank {
+                result.push('\n');
+                pending_blank = false;
+            }
+            if !result.is_empty() {
+                result.push('\n');
+            }
+            result.push_str(line);
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::CleanupMode;
+
+    const MESSAGE: &str = "\n\nSummary  \n\n\n# Not a comment\nBody line\t\n\n#comment\n\n\n";
+
+    #[test]
+    fn cleanup_none() {
+        assert_eq!(CleanupMode::None.cleanup(MESSAGE), MESSAGE);
+    }
+
+    #[test]
+    fn cleanup_whitespace() {
+        assert_eq!(
+            CleanupMode::Whitespace.cleanup(MESSAGE),
+            "Summary\n\n# Not a comment\nBody line\n\n#comment"
+        );
+    }
+
+    #[test]
+    fn cleanup_strip() {
+        assert_eq!(CleanupMode::Strip.cleanup(MESSAGE), "Summary\n\nBody line");
+    }
+}
@@ -0,0 +1,49 @@
+//! Transparent decompression of patch files, based on their extension.
+//!
+//! Each compression format is gated behind its own feature,
+//! and compressed patches are ignored if their feature isn't enabled.
+use std::io::{self, Read};
+use std::path::Path;
+
+/// The extensions of patch files, including the supported compression formats
+const PATCH_EXTENSIONS: &[&str] = &[
+    ".patch",
+    #[cfg(feature = "gzip")]
+    ".patch.gz",
+    #[cfg(feature = "zstd")]
+    ".patch.zst",
+];
+
+/// Remove the extension of a patch file, including any compression suffix.
+///
+/// Returns `None` if the file isn't a (supported) patch file.
+pub fn strip_patch_extension(file_name: &str) -> Option<&str> {
+    PATCH_EXTENSIONS
+        .iter()
+        .find_map(|extension| file_name.strip_suffix(extension))
+}
+
+/// Read a patch file, decompressing it according to its extension.
+///
+/// If there is a size limit, reading stops after one byte more than the limit.
+/// This guards against compression bombs, while still detecting the patch is too large.
+pub fn read_patch_file(path: &Path, limit: Option<u64>) -> io::Result<Vec<u8>> {
+    let file = std::fs::File::open(path)?;
+    #[cfg(feature = "gzip")]
+    if path.extension() == Some("gz".as_ref()) {
+        return read_limited(flate2::read::GzDecoder::new(file), limit);
+    }
+    #[cfg(feature = "zstd")]
+    if path.extension() == Some("zst".as_ref()) {
+        return read_limited(zstd::Decoder::new(file)?, limit);
+    }
+    read_limited(file, limit)
+}
+
+fn read_limited(reader: impl Read, limit: Option<u64>) -> io::Result<Vec<u8>> {
+    let mut contents = Vec::new();
+    reader
+        .take(limit.map_or(u64::MAX, |limit| limit.saturating_add(1)))
+        .read_to_end(&mut contents)?;
+    Ok(contents)
+}
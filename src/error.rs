@@ -0,0 +1,43 @@
+use crate::apply_patches::bulk::{BulkApplyError, ResetUpstreamError};
+use crate::apply_patches::email::{EmailSerializeError, InvalidEmailMessage, PatchApplyError};
+use crate::format_patches::PatchFormatError;
+use crate::regenerate_patches::patch_file::PatchError;
+use crate::resolve::{FetchRemoteError, ResolveCommitishError};
+
+/// A unified error type for all of gitpatcher's operations.
+///
+/// This is convenient for applications that use several entry points,
+/// and don't care about the specific type of error.
+/// The more specific error types are still available for callers that want them.
+///
+/// ```no_run
+/// use gitpatcher::apply_patches::EmailMessage;
+///
+/// fn apply(repo: &git2::Repository, patch: &str) -> Result<(), gitpatcher::Error> {
+///     let email = EmailMessage::parse(patch)?;
+///     email.apply_commit(repo)?;
+///     Ok(())
+/// }
+/// ```
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum Error {
+    #[error(transparent)]
+    InvalidEmailMessage(#[from] InvalidEmailMessage),
+    #[error(transparent)]
+    PatchApply(#[from] PatchApplyError),
+    #[error(transparent)]
+    EmailSerialize(#[from] EmailSerializeError),
+    #[error(transparent)]
+    BulkApply(#[from] BulkApplyError),
+    #[error(transparent)]
+    ResetUpstream(#[from] ResetUpstreamError),
+    #[error(transparent)]
+    PatchFormat(#[from] PatchFormatError),
+    #[error(transparent)]
+    Patch(#[from] PatchError),
+    #[error(transparent)]
+    ResolveCommitish(#[from] ResolveCommitishError),
+    #[error(transparent)]
+    FetchRemote(#[from] FetchRemoteError),
+}
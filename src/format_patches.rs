@@ -1,48 +1,406 @@
-use bstr::{BStr, BString, ByteSlice, ByteVec};
+use std::borrow::Cow;
+#[cfg(feature = "git")]
+use std::collections::BTreeSet;
+#[cfg(feature = "git")]
+use std::io::Write;
+
+use bstr::ByteSlice;
+#[cfg(feature = "git")]
+use bstr::{BStr, BString, ByteVec};
+#[cfg(feature = "git")]
+use camino::Utf8Path;
 use camino::Utf8PathBuf;
-use git2::{Commit, DiffOptions, EmailCreateOptions, Oid, Repository};
-use slog::{error, info, Logger};
+#[cfg(feature = "git")]
+use git2::build::TreeUpdateBuilder;
+#[cfg(feature = "git")]
+use git2::{
+    Commit, Diff, DiffFindOptions, DiffFormat, DiffOptions, EmailCreateOptions, Oid, Repository,
+    Tree,
+};
+#[cfg(feature = "git")]
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+#[cfg(feature = "git")]
+use slog::{debug, info, Logger};
+#[cfg(feature = "git")]
+use time::OffsetDateTime;
 
-use crate::format_patches::format::{CommitMessage, InvalidCommitMessage};
-use crate::utils::SimpleParser;
+#[cfg(feature = "git")]
+use crate::apply_patches::EmailMessage;
+#[cfg(feature = "git")]
+use crate::format_patches::format::decode_commit_message;
+#[cfg(feature = "git")]
+use crate::utils::log::ShortId;
+#[cfg(feature = "git")]
+use crate::utils::{file_mode_from_raw, optional_config, SimpleParser};
 
 mod format;
 
+pub use self::format::{CommitMessage, InvalidCommitMessage};
+
+/// Options controlling how commits are formatted into patches.
+///
+/// Every subject line is prefixed with `[PATCH]` (see [FormatOptions::subject_prefix]),
+/// which is removed again when the patch is applied
+/// unless [ApplyOptions::keep_subject_prefix](crate::apply_patches::ApplyOptions::keep_subject_prefix) is set.
+#[cfg(feature = "git")]
 pub struct FormatOptions {
     email_opts: EmailCreateOptions,
+    /// If the diff options were changed with [FormatOptions::diff_opts]
+    custom_diff_opts: bool,
+    /// Override the default name of each patch file.
+    ///
+    /// Note that [PatchFileSet](crate::regenerate_patches::PatchFileSet)
+    /// requires patch names to match `NNNN-*.patch`,
+    /// so names that don't follow that convention can only be used for formatting,
+    /// and will fail when regenerating patches.
+    /// Names must be a single file name in the output directory,
+    /// so any path separators (or `..`) are rejected with [PatchFormatError::InvalidPatchName].
+    pub name_fn: Option<PatchNameFn>,
+    /// Skip formatting commits whose patch file already exists.
+    ///
+    /// An existing patch is considered up to date if it has the expected name,
+    /// its `From <commit>` header matches the id of the commit,
+    /// and it has the expected [line endings](FormatOptions::patch_file_eol).
+    /// Since the commit id covers the tree, message and parents,
+    /// the patch would be regenerated identically with the same options.
+    ///
+    /// The other formatting options (like the [subject_prefix](FormatOptions::subject_prefix)
+    /// or the diff options) can't be recovered from an existing patch,
+    /// so this is ignored unless they have their default values.
+    /// It is also ignored when [notes](FormatOptions::include_notes) are included
+    /// or paths are excluded by an [ignore file](IGNORE_FILE_NAME),
+    /// since those can change without changing the commit.
+    pub skip_unchanged: bool,
+    /// The line endings of the written patch files.
+    ///
+    /// This applies to the entire file, independent of the line endings in the diff.
+    /// Every patch file ends with exactly one line ending.
+    /// The default is LF on every platform.
+    /// Patch files are written directly, bypassing `core.autocrlf` and any other filters,
+    /// so the generated patches are identical on Windows and Linux checkouts.
+    pub patch_file_eol: LineEnding,
+    /// Override the commit id in the `From <id> Mon Sep 17 00:00:00 2001` line.
+    ///
+    /// By default, the id of the formatted commit is used.
+    /// A fixed id (like [Oid::zero]) gives stable headers that don't identify the original commits.
+    /// Since existing patches can then no longer be matched to their commits,
+    /// this disables [FormatOptions::skip_unchanged].
+    pub from_line_id: Option<Oid>,
+    /// Mark the patches as a new version of the series,
+    /// by emitting `[PATCH vN]` subjects (like `git format-patch --reroll-count`).
+    ///
+    /// The version prefix is removed again when the patches are applied.
+    pub reroll_count: Option<u32>,
+    /// The text in brackets at the start of each subject, like `git format-patch --subject-prefix`.
+    ///
+    /// The default is `PATCH`, and [FormatOptions::from_config] respects `format.subjectPrefix`.
+    /// A prefix is only removed again when applying the patches if it contains the word `PATCH`
+    /// (like `my-project PATCH` or `RFC PATCH`), since other bracketed text
+    /// may be part of the original summary.
+    pub subject_prefix: String,
+    /// Write each patch to a temporary file, then rename it into place.
+    ///
+    /// This ensures an interrupted run never leaves a truncated patch file behind,
+    /// which would fail to parse the next time the patches are applied.
+    pub atomic_writes: bool,
+    /// Drop merge commits from the series, like `git format-patch` does.
+    ///
+    /// Each remaining commit is diffed against its first parent,
+    /// so the changes brought in by a merge (like periodically merging upstream)
+    /// aren't attributed to the commit that follows it.
+    /// The commits are still walked along all parents, not just the first,
+    /// so non-merge commits on a merged branch are included
+    /// unless they are reachable from the base.
+    pub ignore_merges: bool,
+    /// Fold the `Subject:` header so its lines fit in the specified number of columns.
+    ///
+    /// Long subjects are broken at spaces into RFC 5322 continuation lines
+    /// (starting with a space), which are joined again when the patch is applied.
+    /// A single word longer than the limit is never broken.
+    pub wrap_headers: Option<usize>,
+    /// Transform the contents of each patch before it is written.
+    ///
+    /// This is invoked after the built-in cleanup (and [FormatOptions::wrap_headers]),
+    /// and is useful to apply project-specific conventions to the generated patches.
+    /// The hook always sees `\n` line endings,
+    /// and [FormatOptions::patch_file_eol] is applied to its result.
+    /// An error from the hook fails formatting with [PatchFormatError::PostProcessFailed].
+    pub post_process: Option<PostProcessFn>,
+    /// Skip commits authored before this date.
+    ///
+    /// Like `git format-patch --since`, this only filters the commits between the base and `HEAD`.
+    /// The patches are numbered consecutively, counting only the included commits.
+    /// Each included commit is still diffed against its parent,
+    /// so the patches may not apply to the base if earlier commits were skipped.
+    pub since: Option<OffsetDateTime>,
+    /// Skip commits authored after this date.
+    ///
+    /// See [FormatOptions::since] for details.
+    pub until: Option<OffsetDateTime>,
+    /// The order of the `From:` and `Date:` headers in the patch files.
+    ///
+    /// The default matches `git format-patch`.
+    /// Pinning the order avoids churn in stored patches if the version of libgit2 changes.
+    pub header_order: HeaderOrder,
+    /// Write emails like `git format-patch`, or plain diffs like `git diff`.
+    pub style: OutputStyle,
+    /// Detect renamed files whose contents are at least this similar (as a percentage),
+    /// like `git format-patch -M<n>%`.
+    ///
+    /// A deleted file and an added file that are similar enough
+    /// are written as a single rename, with a diff of the changes between them.
+    /// Git uses a threshold of 50% by default.
+    /// If this is `None`, renames aren't detected,
+    /// so a moved file is always written as a deletion and an addition.
+    /// The threshold must be at most 100, which only detects exact renames.
+    pub rename_threshold: Option<u16>,
+    /// Include the `git notes` of each commit, like `git format-patch --notes`.
+    ///
+    /// The notes from the default notes ref are written in a `Notes:` section
+    /// after a `---` line, which isn't part of the commit message.
+    /// Use [ApplyOptions::restore_notes](crate::apply_patches::ApplyOptions::restore_notes)
+    /// to attach them to the applied commits again.
+    /// Commits without notes are formatted as usual.
+    /// This is ignored for [OutputStyle::Plain].
+    pub include_notes: bool,
+    /// The last commit of the series, which is formatted along with its ancestors after the base.
+    ///
+    /// This formats the series of a branch without checking it out.
+    /// If this is `None`, the series ends at `HEAD`.
+    pub tip: Option<Oid>,
+    /// Patch numbers to leave unused when numbering the series.
+    ///
+    /// The patches are numbered consecutively, skipping each of these numbers.
+    /// This keeps existing patch files that aren't regenerated (like excluded patches)
+    /// in their place in the series, without a generated patch taking the same number.
+    pub reserved_numbers: Vec<u32>,
+}
+
+/// The kind of patches written by a [PatchFormatter].
+///
+/// See [FormatOptions::style].
+#[cfg(feature = "git")]
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub enum OutputStyle {
+    /// An email with the author, date and message of the commit, like `git format-patch`
+    #[default]
+    Email,
+    /// Only the unified diff of each commit, like `git diff`.
+    ///
+    /// This is the input expected by `git apply` or `patch -p1`,
+    /// but the commit metadata is lost, so the patches can't be applied as commits
+    /// (or regenerated) by gitpatcher.
+    /// Since the `From <commit>` line is missing, [FormatOptions::skip_unchanged] is ignored,
+    /// and options that only affect the email headers (like [FormatOptions::wrap_headers]) have no effect.
+    Plain,
+}
+
+/// The order of the `From:` (author) and `Date:` headers of a patch.
+///
+/// The `Subject:` header always follows both of them.
+#[cfg(feature = "git")]
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub enum HeaderOrder {
+    /// `From:` followed by `Date:`, like `git format-patch`
+    #[default]
+    FromDate,
+    /// `Date:` followed by `From:`
+    DateFrom,
+}
+#[cfg(feature = "git")]
+impl HeaderOrder {
+    /// Reorder the `From:` and `Date:` lines among the headers before the subject.
+    fn apply(self, headers: &mut [BString]) {
+        let position = |prefix: &[u8]| headers.iter().position(|line| line.starts_with(prefix));
+        if let (Some(from), Some(date)) = (position(b"From: "), position(b"Date: ")) {
+            if (from < date) != (self == HeaderOrder::FromDate) {
+                headers.swap(from, date);
+            }
+        }
+    }
 }
 
+/// The line endings used for a file.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub enum LineEnding {
+    /// Unix style line endings (`\n`)
+    #[default]
+    Lf,
+    /// Windows style line endings (`\r\n`)
+    CrLf,
+}
+
+/// A function that determines the name of a patch file.
+#[cfg(feature = "git")]
+pub type PatchNameFn = Box<dyn Fn(&NamingContext) -> String>;
+
+/// A function that transforms the contents of a patch before it is written.
+///
+/// See [FormatOptions::post_process].
+#[cfg(feature = "git")]
+pub type PostProcessFn =
+    Box<dyn FnMut(&BStr) -> Result<BString, Box<dyn std::error::Error + Send + Sync>>>;
+
+/// Information about a commit that is available to [FormatOptions::name_fn].
+#[cfg(feature = "git")]
+#[derive(Debug)]
+pub struct NamingContext<'a> {
+    /// The number of the patch in the series, starting from one
+    pub index: u32,
+    /// The id of the commit being formatted
+    pub commit_id: Oid,
+    /// The summary line of the commit message
+    pub summary: &'a str,
+    /// The trailers parsed from the commit message (like `Signed-off-by`)
+    pub trailers: Vec<(String, String)>,
+    /// The name that would be used by default
+    pub default_name: String,
+}
+
+#[cfg(feature = "git")]
 impl FormatOptions {
+    /// Use the settings from the git config as the defaults,
+    /// like `git format-patch` would.
+    ///
+    /// This currently respects `diff.context`, `diff.interHunkContext` and `format.subjectPrefix`.
+    /// Options set explicitly after this take precedence over the config,
+    /// and anything missing from the config keeps the built-in default.
+    pub fn from_config(config: &git2::Config) -> Result<Self, git2::Error> {
+        let mut opts = FormatOptions::default();
+        if let Some(lines) = optional_config(config.get_i32("diff.context"))? {
+            opts.diff_opts()
+                .context_lines(lines.try_into().unwrap_or(0));
+        }
+        if let Some(lines) = optional_config(config.get_i32("diff.interHunkContext"))? {
+            opts.diff_opts()
+                .interhunk_lines(lines.try_into().unwrap_or(0));
+        }
+        if let Some(prefix) = optional_config(config.get_string("format.subjectPrefix"))? {
+            opts.subject_prefix = prefix;
+        }
+        Ok(opts)
+    }
     pub fn diff_opts(&mut self) -> &mut DiffOptions {
+        self.custom_diff_opts = true;
         self.email_opts.diff_options()
     }
+    /// Check if the options that change the contents of a patch have their default values,
+    /// so an existing patch for the same commit would be formatted identically.
+    fn has_default_contents(&self) -> bool {
+        !self.custom_diff_opts
+            && self.style == OutputStyle::Email
+            && self.from_line_id.is_none()
+            && self.reroll_count.is_none()
+            && self.subject_prefix == "PATCH"
+            && self.wrap_headers.is_none()
+            && self.post_process.is_none()
+            && self.header_order == HeaderOrder::default()
+            && self.rename_threshold.is_none()
+            && !self.include_notes
+    }
 }
+#[cfg(feature = "git")]
 impl Default for FormatOptions {
     fn default() -> Self {
         FormatOptions {
             email_opts: EmailCreateOptions::new(),
+            custom_diff_opts: false,
+            name_fn: None,
+            skip_unchanged: false,
+            patch_file_eol: LineEnding::default(),
+            from_line_id: None,
+            reroll_count: None,
+            subject_prefix: String::from("PATCH"),
+            atomic_writes: true,
+            ignore_merges: false,
+            wrap_headers: None,
+            post_process: None,
+            since: None,
+            until: None,
+            header_order: HeaderOrder::default(),
+            style: OutputStyle::default(),
+            rename_threshold: None,
+            include_notes: false,
+            tip: None,
+            reserved_numbers: Vec::new(),
         }
     }
 }
 
+/// A patch file that was written by a [PatchFormatter]
+#[derive(Debug, Clone)]
+pub struct FormattedPatch {
+    /// The path of the written patch file
+    pub path: Utf8PathBuf,
+    /// The last non-blank line of the patch file.
+    ///
+    /// This is usually the version of git (or libgit2) that generated the patch.
+    /// It is `None` if the patch was up to date and wasn't rewritten.
+    pub trailer: Option<String>,
+    /// If the existing patch was up to date, and formatting was skipped.
+    ///
+    /// See [FormatOptions::skip_unchanged]
+    pub unchanged: bool,
+}
+
+/// The name of the file that lists paths to exclude from the patches.
+///
+/// It uses the same syntax as `.gitignore`,
+/// and is read from the root of the worktree that is being formatted.
+pub const IGNORE_FILE_NAME: &str = ".gitpatcherignore";
+
+#[cfg(feature = "git")]
 pub struct PatchFormatter<'repo> {
     logger: Logger,
+    ignore: Option<Gitignore>,
+    generated: Vec<FormattedPatch>,
     base: Commit<'repo>,
     last_commit: Commit<'repo>,
     out_dir: Utf8PathBuf,
     opts: FormatOptions,
     target: &'repo Repository,
 }
+#[cfg(feature = "git")]
 impl<'repo> PatchFormatter<'repo> {
     pub fn new(
         logger: Logger,
         out_dir: Utf8PathBuf,
         target: &'repo Repository,
         base: Commit<'repo>,
-        opts: FormatOptions,
+        mut opts: FormatOptions,
     ) -> Result<Self, PatchFormatError> {
+        if let Some(reroll_count) = opts.reroll_count {
+            opts.email_opts.reroll_number(reroll_count as usize);
+        }
+        if opts.subject_prefix.contains(['\0', ']']) {
+            return Err(PatchFormatError::InvalidSubjectPrefix {
+                prefix: opts.subject_prefix,
+            });
+        }
+        opts.email_opts.subject_prefix(opts.subject_prefix.as_str());
+        if let Some(threshold @ 101..) = opts.rename_threshold {
+            return Err(PatchFormatError::InvalidRenameThreshold { threshold });
+        }
+        let ignore =
+            match target.workdir() {
+                Some(workdir) if workdir.join(IGNORE_FILE_NAME).is_file() => {
+                    let ignore_file = workdir.join(IGNORE_FILE_NAME);
+                    let mut builder = GitignoreBuilder::new(workdir);
+                    let res = match builder.add(&ignore_file) {
+                        Some(cause) => Err(cause),
+                        None => builder.build(),
+                    };
+                    Some(res.map_err(|cause| PatchFormatError::InvalidIgnoreFile {
+                        ignore_file,
+                        cause,
+                    })?)
+                }
+                _ => None,
+            };
         Ok(PatchFormatter {
             logger,
+            ignore,
+            generated: Vec::new(),
             opts,
             out_dir,
             last_commit: base.clone(),
@@ -50,62 +408,529 @@ impl<'repo> PatchFormatter<'repo> {
             target,
         })
     }
+    /// Change the base commit, so the formatter can be reused for another range.
+    ///
+    /// The next call to [PatchFormatter::generate_all] (or [PatchFormatter::generate_mbox])
+    /// formats the commits from the new base to the [tip](FormatOptions::tip),
+    /// numbering them from one again.
+    /// The [generated patches](PatchFormatter::generated_patches) are cleared.
+    pub fn set_base(&mut self, base: Commit<'repo>) {
+        self.last_commit = base.clone();
+        self.base = base;
+        self.generated.clear();
+    }
     pub fn generate_all(&mut self) -> Result<(), PatchFormatError> {
-        // Walk all commits from [base]->HEAD
+        std::fs::create_dir_all(&self.out_dir).map_err(|cause| {
+            PatchFormatError::CreateOutDirError {
+                cause,
+                out_dir: self.out_dir.clone(),
+            }
+        })?;
+        self.generate_each(&mut None)
+    }
+    /// Write all the patches to a single mbox, instead of separate files in the output directory.
+    ///
+    /// This is the equivalent of `git format-patch --stdout`.
+    /// Every patch already starts with a `From <commit>` line,
+    /// so they are simply concatenated with a blank line in between.
+    /// The output directory isn't created or touched,
+    /// and [FormatOptions::skip_unchanged] is ignored.
+    /// The [FormattedPatch::path] of each patch is the path it would have been written to.
+    pub fn generate_mbox(&mut self, out: &mut dyn Write) -> Result<(), PatchFormatError> {
+        self.generate_each(&mut Some(out))
+    }
+    fn generate_each(&mut self, mbox: &mut Option<&mut dyn Write>) -> Result<(), PatchFormatError> {
+        // Walk all commits from [base]->tip
         let mut revwalk = self.target.revwalk()?;
         revwalk.hide(self.base.id())?;
-        revwalk.push_head()?;
+        match self.opts.tip {
+            Some(tip) => revwalk.push(tip)?,
+            None => revwalk.push_head()?,
+        }
         revwalk.set_sorting(git2::Sort::REVERSE | git2::Sort::TOPOLOGICAL)?;
-        for (index, oid) in revwalk.enumerate() {
-            let commit = self.target.find_commit(oid?)?;
-            self.generate(index, &commit)?;
+        let mut index = 0;
+        for oid in revwalk {
+            let oid = oid?;
+            let commit = self.target.find_commit(oid)?;
+            if self.opts.ignore_merges {
+                if commit.parent_count() > 1 {
+                    debug!(
+                        self.logger,
+                        "Skipping merge commit {}",
+                        ShortId(commit.id())
+                    );
+                    continue;
+                }
+                self.last_commit = commit.parent(0)?;
+            }
+            let when = commit.author().when();
+            let date = OffsetDateTime::from_unix_timestamp(when.seconds())
+                .map_err(|_| PatchFormatError::InvalidCommitDate { commit_id: oid })?;
+            if self.opts.since.is_some_and(|since| date < since)
+                || self.opts.until.is_some_and(|until| date > until)
+            {
+                debug!(
+                    self.logger,
+                    "Skipping commit outside date range: {}",
+                    ShortId(oid)
+                );
+                self.last_commit = commit;
+                continue;
+            }
+            self.generate(index, &commit, mbox)?;
             self.last_commit = commit;
+            index += 1;
         }
         Ok(())
     }
-    fn generate(&mut self, index: usize, commit: &Commit<'repo>) -> Result<(), PatchFormatError> {
-        let message = CommitMessage::from_commit(commit).map_err(|cause| {
-            PatchFormatError::InvalidCommitMessage {
-                cause,
+    /// The number of the patch at the specified index of the series, starting from one.
+    ///
+    /// This skips the [reserved numbers](FormatOptions::reserved_numbers).
+    fn patch_number(&self, index: usize) -> u32 {
+        (1..)
+            .filter(|number| !self.opts.reserved_numbers.contains(number))
+            .nth(index)
+            .unwrap()
+    }
+    /// The patches that have been generated so far, in order.
+    #[inline]
+    pub fn generated_patches(&self) -> &[FormattedPatch] {
+        &self.generated
+    }
+    fn generate(
+        &mut self,
+        index: usize,
+        commit: &Commit<'repo>,
+        mbox: &mut Option<&mut dyn Write>,
+    ) -> Result<(), PatchFormatError> {
+        let invalid_message = |cause| PatchFormatError::InvalidCommitMessage {
+            cause,
+            commit_id: commit.id(),
+        };
+        let decoded_message = decode_commit_message(commit).map_err(invalid_message)?;
+        let message = CommitMessage::parse(&decoded_message).map_err(invalid_message)?;
+        let patch_no = self.patch_number(index);
+        let mut patch_name = message.patch_file_name(patch_no);
+        if let Some(ref name_fn) = self.opts.name_fn {
+            let trailers = git2::message_trailers_strs(message.full())?
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect();
+            patch_name = name_fn(&NamingContext {
+                index: patch_no,
                 commit_id: commit.id(),
+                summary: message.summary(),
+                trailers,
+                default_name: patch_name,
+            });
+            // The name must not escape the output directory
+            if patch_name.is_empty()
+                || patch_name == "."
+                || patch_name == ".."
+                || patch_name.contains(['/', '\\', '\0'])
+            {
+                return Err(PatchFormatError::InvalidPatchName {
+                    name: patch_name,
+                    commit_id: commit.id(),
+                });
             }
-        })?;
+        }
+        let patch = self.out_dir.join(&patch_name);
+        if self.opts.skip_unchanged
+            && self.opts.has_default_contents()
+            && self.ignore.is_none()
+            && mbox.is_none()
+            && is_patch_for_commit(&patch, commit.id(), self.opts.patch_file_eol)
+        {
+            debug!(
+                self.logger, "Skipping unchanged patch: {}", patch_name;
+                "patch_index" => patch_no,
+            );
+            self.generated.push(FormattedPatch {
+                path: patch,
+                trailer: None,
+                unchanged: true,
+            });
+            return Ok(());
+        }
         let last_tree = self.last_commit.tree()?;
         let tree = commit.tree()?;
-        let diff = self.target.diff_tree_to_tree(
+        let mut diff = self.target.diff_tree_to_tree(
             Some(&last_tree),
             Some(&tree),
             // TODO: Why does diff_opts need to be mutable?
-            Some(self.opts.diff_opts()),
-        )?;
-        let patch_name = message.patch_file_name(index as u32 + 1);
-        let patch = self.out_dir.join(&patch_name);
-        let email = git2::Email::from_diff(
-            &diff,
-            /* patch_idx */ 1,
-            /* patch_count */ 1,
-            /* commit_id */ &commit.id(),
-            /* summary */ message.summary(),
-            /* body */ message.body(),
-            /* author */ &commit.author(),
-            &mut self.opts.email_opts,
+            Some(self.opts.email_opts.diff_options()),
         )?;
-        let s = cleanup_patch(BStr::new(email.as_slice())).map_err(|cause| {
-            PatchFormatError::PatchCleanupError {
-                cause,
-                patch_file: patch.clone(),
+        if let Some(filtered_tree) = self.revert_ignored(&last_tree, &tree, &diff)? {
+            diff = self.target.diff_tree_to_tree(
+                Some(&last_tree),
+                Some(&filtered_tree),
+                Some(self.opts.email_opts.diff_options()),
+            )?;
+        }
+        if let Some(threshold) = self.opts.rename_threshold {
+            diff.find_similar(Some(
+                DiffFindOptions::new()
+                    .renames(true)
+                    .rename_threshold(threshold),
+            ))?;
+        }
+        let mut s = match self.opts.style {
+            OutputStyle::Email => {
+                let email = git2::Email::from_diff(
+                    &diff,
+                    /* patch_idx */ 1,
+                    /* patch_count */ 1,
+                    /* commit_id */ &self.opts.from_line_id.unwrap_or(commit.id()),
+                    /* summary */ message.summary(),
+                    /* body */ message.body(),
+                    /* author */ &commit.author(),
+                    &mut self.opts.email_opts,
+                )?;
+                cleanup_patch(
+                    BStr::new(email.as_slice()),
+                    commit.message_encoding(),
+                    self.opts.header_order,
+                )
+                .map_err(|cause| PatchFormatError::PatchCleanupError {
+                    cause,
+                    patch_file: patch.clone(),
+                })?
             }
-        })?;
-        std::fs::write(&patch, s).map_err(|cause| PatchFormatError::PatchWriteError {
+            OutputStyle::Plain => {
+                let mut plain = BString::new(Vec::new());
+                diff.print(DiffFormat::Patch, |_delta, _hunk, line| {
+                    if let origin @ (' ' | '+' | '-') = line.origin() {
+                        plain.push_char(origin);
+                    }
+                    plain.push_str(line.content());
+                    true
+                })?;
+                plain
+            }
+        };
+        if let (true, OutputStyle::Email) = (self.opts.include_notes, self.opts.style) {
+            match self.target.find_note(None, commit.id()) {
+                Ok(note) => {
+                    let text = note.message_bytes().to_str_lossy();
+                    s = insert_notes(BStr::new(&s), &text);
+                }
+                Err(e) if e.code() == git2::ErrorCode::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        if let (Some(width), OutputStyle::Email) = (self.opts.wrap_headers, self.opts.style) {
+            s = fold_subject(BStr::new(&s), width);
+        }
+        if let (Some(label), OutputStyle::Email) = (commit.message_encoding(), self.opts.style) {
+            s = encode_message(BStr::new(&s), label);
+        }
+        if let Some(ref mut post_process) = self.opts.post_process {
+            s = post_process(BStr::new(&s)).map_err(|cause| {
+                PatchFormatError::PostProcessFailed {
+                    cause,
+                    patch_file: patch.clone(),
+                }
+            })?;
+        }
+        let trailer = s
+            .lines()
+            .rev()
+            .find(|line| !line.trim().is_empty())
+            .map_or_else(String::new, |line| line.trim().to_str_lossy().into_owned());
+        // Always end with a single newline, regardless of what libgit2 generates
+        while s.ends_with(b"\n") {
+            s.pop();
+        }
+        s.push(b'\n');
+        if self.opts.patch_file_eol == LineEnding::CrLf {
+            s = s.replace(b"\n", b"\r\n").into();
+        }
+        let res = match mbox {
+            Some(out) => {
+                // Separate the messages with a blank line
+                let separator = match self.opts.patch_file_eol {
+                    _ if index == 0 => "",
+                    LineEnding::Lf => "\n",
+                    LineEnding::CrLf => "\r\n",
+                };
+                out.write_all(separator.as_bytes())
+                    .and_then(|()| out.write_all(&s))
+            }
+            None if self.opts.atomic_writes => crate::utils::write_atomic(patch.as_std_path(), &s),
+            None => std::fs::write(&patch, s),
+        };
+        res.map_err(|cause| PatchFormatError::PatchWriteError {
             cause,
             patch_file: patch.clone(),
         })?;
-        info!(self.logger, "Generating patch: {}", patch_name);
+        info!(
+            self.logger, "Generating patch: {}", patch_name;
+            "patch_index" => patch_no,
+        );
+        self.generated.push(FormattedPatch {
+            path: patch,
+            trailer: Some(trailer),
+            unchanged: false,
+        });
         Ok(())
     }
 }
 
-fn cleanup_patch(s: &BStr) -> Result<BString, CleanupPatchErr> {
+/// A [PatchFormatter] that doesn't borrow the repository.
+///
+/// This stores the path to the repository and the id of the base commit,
+/// and only opens the repository when generating patches.
+/// It is convenient to store in a struct or pass across function boundaries.
+#[cfg(feature = "git")]
+pub struct OwnedPatchFormatter {
+    logger: Logger,
+    repo_path: std::path::PathBuf,
+    base: Oid,
+    out_dir: Utf8PathBuf,
+    opts: FormatOptions,
+}
+#[cfg(feature = "git")]
+impl OwnedPatchFormatter {
+    pub fn new(
+        logger: Logger,
+        out_dir: Utf8PathBuf,
+        repo_path: impl Into<std::path::PathBuf>,
+        base: Oid,
+        opts: FormatOptions,
+    ) -> Self {
+        OwnedPatchFormatter {
+            logger,
+            repo_path: repo_path.into(),
+            base,
+            out_dir,
+            opts,
+        }
+    }
+    /// Generate patches for all the commits from the base to `HEAD`,
+    /// returning the patches that were generated.
+    pub fn generate_all(&mut self) -> Result<Vec<FormattedPatch>, PatchFormatError> {
+        let repo = Repository::open(&self.repo_path).map_err(|cause| {
+            PatchFormatError::OpenRepository {
+                repo_path: self.repo_path.clone(),
+                cause,
+            }
+        })?;
+        let base = repo.find_commit(self.base)?;
+        let mut formatter = PatchFormatter::new(
+            self.logger.clone(),
+            self.out_dir.clone(),
+            &repo,
+            base,
+            std::mem::take(&mut self.opts),
+        )?;
+        let res = formatter.generate_all();
+        self.opts = formatter.opts;
+        res.map(|()| formatter.generated)
+    }
+}
+
+#[cfg(feature = "git")]
+impl<'repo> PatchFormatter<'repo> {
+    /// Revert the changes to any paths excluded by the [IGNORE_FILE_NAME],
+    /// so they are left out of the diff.
+    ///
+    /// Returns `None` if the diff doesn't touch any excluded paths.
+    fn revert_ignored(
+        &self,
+        old_tree: &Tree<'repo>,
+        new_tree: &Tree<'repo>,
+        diff: &Diff,
+    ) -> Result<Option<Tree<'repo>>, PatchFormatError> {
+        let Some(ref ignore) = self.ignore else {
+            return Ok(None);
+        };
+        let ignored_paths = diff
+            .deltas()
+            .flat_map(|delta| [delta.old_file().path(), delta.new_file().path()])
+            .flatten()
+            .filter(|path| ignore.matched_path_or_any_parents(path, false).is_ignore())
+            .collect::<BTreeSet<_>>();
+        if ignored_paths.is_empty() {
+            return Ok(None);
+        }
+        let mut builder = TreeUpdateBuilder::new();
+        for path in ignored_paths {
+            debug!(self.logger, "Excluding ignored path: {}", path.display());
+            match old_tree.get_path(path) {
+                Ok(entry) => {
+                    builder.upsert(path, entry.id(), file_mode_from_raw(entry.filemode()));
+                }
+                Err(e) if e.code() == git2::ErrorCode::NotFound => {
+                    builder.remove(path);
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        let filtered = builder.create_updated(self.target, new_tree)?;
+        Ok(Some(self.target.find_tree(filtered)?))
+    }
+}
+
+/// Check if the existing patch file was generated from the specified commit.
+#[cfg(feature = "git")]
+fn is_patch_for_commit(patch: &Utf8Path, commit_id: Oid, eol: LineEnding) -> bool {
+    use std::io::BufRead;
+    let mut first_line = String::new();
+    match std::fs::File::open(patch) {
+        Ok(file) => {
+            if std::io::BufReader::new(file)
+                .read_line(&mut first_line)
+                .is_err()
+            {
+                return false;
+            }
+        }
+        Err(_) => return false,
+    }
+    if first_line.ends_with("\r\n") != (eol == LineEnding::CrLf) {
+        return false;
+    }
+    first_line
+        .strip_prefix("From ")
+        .and_then(|rest| rest.split(' ').next())
+        == Some(commit_id.to_string().as_str())
+}
+
+/// The name [PatchFormatter] would give a patch file, based on the subject it contains.
+///
+/// The patch number is taken from the `NNNN-` prefix of the current name,
+/// and any compressed extension (like `.patch.gz`) is kept.
+/// Returns `None` if the current name doesn't have a patch number.
+/// Note that patches named by a custom [FormatOptions::name_fn] won't match.
+#[cfg(feature = "git")]
+pub fn expected_patch_name(
+    file_name: &str,
+    email: &EmailMessage,
+) -> Result<Option<String>, InvalidCommitMessage> {
+    let Some(stem) = crate::compression::strip_patch_extension(file_name) else {
+        return Ok(None);
+    };
+    let patch_no = match stem.split_once('-') {
+        Some((number, _)) if number.len() == 4 => match number.parse::<u32>() {
+            Ok(patch_no) if patch_no >= 1 => patch_no,
+            _ => return Ok(None),
+        },
+        _ => return Ok(None),
+    };
+    let message = email.full_message();
+    let expected = CommitMessage::parse(&message)?.patch_file_name(patch_no);
+    // Keep the compression suffix after `.patch`
+    Ok(Some(format!(
+        "{expected}{}",
+        &file_name[stem.len() + ".patch".len()..]
+    )))
+}
+
+/// The MIME header that declares the charset of the commit message, like `git format-patch`.
+///
+/// Written when the original commit has an `encoding` header,
+/// in which case the message of the patch (everything before the diff) is in that encoding.
+/// It's converted back into UTF-8 when the patch is read, see [decode_message].
+pub(crate) const CONTENT_TYPE_HEADER: &str = "Content-Type: text/plain; charset=";
+
+/// The length of the message of a patch, up to the start of the diff.
+fn message_len(patch: &[u8]) -> usize {
+    patch
+        .find("\ndiff --git ")
+        .map_or(patch.len(), |pos| pos + 1)
+}
+
+/// Convert the message of a patch from UTF-8 into the encoding with the specified label.
+///
+/// The diff is left alone, since git never converts the contents of files.
+#[cfg(feature = "git")]
+fn encode_message(patch: &BStr, label: &str) -> BString {
+    let Some(encoding) = encoding_rs::Encoding::for_label(label.as_bytes()) else {
+        return patch.to_owned();
+    };
+    let (message, diff) = patch.split_at(message_len(patch));
+    let message = message.to_str_lossy();
+    let mut result = BString::from(encoding.encode(&message).0.into_owned());
+    result.push_str(diff);
+    result
+}
+
+/// Convert the message of a patch into UTF-8,
+/// according to the charset of its [CONTENT_TYPE_HEADER].
+///
+/// Patches without the header (or with an unknown charset) are returned unchanged.
+pub(crate) fn decode_message(patch: &[u8]) -> Cow<'_, [u8]> {
+    let (message, diff) = patch.split_at(message_len(patch));
+    let encoding = message
+        .lines()
+        .take_while(|line| !line.is_empty())
+        .find_map(|line| line.strip_prefix(CONTENT_TYPE_HEADER.as_bytes()))
+        .and_then(encoding_rs::Encoding::for_label);
+    match encoding {
+        Some(encoding) if encoding != encoding_rs::UTF_8 => {
+            let mut decoded = encoding
+                .decode_without_bom_handling(message)
+                .0
+                .into_owned()
+                .into_bytes();
+            decoded.extend_from_slice(diff);
+            Cow::Owned(decoded)
+        }
+        _ => Cow::Borrowed(patch),
+    }
+}
+
+/// Format the `git notes` of a commit like `git format-patch --notes`,
+/// as a `---` line followed by the indented notes.
+pub(crate) fn notes_section(notes: &str) -> String {
+    let mut section = String::from("---\nNotes:\n");
+    for line in notes.trim_end().lines() {
+        if !line.is_empty() {
+            section.push_str("    ");
+        }
+        section.push_str(line);
+        section.push('\n');
+    }
+    section
+}
+
+/// Insert the [notes section](notes_section) between the commit message and the diff.
+#[cfg(feature = "git")]
+fn insert_notes(patch: &BStr, notes: &str) -> BString {
+    let mut result = BString::from(patch.to_vec());
+    // The message is always followed by a blank line
+    let end_of_message = patch
+        .find("\ndiff --git ")
+        .unwrap_or_else(|| patch.trim_end().len() + 1);
+    result.insert_str(end_of_message.min(patch.len()), notes_section(notes));
+    result
+}
+
+/// Normalize a patch generated by another tool into the form written by [PatchFormatter].
+///
+/// This removes the diff stats, ensures the commit message is separated from the subject
+/// and the diff by a single blank line, and ends the patch with a single newline.
+/// Normalizing the patches before storing them avoids spurious differences
+/// between the tools that generated them.
+/// The `From:` and `Date:` headers are put in the default [HeaderOrder].
+/// Normalizing an already normalized patch doesn't change it.
+#[cfg(feature = "git")]
+pub fn normalize_patch(bytes: &[u8]) -> Result<BString, CleanupPatchErr> {
+    let mut result = cleanup_patch(BStr::new(bytes), None, HeaderOrder::default())?;
+    while result.ends_with(b"\n") {
+        result.pop();
+    }
+    result.push(b'\n');
+    Ok(result)
+}
+
+#[cfg(feature = "git")]
+fn cleanup_patch(
+    s: &BStr,
+    encoding: Option<&str>,
+    header_order: HeaderOrder,
+) -> Result<BString, CleanupPatchErr> {
     let mut result = BString::new(Vec::new());
     let mut pushln = |line: &BStr| {
         result.push_str(line);
@@ -114,24 +939,42 @@ fn cleanup_patch(s: &BStr) -> Result<BString, CleanupPatchErr> {
     let mut parser = SimpleParser::new(s);
     /*
      * Ensure there is one and only one newline between
-     * the summary line (Subject: [PATCH]),
+     * the summary line (Subject: [PATCH] ...),
      * and the rest of the commit message
      */
+    let mut headers = Vec::new();
     let subject_line = parser
-        .take_until(|line| line.starts_with(b"Subject: [PATCH]"), &mut pushln)
+        .take_until(
+            |line| line.starts_with(b"Subject: "),
+            |line| headers.push(BString::from(line)),
+        )
         .map_err(|_| CleanupPatchErr::UnexpectedEof {
             expected: "Subject line",
         })?;
+    header_order.apply(&mut headers);
+    for header in &headers {
+        pushln(header.as_ref());
+    }
     pushln(subject_line);
+    // Keep any continuation lines of a folded subject
+    parser.take_while(|line| line.starts_with(b" "), &mut pushln);
+    match encoding {
+        Some(encoding) => pushln(BStr::new(&format!("{CONTENT_TYPE_HEADER}{encoding}"))),
+        None => parser.take_while(
+            |line| line.starts_with(CONTENT_TYPE_HEADER.as_bytes()),
+            &mut pushln,
+        ),
+    }
     parser.skip_whitespace();
     /*
      * libgit2 generates diff stats, which we don't care about
      * Skip until we see start of diff stats `---`
      */
     let mut trailing_commit_message = BString::new(Vec::new());
-    parser
+    let end_of_message = parser
         .take_until(
-            |line| line.starts_with(b"---"),
+            // An already cleaned up patch has no diff stats
+            |line| line.starts_with(b"---") || line.starts_with(b"diff --git "),
             |line| {
                 trailing_commit_message.push_str(line);
                 trailing_commit_message.push_char('\n');
@@ -147,19 +990,75 @@ fn cleanup_patch(s: &BStr) -> Result<BString, CleanupPatchErr> {
     }
     pushln(BStr::new(""));
     // Ignore until we see a `diff --git a/file.txt b/file.txt` line
-    let diff_line = parser
-        .take_until(|line| line.starts_with(b"diff"), |_| {})
-        .map_err(|_| CleanupPatchErr::UnexpectedEof {
-            expected: "Diff line",
-        })?;
+    let diff_line = match end_of_message {
+        line if line.starts_with(b"diff") => line,
+        _ => match parser.take_until(|line| line.starts_with(b"diff"), |_| {}) {
+            Ok(line) => line,
+            // An empty commit has no diff at all, only the message
+            Err(_) => return Ok(result),
+        },
+    };
     pushln(diff_line);
+    /*
+     * A file with CRLF line endings has CRLF in its hunks, even though
+     * the structural lines (like `@@`) only use LF, so keep the CRLF intact.
+     * If the structural lines also use CRLF, the whole patch was converted.
+     */
+    let converted_to_crlf = parser.peek_raw().is_ok_and(|line| line.ends_with(b"\r\n"));
     // Dump all remaining lines
-    while let Ok(line) = parser.pop() {
-        result.push_str(line);
+    while let Ok(line) = parser.pop_raw() {
+        match line.strip_suffix(b"\r\n") {
+            Some(line) if converted_to_crlf => result.push_str(line),
+            _ => result.push_str(line.strip_suffix(b"\n").unwrap_or(line)),
+        }
         result.push_char('\n')
     }
     Ok(result)
 }
+/// Fold the `Subject:` header of the patch at the specified column.
+///
+/// The bracketed `[PATCH]` prefix and the first word of the summary always stay on the first line,
+/// since that is where they are expected when parsing.
+#[cfg(feature = "git")]
+fn fold_subject(patch: &BStr, width: usize) -> BString {
+    let mut result = BString::new(Vec::with_capacity(patch.len() + 16));
+    let mut in_headers = true;
+    for line in patch.lines_with_terminator() {
+        let Some(subject) = line
+            .strip_prefix(b"Subject: ")
+            .filter(|_| in_headers)
+            .map(|subject| subject.trim_end_with(|c| c == '\n'))
+        else {
+            in_headers &= line != b"\n";
+            result.push_str(line);
+            continue;
+        };
+        in_headers = false;
+        let prefix_len = match subject.find(b"] ") {
+            Some(end) if subject.starts_with(b"[") => end + 2,
+            _ => 0,
+        };
+        result.push_str(b"Subject: ");
+        result.push_str(&subject[..prefix_len]);
+        let mut column = "Subject: ".len() + subject[..prefix_len].chars().count();
+        for (index, word) in subject[prefix_len..].split_str(" ").enumerate() {
+            let word_width = word.chars().count();
+            if index > 0 {
+                if column + 1 + word_width > width {
+                    result.push(b'\n');
+                    column = 0;
+                }
+                result.push(b' ');
+                column += 1;
+            }
+            result.push_str(word);
+            column += word_width;
+        }
+        result.push(b'\n');
+    }
+    result
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum CleanupPatchErr {
     #[error("Unexpected EOF, expected {expected}")]
@@ -171,6 +1070,7 @@ pub enum CleanupPatchErr {
     },
 }
 
+#[cfg(feature = "git")]
 #[derive(Debug, thiserror::Error)]
 pub enum PatchFormatError {
     #[error("Invalid commit message for {}: {}", commit_id, cause)]
@@ -179,6 +1079,24 @@ pub enum PatchFormatError {
         #[source]
         cause: InvalidCommitMessage,
     },
+    /// The [FormatOptions::subject_prefix] contains a `]` or a NUL byte
+    #[error("Invalid subject prefix: {prefix:?}")]
+    InvalidSubjectPrefix { prefix: String },
+    /// The [FormatOptions::rename_threshold] is more than 100%
+    #[error("Invalid rename threshold: {threshold}%")]
+    InvalidRenameThreshold { threshold: u16 },
+    /// The [FormatOptions::name_fn] returned a name that isn't a plain file name,
+    /// like one containing a path separator
+    #[error("Invalid patch name for {commit_id}: {name:?}")]
+    InvalidPatchName { name: String, commit_id: Oid },
+    #[error("Commit {commit_id} has an out of range author date")]
+    InvalidCommitDate { commit_id: Oid },
+    #[error("Unable to create output directory {out_dir}: {cause}")]
+    CreateOutDirError {
+        out_dir: Utf8PathBuf,
+        #[source]
+        cause: std::io::Error,
+    },
     #[error("Error writing to {patch_file}: {cause}")]
     PatchWriteError {
         patch_file: Utf8PathBuf,
@@ -190,6 +1108,24 @@ pub enum PatchFormatError {
         patch_file: Utf8PathBuf,
         cause: CleanupPatchErr,
     },
+    #[error("Failed to post-process {patch_file}: {cause}")]
+    PostProcessFailed {
+        patch_file: Utf8PathBuf,
+        #[source]
+        cause: Box<dyn std::error::Error + Send + Sync>,
+    },
+    #[error("Unable to open repository {}: {cause}", repo_path.display())]
+    OpenRepository {
+        repo_path: std::path::PathBuf,
+        #[source]
+        cause: git2::Error,
+    },
+    #[error("Invalid ignore file {}: {cause}", ignore_file.display())]
+    InvalidIgnoreFile {
+        ignore_file: std::path::PathBuf,
+        #[source]
+        cause: ignore::Error,
+    },
     #[error(transparent)]
     PathNotUtf8(#[from] camino::FromPathBufError),
     #[error("Internal git error: {0}")]
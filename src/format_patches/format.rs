@@ -1,5 +1,8 @@
+#[cfg(feature = "git")]
+use std::borrow::Cow;
 use std::ops::Range;
 
+#[cfg(feature = "git")]
 use git2::Commit;
 
 #[derive(Debug)]
@@ -10,7 +13,6 @@ pub struct CommitMessage<'a> {
 }
 impl<'a> CommitMessage<'a> {
     #[inline]
-    #[allow(dead_code)]
     pub fn full(&self) -> &'a str {
         self.full
     }
@@ -50,9 +52,6 @@ impl<'a> CommitMessage<'a> {
             tail_range: body_start..body_end,
         })
     }
-    pub fn from_commit(commit: &'a Commit) -> Result<Self, InvalidCommitMessage> {
-        Self::parse(commit.message().ok_or(InvalidCommitMessage::InvalidUtf8)?)
-    }
 
     pub fn patch_file_name(&self, patch_no: u32) -> String {
         assert!(patch_no >= 1);
@@ -81,10 +80,42 @@ impl<'a> CommitMessage<'a> {
     }
 }
 
+/// Decode the message of the specified commit into UTF-8.
+///
+/// Commits with an `encoding` header are converted from that encoding,
+/// and all other commits must already be valid UTF-8.
+#[cfg(feature = "git")]
+pub fn decode_commit_message<'a>(commit: &'a Commit) -> Result<Cow<'a, str>, InvalidCommitMessage> {
+    match commit.message_encoding() {
+        Some(label) => {
+            let encoding = encoding_rs::Encoding::for_label(label.as_bytes()).ok_or_else(|| {
+                InvalidCommitMessage::UnknownEncoding {
+                    label: label.into(),
+                }
+            })?;
+            let (decoded, had_errors) =
+                encoding.decode_without_bom_handling(commit.message_raw_bytes());
+            if had_errors {
+                return Err(InvalidCommitMessage::InvalidEncoding {
+                    label: label.into(),
+                });
+            }
+            Ok(decoded)
+        }
+        None => Ok(Cow::Borrowed(
+            commit.message().ok_or(InvalidCommitMessage::InvalidUtf8)?,
+        )),
+    }
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum InvalidCommitMessage {
     #[error("Invalid UTF8 in commit message")]
     InvalidUtf8,
+    #[error("Unknown commit message encoding {label:?}")]
+    UnknownEncoding { label: String },
+    #[error("Commit message is not valid {label}")]
+    InvalidEncoding { label: String },
     /// Indicates that a message was completely empty (zero-length)
     #[error("Empty commit message")]
     EmptyMessage,
@@ -1,5 +1,18 @@
 #![cfg_attr(feature = "backtrace", feature(error_generic_member_access))]
 pub mod apply_patches;
+#[cfg(feature = "git")]
+mod compression;
+#[cfg(feature = "git")]
+mod error;
 pub mod format_patches;
+#[cfg(feature = "git")]
 pub mod regenerate_patches;
+#[cfg(feature = "git")]
+pub mod resolve;
+#[cfg(feature = "git")]
 mod utils;
+
+#[cfg(feature = "git")]
+pub use self::error::Error;
+#[cfg(feature = "git")]
+pub use self::utils::log::{abbrev_len, set_abbrev_len, DEFAULT_ABBREV_LEN};
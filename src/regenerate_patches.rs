@@ -1,3 +1,5 @@
 pub mod patch_file;
 
-pub use self::patch_file::{regenerate_patches, PatchFile, PatchFileSet, RegenerateOptions};
+pub use self::patch_file::{
+    regenerate_patches, DiffSummary, PatchFile, PatchFileSet, RegenerateOptions,
+};
@@ -1,12 +1,15 @@
-use std::collections::HashMap;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
-use std::io::{BufRead, BufReader};
+use std::io::{Read, Seek, SeekFrom};
+use std::ops::AddAssign;
 use std::str::FromStr;
+use std::time::Instant;
 
 use bstr::ByteSlice;
 use camino::{Utf8Path, Utf8PathBuf};
 use git2::build::CheckoutBuilder;
-use git2::{Commit, DiffFormat, DiffOptions, Repository, RepositoryState};
+use git2::{Commit, DiffFormat, DiffOptions, Oid, Repository, RepositoryState};
 use nom::branch::alt;
 use nom::bytes::complete::{tag, take, take_until, take_while1};
 use nom::character::is_hex_digit;
@@ -15,25 +18,43 @@ use nom::sequence::tuple;
 use nom::IResult;
 use slog::{debug, info, trace, warn, Logger};
 
-use crate::format_patches::{FormatOptions, PatchFormatError, PatchFormatter};
+use crate::apply_patches::bulk::{BulkApplyError, BulkPatchApply, ExcludePatterns};
+use crate::apply_patches::email::InvalidEmailMessage;
+use crate::apply_patches::EmailMessage;
+use crate::compression::{read_patch_file, strip_patch_extension};
+use crate::format_patches::{FormatOptions, LineEnding, PatchFormatError, PatchFormatter};
 use crate::utils::RememberLast;
 
 pub struct PatchFileSet<'a> {
     root_repo: &'a Repository,
     patch_dir: Utf8PathBuf,
+    /// The patch dir relative to the worktree of the root repo, for staging and diffing
+    repo_patch_dir: Utf8PathBuf,
     patches: Vec<PatchFile>,
+    /// The stats of each patch, keyed by the hash of its contents
+    stats_cache: RefCell<HashMap<Oid, DiffSummary>>,
 }
 impl<'a> PatchFileSet<'a> {
+    /// Load the patches in the specified directory, which must be inside the worktree of `target`.
+    ///
+    /// A relative patch dir is resolved against the current directory.
     pub fn load(target: &'a Repository, patch_dir: &Utf8Path) -> Result<Self, PatchError> {
-        assert!(patch_dir.is_relative());
+        let repo_patch_dir = repo_relative_dir(target, patch_dir)?;
         let mut set = PatchFileSet {
             root_repo: target,
             patches: Vec::new(),
             patch_dir: patch_dir.into(),
+            repo_patch_dir,
+            stats_cache: RefCell::default(),
         };
         set.reload_files()?;
         Ok(set)
     }
+    /// The patch files in this set, ordered by their number
+    #[inline]
+    pub fn patches(&self) -> &[PatchFile] {
+        &self.patches
+    }
     pub fn reload_files(&mut self) -> Result<(), PatchError> {
         self.patches.clear();
         for entry in std::fs::read_dir(&self.patch_dir)? {
@@ -43,7 +64,7 @@ impl<'a> PatchFileSet<'a> {
                 None => continue, // Ignore non-UTF8 paths
             };
             // Ignore all files that aren't patches
-            if !file_name.ends_with(".patch") {
+            if strip_patch_extension(&file_name).is_none() {
                 continue;
             }
             self.patches
@@ -62,21 +83,122 @@ impl<'a> PatchFileSet<'a> {
     pub fn stage_changes(&mut self) -> Result<(), git2::Error> {
         let mut index = self.root_repo.index()?;
         index.add_all(
-            [self.patch_dir.as_std_path()],
+            [self.repo_patch_dir.as_std_path()],
             git2::IndexAddOption::DEFAULT,
             None,
         )?;
         index.write()
     }
+
+    /// The total number of files changed, and lines inserted or deleted by the patches.
+    ///
+    /// This parses the patches without applying them.
+    /// The stats of each patch are cached by the hash of its contents,
+    /// so repeated calls only parse the patches that changed.
+    pub fn total_stats(&self) -> Result<DiffSummary, PatchError> {
+        let mut cache = self.stats_cache.borrow_mut();
+        let mut total = DiffSummary::default();
+        for patch in &self.patches {
+            let contents = patch.read()?;
+            let hash = Oid::hash_object(git2::ObjectType::Blob, &contents)?;
+            let stats = match cache.get(&hash) {
+                Some(&stats) => stats,
+                None => {
+                    let email = EmailMessage::parse_bytes(&contents).map_err(|cause| {
+                        PatchError::InvalidPatch {
+                            path: patch.path.clone(),
+                            cause,
+                        }
+                    })?;
+                    let stats = DiffSummary::from(&email.stats()?);
+                    cache.insert(hash, stats);
+                    stats
+                }
+            };
+            total += stats;
+        }
+        Ok(total)
+    }
+
+    /// Apply all the patches in this set to the target repository, in order.
+    ///
+    /// This is equivalent to using [BulkPatchApply] on the patch directory,
+    /// but reuses the files that are already loaded.
+    pub fn apply_all(&self, target: &Repository) -> Result<(), BulkApplyError> {
+        let logger = Logger::root(slog::Discard, slog::o!());
+        let bulk = BulkPatchApply::new(&logger, target, self.patch_dir.clone().into());
+        bulk.apply_files(
+            self.patches
+                .iter()
+                .map(|patch| {
+                    let file_name = patch.path.file_name().unwrap_or(patch.path.as_str());
+                    let name = strip_patch_extension(file_name).unwrap_or(file_name);
+                    (name.to_string(), patch.path.clone().into())
+                })
+                .collect(),
+        )
+    }
+}
+/// The number of files changed, and lines inserted or deleted by a diff.
+///
+/// Unlike [git2::DiffStats], this can be summed across patches.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct DiffSummary {
+    pub files_changed: usize,
+    pub insertions: usize,
+    pub deletions: usize,
+}
+impl From<&git2::DiffStats> for DiffSummary {
+    fn from(stats: &git2::DiffStats) -> Self {
+        DiffSummary {
+            files_changed: stats.files_changed(),
+            insertions: stats.insertions(),
+            deletions: stats.deletions(),
+        }
+    }
 }
+impl AddAssign for DiffSummary {
+    fn add_assign(&mut self, other: Self) {
+        self.files_changed += other.files_changed;
+        self.insertions += other.insertions;
+        self.deletions += other.deletions;
+    }
+}
+
 pub struct PatchFile {
     index: usize,
     path: Utf8PathBuf,
 }
 impl PatchFile {
+    /// The number of this patch in the series, from the `NNNN-` prefix of its name
+    #[inline]
+    pub fn index(&self) -> usize {
+        self.index
+    }
+    /// The path of the patch file
+    #[inline]
+    pub fn path(&self) -> &Utf8Path {
+        &self.path
+    }
+    /// Read the contents of the patch, decompressing it if needed
+    pub fn read(&self) -> std::io::Result<Vec<u8>> {
+        read_patch_file(self.path.as_std_path(), None)
+    }
+    /// Check if the patch changes any binary files, without parsing it.
+    ///
+    /// This only looks for the `GIT binary patch` and `Binary files ... differ` markers,
+    /// so it is much cheaper than parsing the diff with [EmailMessage].
+    pub fn touches_binary(&self) -> std::io::Result<bool> {
+        Ok(self.read()?.lines().any(|line| {
+            line == b"GIT binary patch"
+                || (line.starts_with(b"Binary files ") && line.ends_with(b" differ"))
+        }))
+    }
     fn parse(parent: &Utf8Path, file_name: &str) -> Result<Self, PatchError> {
-        // Must match ASCII regex `[\d]{4}-(commit_name).patch`
-        if file_name.len() >= 5 && file_name.as_bytes()[4] == b'-' && file_name.ends_with(".patch")
+        // Must match ASCII regex `[\d]{4}-(commit_name).patch`, optionally compressed
+        if file_name.len() >= 5
+            && file_name.as_bytes()[4] == b'-'
+            && strip_patch_extension(file_name).is_some()
         {
             let index =
                 usize::from_str(&file_name[..4]).map_err(|_| PatchError::InvalidPatchName {
@@ -97,8 +219,59 @@ impl PatchFile {
 #[derive(Default)]
 pub struct RegenerateOptions {
     pub format_opts: FormatOptions,
+    /// Only reformat commits that differ from the existing patches.
+    ///
+    /// Existing patches that already encode the same commit are left untouched
+    /// (neither deleted nor rewritten), which avoids reformatting the whole series
+    /// when only the last few commits changed.
+    /// This has no effect unless the format options that change the contents of the patches
+    /// have their default values (see [FormatOptions::skip_unchanged]).
+    pub incremental: bool,
+    /// The line endings of the regenerated patch files.
+    ///
+    /// This overrides [FormatOptions::patch_file_eol].
+    pub patch_file_eol: LineEnding,
+    /// Fail if the patched repository has uncommitted changes, instead of only warning.
+    ///
+    /// Patches are formatted from commits, so changes in the worktree or index
+    /// are silently left out of the regenerated patches.
+    /// Untracked files aren't considered uncommitted changes.
+    pub strict: bool,
+    /// Log how long each phase of regeneration took, at the info level.
+    ///
+    /// The phases are removing the old patches, formatting, staging,
+    /// and restoring the patches with only trivial changes.
+    /// This shows which phase dominates when regenerating a large series.
+    pub timings: bool,
+    /// Existing patch files to leave untouched, instead of removing them.
+    ///
+    /// This keeps a patch that was skipped when applying the series,
+    /// and so isn't in the patched repository.
+    /// The numbers of the excluded patches are skipped when numbering the regenerated patches,
+    /// so they keep their place in the series (see [FormatOptions::reserved_numbers]).
+    pub exclude: ExcludePatterns,
+    /// Remove the existing patches even if there are no commits after `base`.
+    ///
+    /// An empty range almost always means the wrong base was given,
+    /// so by default this fails instead of wiping out the series.
+    pub allow_empty: bool,
+    /// Format the patches relative to this commit, instead of the `base` given to [regenerate_patches].
+    ///
+    /// This takes precedence over the `base` argument everywhere,
+    /// including the check for an [empty range](RegenerateOptions::allow_empty).
+    /// It lets an embedder keep using one base (like the resolved upstream) for its own logic,
+    /// while formatting the series against another.
+    /// The commit must be in the patched repository.
+    pub base_override: Option<Oid>,
 }
 
+/// Regenerate the patches in `patch_set` from the commits after `base` in `target`.
+///
+/// The patches may be stored in the patched repository itself,
+/// alongside the code they modify (the single-repo layout).
+/// In that case changes to the patch dir aren't counted as uncommitted changes,
+/// but any commits after `base` touching it will end up in the patches,
+/// unless the patch dir is listed in `.gitpatcherignore`.
 pub fn regenerate_patches(
     base: &Commit,
     patch_set: &mut PatchFileSet,
@@ -106,12 +279,61 @@ pub fn regenerate_patches(
     logger: Logger,
     options: RegenerateOptions,
 ) -> Result<(), PatchError> {
-    let target_name = target
-        .path()
-        .file_name()
-        .and_then(|name| name.to_str())
-        .unwrap_or_else(|| panic!("Invalid path for target repo: {}", target.path().display()));
+    let target_dir = target.workdir().unwrap_or(target.path());
+    // Fallback to the full path if the directory has no usable name (like `/`)
+    let target_name = target_dir.file_name().map_or_else(
+        || target_dir.to_string_lossy(),
+        |name| name.to_string_lossy(),
+    );
+    let base_override = options
+        .base_override
+        .map(|id| target.find_commit(id))
+        .transpose()?;
+    let base = base_override.as_ref().unwrap_or(base);
+    /*
+     * The old patches are removed before formatting,
+     * so only writing the commits in the date range would silently truncate the series.
+     */
+    if options.format_opts.since.is_some() || options.format_opts.until.is_some() {
+        return Err(PatchError::DateFilteredSeries);
+    }
     info!(logger, "Formatting patches for {}", patch_set.patch_dir);
+    let single_repo = is_same_repo(patch_set.root_repo, target);
+    if single_repo {
+        debug!(logger, "Patches are stored in the patched repo");
+    }
+    if target.state() == RepositoryState::Clean
+        && !target.is_bare()
+        && has_uncommitted_changes(target, single_repo.then_some(&*patch_set.repo_patch_dir))?
+    {
+        if options.strict {
+            return Err(PatchError::UncommittedChanges);
+        }
+        warn!(
+            logger,
+            "Patched repo has uncommitted changes, which won't be included in the patches"
+        );
+    }
+    if target.state() == RepositoryState::Clean
+        && !options.allow_empty
+        && !patch_set.patches.is_empty()
+    {
+        // Only the commits up to the tip are formatted, regardless of HEAD
+        let tip = match options.format_opts.tip {
+            Some(tip) => tip,
+            None => target.head()?.peel_to_commit()?.id(),
+        };
+        let (ahead, _behind) = target.graph_ahead_behind(tip, base.id())?;
+        if ahead == 0 {
+            return Err(PatchError::EmptyRange {
+                base: base.id(),
+                existing: patch_set.patches.len(),
+            });
+        }
+    }
+    // Fail before touching anything, instead of leaving a half-updated patch dir
+    check_writable(patch_set)?;
+    let mut timer = PhaseTimer::new(&logger, options.timings);
     // Remove old patches
     match target.state() {
         RepositoryState::Rebase | RepositoryState::RebaseInteractive => {
@@ -120,39 +342,97 @@ pub fn regenerate_patches(
             let mut rebase = patch_set.root_repo.open_rebase(None)?;
             let next = rebase.operation_current().unwrap_or(0);
             for patch in &patch_set.patches[..next] {
-                std::fs::remove_file(&patch.path)?;
+                if !is_excluded(&options.exclude, patch) {
+                    std::fs::remove_file(&patch.path)?;
+                }
             }
         }
+        RepositoryState::Clean if options.incremental => {
+            // Stale patches are removed after formatting
+        }
         RepositoryState::Clean => {
             for patch in &patch_set.patches {
-                std::fs::remove_file(&patch.path)?;
+                if !is_excluded(&options.exclude, patch) {
+                    std::fs::remove_file(&patch.path)?;
+                }
             }
         }
         state => {
             return Err(PatchError::PatchedRepoInvalidState { state });
         }
     }
+    timer.finish("removing old patches");
 
     // Regenerate the patches
-    {
-        PatchFormatter::new(
+    let generated = {
+        let mut format_opts = options.format_opts;
+        format_opts.skip_unchanged |= options.incremental;
+        format_opts.patch_file_eol = options.patch_file_eol;
+        // The excluded patches keep their place in the series
+        format_opts.reserved_numbers.extend(
+            patch_set
+                .patches
+                .iter()
+                .filter(|patch| is_excluded(&options.exclude, patch))
+                .map(|patch| patch.index as u32),
+        );
+        let mut formatter = PatchFormatter::new(
             logger.clone(),
             patch_set.patch_dir.clone(),
             target,
             base.clone(),
-            options.format_opts,
-        )?
-        .generate_all()?;
+            format_opts,
+        )?;
+        formatter.generate_all()?;
+        formatter.generated_patches().to_vec()
+    };
+    if matches!(target.state(), RepositoryState::Clean) {
+        let generated_paths = generated
+            .iter()
+            .map(|patch| patch.path.as_path())
+            .collect::<HashSet<_>>();
+        if options.incremental {
+            for patch in &patch_set.patches {
+                if !generated_paths.contains(patch.path.as_path())
+                    && !is_excluded(&options.exclude, patch)
+                {
+                    debug!(logger, "Removing stale patch"; "patch" => patch.path.as_str());
+                    std::fs::remove_file(&patch.path)?;
+                }
+            }
+        }
+        // We know exactly which patches exist, so there is no need to rescan the directory
+        let mut patches = generated
+            .iter()
+            .map(|patch| PatchFile::parse(&patch_set.patch_dir, patch.path.file_name().unwrap()))
+            .collect::<Result<Vec<_>, _>>()?;
+        patches.extend(
+            patch_set
+                .patches
+                .drain(..)
+                .filter(|patch| is_excluded(&options.exclude, patch))
+                .filter(|patch| !generated_paths.contains(patch.path.as_path())),
+        );
+        patches.sort_by_key(|patch| patch.index);
+        patch_set.patches = patches;
+    } else {
+        // Some old patches were kept, so we need to rescan
         patch_set.reload_files()?;
     }
+    let trailers_by_path = generated
+        .iter()
+        .filter_map(|patch| Some((patch.path.as_path(), patch.trailer.as_deref()?)))
+        .collect::<HashMap<_, _>>();
+    timer.finish("formatting");
 
     patch_set.stage_changes()?;
+    timer.finish("staging");
 
     // Remove any 'trivial' patches
     {
         let head_tree = patch_set.root_repo.head()?.peel_to_tree()?;
         let mut filtered_tree = None;
-        let mut parents = patch_set.patch_dir.ancestors().collect::<Vec<_>>();
+        let mut parents = patch_set.repo_patch_dir.ancestors().collect::<Vec<_>>();
         let len = parents.len();
         parents.truncate(len - 1); // Trim last (empty)
         for path in parents {
@@ -166,12 +446,12 @@ pub fn regenerate_patches(
                 Some(existing_tree) => existing_tree,
             };
             let mut builder = patch_set.root_repo.treebuilder(None)?;
-            builder.insert(
-                path.file_name()
-                    .unwrap_or_else(|| panic!("Invalid parent {:?}", path)),
-                child_tree.write()?,
-                entry.filemode(),
-            )?;
+            let name = path
+                .file_name()
+                .ok_or_else(|| PatchError::InvalidPatchDir {
+                    patch_dir: patch_set.patch_dir.clone(),
+                })?;
+            builder.insert(name, child_tree.write()?, entry.filemode())?;
             filtered_tree = Some(builder);
         }
         let filtered_tree = patch_set
@@ -179,9 +459,14 @@ pub fn regenerate_patches(
             .find_tree(filtered_tree.unwrap().write()?)?;
         let mut ops = DiffOptions::new();
         ops.ignore_whitespace_eol(true);
-        let diff = patch_set
-            .root_repo
-            .diff_tree_to_index(Some(&filtered_tree), None, None)?;
+        // Only consider the patch dir, since the rest of the index may be unrelated code
+        let mut patch_dir_only = DiffOptions::new();
+        patch_dir_only.pathspec(patch_set.repo_patch_dir.as_str());
+        let diff = patch_set.root_repo.diff_tree_to_index(
+            Some(&filtered_tree),
+            None,
+            Some(&mut patch_dir_only),
+        )?;
         let mut deltas_by_path = HashMap::new();
         diff.print(DiffFormat::Patch, |delta, _hunk, line| {
             // TODO: Propagate errors instead of panicking
@@ -199,35 +484,30 @@ pub fn regenerate_patches(
         let mut checkout_patches = CheckoutBuilder::new();
         checkout_patches.recreate_missing(true);
         checkout_patches.force();
+        // Patch files are always LF, even if `core.autocrlf` would convert them to CRLF
+        checkout_patches.disable_filters(true);
         let mut num_trivial = 0;
         for patch in &patch_set.patches {
-            let git_version = {
-                let mut reader = BufReader::new(File::open(&patch.path)?);
-                let mut remember = RememberLast::<_, 2>::new();
-                let mut buffer = String::new();
-                while reader.read_line(&mut buffer)? != 0 {
-                    remember.remember(&buffer);
-                    buffer.clear();
-                }
-                let last = remember.as_slice();
-                if last[1].chars().all(|c| c.is_whitespace()) {
-                    // If the last line is all whitespace go with the second to last line
-                    &last[0]
-                } else {
-                    &last[1]
-                }
-                .trim()
-                .to_string()
-            };
-            let delta = match deltas_by_path.get(patch.path.as_std_path()) {
+            if is_excluded(&options.exclude, patch) {
+                continue;
+            }
+            // The diff and checkout use paths relative to the root repo
+            let repo_path = patch_set
+                .repo_patch_dir
+                .join(patch.path.file_name().unwrap());
+            let delta = match deltas_by_path.get(repo_path.as_std_path()) {
                 Some(delta) => delta,
                 None => continue, // no delta -> no changes to checkout
             };
+            let git_version = match trailers_by_path.get(patch.path.as_path()) {
+                Some(&trailer) => trailer.to_string(),
+                None => read_trailer(&patch.path)?,
+            };
             let patch_logger = logger.new(slog::o!("patch" => patch.path.as_str().to_string()));
             if is_trivial_patch_change(&patch_logger, delta, &git_version) {
                 debug!(patch_logger, "Ignoring trivial patch");
                 num_trivial += 1;
-                checkout_patches.path(patch.path.as_std_path());
+                checkout_patches.path(repo_path.as_std_path());
             }
         }
         if num_trivial > 0 {
@@ -236,15 +516,79 @@ pub fn regenerate_patches(
                 .checkout_head(Some(&mut checkout_patches))?;
         }
     }
+    timer.finish("pruning trivial changes");
 
     info!(logger, "Patches for {}", target_name);
     Ok(())
 }
+/// Measures the time spent in each phase of [regenerate_patches].
+///
+/// See [RegenerateOptions::timings].
+struct PhaseTimer<'a> {
+    logger: &'a Logger,
+    enabled: bool,
+    start: Instant,
+}
+impl<'a> PhaseTimer<'a> {
+    fn new(logger: &'a Logger, enabled: bool) -> Self {
+        PhaseTimer {
+            logger,
+            enabled,
+            start: Instant::now(),
+        }
+    }
+    /// Report the time since the last phase finished.
+    fn finish(&mut self, phase: &'static str) {
+        let now = Instant::now();
+        if self.enabled {
+            let elapsed = now - self.start;
+            info!(
+                self.logger, "Finished {} in {:.1?}", phase, elapsed;
+                "phase" => phase,
+                "elapsed_ms" => elapsed.as_millis() as u64,
+            );
+        }
+        self.start = now;
+    }
+}
+
+/// Read the last non-blank line of the specified patch file.
+///
+/// This is only needed for patches that weren't just generated.
+/// Only the end of the file is read, so this is cheap even for large patches.
+fn read_trailer(path: &Utf8Path) -> Result<String, std::io::Error> {
+    const INITIAL_CHUNK_SIZE: u64 = 4096;
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len();
+    let mut chunk_size = INITIAL_CHUNK_SIZE;
+    let mut buffer = Vec::new();
+    loop {
+        let start = len.saturating_sub(chunk_size);
+        file.seek(SeekFrom::Start(start))?;
+        buffer.clear();
+        (&mut file).take(len - start).read_to_end(&mut buffer)?;
+        let mut lines = buffer.split(|&b| b == b'\n');
+        if start > 0 {
+            // The first line is incomplete unless we read the whole file
+            lines.next();
+        }
+        if let Some(line) = lines.rev().find(|line| !line.trim().is_empty()) {
+            return Ok(String::from_utf8_lossy(line.trim()).into_owned());
+        }
+        if start == 0 {
+            return Ok(String::new());
+        }
+        chunk_size *= 2;
+    }
+}
 fn is_trivial_patch_change(logger: &Logger, diff: &str, git_ver: &str) -> bool {
     const CHANGE_MARKERS: &[char] = &['+', '-'];
     let lines = diff.lines();
-    // NOTE: Remember one more than we strictly need
-    let mut remember = RememberLast::<_, 5>::new();
+    /*
+     * NOTE: Remember one more than we strictly need
+     * At most 5 changes are ignored, so a sixth change means the patch isn't trivial.
+     */
+    let mut remember = RememberLast::<_, 6>::new();
     for (idx, line) in lines.enumerate() {
         // We only care about lines that are (+|-)
         if !line.starts_with(CHANGE_MARKERS) {
@@ -258,27 +602,26 @@ fn is_trivial_patch_change(logger: &Logger, diff: &str, git_ver: &str) -> bool {
             remember.remember(&line);
         }
     }
+    // The contents of a changed line, without the leading `+` or `-`
+    let back = |offset: usize| remember.back(offset).map(|line| line[1..].trim());
     match remember.len() {
         0 => true,
         // Ignore changes to $git_ver
-        1 => remember.back(0)[1..].trim() == git_ver,
+        1 => back(0) == Some(git_ver),
         _ => {
             // Ignore changes to trailing git version info
             let mut ignored_changes = 0;
             // There could be a blank line before the change to git version
-            if remember.back(0)[1..].trim().is_empty() {
+            if back(0).is_some_and(str::is_empty) {
                 ignored_changes += 1;
             }
-            if remember.back(ignored_changes)[1..].trim() == git_ver {
+            if back(ignored_changes) == Some(git_ver) {
                 ignored_changes += 1;
                 /*
                  * The last change was to the git version
                  * Strip any other related changes
                  */
-                if remember.len() + ignored_changes >= 2
-                    && remember.back(ignored_changes)[1..].trim() == "--"
-                    && remember.back(ignored_changes + 2)[1..].trim() == "--"
-                {
+                if back(ignored_changes) == Some("--") && back(ignored_changes + 2) == Some("--") {
                     // They also changed the -- at the end
                     ignored_changes += 3;
                 } else {
@@ -286,11 +629,69 @@ fn is_trivial_patch_change(logger: &Logger, diff: &str, git_ver: &str) -> bool {
                     ignored_changes += 1;
                 }
             }
-            assert!(ignored_changes <= remember.as_slice().len());
-            ignored_changes == remember.as_slice().len()
+            ignored_changes == remember.len()
         }
     }
 }
+/// Check that the patch dir and the existing patches can be replaced.
+///
+/// Patch files are sometimes checked out read-only (like by a VCS with file locking).
+fn check_writable(patch_set: &PatchFileSet) -> Result<(), PatchError> {
+    let paths = std::iter::once(patch_set.patch_dir.as_path())
+        .chain(patch_set.patches.iter().map(|patch| patch.path.as_path()));
+    for path in paths {
+        if path.metadata()?.permissions().readonly() {
+            return Err(PatchError::ReadOnly { path: path.into() });
+        }
+    }
+    Ok(())
+}
+
+/// The path of the patch dir relative to the worktree of the repository.
+fn repo_relative_dir(repo: &Repository, patch_dir: &Utf8Path) -> Result<Utf8PathBuf, PatchError> {
+    let invalid = || PatchError::InvalidPatchDir {
+        patch_dir: patch_dir.into(),
+    };
+    let workdir = repo.workdir().ok_or_else(invalid)?.canonicalize()?;
+    let canonical = patch_dir.canonicalize()?;
+    match canonical.strip_prefix(&workdir) {
+        Ok(relative) if relative.as_os_str().is_empty() => Err(invalid()),
+        Ok(relative) => Utf8PathBuf::try_from(relative.to_path_buf()).map_err(|_| invalid()),
+        Err(_) => Err(invalid()),
+    }
+}
+
+/// Check if both repositories are the same, even if they were opened through different paths.
+fn is_same_repo(first: &Repository, second: &Repository) -> bool {
+    match (first.path().canonicalize(), second.path().canonicalize()) {
+        (Ok(first), Ok(second)) => first == second,
+        _ => first.path() == second.path(),
+    }
+}
+
+fn is_excluded(exclude: &ExcludePatterns, patch: &PatchFile) -> bool {
+    patch
+        .path
+        .file_name()
+        .is_some_and(|name| exclude.matches(name))
+}
+
+/// If the repository has uncommitted changes outside the `ignored_dir`.
+fn has_uncommitted_changes(
+    repo: &Repository,
+    ignored_dir: Option<&Utf8Path>,
+) -> Result<bool, git2::Error> {
+    let mut opts = git2::StatusOptions::new();
+    opts.include_untracked(false).include_ignored(false);
+    Ok(repo.statuses(Some(&mut opts))?.iter().any(|entry| {
+        !ignored_dir.is_some_and(|dir| {
+            entry
+                .path()
+                .is_some_and(|path| Utf8Path::new(path).starts_with(dir))
+        })
+    }))
+}
+
 fn is_trivial_line(line: &[u8]) -> bool {
     if line.contains_str("--- a") | line.contains_str("+++ b") {
         true
@@ -308,10 +709,36 @@ pub enum PatchError {
     /// The patched repo was in an invalid [RepositoryState]
     #[error("Target repo is in unexpected state: {state:?}")]
     PatchedRepoInvalidState { state: RepositoryState },
+    /// The patched repo has uncommitted changes, and [RegenerateOptions::strict] is set
+    #[error("Target repo has uncommitted changes, which would be missing from the patches")]
+    UncommittedChanges,
+    /// There are no commits after the base, which would remove every existing patch
+    ///
+    /// See [RegenerateOptions::allow_empty].
+    #[error("No commits after {base}, refusing to remove the {existing} existing patches")]
+    EmptyRange { base: Oid, existing: usize },
+    /// The [FormatOptions::since] or [FormatOptions::until] filters were set,
+    /// which would replace the series with only the commits in the date range.
+    ///
+    /// Use [PatchFormatter::generate_mbox] to export part of the series instead.
+    #[error("Refusing to regenerate the patch dir with a date filter, which would drop the other patches")]
+    DateFilteredSeries,
+    #[error("Invalid patch {path}: {cause}")]
+    InvalidPatch {
+        path: Utf8PathBuf,
+        #[source]
+        cause: InvalidEmailMessage,
+    },
     #[error("Invalid name for patch: {name:?}")]
     InvalidPatchName { name: String },
+    /// An existing patch (or the patch dir) is read-only, so it can't be regenerated
+    #[error("Unable to regenerate patches, {path} is read-only")]
+    ReadOnly { path: Utf8PathBuf },
     #[error("Failed to format patches: {0}")]
     PatchFormatFailed(#[from] PatchFormatError),
+    /// The patch dir must be inside the worktree of the root repo, but not the worktree itself
+    #[error("Invalid patch dir: {patch_dir}")]
+    InvalidPatchDir { patch_dir: Utf8PathBuf },
     #[error("Missing patch dir {}: {cause}", patch_dir)]
     MissingPatchDir {
         patch_dir: Utf8PathBuf,
@@ -324,3 +751,70 @@ pub enum PatchError {
     #[error("Unexpected IO error: {0}")]
     Io(#[from] std::io::Error),
 }
+
+#[cfg(test)]
+mod test {
+    use super::{is_trivial_patch_change, read_trailer};
+
+    fn logger() -> slog::Logger {
+        slog::Logger::root(slog::Discard, slog::o!())
+    }
+
+    #[test]
+    fn trivial_single_line_changes() {
+        let logger = logger();
+        assert!(is_trivial_patch_change(&logger, "", "2.40.0"));
+        assert!(is_trivial_patch_change(
+            &logger,
+            "-2.39.0\n+2.40.0\n",
+            "2.40.0"
+        ));
+        assert!(!is_trivial_patch_change(&logger, "+foo\n", "2.40.0"));
+        // Used to index before the start of the remembered lines
+        assert!(!is_trivial_patch_change(&logger, "+2.40.0\n+\n", "2.40.0"));
+        assert!(!is_trivial_patch_change(&logger, "-\n+\n", "2.40.0"));
+    }
+
+    #[test]
+    fn trivial_signature_changes() {
+        let logger = logger();
+        // The most changes that are ignored: the signature, the version and a blank line
+        const SIGNATURE_CHANGES: &str = "--- \n-2.39.0\n+-- \n+2.40.0\n+\n";
+        assert!(is_trivial_patch_change(
+            &logger,
+            SIGNATURE_CHANGES,
+            "2.40.0"
+        ));
+        // A single real change before them must not be forgotten
+        assert!(!is_trivial_patch_change(
+            &logger,
+            &format!("+foo\n{SIGNATURE_CHANGES}"),
+            "2.40.0"
+        ));
+    }
+
+    #[test]
+    fn trailer_of_large_patch() {
+        let dir = testdir::testdir!();
+        let path = camino::Utf8PathBuf::try_from(dir.join("large.patch")).unwrap();
+        let mut contents = String::from(
+            "From 0000000000000000000000000000000000000000 Mon Sep 17 00:00:00 2001\n",
+        );
+        for i in 0..100_000 {
+            contents.push_str(&format!("+line {i}\n"));
+        }
+        contents.push_str("-- \n2.41.0\n\n");
+        std::fs::write(&path, &contents).unwrap();
+        assert_eq!(read_trailer(&path).unwrap(), "2.41.0");
+
+        // A trailing line longer than the chunk size, followed by lots of blank lines
+        let long_line = "x".repeat(10_000);
+        contents.push_str(&long_line);
+        contents.push_str(&"\n".repeat(5000));
+        std::fs::write(&path, &contents).unwrap();
+        assert_eq!(read_trailer(&path).unwrap(), long_line);
+
+        std::fs::write(&path, "\n\n").unwrap();
+        assert_eq!(read_trailer(&path).unwrap(), "");
+    }
+}
@@ -0,0 +1,157 @@
+//! Resolve user-provided revisions into commits.
+use std::borrow::Cow;
+use std::fmt::{self, Display};
+
+use git2::{Commit, ObjectType, Repository};
+
+/// Resolve a commit id (full or abbreviated), reference name, or tag into a commit.
+///
+/// The candidates are tried in order:
+/// 1. A revision understood by [Repository::revparse_single] (including short shas),
+///    like `HEAD~2` or `@{upstream}`. Like git, a leading `@` is short for `HEAD`.
+/// 2. A reference name, like `upstream/master`
+/// 3. A tag name, which is peeled to the commit it points at
+///
+/// If none of them succeed, the error lists the reason each one failed.
+pub fn resolve_commitish<'repo>(
+    repo: &'repo Repository,
+    spec: &str,
+) -> Result<Commit<'repo>, ResolveCommitishError> {
+    let mut attempts = Vec::new();
+    let mut attempt = |kind: &'static str, res: Result<git2::Object<'repo>, git2::Error>| match res
+        .and_then(|obj| obj.peel_to_commit())
+    {
+        Ok(commit) => Some(commit),
+        Err(cause) => {
+            attempts.push((kind, cause));
+            None
+        }
+    };
+    if let Some(commit) = attempt(
+        "revision",
+        repo.revparse_single(&expand_head_shorthand(spec)),
+    ) {
+        return Ok(commit);
+    }
+    let reference = repo
+        .resolve_reference_from_short_name(spec)
+        .and_then(|reference| reference.peel(ObjectType::Any));
+    if let Some(commit) = attempt("reference", reference) {
+        return Ok(commit);
+    }
+    let tag = repo
+        .find_reference(&format!("refs/tags/{spec}"))
+        .and_then(|reference| reference.peel(ObjectType::Any));
+    if let Some(commit) = attempt("tag", tag) {
+        return Ok(commit);
+    }
+    Err(ResolveCommitishError {
+        spec: spec.into(),
+        attempts,
+    })
+}
+
+/// Expand the `@` shorthand for `HEAD`, which libgit2 only understands before a `{`.
+///
+/// Only a bare `@` or one followed by `~` or `^` is expanded,
+/// since a reference name like `@foo` can also start with an `@`.
+fn expand_head_shorthand(spec: &str) -> Cow<'_, str> {
+    match spec.strip_prefix('@') {
+        Some(rest) if rest.is_empty() || rest.starts_with(['~', '^']) => {
+            Cow::Owned(format!("HEAD{rest}"))
+        }
+        _ => Cow::Borrowed(spec),
+    }
+}
+
+/// Indicates that [resolve_commitish] couldn't find a matching commit.
+#[derive(Debug, thiserror::Error)]
+pub struct ResolveCommitishError {
+    /// The revision that failed to resolve
+    pub spec: String,
+    /// The kind of each resolution attempted, along with the reason it failed
+    pub attempts: Vec<(&'static str, git2::Error)>,
+}
+impl Display for ResolveCommitishError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Unable to resolve {:?} to a commit", self.spec)?;
+        for (kind, cause) in &self.attempts {
+            write!(f, "\n  as {kind}: {}", cause.message())?;
+        }
+        Ok(())
+    }
+}
+
+/// Fetch a remote-tracking reference like `origin/master` if it doesn't exist locally.
+///
+/// Returns `true` if the reference was fetched,
+/// and `false` if it already resolves or doesn't name a configured remote.
+/// Credentials are taken from the ssh agent or the configured git credential helpers.
+pub fn fetch_missing_remote_ref(repo: &Repository, spec: &str) -> Result<bool, FetchRemoteError> {
+    if resolve_commitish(repo, spec).is_ok() {
+        return Ok(false);
+    }
+    let short_name = spec.strip_prefix("refs/remotes/").unwrap_or(spec);
+    let Some((remote_name, branch)) = short_name.split_once('/') else {
+        return Ok(false);
+    };
+    let mut remote = match repo.find_remote(remote_name) {
+        Ok(remote) => remote,
+        Err(e) if e.code() == git2::ErrorCode::NotFound => return Ok(false),
+        Err(cause) => {
+            return Err(FetchRemoteError {
+                remote: remote_name.into(),
+                cause,
+            })
+        }
+    };
+    let refspec = format!("refs/heads/{branch}:refs/remotes/{remote_name}/{branch}");
+    let mut fetch_opts = git2::FetchOptions::new();
+    fetch_opts.remote_callbacks(credential_callbacks(repo));
+    remote
+        .fetch(&[refspec], Some(&mut fetch_opts), None)
+        .map_err(|cause| FetchRemoteError {
+            remote: remote_name.into(),
+            cause,
+        })?;
+    Ok(true)
+}
+
+/// Callbacks that try each kind of credential that libgit2 asks for.
+fn credential_callbacks(repo: &Repository) -> git2::RemoteCallbacks<'static> {
+    use git2::{Cred, CredentialType};
+    let config = repo.config().ok();
+    let mut attempts = 0;
+    let mut callbacks = git2::RemoteCallbacks::new();
+    callbacks.credentials(move |url, username, allowed| {
+        // libgit2 keeps asking as long as we return credentials, even if they are rejected
+        attempts += 1;
+        if attempts > 3 {
+            return Err(git2::Error::from_str("Authentication failed"));
+        }
+        if allowed.contains(CredentialType::SSH_KEY) {
+            if let Some(username) = username {
+                return Cred::ssh_key_from_agent(username);
+            }
+        }
+        if allowed.contains(CredentialType::USER_PASS_PLAINTEXT) {
+            if let Some(ref config) = config {
+                return Cred::credential_helper(config, url, username);
+            }
+        }
+        if allowed.contains(CredentialType::DEFAULT) {
+            return Cred::default();
+        }
+        Err(git2::Error::from_str("No supported credentials available"))
+    });
+    callbacks
+}
+
+/// Indicates that [fetch_missing_remote_ref] failed to fetch from the remote.
+#[derive(Debug, thiserror::Error)]
+#[error("Failed to fetch from remote {remote:?}")]
+pub struct FetchRemoteError {
+    pub remote: String,
+    #[source]
+    pub cause: git2::Error,
+}
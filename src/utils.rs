@@ -4,18 +4,29 @@ use arrayvec::ArrayVec;
 use bstr::{BStr, ByteSlice};
 
 pub struct SimpleParser<'a> {
-    lines: Peekable<bstr::Lines<'a>>,
+    lines: Peekable<bstr::LinesWithTerminator<'a>>,
     line_number: usize,
 }
 impl<'a> SimpleParser<'a> {
     pub fn new(s: &'a BStr) -> Self {
         SimpleParser {
-            lines: s.lines().peekable(),
+            lines: s.lines_with_terminator().peekable(),
             line_number: 1,
         }
     }
+    /// Remove the `\n` or `\r\n` terminator from a line
+    #[inline]
+    fn strip_terminator(line: &[u8]) -> &BStr {
+        let line = line.strip_suffix(b"\n").unwrap_or(line);
+        BStr::new(line.strip_suffix(b"\r").unwrap_or(line))
+    }
     #[inline]
     pub fn peek(&mut self) -> Result<&'a BStr, UnexpectedEof> {
+        self.peek_raw().map(|line| Self::strip_terminator(line))
+    }
+    /// Peek at the next line, including its terminator
+    #[inline]
+    pub fn peek_raw(&mut self) -> Result<&'a BStr, UnexpectedEof> {
         match self.lines.peek() {
             Some(&line) => Ok(BStr::new(line)),
             None => Err(UnexpectedEof),
@@ -23,6 +34,11 @@ impl<'a> SimpleParser<'a> {
     }
     #[inline]
     pub fn pop(&mut self) -> Result<&'a BStr, UnexpectedEof> {
+        self.pop_raw().map(|line| Self::strip_terminator(line))
+    }
+    /// Pop the next line, including its terminator
+    #[inline]
+    pub fn pop_raw(&mut self) -> Result<&'a BStr, UnexpectedEof> {
         let line = self.lines.next().ok_or(UnexpectedEof)?;
         self.line_number += 1;
         Ok(BStr::new(line))
@@ -82,15 +98,12 @@ impl<T: Clone, const LIMIT: usize> RememberLast<T, LIMIT> {
             Clone::clone_from(self.last.last_mut().unwrap(), element);
         }
     }
+    /// The element `offset` places before the newest one,
+    /// or `None` if we don't remember that many elements.
     #[inline]
-    pub fn back(&self, offset: usize) -> &T {
-        &self.last[self.last.len() - offset - 1]
-    }
-    /// The last elements we remember,
-    /// from oldest to newest
-    #[inline]
-    pub fn as_slice(&self) -> &[T] {
-        &self.last
+    pub fn back(&self, offset: usize) -> Option<&T> {
+        let index = self.last.len().checked_sub(offset)?.checked_sub(1)?;
+        self.last.get(index)
     }
     #[inline]
     pub fn len(&self) -> usize {
@@ -102,9 +115,121 @@ impl<T: Clone, const LIMIT: usize> RememberLast<T, LIMIT> {
 #[error("Unexpected EOF")]
 pub struct UnexpectedEof;
 
+/// Convert the raw mode of a [git2::TreeEntry] into a [git2::FileMode]
+pub fn file_mode_from_raw(mode: i32) -> git2::FileMode {
+    use git2::FileMode;
+    match mode {
+        0o040000 => FileMode::Tree,
+        0o100755 => FileMode::BlobExecutable,
+        0o120000 => FileMode::Link,
+        0o160000 => FileMode::Commit,
+        0o100664 => FileMode::BlobGroupWritable,
+        0 => FileMode::Unreadable,
+        _ => FileMode::Blob,
+    }
+}
+
+/// Treat a missing git config entry as `None`, instead of an error.
+pub fn optional_config<T>(res: Result<T, git2::Error>) -> Result<Option<T>, git2::Error> {
+    match res {
+        Ok(value) => Ok(Some(value)),
+        Err(e) if e.code() == git2::ErrorCode::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Write a file atomically, by writing a temporary file in the same directory
+/// and then renaming it over the destination.
+///
+/// If writing is interrupted, the destination is either unchanged or completely written.
+/// Only a hidden `.{name}.{pid}-{n}.tmp` file may be left behind.
+/// The temporary name is unique to each call, so concurrent writes to the same path
+/// never clobber each other's temporary file (the last rename wins).
+pub fn write_atomic(path: &std::path::Path, contents: &[u8]) -> std::io::Result<()> {
+    use std::io::Write;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    static NEXT_TEMP_ID: AtomicU64 = AtomicU64::new(0);
+    let file_name = path.file_name().ok_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, "Path has no file name")
+    })?;
+    let (temp_path, mut file) = loop {
+        let mut temp_name = std::ffi::OsString::from(".");
+        temp_name.push(file_name);
+        temp_name.push(format!(
+            ".{}-{}.tmp",
+            std::process::id(),
+            NEXT_TEMP_ID.fetch_add(1, Ordering::Relaxed)
+        ));
+        let temp_path = path.with_file_name(temp_name);
+        // A leftover file from a process that had the same pid is skipped
+        match std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&temp_path)
+        {
+            Ok(file) => break (temp_path, file),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    };
+    let res = file
+        .write_all(contents)
+        .and_then(|()| file.sync_all())
+        .and_then(|()| {
+            drop(file);
+            std::fs::rename(&temp_path, path)
+        });
+    if res.is_err() {
+        let _ = std::fs::remove_file(&temp_path);
+    }
+    res
+}
+
 /// Utilities for logging
 pub mod log {
+    use std::fmt::{self, Display};
     use std::path::{Path, PathBuf};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// The default number of hex digits used to display ids, like git's automatic abbreviation
+    pub const DEFAULT_ABBREV_LEN: usize = 10;
+
+    static ABBREV_LEN: AtomicUsize = AtomicUsize::new(DEFAULT_ABBREV_LEN);
+
+    /// Set the number of hex digits used to display commit ids in logs.
+    ///
+    /// This applies to the entire crate. Like git's `core.abbrev`,
+    /// the length is clamped between 4 and the full 40 digits.
+    pub fn set_abbrev_len(len: usize) {
+        ABBREV_LEN.store(len.clamp(4, 40), Ordering::Relaxed);
+    }
+
+    /// The number of hex digits used to display commit ids in logs.
+    ///
+    /// See [set_abbrev_len].
+    pub fn abbrev_len() -> usize {
+        ABBREV_LEN.load(Ordering::Relaxed)
+    }
+
+    /// Wrapper for [git2::Oid] that displays the id abbreviated to the [abbrev_len]
+    #[derive(Debug, Copy, Clone)]
+    pub struct ShortId(pub git2::Oid);
+    impl Display for ShortId {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            let id = self.0.to_string();
+            f.write_str(&id[..abbrev_len().min(id.len())])
+        }
+    }
+    impl slog::Value for ShortId {
+        fn serialize(
+            &self,
+            record: &slog::Record,
+            key: slog::Key,
+            serializer: &mut dyn slog::Serializer,
+        ) -> slog::Result {
+            slog::Value::serialize(&self.to_string(), record, key, serializer)
+        }
+    }
 
     /// Wrapper for [PathBuf] that implements [slog::Value]
     #[derive(Debug, Clone)]
@@ -134,3 +259,46 @@ pub mod log {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{write_atomic, RememberLast};
+
+    #[test]
+    fn concurrent_write_atomic() {
+        let dir = testdir::testdir!();
+        let path = dir.join("file.txt");
+        std::thread::scope(|scope| {
+            for i in 0..8 {
+                let path = &path;
+                scope.spawn(move || {
+                    for _ in 0..20 {
+                        write_atomic(path, format!("contents {i}\n").repeat(100).as_bytes())
+                            .unwrap();
+                    }
+                });
+            }
+        });
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert!((0..8).any(|i| contents == format!("contents {i}\n").repeat(100)));
+        // Every temporary file was renamed over the destination
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn remember_last_back() {
+        let mut remember = RememberLast::<u32, 3>::new();
+        assert_eq!(remember.back(0), None);
+        remember.remember(&1);
+        assert_eq!(remember.back(0), Some(&1));
+        assert_eq!(remember.back(1), None);
+        for i in 2..=5 {
+            remember.remember(&i);
+        }
+        assert_eq!(remember.len(), 3);
+        assert_eq!(remember.back(0), Some(&5));
+        assert_eq!(remember.back(2), Some(&3));
+        assert_eq!(remember.back(3), None);
+        assert_eq!(remember.back(usize::MAX), None);
+    }
+}
@@ -0,0 +1,1819 @@
+//! Tests for applying individual patches.
+use gitpatcher::apply_patches::email::{DeltaApplyError, PatchApplyError};
+use gitpatcher::apply_patches::{ApplyOptions, EmailMessage, IgnoredPathCheck, WhitespaceAction};
+
+mod common;
+
+#[test]
+pub fn leave_conflicts_in_worktree() -> anyhow::Result<()> {
+    let tempdir = testdir::testdir!();
+    let source = common::init_repo(&tempdir.join("source"))?;
+    let base = common::commit_files(
+        &source,
+        &[("a.txt", Some("1\n2\n3\n")), ("b.txt", Some("x\ny\nz\n"))],
+        "Initial commit",
+    )?;
+    common::commit_files(
+        &source,
+        &[
+            ("a.txt", Some("1\n2 changed\n3\n")),
+            ("b.txt", Some("x\ny changed\nz\n")),
+        ],
+        "Change both files",
+    )?;
+    let patches = common::format_patches(&source, base, &tempdir.join("patches"))?;
+    let email = EmailMessage::parse(&patches[0])?;
+
+    let target = common::init_repo(&tempdir.join("target"))?;
+    let target_base = common::commit_files(
+        &target,
+        &[("a.txt", Some("1\n2\n3\n")), ("b.txt", Some("p\nq\nr\n"))],
+        "Conflicting initial commit",
+    )?;
+    let opts = ApplyOptions {
+        leave_conflicts_in_worktree: true,
+        ..Default::default()
+    };
+    match email.apply_commit_with(&target, &opts) {
+        Err(PatchApplyError::LeftInWorktree { rejected }) => assert_eq!(rejected.len(), 1),
+        other => panic!("Expected LeftInWorktree, got {other:?}"),
+    }
+    let workdir = target.workdir().unwrap();
+    assert_eq!(
+        std::fs::read_to_string(workdir.join("a.txt"))?,
+        "1\n2 changed\n3\n"
+    );
+    assert_eq!(std::fs::read_to_string(workdir.join("b.txt"))?, "p\nq\nr\n");
+    assert!(workdir.join("b.txt.rej").is_file());
+    // No commit should have been created
+    assert_eq!(target.head()?.peel_to_commit()?.id(), target_base);
+
+    // Uncommitted changes to the patched files are never overwritten
+    let dirty = common::init_repo(&tempdir.join("dirty"))?;
+    common::commit_files(
+        &dirty,
+        &[("a.txt", Some("1\n2\n3\n")), ("b.txt", Some("p\nq\nr\n"))],
+        "Conflicting initial commit",
+    )?;
+    let dirty_workdir = dirty.workdir().unwrap();
+    std::fs::write(dirty_workdir.join("a.txt"), "1\n2\n3\nuncommitted\n")?;
+    match email.apply_commit_with(&dirty, &opts) {
+        Err(PatchApplyError::DirtyWorktree { paths }) => assert_eq!(paths, ["a.txt"]),
+        other => panic!("Expected DirtyWorktree, got {other:?}"),
+    }
+    assert_eq!(
+        std::fs::read_to_string(dirty_workdir.join("a.txt"))?,
+        "1\n2\n3\nuncommitted\n"
+    );
+    assert!(!dirty_workdir.join("b.txt.rej").exists());
+    Ok(())
+}
+
+#[test]
+pub fn path_prefix() -> anyhow::Result<()> {
+    let tempdir = testdir::testdir!();
+    let source = common::init_repo(&tempdir.join("source"))?;
+    let base = common::commit_files(&source, &[("lib.rs", Some("fn a() {}\n"))], "Initial")?;
+    common::commit_files(
+        &source,
+        &[
+            ("lib.rs", Some("fn a() {}\nfn b() {}\n")),
+            ("new.rs", Some("fn c() {}\n")),
+        ],
+        "Add b and c",
+    )?;
+    let patches = common::format_patches(&source, base, &tempdir.join("patches"))?;
+    let email = EmailMessage::parse(&patches[0])?;
+
+    let target = common::init_repo(&tempdir.join("target"))?;
+    common::commit_files(
+        &target,
+        &[("vendor/lib/lib.rs", Some("fn a() {}\n"))],
+        "Vendor lib",
+    )?;
+    let opts = ApplyOptions {
+        path_prefix: Some("vendor/lib/".into()),
+        ..Default::default()
+    };
+    email.apply_commit_with(&target, &opts)?;
+    assert_eq!(
+        common::read_head_file(&target, "vendor/lib/lib.rs")?.as_deref(),
+        Some("fn a() {}\nfn b() {}\n")
+    );
+    assert_eq!(
+        common::read_head_file(&target, "vendor/lib/new.rs")?.as_deref(),
+        Some("fn c() {}\n")
+    );
+    assert_eq!(common::read_head_file(&target, "lib.rs")?, None);
+
+    for invalid in ["../escape", "/absolute"] {
+        let opts = ApplyOptions {
+            path_prefix: Some(invalid.into()),
+            ..Default::default()
+        };
+        assert!(matches!(
+            email.apply_commit_with(&target, &opts),
+            Err(PatchApplyError::InvalidOptions(_))
+        ));
+    }
+    Ok(())
+}
+
+#[test]
+pub fn strip_components() -> anyhow::Result<()> {
+    let tempdir = testdir::testdir!();
+    let source = common::init_repo(&tempdir.join("source"))?;
+    let base = common::commit_files(&source, &[("one/two/file.txt", Some("a\n"))], "Initial")?;
+    common::commit_files(&source, &[("one/two/file.txt", Some("b\n"))], "Change")?;
+    let patches = common::format_patches(&source, base, &tempdir.join("patches"))?;
+    let email = EmailMessage::parse(&patches[0])?;
+
+    for (strip_components, target_path) in [
+        (0, "one/two/file.txt"),
+        (1, "two/file.txt"),
+        (2, "file.txt"),
+    ] {
+        let target = common::init_repo(&tempdir.join(format!("target-{strip_components}")))?;
+        common::commit_files(&target, &[(target_path, Some("a\n"))], "Initial")?;
+        let opts = ApplyOptions {
+            strip_components,
+            ..Default::default()
+        };
+        email.apply_commit_with(&target, &opts)?;
+        assert_eq!(
+            common::read_head_file(&target, target_path)?.as_deref(),
+            Some("b\n")
+        );
+    }
+
+    let target = common::init_repo(&tempdir.join("target-empty"))?;
+    common::commit_files(&target, &[("file.txt", Some("a\n"))], "Initial")?;
+    let opts = ApplyOptions {
+        strip_components: 3,
+        ..Default::default()
+    };
+    assert!(matches!(
+        email.apply_commit_with(&target, &opts),
+        Err(PatchApplyError::StripEmptyPath { .. })
+    ));
+    Ok(())
+}
+
+#[test]
+pub fn warn_adding_ignored_file() -> anyhow::Result<()> {
+    let tempdir = testdir::testdir!();
+    let source = common::init_repo(&tempdir.join("source"))?;
+    let base = common::commit_files(&source, &[("README", Some("readme\n"))], "Initial")?;
+    common::commit_files(
+        &source,
+        &[("build/output.txt", Some("built\n"))],
+        "Add output",
+    )?;
+    let patches = common::format_patches(&source, base, &tempdir.join("patches"))?;
+    let email = EmailMessage::parse(&patches[0])?;
+
+    let target = common::init_repo(&tempdir.join("target"))?;
+    common::commit_files(
+        &target,
+        &[(".gitignore", Some("/build/\n"))],
+        "Ignore build",
+    )?;
+    let drain = common::CapturingDrain::default();
+    let opts = ApplyOptions {
+        check_ignored: IgnoredPathCheck::Error,
+        ..Default::default()
+    };
+    assert!(matches!(
+        email.apply_commit_with(&target, &opts),
+        Err(PatchApplyError::AddsIgnoredFile { .. })
+    ));
+    let opts = ApplyOptions {
+        check_ignored: IgnoredPathCheck::Warn,
+        logger: Some(drain.logger()),
+        ..Default::default()
+    };
+    email.apply_commit_with(&target, &opts)?;
+    assert_eq!(
+        drain.messages(slog::Level::Warning),
+        vec!["Patch adds ignored file"]
+    );
+    assert_eq!(
+        common::read_head_file(&target, "build/output.txt")?.as_deref(),
+        Some("built\n")
+    );
+    Ok(())
+}
+
+#[test]
+pub fn committer_date_is_author_date() -> anyhow::Result<()> {
+    let tempdir = testdir::testdir!();
+    let source = common::init_repo(&tempdir.join("source"))?;
+    let base = common::commit_files(&source, &[("file.txt", Some("a\n"))], "Initial")?;
+    common::commit_files(&source, &[("file.txt", Some("b\n"))], "Change")?;
+    let patches = common::format_patches(&source, base, &tempdir.join("patches"))?;
+    let email = EmailMessage::parse(&patches[0])?;
+    let committer = git2::Signature::new(
+        "Committer",
+        "committer@example.com",
+        &git2::Time::new(2_000_000_000, 60),
+    )?;
+
+    for committer_date_is_author_date in [false, true] {
+        let target =
+            common::init_repo(&tempdir.join(format!("target-{committer_date_is_author_date}")))?;
+        common::commit_files(&target, &[("file.txt", Some("a\n"))], "Initial")?;
+        let opts = ApplyOptions {
+            committer: Some(committer.clone()),
+            committer_date_is_author_date,
+            ..Default::default()
+        };
+        email.apply_commit_with(&target, &opts)?;
+        let commit = target.head()?.peel_to_commit()?;
+        assert_eq!(commit.committer().name(), Some("Committer"));
+        assert_eq!(commit.author().name(), Some("dummy"));
+        if committer_date_is_author_date {
+            assert_eq!(commit.committer().when(), commit.author().when());
+        } else {
+            assert_eq!(commit.committer().when(), committer.when());
+            assert_ne!(commit.committer().when(), commit.author().when());
+        }
+    }
+    Ok(())
+}
+
+#[test]
+pub fn options_from_config() -> anyhow::Result<()> {
+    let tempdir = testdir::testdir!();
+    let repo = common::init_repo(&tempdir.join("repo"))?;
+    let mut config = repo.config()?;
+    config.set_str("user.name", "Configured")?;
+    config.set_str("user.email", "configured@example.com")?;
+    config.set_bool("am.threeWay", true)?;
+    config.set_str("apply.whitespace", "fix")?;
+
+    let opts = ApplyOptions::from_config(&repo.config()?)?;
+    assert!(opts.three_way);
+    assert_eq!(opts.whitespace, WhitespaceAction::Fix);
+    // The committer is opt-in, so re-applying a series reproduces the same commits
+    assert!(opts.committer.is_none());
+    assert!(ApplyOptions::config_committer(&repo.config()?)?.is_some());
+
+    config.set_str("apply.whitespace", "sometimes")?;
+    assert!(ApplyOptions::from_config(&repo.config()?).is_err());
+    Ok(())
+}
+
+#[test]
+pub fn trailing_whitespace() -> anyhow::Result<()> {
+    let tempdir = testdir::testdir!();
+    let source = common::init_repo(&tempdir.join("source"))?;
+    let base = common::commit_files(&source, &[("file.txt", Some("a\n"))], "Initial")?;
+    common::commit_files(&source, &[("file.txt", Some("a\nb \t\nc\n"))], "Change")?;
+    let patches = common::format_patches(&source, base, &tempdir.join("patches"))?;
+    let email = EmailMessage::parse(&patches[0])?;
+
+    for (action, expected) in [
+        (WhitespaceAction::NoWarn, Some("a\nb \t\nc\n")),
+        (WhitespaceAction::Warn, Some("a\nb \t\nc\n")),
+        (WhitespaceAction::Fix, Some("a\nb\nc\n")),
+        (WhitespaceAction::Error, None),
+    ] {
+        let target = common::init_repo(&tempdir.join(format!("target-{action:?}")))?;
+        common::commit_files(&target, &[("file.txt", Some("a\n"))], "Initial")?;
+        let opts = ApplyOptions {
+            whitespace: action,
+            ..Default::default()
+        };
+        match (email.apply_commit_with(&target, &opts), expected) {
+            (Ok(()), Some(expected)) => {
+                assert_eq!(
+                    common::read_head_file(&target, "file.txt")?.as_deref(),
+                    Some(expected)
+                );
+            }
+            (
+                Err(PatchApplyError::FailDelta {
+                    cause: DeltaApplyError::TrailingWhitespace { lines: 1 },
+                    ..
+                }),
+                None,
+            ) => {}
+            (res, _) => panic!("Unexpected result with {action:?}: {res:?}"),
+        }
+    }
+    Ok(())
+}
+
+#[test]
+pub fn apply_amending() -> anyhow::Result<()> {
+    let tempdir = testdir::testdir!();
+    let source = common::init_repo(&tempdir.join("source"))?;
+    let base = common::commit_files(&source, &[("file.txt", Some("a\n"))], "Initial")?;
+    common::commit_files(&source, &[("file.txt", Some("b\n"))], "Fixup")?;
+    let patches = common::format_patches(&source, base, &tempdir.join("patches"))?;
+    let email = EmailMessage::parse(&patches[0])?;
+
+    let target = common::init_repo(&tempdir.join("target"))?;
+    let target_base = common::commit_files(&target, &[("other.txt", Some("other\n"))], "Base")?;
+    let head = common::commit_files(&target, &[("file.txt", Some("a\n"))], "Add file")?;
+    email.apply_amending(&target)?;
+    let amended = target.head()?.peel_to_commit()?;
+    assert_ne!(amended.id(), head);
+    assert_eq!(amended.parent_ids().collect::<Vec<_>>(), [target_base]);
+    assert_eq!(amended.message(), Some("Add file"));
+    assert_eq!(
+        common::read_head_file(&target, "file.txt")?.as_deref(),
+        Some("b\n")
+    );
+    Ok(())
+}
+
+#[test]
+pub fn counting_blob_writer() -> anyhow::Result<()> {
+    use std::cell::RefCell;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    let tempdir = testdir::testdir!();
+    let source = common::init_repo(&tempdir.join("source"))?;
+    let base = common::commit_files(&source, &[("file.txt", Some("a\n"))], "Initial")?;
+    common::commit_files(
+        &source,
+        &[("file.txt", Some("b\n")), ("new.txt", Some("new\n"))],
+        "Change",
+    )?;
+    let patches = common::format_patches(&source, base, &tempdir.join("patches"))?;
+    let email = EmailMessage::parse(&patches[0])?;
+
+    let target = common::init_repo(&tempdir.join("target"))?;
+    common::commit_files(&target, &[("file.txt", Some("a\n"))], "Initial")?;
+    let count = Arc::new(AtomicUsize::new(0));
+    let blob_repo = git2::Repository::open(target.path())?;
+    let opts = ApplyOptions {
+        blob_writer: Some(RefCell::new(Box::new({
+            let count = Arc::clone(&count);
+            move |data| {
+                count.fetch_add(1, Ordering::SeqCst);
+                blob_repo.blob(data)
+            }
+        }))),
+        ..Default::default()
+    };
+    email.apply_commit_with(&target, &opts)?;
+    assert_eq!(count.load(Ordering::SeqCst), 2);
+    assert_eq!(
+        common::read_head_file(&target, "new.txt")?.as_deref(),
+        Some("new\n")
+    );
+    Ok(())
+}
+
+#[test]
+pub fn round_trip_message_encoding() -> anyhow::Result<()> {
+    use gitpatcher::format_patches::{FormatOptions, PatchFormatter};
+    let tempdir = testdir::testdir!();
+    let source = common::init_repo(&tempdir.join("source"))?;
+    let base = common::commit_files(&source, &[("file.txt", Some("a\n"))], "Initial")?;
+    let placeholder = common::commit_files(&source, &[("file.txt", Some("b\n"))], "Placeholder")?;
+    // Rewrite the commit with an ISO-8859-1 message
+    let placeholder = source.find_commit(placeholder)?;
+    let sig = common::signature();
+    let buffer = source.commit_create_buffer(
+        &sig,
+        &sig,
+        "",
+        &placeholder.tree()?,
+        &[&source.find_commit(base)?],
+    )?;
+    let mut raw_commit = buffer.strip_suffix(b"\n").unwrap().to_vec();
+    raw_commit.extend_from_slice(b"encoding ISO-8859-1\n\nCaf\xe9 changes\n");
+    let latin1_commit = source.odb()?.write(git2::ObjectType::Commit, &raw_commit)?;
+    source
+        .head()?
+        .set_target(latin1_commit, "Rewrite as latin1")?;
+
+    // The message is written in its original encoding, like `git format-patch --encoding`
+    let out_dir = camino::Utf8PathBuf::try_from(tempdir.join("patches"))?;
+    let mut formatter = PatchFormatter::new(
+        common::logger(),
+        out_dir,
+        &source,
+        source.find_commit(base)?,
+        FormatOptions::default(),
+    )?;
+    formatter.generate_all()?;
+    let patch = std::fs::read(&formatter.generated_patches()[0].path)?;
+    let headers: &[u8] =
+        b"Subject: [PATCH] Caf\xe9 changes\nContent-Type: text/plain; charset=ISO-8859-1\n";
+    assert!(patch.windows(headers.len()).any(|window| window == headers));
+    let email = EmailMessage::parse_bytes(&patch)?;
+    assert_eq!(email.summary(), "Café changes");
+    assert_eq!(
+        EmailMessage::parse(&email.to_patch_string()?)?.to_patch_string()?,
+        email.to_patch_string()?
+    );
+
+    let target = common::init_repo(&tempdir.join("target"))?;
+    common::commit_files(&target, &[("file.txt", Some("a\n"))], "Initial")?;
+    email.apply_commit(&target)?;
+    let commit = target.head()?.peel_to_commit()?;
+    assert_eq!(commit.message_encoding(), Some("ISO-8859-1"));
+    assert_eq!(commit.message_raw_bytes(), b"Caf\xe9 changes");
+    assert_eq!(
+        common::read_head_file(&target, "file.txt")?.as_deref(),
+        Some("b\n")
+    );
+    Ok(())
+}
+
+#[test]
+pub fn round_trip_notes() -> anyhow::Result<()> {
+    use gitpatcher::format_patches::{FormatOptions, PatchFormatter};
+    let tempdir = testdir::testdir!();
+    let source = common::init_repo(&tempdir.join("source"))?;
+    let base = common::commit_files(&source, &[("file.txt", Some("a\n"))], "Initial")?;
+    let noted = common::commit_files(
+        &source,
+        &[("file.txt", Some("b\n"))],
+        "Change file\n\nWith a body",
+    )?;
+    common::commit_files(&source, &[("file.txt", Some("c\n"))], "Change again")?;
+    let sig = common::signature();
+    let note = "Reviewed-in: #42\n\nCI: passed\n";
+    source.note(&sig, &sig, None, noted, note, false)?;
+
+    let out_dir = camino::Utf8PathBuf::try_from(tempdir.join("patches"))?;
+    let mut opts = FormatOptions::default();
+    opts.include_notes = true;
+    let mut formatter = PatchFormatter::new(
+        common::logger(),
+        out_dir,
+        &source,
+        source.find_commit(base)?,
+        opts,
+    )?;
+    formatter.generate_all()?;
+    let patches = formatter
+        .generated_patches()
+        .iter()
+        .map(|patch| std::fs::read_to_string(&patch.path))
+        .collect::<Result<Vec<_>, _>>()?;
+    assert!(
+        patches[0].contains(
+            "With a body\n---\nNotes:\n    Reviewed-in: #42\n\n    CI: passed\n\ndiff --git"
+        ),
+        "{}",
+        patches[0]
+    );
+    // Commits without notes are unchanged
+    assert!(!patches[1].contains("Notes:"), "{}", patches[1]);
+    let emails = patches
+        .iter()
+        .map(|patch| EmailMessage::parse(patch))
+        .collect::<Result<Vec<_>, _>>()?;
+    assert_eq!(emails[0].notes(), Some(note));
+    assert_eq!(emails[0].full_message(), "Change file\n\nWith a body");
+    assert_eq!(
+        emails[0].to_patch_string()?,
+        EmailMessage::parse(&emails[0].to_patch_string()?)?.to_patch_string()?
+    );
+    assert_eq!(emails[1].notes(), None);
+
+    let target = common::init_repo(&tempdir.join("target"))?;
+    common::commit_files(&target, &[("file.txt", Some("a\n"))], "Initial")?;
+    let opts = ApplyOptions {
+        restore_notes: true,
+        ..Default::default()
+    };
+    emails[0].apply_commit_with(&target, &opts)?;
+    let commit = target.head()?.target().unwrap();
+    assert_eq!(target.find_note(None, commit)?.message(), Some(note));
+    emails[1].apply_commit_with(&target, &opts)?;
+    let commit = target.head()?.target().unwrap();
+    assert!(target.find_note(None, commit).is_err());
+    Ok(())
+}
+
+#[test]
+pub fn mark_cherry_pick() -> anyhow::Result<()> {
+    let tempdir = testdir::testdir!();
+    let source = common::init_repo(&tempdir.join("source"))?;
+    let base = common::commit_files(&source, &[("file.txt", Some("a\n"))], "Initial")?;
+    let plain = common::commit_files(&source, &[("file.txt", Some("b\n"))], "Plain")?;
+    let signed = common::commit_files(
+        &source,
+        &[("file.txt", Some("c\n"))],
+        "Signed\n\nSigned-off-by: dummy <dummy@dumb.gov>",
+    )?;
+    common::commit_files(&source, &[("file.txt", Some("d\n"))], "Not a commit")?;
+    let mut patches = common::format_patches(&source, base, &tempdir.join("patches"))?;
+    // Patches that don't come from a commit have an all-zero sha
+    let last = patches.pop().unwrap();
+    let rest = last.splitn(3, ' ').nth(2).unwrap();
+    patches.push(format!("From {} {rest}", git2::Oid::zero()));
+
+    let target = common::init_repo(&tempdir.join("target"))?;
+    common::commit_files(&target, &[("file.txt", Some("a\n"))], "Initial")?;
+    let opts = ApplyOptions {
+        mark_cherry_pick: true,
+        ..Default::default()
+    };
+    let mut messages = Vec::new();
+    for patch in &patches {
+        EmailMessage::parse(patch)?.apply_commit_with(&target, &opts)?;
+        let head = target.head()?.peel_to_commit()?;
+        messages.push(head.message().unwrap().to_string());
+    }
+    assert_eq!(
+        messages,
+        [
+            format!("Plain\n\n(cherry picked from commit {plain})"),
+            format!(
+                "Signed\n\nSigned-off-by: dummy <dummy@dumb.gov>\n(cherry picked from commit {signed})"
+            ),
+            "Not a commit".to_string(),
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+pub fn verify_source_commit() -> anyhow::Result<()> {
+    let tempdir = testdir::testdir!();
+    let source = common::init_repo(&tempdir.join("source"))?;
+    let base = common::commit_files(&source, &[("file.txt", Some("a\n"))], "Initial")?;
+    common::commit_files(&source, &[("file.txt", Some("b\n"))], "Change")?;
+    let tip = common::commit_files(
+        &source,
+        &[("other.txt", Some("c\n"))],
+        "Add other\n\nWith a body",
+    )?;
+    let patches = common::format_patches(&source, base, &tempdir.join("patches"))?;
+
+    let apply_all = |name: &str, committer: Option<git2::Signature<'static>>| {
+        let target = common::init_repo(&tempdir.join(name))?;
+        // The same base commit as the source
+        assert_eq!(
+            common::commit_files(&target, &[("file.txt", Some("a\n"))], "Initial")?,
+            base
+        );
+        let drain = common::CapturingDrain::default();
+        let opts = ApplyOptions {
+            verify_source_commit: true,
+            logger: Some(drain.logger()),
+            committer,
+            ..Default::default()
+        };
+        for patch in &patches {
+            EmailMessage::parse(patch)?.apply_commit_with(&target, &opts)?;
+        }
+        let head = target.head()?.target().unwrap();
+        anyhow::Ok((head, drain.messages(slog::Level::Warning)))
+    };
+    let (head, warnings) = apply_all("faithful", None)?;
+    assert_eq!(head, tip);
+    assert_eq!(warnings, Vec::<String>::new());
+
+    let other = git2::Signature::new("other", "other@dumb.gov", &git2::Time::new(0, 0))?;
+    let (head, warnings) = apply_all("diverged", Some(other))?;
+    assert_ne!(head, tip);
+    assert_eq!(warnings.len(), 2, "{warnings:?}");
+    Ok(())
+}
+
+#[test]
+pub fn apply_selected_hunks() -> anyhow::Result<()> {
+    let tempdir = testdir::testdir!();
+    let source = common::init_repo(&tempdir.join("source"))?;
+    let original = (1..=20).map(|i| format!("line {i}\n")).collect::<String>();
+    let base = common::commit_files(&source, &[("file.txt", Some(&original))], "Initial")?;
+    let changed = original
+        .replace("line 2\n", "line 2\nfirst\nextra\n")
+        .replace("line 19\n", "second\n");
+    common::commit_files(
+        &source,
+        &[("file.txt", Some(&changed)), ("other.txt", Some("other\n"))],
+        "Two hunks",
+    )?;
+    let patches = common::format_patches(&source, base, &tempdir.join("patches"))?;
+    let message = EmailMessage::parse(&patches[0])?;
+
+    let apply_with = |name: &str, selected_hunk: usize| {
+        let target = common::init_repo(&tempdir.join(name))?;
+        common::commit_files(&target, &[("file.txt", Some(&original))], "Initial")?;
+        message.apply_selected_hunks(&target, |desc, hunk| {
+            desc.new_path().is_some_and(|path| path == "file.txt") && hunk == selected_hunk
+        })?;
+        // Staged in the index, without committing
+        let head = target.head()?.peel_to_commit()?;
+        assert_eq!(head.summary(), Some("Initial"));
+        let mut index = target.index()?;
+        let staged = index.write_tree_to(&target)?;
+        assert_ne!(staged, head.tree_id());
+        let contents = std::fs::read_to_string(target.workdir().unwrap().join("file.txt"))?;
+        assert!(!target.workdir().unwrap().join("other.txt").exists());
+        anyhow::Ok(contents)
+    };
+    assert_eq!(
+        apply_with("first", 0)?,
+        original.replace("line 2\n", "line 2\nfirst\nextra\n")
+    );
+    // The second hunk is found even though the first one would have moved it
+    assert_eq!(
+        apply_with("second", 1)?,
+        original.replace("line 19\n", "second\n")
+    );
+    Ok(())
+}
+
+#[test]
+pub fn set_author_and_date() -> anyhow::Result<()> {
+    let tempdir = testdir::testdir!();
+    let source = common::init_repo(&tempdir.join("source"))?;
+    let base = common::commit_files(&source, &[("file.txt", Some("a\n"))], "Initial")?;
+    common::commit_files(&source, &[("file.txt", Some("b\n"))], "Change")?;
+    let patches = common::format_patches(&source, base, &tempdir.join("patches"))?;
+    let target = common::init_repo(&tempdir.join("target"))?;
+    common::commit_files(&target, &[("file.txt", Some("a\n"))], "Initial")?;
+    let mut email = EmailMessage::parse(&patches[0])?;
+    email.set_author("Jane Doe", "jane@example.com");
+    let date = time::macros::datetime!(2024-01-02 03:04:05 +01:00);
+    email.set_date(date);
+    assert_eq!(email.date(), date);
+    email.apply_commit(&target)?;
+    let head = target.head()?.peel_to_commit()?;
+    assert_eq!(head.author().name(), Some("Jane Doe"));
+    assert_eq!(head.author().email(), Some("jane@example.com"));
+    assert_eq!(head.author().when().seconds(), date.unix_timestamp());
+    assert_eq!(head.author().when().offset_minutes(), 60);
+    // The edits are serialized too
+    let serialized = email.to_patch_string()?;
+    assert!(
+        serialized
+            .contains("From: Jane Doe <jane@example.com>\nDate: Tue, 02 Jan 2024 03:04:05 +0100\n"),
+        "{serialized}"
+    );
+    Ok(())
+}
+
+#[test]
+pub fn reject_large_patches() -> anyhow::Result<()> {
+    use gitpatcher::apply_patches::bulk::{BulkApplyError, BulkPatchApply};
+    use gitpatcher::apply_patches::email::InvalidEmailMessage;
+    let tempdir = testdir::testdir!();
+    let source = common::init_repo(&tempdir.join("source"))?;
+    let base = common::commit_files(&source, &[("file.txt", Some("a\n"))], "Initial")?;
+    common::commit_files(
+        &source,
+        &[("file.txt", Some("b\n".repeat(100).as_str()))],
+        "Grow",
+    )?;
+    let patch_dir = tempdir.join("patches");
+    let patches = common::format_patches(&source, base, &patch_dir)?;
+    let size = patches[0].len() as u64;
+    assert!(EmailMessage::parse_bytes_limited(patches[0].as_bytes(), Some(size)).is_ok());
+    match EmailMessage::parse_bytes_limited(patches[0].as_bytes(), Some(64)) {
+        Err(InvalidEmailMessage::PatchTooLarge {
+            size: actual,
+            limit,
+        }) => {
+            assert_eq!((actual, limit), (size, 64));
+        }
+        Err(e) => panic!("Unexpected error: {e}"),
+        Ok(_) => panic!("Expected patch to be rejected"),
+    }
+
+    let target = common::init_repo(&tempdir.join("target"))?;
+    common::commit_files(&target, &[("file.txt", Some("a\n"))], "Initial")?;
+    let logger = common::logger();
+    let mut bulk = BulkPatchApply::new(&logger, &target, patch_dir);
+    bulk.set_max_patch_size(Some(64));
+    assert!(matches!(
+        bulk.apply_all(),
+        Err(BulkApplyError::FailedParsePatch {
+            cause: InvalidEmailMessage::PatchTooLarge { limit: 64, .. },
+            ..
+        })
+    ));
+    assert_eq!(
+        common::read_head_file(&target, "file.txt")?.as_deref(),
+        Some("a\n")
+    );
+    Ok(())
+}
+
+#[test]
+pub fn keep_subject_prefix() -> anyhow::Result<()> {
+    let tempdir = testdir::testdir!();
+    let source = common::init_repo(&tempdir.join("source"))?;
+    let base = common::commit_files(&source, &[("file.txt", Some("a\n"))], "Initial")?;
+    common::commit_files(&source, &[("file.txt", Some("b\n"))], "Change")?;
+    common::commit_files(&source, &[("file.txt", Some("c\n"))], "[PATCH] Prefixed")?;
+    common::commit_files(&source, &[("file.txt", Some("d\n"))], "[RFC] Foo")?;
+    let patches = common::format_patches(&source, base, &tempdir.join("patches"))?;
+    assert!(patches[2].contains("\nSubject: [PATCH] [RFC] Foo\n"));
+
+    // Like `git am --keep-non-patch` by default, and `git am --keep` with the option
+    for (keep_subject_prefix, expected) in [
+        (false, ["Change", "[PATCH] Prefixed", "[RFC] Foo"]),
+        (
+            true,
+            [
+                "[PATCH] Change",
+                "[PATCH] [PATCH] Prefixed",
+                "[PATCH] [RFC] Foo",
+            ],
+        ),
+    ] {
+        let target = common::init_repo(&tempdir.join(format!("target-{keep_subject_prefix}")))?;
+        common::commit_files(&target, &[("file.txt", Some("a\n"))], "Initial")?;
+        let opts = ApplyOptions {
+            keep_subject_prefix,
+            ..Default::default()
+        };
+        for (patch, expected) in patches.iter().zip(expected) {
+            let email = EmailMessage::parse(patch)?;
+            let subject = patch.lines().find(|line| line.starts_with("Subject: "));
+            assert!(email.to_patch_string()?.contains(subject.unwrap()));
+            email.apply_commit_with(&target, &opts)?;
+            let commit = target.head()?.peel_to_commit()?;
+            assert_eq!(commit.message(), Some(expected));
+        }
+    }
+    Ok(())
+}
+
+#[test]
+pub fn use_mailmap() -> anyhow::Result<()> {
+    let tempdir = testdir::testdir!();
+    let source = common::init_repo(&tempdir.join("source"))?;
+    let base = common::commit_files(&source, &[("file.txt", Some("a\n"))], "Initial")?;
+    common::commit_files(&source, &[("file.txt", Some("b\n"))], "Change")?;
+    let patches = common::format_patches(&source, base, &tempdir.join("patches"))?;
+    let email = EmailMessage::parse(&patches[0])?;
+
+    let target = common::init_repo(&tempdir.join("target"))?;
+    common::commit_files(
+        &target,
+        &[
+            ("file.txt", Some("a\n")),
+            (
+                ".mailmap",
+                Some("Canonical Name <canonical@example.com> <dummy@dumb.gov>\n"),
+            ),
+        ],
+        "Initial",
+    )?;
+    email.apply_commit(&target)?;
+    let commit = target.head()?.peel_to_commit()?;
+    assert_eq!(commit.author().email(), Some("dummy@dumb.gov"));
+
+    target.reset(
+        &commit.parent(0)?.into_object(),
+        git2::ResetType::Hard,
+        None,
+    )?;
+    let opts = ApplyOptions {
+        use_mailmap: true,
+        ..Default::default()
+    };
+    email.apply_commit_with(&target, &opts)?;
+    let commit = target.head()?.peel_to_commit()?;
+    assert_eq!(commit.author().name(), Some("Canonical Name"));
+    assert_eq!(commit.author().email(), Some("canonical@example.com"));
+    assert_eq!(commit.author().when(), common::signature().when());
+    assert_eq!(commit.committer().email(), Some("canonical@example.com"));
+    Ok(())
+}
+
+#[test]
+pub fn preserve_crlf_line_endings() -> anyhow::Result<()> {
+    use gitpatcher::format_patches::LineEnding;
+    let tempdir = testdir::testdir!();
+    let source = common::init_repo(&tempdir.join("source"))?;
+    let base = common::commit_files(
+        &source,
+        &[
+            ("crlf.txt", Some("a\nb\nc\n")),
+            ("lf.txt", Some("a\nb\nc\n")),
+        ],
+        "Initial",
+    )?;
+    common::commit_files(
+        &source,
+        &[
+            ("crlf.txt", Some("a\nB\nc\n")),
+            ("lf.txt", Some("a\nB\nc\n")),
+        ],
+        "Change b",
+    )?;
+    let patches = common::format_patches(&source, base, &tempdir.join("patches"))?;
+    let email = EmailMessage::parse(&patches[0])?;
+
+    for (line_endings, expected_crlf) in [
+        (None, "a\r\nB\r\nc\r\n"),
+        (Some(LineEnding::Lf), "a\nB\nc\n"),
+        (Some(LineEnding::CrLf), "a\r\nB\r\nc\r\n"),
+    ] {
+        let target = common::init_repo(&tempdir.join(format!("target-{line_endings:?}")))?;
+        common::commit_files(
+            &target,
+            &[
+                ("crlf.txt", Some("a\r\nb\r\nc\r\n")),
+                ("lf.txt", Some("a\nb\nc\n")),
+            ],
+            "Initial",
+        )?;
+        let opts = ApplyOptions {
+            line_endings,
+            ..Default::default()
+        };
+        email.apply_commit_with(&target, &opts)?;
+        assert_eq!(
+            common::read_head_file(&target, "crlf.txt")?.as_deref(),
+            Some(expected_crlf)
+        );
+        let expected_lf = match line_endings {
+            Some(LineEnding::CrLf) => "a\r\nB\r\nc\r\n",
+            _ => "a\nB\nc\n",
+        };
+        assert_eq!(
+            common::read_head_file(&target, "lf.txt")?.as_deref(),
+            Some(expected_lf)
+        );
+    }
+    Ok(())
+}
+
+#[test]
+pub fn round_trip_crlf_content() -> anyhow::Result<()> {
+    use gitpatcher::format_patches::{FormatOptions, LineEnding, PatchFormatter};
+    let tempdir = testdir::testdir!();
+    let source = common::init_repo(&tempdir.join("source"))?;
+    fn commit(
+        repo: &git2::Repository,
+        (crlf, mixed): (&str, &str),
+        message: &str,
+    ) -> anyhow::Result<git2::Oid> {
+        common::commit_files(
+            repo,
+            &[("crlf.txt", Some(crlf)), ("mixed.txt", Some(mixed))],
+            message,
+        )
+    }
+    let original = ("a\r\nb\r\nc\r\n", "a\nb\r\nc\n");
+    let changes = [
+        ("a\r\nB\r\nc\r\nd\r\n", "a\nB\r\nc\n"),
+        // Switching the line ending of a single line
+        ("a\r\nB\r\nc\nd\r\n", "a\r\nB\r\nc\n"),
+    ];
+    let base = commit(&source, original, "Initial")?;
+    for (idx, change) in changes.iter().enumerate() {
+        commit(&source, *change, &format!("Change {idx}"))?;
+    }
+
+    for patch_file_eol in [LineEnding::Lf, LineEnding::CrLf] {
+        let out_dir = camino::Utf8PathBuf::try_from(tempdir.join(format!("{patch_file_eol:?}")))?;
+        let mut opts = FormatOptions::default();
+        opts.patch_file_eol = patch_file_eol;
+        let mut formatter = PatchFormatter::new(
+            common::logger(),
+            out_dir,
+            &source,
+            source.find_commit(base)?,
+            opts,
+        )?;
+        formatter.generate_all()?;
+        let patches = formatter
+            .generated_patches()
+            .iter()
+            .map(|patch| std::fs::read(&patch.path))
+            .collect::<Result<Vec<_>, _>>()?;
+        if patch_file_eol == LineEnding::Lf {
+            // The structural lines use LF, but the hunk content keeps its CRLF
+            let patch = String::from_utf8(patches[0].clone())?;
+            assert!(
+                patch.contains("\n@@ -1,3 +1,4 @@\n a\r\n-b\r\n+B\r\n"),
+                "{patch:?}"
+            );
+        }
+
+        let target = common::init_repo(&tempdir.join(format!("target-{patch_file_eol:?}")))?;
+        commit(&target, original, "Initial")?;
+        for (patch, (crlf, mixed)) in patches.iter().zip(changes) {
+            let email = EmailMessage::parse(std::str::from_utf8(patch)?)?;
+            email.apply_commit(&target)?;
+            assert_eq!(
+                common::read_head_file(&target, "crlf.txt")?.as_deref(),
+                Some(crlf)
+            );
+            assert_eq!(
+                common::read_head_file(&target, "mixed.txt")?.as_deref(),
+                Some(mixed)
+            );
+        }
+    }
+    Ok(())
+}
+
+#[test]
+pub fn added_file_with_bogus_old_path() -> anyhow::Result<()> {
+    let tempdir = testdir::testdir!();
+    let source = common::init_repo(&tempdir.join("source"))?;
+    let base = common::commit_files(&source, &[("file.txt", Some("a\n"))], "Initial")?;
+    common::commit_files(&source, &[("new.txt", Some("new\n"))], "Add file")?;
+    let patches = common::format_patches(&source, base, &tempdir.join("patches"))?;
+    let patch = patches[0].replace(
+        "diff --git a/new.txt b/new.txt",
+        "diff --git a/bogus.txt b/new.txt",
+    );
+    assert_ne!(patch, patches[0]);
+    let email = EmailMessage::parse(&patch)?;
+
+    for leave_conflicts_in_worktree in [false, true] {
+        let target =
+            common::init_repo(&tempdir.join(format!("target-{leave_conflicts_in_worktree}")))?;
+        common::commit_files(
+            &target,
+            &[("file.txt", Some("a\n")), ("bogus.txt", Some("bogus\n"))],
+            "Initial",
+        )?;
+        let opts = ApplyOptions {
+            leave_conflicts_in_worktree,
+            ..Default::default()
+        };
+        email.apply_commit_with(&target, &opts)?;
+        assert_eq!(
+            common::read_head_file(&target, "new.txt")?.as_deref(),
+            Some("new\n")
+        );
+        assert_eq!(
+            common::read_head_file(&target, "bogus.txt")?.as_deref(),
+            Some("bogus\n")
+        );
+    }
+    Ok(())
+}
+
+#[test]
+pub fn trace_tree_changes() -> anyhow::Result<()> {
+    let tempdir = testdir::testdir!();
+    let source = common::init_repo(&tempdir.join("source"))?;
+    let base = common::commit_files(&source, &[("file.txt", Some("old\n"))], "Initial")?;
+    common::commit_files(&source, &[("file.txt", Some("new\n"))], "Change file")?;
+    let patches = common::format_patches(&source, base, &tempdir.join("patches"))?;
+    let email = EmailMessage::parse(&patches[0])?;
+
+    let target = common::init_repo(&tempdir.join("target"))?;
+    common::commit_files(&target, &[("file.txt", Some("old\n"))], "Initial")?;
+    let old_tree = target.head()?.peel_to_tree()?.id();
+    let drain = common::CapturingDrain::default();
+    let opts = ApplyOptions {
+        logger: Some(drain.logger()),
+        ..Default::default()
+    };
+    email.apply_commit_with(&target, &opts)?;
+    let new_tree = target.head()?.peel_to_tree()?.id();
+    assert_ne!(old_tree, new_tree);
+    let messages = drain.messages(slog::Level::Trace);
+    // Tree ids are abbreviated like commit ids
+    let short = |id: git2::Oid| id.to_string()[..gitpatcher::DEFAULT_ABBREV_LEN].to_string();
+    assert_eq!(
+        messages[0],
+        format!(
+            "Applied patch: tree {} -> {}",
+            short(old_tree),
+            short(new_tree)
+        )
+    );
+    assert!(
+        messages[1].starts_with("Changed tree entry: file.txt "),
+        "{messages:?}"
+    );
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+pub fn mode_only_change() -> anyhow::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let tempdir = testdir::testdir!();
+    let source = common::init_repo(&tempdir.join("source"))?;
+    let base = common::commit_files(&source, &[("run.sh", Some("echo hi\n"))], "Initial")?;
+    let script = source.workdir().unwrap().join("run.sh");
+    std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755))?;
+    // Writing the existing file keeps its permissions
+    common::commit_files(
+        &source,
+        &[("run.sh", Some("echo hi\n"))],
+        "Make script executable",
+    )?;
+    let patches = common::format_patches(&source, base, &tempdir.join("patches"))?;
+    assert!(
+        patches[0].contains("old mode 100644\nnew mode 100755\n"),
+        "{}",
+        patches[0]
+    );
+    let email = EmailMessage::parse(&patches[0])?;
+
+    let target = common::init_repo(&tempdir.join("target"))?;
+    let original = common::commit_files(&target, &[("run.sh", Some("echo hi\n"))], "Initial")?;
+    email.apply_commit(&target)?;
+    let head = target.head()?.peel_to_commit()?;
+    assert_ne!(head.id(), original);
+    let entry = head.tree()?.get_path(std::path::Path::new("run.sh"))?;
+    assert_eq!(entry.filemode(), 0o100755);
+    assert_eq!(
+        common::read_head_file(&target, "run.sh")?.as_deref(),
+        Some("echo hi\n")
+    );
+    Ok(())
+}
+
+#[test]
+pub fn rename_with_changes() -> anyhow::Result<()> {
+    const PATCH: &str = "\
+From 0000000000000000000000000000000000000000 Mon Sep 17 00:00:00 2001
+From: dummy <dummy@dumb.gov>
+Date: Fri, 25 Aug 2023 06:13:20 +0000
+Subject: [PATCH] Rename and rewrite
+
+---
+ old.txt => new.txt | 2 +-
+ other.txt          | 4 ++--
+ 2 files changed, 3 insertions(+), 3 deletions(-)
+
+diff --git a/old.txt b/new.txt
+similarity index 80%
+rename from old.txt
+rename to new.txt
+index 8a1218a..d5c5ba5 100644
+--- a/old.txt
++++ b/new.txt
+@@ -1,5 +1,5 @@
+ 1
+ 2
+-3
++three
+ 4
+ 5
+diff --git a/same.txt b/moved.txt
+similarity index 100%
+rename from same.txt
+rename to moved.txt
+diff --git a/other.txt b/other.txt
+dissimilarity index 100%
+index 0000001..0000002 100644
+--- a/other.txt
++++ b/other.txt
+@@ -1,2 +1,2 @@
+-foo
+-bar
++completely
++different
+-- 
+2.41.0
+";
+    let tempdir = testdir::testdir!();
+    let target = common::init_repo(&tempdir.join("target"))?;
+    common::commit_files(
+        &target,
+        &[
+            ("old.txt", Some("1\n2\n3\n4\n5\n")),
+            ("other.txt", Some("foo\nbar\n")),
+            ("same.txt", Some("unchanged\n")),
+        ],
+        "Initial",
+    )?;
+    EmailMessage::parse(PATCH)?.apply_commit(&target)?;
+    assert_eq!(common::read_head_file(&target, "same.txt")?, None);
+    assert_eq!(
+        common::read_head_file(&target, "moved.txt")?.as_deref(),
+        Some("unchanged\n")
+    );
+    assert_eq!(common::read_head_file(&target, "old.txt")?, None);
+    assert_eq!(
+        common::read_head_file(&target, "new.txt")?.as_deref(),
+        Some("1\n2\nthree\n4\n5\n")
+    );
+    assert_eq!(
+        common::read_head_file(&target, "other.txt")?.as_deref(),
+        Some("completely\ndifferent\n")
+    );
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+pub fn run_hooks() -> anyhow::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let write_hook = |path: &std::path::Path, script: &str| -> anyhow::Result<()> {
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        std::fs::write(path, format!("#!/bin/sh\n{script}\n"))?;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o755))?;
+        Ok(())
+    };
+    let tempdir = testdir::testdir!();
+    let source = common::init_repo(&tempdir.join("source"))?;
+    let base = common::commit_files(&source, &[("file.txt", Some("old\n"))], "Initial")?;
+    common::commit_files(
+        &source,
+        &[("file.txt", Some("new\n")), ("added.txt", Some("added\n"))],
+        "Change file",
+    )?;
+    let patches = common::format_patches(&source, base, &tempdir.join("patches"))?;
+    let email = EmailMessage::parse(&patches[0])?;
+
+    let target = common::init_repo(&tempdir.join("target"))?;
+    let original = common::commit_files(
+        &target,
+        &[("file.txt", Some("old\n")), ("other.txt", Some("other\n"))],
+        "Initial",
+    )?;
+    // The hook sees the patched worktree, and rejects it
+    write_hook(
+        &target.path().join("hooks/pre-applypatch"),
+        "grep -q new file.txt && exit 1",
+    )?;
+    let opts = ApplyOptions {
+        run_hooks: true,
+        ..Default::default()
+    };
+    let workdir = target.workdir().unwrap();
+    // Uncommitted changes to other files are kept
+    std::fs::write(workdir.join("other.txt"), "uncommitted\n")?;
+    assert!(matches!(
+        email.apply_commit_with(&target, &opts),
+        Err(PatchApplyError::HookFailed {
+            hook: "pre-applypatch",
+            ..
+        })
+    ));
+    assert_eq!(target.head()?.peel_to_commit()?.id(), original);
+    assert_eq!(std::fs::read_to_string(workdir.join("file.txt"))?, "old\n");
+    assert!(!workdir.join("added.txt").exists());
+    assert_eq!(
+        std::fs::read_to_string(workdir.join("other.txt"))?,
+        "uncommitted\n"
+    );
+    assert!(target.index()?.get_path("added.txt".as_ref(), 0).is_none());
+    // Uncommitted changes to the patched files are never overwritten
+    std::fs::write(workdir.join("file.txt"), "uncommitted\n")?;
+    match email.apply_commit_with(&target, &opts) {
+        Err(PatchApplyError::DirtyWorktree { paths }) => assert_eq!(paths, ["file.txt"]),
+        res => panic!("Unexpected result: {res:?}"),
+    }
+    assert_eq!(
+        std::fs::read_to_string(workdir.join("file.txt"))?,
+        "uncommitted\n"
+    );
+    std::fs::write(workdir.join("file.txt"), "old\n")?;
+    // Hooks are ignored unless requested
+    email.apply_commit(&target)?;
+    assert_ne!(target.head()?.peel_to_commit()?.id(), original);
+
+    // Use hooks from `core.hooksPath`, which can edit the message
+    target.reset(
+        target.find_commit(original)?.as_object(),
+        git2::ResetType::Hard,
+        None,
+    )?;
+    target.config()?.set_str("core.hooksPath", "custom-hooks")?;
+    write_hook(
+        &workdir.join("custom-hooks/applypatch-msg"),
+        "echo 'Checked-by: hook' >> \"$1\"",
+    )?;
+    email.apply_commit_with(&target, &opts)?;
+    let head = target.head()?.peel_to_commit()?;
+    assert_eq!(head.message(), Some("Change file\nChecked-by: hook"));
+    Ok(())
+}
+
+#[test]
+pub fn apply_to_tree() -> anyhow::Result<()> {
+    let tempdir = testdir::testdir!();
+    let repo = common::init_repo(&tempdir.join("repo"))?;
+    let base = common::commit_files(&repo, &[("hello.txt", Some("Hello\n"))], "Initial")?;
+    common::commit_files(&repo, &[("hello.txt", Some("Hello World\n"))], "Greet")?;
+    let patches = common::format_patches(&repo, base, &tempdir.join("patches"))?;
+    let email = EmailMessage::parse(&patches[0])?;
+
+    // Apply onto a tree that isn't checked out anywhere
+    let other = common::commit_files(
+        &repo,
+        &[
+            ("hello.txt", Some("Hello\n")),
+            ("other.txt", Some("Other\n")),
+        ],
+        "Unrelated",
+    )?;
+    let head_before = repo.head()?.target();
+    let index_before = repo.index()?.write_tree()?;
+    let tree_id = email.apply_to_tree(&repo, &repo.find_commit(other)?.tree()?)?;
+    let tree = repo.find_tree(tree_id)?;
+    let read = |name: &str| -> anyhow::Result<String> {
+        let blob = repo.find_blob(tree.get_path(std::path::Path::new(name))?.id())?;
+        Ok(String::from_utf8(blob.content().to_vec())?)
+    };
+    assert_eq!(read("hello.txt")?, "Hello World\n");
+    assert_eq!(read("other.txt")?, "Other\n");
+    assert_eq!(repo.head()?.target(), head_before);
+    assert_eq!(repo.index()?.write_tree()?, index_before);
+    assert_eq!(
+        std::fs::read_to_string(repo.workdir().unwrap().join("hello.txt"))?,
+        "Hello\n"
+    );
+    Ok(())
+}
+
+#[test]
+pub fn describe_deltas() -> anyhow::Result<()> {
+    use gitpatcher::apply_patches::email::DeltaStatus;
+    let tempdir = testdir::testdir!();
+    let repo = common::init_repo(&tempdir.join("repo"))?;
+    let base = common::commit_files(
+        &repo,
+        &[
+            ("keep.txt", Some("Keep\n")),
+            ("remove.txt", Some("Remove\n")),
+        ],
+        "Initial",
+    )?;
+    common::commit_files(
+        &repo,
+        &[
+            ("add.txt", Some("Add\n")),
+            ("keep.txt", Some("Keep changed\n")),
+            ("remove.txt", None),
+        ],
+        "Mixed changes",
+    )?;
+    let patches = common::format_patches(&repo, base, &tempdir.join("patches"))?;
+    let email = EmailMessage::parse(&patches[0])?;
+    let deltas = email
+        .deltas()?
+        .iter()
+        .map(|delta| {
+            (
+                delta.status(),
+                delta.old_path().map(|path| path.to_string()),
+                delta.new_path().map(|path| path.to_string()),
+                delta.is_binary(),
+            )
+        })
+        .collect::<Vec<_>>();
+    let path = |name: &str| Some(name.to_string());
+    assert_eq!(
+        deltas,
+        [
+            (DeltaStatus::Added, None, path("add.txt"), false),
+            (
+                DeltaStatus::Modified,
+                path("keep.txt"),
+                path("keep.txt"),
+                false
+            ),
+            (DeltaStatus::Deleted, path("remove.txt"), None, false),
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+pub fn three_way_with_fake_ancestor() -> anyhow::Result<()> {
+    const LINES: &str = "1\n2\n3\n4\n5\n6\n7\n8\n9\n";
+    let tempdir = testdir::testdir!();
+    let repo = common::init_repo(&tempdir.join("repo"))?;
+    let base = common::commit_files(&repo, &[("lines.txt", Some(LINES))], "Initial")?;
+    common::commit_files(
+        &repo,
+        &[("lines.txt", Some(&LINES.replace("5\n", "five\n")))],
+        "Spell out five",
+    )?;
+    let patches = common::format_patches(&repo, base, &tempdir.join("patches"))?;
+    let email = EmailMessage::parse(&patches[0])?;
+
+    // Upstream changes the context of the hunk
+    repo.reset(&repo.find_object(base, None)?, git2::ResetType::Hard, None)?;
+    common::commit_files(
+        &repo,
+        &[("lines.txt", Some(&LINES.replace("2\n", "two\n")))],
+        "Spell out two",
+    )?;
+    assert!(matches!(
+        email.apply_commit(&repo),
+        Err(PatchApplyError::FailDelta { .. })
+    ));
+
+    let ancestor = email.build_fake_ancestor(&repo)?;
+    let blob = ancestor.get_path(std::path::Path::new("lines.txt"))?.id();
+    assert_eq!(repo.find_blob(blob)?.content(), LINES.as_bytes());
+    let theirs = repo.find_tree(email.apply_to_tree(&repo, &ancestor)?)?;
+    let ours = repo.head()?.peel_to_tree()?;
+    let mut merged = repo.merge_trees(&ancestor, &ours, &theirs, None)?;
+    assert!(!merged.has_conflicts());
+    let merged = repo.find_tree(merged.write_tree_to(&repo)?)?;
+    let blob = merged.get_path(std::path::Path::new("lines.txt"))?.id();
+    assert_eq!(
+        std::str::from_utf8(repo.find_blob(blob)?.content())?,
+        "1\ntwo\n3\n4\nfive\n6\n7\n8\n9\n"
+    );
+
+    // The same merge is done when applying with the 3-way fallback
+    let opts = ApplyOptions {
+        three_way: true,
+        ..Default::default()
+    };
+    email.apply_commit_with(&repo, &opts)?;
+    assert_eq!(
+        common::read_head_file(&repo, "lines.txt")?.as_deref(),
+        Some("1\ntwo\n3\n4\nfive\n6\n7\n8\n9\n")
+    );
+    Ok(())
+}
+
+#[test]
+pub fn three_way_missing_original() -> anyhow::Result<()> {
+    let tempdir = testdir::testdir!();
+    let repo = common::init_repo(&tempdir.join("repo"))?;
+    let base = common::commit_files(
+        &repo,
+        &[
+            ("kept.txt", Some("kept\n")),
+            ("gone.txt", Some("1\n2\n3\n")),
+        ],
+        "Initial",
+    )?;
+    common::commit_files(&repo, &[("gone.txt", Some("1\ntwo\n3\n"))], "Spell out two")?;
+    let patches = common::format_patches(&repo, base, &tempdir.join("patches"))?;
+    let email = EmailMessage::parse(&patches[0])?;
+
+    // Upstream removed the file, but its preimage is still in the repository
+    repo.reset(&repo.find_object(base, None)?, git2::ResetType::Hard, None)?;
+    common::commit_files(&repo, &[("gone.txt", None)], "Remove file")?;
+    match email.apply_commit(&repo) {
+        Err(PatchApplyError::FailDelta { cause, .. }) => assert!(
+            matches!(cause, DeltaApplyError::MissingOriginalFile { ref path } if path.as_os_str() == "gone.txt"),
+            "{cause:?}"
+        ),
+        res => panic!("Unexpected result: {res:?}"),
+    }
+
+    // A modify/delete conflict, instead of silently restoring the file
+    let head = repo.head()?.target();
+    let opts = ApplyOptions {
+        three_way: true,
+        ..Default::default()
+    };
+    match email.apply_commit_with(&repo, &opts) {
+        Err(PatchApplyError::MergeConflict { paths }) => assert_eq!(paths, ["gone.txt"]),
+        res => panic!("Unexpected result: {res:?}"),
+    }
+    assert_eq!(repo.head()?.target(), head);
+    assert_eq!(common::read_head_file(&repo, "gone.txt")?, None);
+    Ok(())
+}
+
+#[test]
+pub fn validate_against() -> anyhow::Result<()> {
+    let tempdir = testdir::testdir!();
+    let source = common::init_repo(&tempdir.join("source"))?;
+    let base = common::commit_files(
+        &source,
+        &[("a.txt", Some("a\n")), ("b.txt", Some("b\n"))],
+        "Initial",
+    )?;
+    common::commit_files(
+        &source,
+        &[("a.txt", Some("a1\n")), ("b.txt", Some("b1\n"))],
+        "Change both",
+    )?;
+    let patches = common::format_patches(&source, base, &tempdir.join("patches"))?;
+    let email = EmailMessage::parse(&patches[0])?;
+
+    let clean = common::init_repo(&tempdir.join("clean"))?;
+    common::commit_files(
+        &clean,
+        &[("a.txt", Some("a\n")), ("b.txt", Some("b\n"))],
+        "Initial",
+    )?;
+    let report = email.validate_against(&clean)?;
+    assert!(report.is_clean());
+    assert_eq!(report.applied.len(), 2);
+
+    let conflicting = common::init_repo(&tempdir.join("conflicting"))?;
+    let head = common::commit_files(
+        &conflicting,
+        &[("a.txt", Some("a\n")), ("b.txt", Some("conflict\n"))],
+        "Initial",
+    )?;
+    let odb_objects = |repo: &git2::Repository| -> anyhow::Result<usize> {
+        let mut count = 0;
+        repo.odb()?.foreach(|_| {
+            count += 1;
+            true
+        })?;
+        Ok(count)
+    };
+    let objects_before = odb_objects(&conflicting)?;
+    let report = email.validate_against(&conflicting)?;
+    assert!(!report.is_clean());
+    assert_eq!(report.applied.len(), 1);
+    assert_eq!(report.applied[0].delta.new_path().unwrap(), "a.txt");
+    assert_eq!(report.failed.len(), 1);
+    assert_eq!(report.failed[0].delta.new_path().unwrap(), "b.txt");
+    // Nothing was written
+    assert_eq!(odb_objects(&conflicting)?, objects_before);
+    assert_eq!(conflicting.head()?.target(), Some(head));
+    assert_eq!(
+        common::read_head_file(&conflicting, "b.txt")?.as_deref(),
+        Some("conflict\n")
+    );
+    Ok(())
+}
+
+#[test]
+pub fn abbreviated_index_line() -> anyhow::Result<()> {
+    use std::collections::HashMap;
+    let tempdir = testdir::testdir!();
+    // Find two different contents whose blob ids share the first four digits
+    let mut by_prefix = HashMap::new();
+    let (first, second) = (0..)
+        .find_map(|i| {
+            let content = format!("{i}\n");
+            let id = git2::Oid::hash_object(git2::ObjectType::Blob, content.as_bytes()).unwrap();
+            let prefix = id.to_string()[..4].to_string();
+            by_prefix
+                .insert(prefix, content.clone())
+                .map(|other| (other, content))
+        })
+        .unwrap();
+    let repo = common::init_repo(&tempdir.join("repo"))?;
+    let base = common::commit_files(&repo, &[("file.txt", Some(&first))], "Initial")?;
+    common::commit_files(&repo, &[("file.txt", Some("changed\n"))], "Change")?;
+    let patches = common::format_patches(&repo, base, &tempdir.join("patches"))?;
+    let abbreviate = |len: usize| {
+        patches[0]
+            .lines()
+            .map(|line| match line.strip_prefix("index ") {
+                Some(ids) => {
+                    let (ids, mode) = ids.split_once(' ').unwrap();
+                    let (old, new) = ids.split_once("..").unwrap();
+                    format!("index {}..{} {mode}\n", &old[..len], &new[..len])
+                }
+                None => format!("{line}\n"),
+            })
+            .collect::<String>()
+    };
+
+    let email = EmailMessage::parse(&abbreviate(7))?;
+    let ancestor = email.build_fake_ancestor(&repo)?;
+    let blob = ancestor.get_path(std::path::Path::new("file.txt"))?.id();
+    assert_eq!(repo.find_blob(blob)?.content(), first.as_bytes());
+
+    // With both blobs in the repo, four digits can't tell them apart
+    repo.blob(second.as_bytes())?;
+    let email = EmailMessage::parse(&abbreviate(4))?;
+    match email.build_fake_ancestor(&repo) {
+        Err(PatchApplyError::MissingPreimage { cause, .. }) => {
+            assert_eq!(cause.code(), git2::ErrorCode::Ambiguous);
+        }
+        res => panic!(
+            "Expected ambiguous preimage, got {:?}",
+            res.map(|tree| tree.id())
+        ),
+    }
+    Ok(())
+}
+
+#[test]
+pub fn libgit2_engine_matches_diffy() -> anyhow::Result<()> {
+    use gitpatcher::apply_patches::email::DeltaApplyError;
+    use gitpatcher::apply_patches::{ApplyEngine, InvalidApplyOptions};
+    let tempdir = testdir::testdir!();
+    let initial: &[(&str, Option<&str>)] = &[
+        ("keep.txt", Some("1\n2\n3\n4\n5\n6\n7\n8\n9\n")),
+        ("remove.txt", Some("Remove\n")),
+        ("rename.txt", Some("a\nb\nc\nd\ne\nf\n")),
+    ];
+    let source = common::init_repo(&tempdir.join("source"))?;
+    let base = common::commit_files(&source, initial, "Initial")?;
+    common::commit_files(
+        &source,
+        &[
+            ("keep.txt", Some("1\ntwo\n3\n4\n5\n6\n7\neight\n9\n")),
+            ("remove.txt", None),
+            ("rename.txt", None),
+            ("renamed.txt", Some("a\nb\nc\nd\ne\nf\ng\n")),
+            ("added.txt", Some("Added\n")),
+        ],
+        "Change everything",
+    )?;
+    let patches = common::format_patches(&source, base, &tempdir.join("patches"))?;
+    let email = EmailMessage::parse(&patches[0])?;
+
+    let mut trees = Vec::new();
+    for engine in [ApplyEngine::Diffy, ApplyEngine::Libgit2] {
+        let target = common::init_repo(&tempdir.join(format!("{engine:?}")))?;
+        common::commit_files(&target, initial, "Initial")?;
+        let opts = ApplyOptions {
+            engine,
+            ..Default::default()
+        };
+        email.apply_commit_with(&target, &opts)?;
+        trees.push(target.head()?.peel_to_tree()?.id());
+    }
+    assert_eq!(trees[0], trees[1]);
+
+    // Conflicts are reported for the delta that failed
+    let target = common::init_repo(&tempdir.join("conflict"))?;
+    let mut conflicting = initial.to_vec();
+    conflicting[0].1 = Some("1\n2\n3\n4\n5\n6\n7\nate\n9\n");
+    let start = common::commit_files(&target, &conflicting, "Initial")?;
+    let opts = ApplyOptions {
+        engine: ApplyEngine::Libgit2,
+        ..Default::default()
+    };
+    match email.apply_commit_with(&target, &opts) {
+        Err(PatchApplyError::FailDelta { delta, cause }) => {
+            assert_eq!(delta.new_path().unwrap(), "keep.txt");
+            assert!(matches!(cause, DeltaApplyError::Libgit2Apply { .. }));
+        }
+        res => panic!("Expected conflict, got {res:?}"),
+    }
+    assert_eq!(target.head()?.target(), Some(start));
+
+    // Options the engine can't honor are rejected instead of ignored
+    for opts in [
+        ApplyOptions {
+            check_ignored: IgnoredPathCheck::Error,
+            ..Default::default()
+        },
+        ApplyOptions {
+            leave_conflicts_in_worktree: true,
+            ..Default::default()
+        },
+    ] {
+        let opts = ApplyOptions {
+            engine: ApplyEngine::Libgit2,
+            ..opts
+        };
+        assert!(matches!(
+            email.apply_commit_with(&target, &opts),
+            Err(PatchApplyError::InvalidOptions(
+                InvalidApplyOptions::UnsupportedByEngine { .. }
+            ))
+        ));
+    }
+    Ok(())
+}
+
+#[test]
+pub fn offset_resolver() -> anyhow::Result<()> {
+    use gitpatcher::apply_patches::Candidate;
+    use std::cell::RefCell;
+    use std::sync::{Arc, Mutex};
+    let tempdir = testdir::testdir!();
+    let block = "x\ny\nz\nw\n";
+    let source = common::init_repo(&tempdir.join("source"))?;
+    let base = common::commit_files(&source, &[("file.txt", Some(block))], "Initial")?;
+    common::commit_files(&source, &[("file.txt", Some("x\nY\nz\nw\n"))], "Change y")?;
+    let patches = common::format_patches(&source, base, &tempdir.join("patches"))?;
+    let email = EmailMessage::parse(&patches[0])?;
+    // The hunk doesn't match at its original line, and matches both blocks
+    let ambiguous = format!("pre\n{block}mid\n{block}");
+
+    let target = common::init_repo(&tempdir.join("closest"))?;
+    common::commit_files(&target, &[("file.txt", Some(&ambiguous))], "Initial")?;
+    email.apply_commit(&target)?;
+    assert_eq!(
+        common::read_head_file(&target, "file.txt")?.as_deref(),
+        Some(&*format!("pre\nx\nY\nz\nw\nmid\n{block}"))
+    );
+
+    let target = common::init_repo(&tempdir.join("resolved"))?;
+    common::commit_files(&target, &[("file.txt", Some(&ambiguous))], "Initial")?;
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let opts = ApplyOptions {
+        offset_resolver: Some(RefCell::new(Box::new({
+            let seen = Arc::clone(&seen);
+            move |delta, candidates| {
+                assert_eq!(delta.new_path().unwrap(), "file.txt");
+                seen.lock().unwrap().extend_from_slice(candidates);
+                candidates.len() - 1
+            }
+        }))),
+        ..Default::default()
+    };
+    email.apply_commit_with(&target, &opts)?;
+    assert_eq!(
+        *seen.lock().unwrap(),
+        [
+            Candidate {
+                hunk: 0,
+                line: 2,
+                offset: 1
+            },
+            Candidate {
+                hunk: 0,
+                line: 7,
+                offset: 6
+            },
+        ]
+    );
+    assert_eq!(
+        common::read_head_file(&target, "file.txt")?.as_deref(),
+        Some(&*format!("pre\n{block}mid\nx\nY\nz\nw\n"))
+    );
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "gzip")]
+pub fn add_binary_file() -> anyhow::Result<()> {
+    // Generated by `git format-patch --binary`
+    let patch = "\
+From bbf9f69a8196b1d9e8dfee908b7d585479510aad Mon Sep 17 00:00:00 2001
+From: a <a@b>
+Date: Thu, 15 Oct 2026 08:11:23 +0000
+Subject: [PATCH] Add binary
+
+---
+ new.bin | Bin 0 -> 11 bytes
+ 1 file changed, 0 insertions(+), 0 deletions(-)
+ create mode 100644 new.bin
+
+diff --git a/new.bin b/new.bin
+new file mode 100644
+index 0000000000000000000000000000000000000000..32d93ae6b54dfbd53ad0081c23b0b56cbdc391c7
+GIT binary patch
+literal 11
+ScmZQzWJ=1+ODwAV&jkPv$^()B
+
+literal 0
+HcmV?d00001
+
+-- 
+2.39.5
+";
+    let tempdir = testdir::testdir!();
+    let target = common::init_repo(&tempdir.join("target"))?;
+    common::commit_files(&target, &[("a.txt", Some("base\n"))], "Initial")?;
+    EmailMessage::parse(patch)?.apply_commit(&target)?;
+    let tree = target.head()?.peel_to_tree()?;
+    let entry = tree.get_path("new.bin".as_ref())?;
+    assert_eq!(
+        entry.id().to_string(),
+        "32d93ae6b54dfbd53ad0081c23b0b56cbdc391c7"
+    );
+    assert_eq!(
+        target.find_blob(entry.id())?.content(),
+        b"\x00\x01\x02binary\xff\n"
+    );
+    assert_eq!(
+        common::read_head_file(&target, "a.txt")?.as_deref(),
+        Some("base\n")
+    );
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "gzip")]
+pub fn binary_literal_with_wrong_length() -> anyhow::Result<()> {
+    // The literal claims to be a terabyte, but only contains 11 bytes
+    let patch = "\
+From bbf9f69a8196b1d9e8dfee908b7d585479510aad Mon Sep 17 00:00:00 2001
+From: a <a@b>
+Date: Thu, 15 Oct 2026 08:11:23 +0000
+Subject: [PATCH] Add binary
+
+---
+ new.bin | Bin 0 -> 11 bytes
+ 1 file changed, 0 insertions(+), 0 deletions(-)
+ create mode 100644 new.bin
+
+diff --git a/new.bin b/new.bin
+new file mode 100644
+index 0000000000000000000000000000000000000000..32d93ae6b54dfbd53ad0081c23b0b56cbdc391c7
+GIT binary patch
+literal 1099511627776
+ScmZQzWJ=1+ODwAV&jkPv$^()B
+
+literal 0
+HcmV?d00001
+
+-- 
+2.39.5
+";
+    let tempdir = testdir::testdir!();
+    let target = common::init_repo(&tempdir.join("target"))?;
+    common::commit_files(&target, &[("a.txt", Some("base\n"))], "Initial")?;
+    for claimed in ["1099511627776", "10"] {
+        let patch = patch.replace("literal 1099511627776", &format!("literal {claimed}"));
+        match EmailMessage::parse(&patch)?.apply_commit(&target) {
+            Err(PatchApplyError::FailDelta { cause, .. }) => assert!(
+                matches!(cause, DeltaApplyError::CorruptBinaryPatch { .. }),
+                "{cause:?}"
+            ),
+            res => panic!("Unexpected result: {res:?}"),
+        }
+    }
+    Ok(())
+}
+
+#[test]
+pub fn report_hunk_offsets() -> anyhow::Result<()> {
+    use gitpatcher::apply_patches::Candidate;
+    let tempdir = testdir::testdir!();
+    let source = common::init_repo(&tempdir.join("source"))?;
+    let base = common::commit_files(
+        &source,
+        &[("file.txt", Some("1\n2\n3\n4\n5\n6\n7\n8\n9\n"))],
+        "Initial",
+    )?;
+    common::commit_files(
+        &source,
+        &[("file.txt", Some("1\n2\n3\n4\nfive\n6\n7\n8\n9\n"))],
+        "Change five",
+    )?;
+    let patches = common::format_patches(&source, base, &tempdir.join("patches"))?;
+    let email = EmailMessage::parse(&patches[0])?;
+
+    let target = common::init_repo(&tempdir.join("target"))?;
+    common::commit_files(
+        &target,
+        &[("file.txt", Some("a\nb\nc\n1\n2\n3\n4\n5\n6\n7\n8\n9\n"))],
+        "Initial",
+    )?;
+    let report = email.validate_against(&target)?;
+    assert!(report.is_clean());
+    assert!(report.applied[0].has_offset());
+    assert_eq!(
+        report.applied[0].hunks,
+        [Candidate {
+            hunk: 0,
+            line: 5,
+            offset: 3
+        }]
+    );
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "gzip")]
+pub fn modify_diverged_binary_file() -> anyhow::Result<()> {
+    // Generated by `git format-patch --binary`
+    let patch = "\
+From 61302cb8ec526acfd2b956d193b528d08cc65676 Mon Sep 17 00:00:00 2001
+From: a <a@b>
+Date: Thu, 15 Oct 2026 09:06:02 +0000
+Subject: [PATCH] Change binary
+
+---
+ new.bin | Bin 11 -> 12 bytes
+ 1 file changed, 0 insertions(+), 0 deletions(-)
+
+diff --git a/new.bin b/new.bin
+index 32d93ae6b54dfbd53ad0081c23b0b56cbdc391c7..28e59db96920b8646997dc2a3683bb64e7d5a634 100644
+GIT binary patch
+literal 12
+TcmZQzWJ=CR%u7#A`OgIa66^!l
+
+literal 11
+ScmZQzWJ=1+ODwAV&jkPv$^()B
+
+-- 
+2.39.5
+";
+    let email = EmailMessage::parse(patch)?;
+    let tempdir = testdir::testdir!();
+    let write_binary = |repo: &git2::Repository, contents: &[u8]| -> anyhow::Result<()> {
+        std::fs::write(repo.workdir().unwrap().join("new.bin"), contents)?;
+        let mut index = repo.index()?;
+        index.add_path(std::path::Path::new("new.bin"))?;
+        index.write()?;
+        common::commit_files(repo, &[], "Binary")?;
+        Ok(())
+    };
+
+    let diverged = common::init_repo(&tempdir.join("diverged"))?;
+    write_binary(&diverged, b"\x00\x01\x02other\xff\n")?;
+    match email.apply_commit(&diverged) {
+        Err(PatchApplyError::FailDelta { cause, .. }) => assert!(
+            matches!(cause, DeltaApplyError::BinaryPreimageMismatch { expected, .. }
+                if expected.to_string() == "32d93ae6b54dfbd53ad0081c23b0b56cbdc391c7"),
+            "{cause:?}"
+        ),
+        res => panic!("Unexpected result: {res:?}"),
+    }
+
+    let original = common::init_repo(&tempdir.join("original"))?;
+    write_binary(&original, b"\x00\x01\x02binary\xff\n")?;
+    email.apply_commit(&original)?;
+    let tree = original.head()?.peel_to_tree()?;
+    let entry = tree.get_path("new.bin".as_ref())?;
+    assert_eq!(
+        original.find_blob(entry.id())?.content(),
+        b"\x00\x01\x02changed\xff\n"
+    );
+    Ok(())
+}
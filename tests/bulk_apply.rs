@@ -0,0 +1,551 @@
+//! Tests for applying an entire directory of patches.
+use gitpatcher::apply_patches::bulk::{BulkApplyError, BulkPatchApply, ExcludePatterns};
+use gitpatcher::apply_patches::{CleanupMode, WhitespaceAction};
+
+mod common;
+
+#[test]
+pub fn fetch_missing_upstream() -> anyhow::Result<()> {
+    let tempdir = testdir::testdir!();
+    let upstream = common::init_repo(&tempdir.join("upstream"))?;
+    common::commit_files(&upstream, &[("file.txt", Some("upstream\n"))], "Upstream")?;
+    let branch = upstream.head()?.shorthand().unwrap().to_string();
+
+    let target = common::init_repo(&tempdir.join("target"))?;
+    common::commit_files(&target, &[("file.txt", Some("local\n"))], "Local")?;
+    target.remote("origin", upstream.path().to_str().unwrap())?;
+    let patch_dir = tempdir.join("patches");
+    std::fs::create_dir(&patch_dir)?;
+    let upstream_name = format!("origin/{branch}");
+
+    let logger = common::logger();
+    let mut bulk = BulkPatchApply::new(&logger, &target, patch_dir);
+    assert!(bulk.reset_upstream(&upstream_name).is_err());
+    bulk.set_fetch_upstream(true);
+    bulk.reset_upstream(&upstream_name)?;
+    assert_eq!(
+        common::read_head_file(&target, "file.txt")?.as_deref(),
+        Some("upstream\n")
+    );
+    Ok(())
+}
+
+#[test]
+pub fn transactional_rollback() -> anyhow::Result<()> {
+    let tempdir = testdir::testdir!();
+    let source = common::init_repo(&tempdir.join("source"))?;
+    let base = common::commit_files(
+        &source,
+        &[("a.txt", Some("a\n")), ("b.txt", Some("b\n"))],
+        "Initial",
+    )?;
+    common::commit_files(&source, &[("a.txt", Some("a1\n"))], "Change a")?;
+    common::commit_files(&source, &[("b.txt", Some("b1\n"))], "Change b")?;
+    common::commit_files(&source, &[("a.txt", Some("a2\n"))], "Change a again")?;
+    let patch_dir = tempdir.join("patches");
+    assert_eq!(common::format_patches(&source, base, &patch_dir)?.len(), 3);
+
+    let target = common::init_repo(&tempdir.join("target"))?;
+    let start = common::commit_files(
+        &target,
+        &[("a.txt", Some("a\n")), ("b.txt", Some("conflict\n"))],
+        "Initial",
+    )?;
+    let logger = common::logger();
+    let mut bulk = BulkPatchApply::new(&logger, &target, patch_dir);
+    bulk.set_transactional(true);
+    assert!(bulk.apply_all().is_err());
+    assert_eq!(target.head()?.peel_to_commit()?.id(), start);
+    let workdir = target.workdir().unwrap();
+    assert_eq!(std::fs::read_to_string(workdir.join("a.txt"))?, "a\n");
+    assert_eq!(
+        std::fs::read_to_string(workdir.join("b.txt"))?,
+        "conflict\n"
+    );
+    Ok(())
+}
+
+#[test]
+pub fn exclude_patches() -> anyhow::Result<()> {
+    let tempdir = testdir::testdir!();
+    let source = common::init_repo(&tempdir.join("source"))?;
+    let base = common::commit_files(
+        &source,
+        &[("a.txt", Some("a\n")), ("b.txt", Some("b\n"))],
+        "Initial",
+    )?;
+    common::commit_files(&source, &[("a.txt", Some("a1\n"))], "Change a")?;
+    common::commit_files(&source, &[("b.txt", Some("b1\n"))], "Change b")?;
+    let patch_dir = tempdir.join("patches");
+    assert_eq!(common::format_patches(&source, base, &patch_dir)?.len(), 2);
+
+    let target = common::init_repo(&tempdir.join("target"))?;
+    common::commit_files(
+        &target,
+        &[("a.txt", Some("a\n")), ("b.txt", Some("b\n"))],
+        "Initial",
+    )?;
+    let drain = common::CapturingDrain::default();
+    let logger = drain.logger();
+    let mut bulk = BulkPatchApply::new(&logger, &target, patch_dir);
+    bulk.set_exclude(ExcludePatterns::new(["0001-*"])?);
+    bulk.apply_all()?;
+    assert_eq!(
+        common::read_head_file(&target, "a.txt")?.as_deref(),
+        Some("a\n")
+    );
+    assert_eq!(
+        common::read_head_file(&target, "b.txt")?.as_deref(),
+        Some("b1\n")
+    );
+    assert_eq!(
+        drain.messages(slog::Level::Warning),
+        ["Skipping excluded patch 0001-Change-a.patch, later patches may fail to apply without it"]
+    );
+    Ok(())
+}
+
+#[test]
+pub fn author_map() -> anyhow::Result<()> {
+    use gitpatcher::apply_patches::AuthorMap;
+    let tempdir = testdir::testdir!();
+    let source = common::init_repo(&tempdir.join("source"))?;
+    let base = common::commit_files(&source, &[("a.txt", Some("a\n"))], "Initial")?;
+    common::commit_files(&source, &[("a.txt", Some("a1\n"))], "Upstream change")?;
+    let upstream_author = git2::Signature::new(
+        "Upstream Dev",
+        "dev@upstream.org",
+        &git2::Time::new(1_692_944_000, 0),
+    )?;
+    source.head()?.peel_to_commit()?.amend(
+        Some("HEAD"),
+        Some(&upstream_author),
+        Some(&upstream_author),
+        None,
+        None,
+        None,
+    )?;
+    common::commit_files(&source, &[("a.txt", Some("a2\n"))], "Local change")?;
+    let patch_dir = tempdir.join("patches");
+    assert_eq!(common::format_patches(&source, base, &patch_dir)?.len(), 2);
+    let map_file = tempdir.join("authors.csv");
+    std::fs::write(
+        &map_file,
+        "# old name,old email,new name,new email\nUpstream Dev,dev@upstream.org,Jane Doe,jane@example.com\n",
+    )?;
+
+    let target = common::init_repo(&tempdir.join("target"))?;
+    common::commit_files(&target, &[("a.txt", Some("a\n"))], "Initial")?;
+    let logger = common::logger();
+    let mut bulk = BulkPatchApply::new(&logger, &target, patch_dir);
+    bulk.set_author_map(AuthorMap::load(&map_file)?);
+    bulk.apply_all()?;
+    let head = target.head()?.peel_to_commit()?;
+    assert_eq!(head.summary(), Some("Local change"));
+    assert_eq!(head.author().name(), Some("dummy"));
+    let mapped = head.parent(0)?;
+    assert_eq!(mapped.summary(), Some("Upstream change"));
+    assert_eq!(mapped.author().name(), Some("Jane Doe"));
+    assert_eq!(mapped.author().email(), Some("jane@example.com"));
+    Ok(())
+}
+
+#[test]
+pub fn squash() -> anyhow::Result<()> {
+    let tempdir = testdir::testdir!();
+    let source = common::init_repo(&tempdir.join("source"))?;
+    let base = common::commit_files(
+        &source,
+        &[("a.txt", Some("a\n")), ("b.txt", Some("b\n"))],
+        "Initial",
+    )?;
+    common::commit_files(&source, &[("a.txt", Some("a1\n"))], "Change a")?;
+    common::commit_files(
+        &source,
+        &[("a.txt", Some("a2\n")), ("b.txt", Some("b1\n"))],
+        "Change both",
+    )?;
+    let patch_dir = tempdir.join("patches");
+    assert_eq!(common::format_patches(&source, base, &patch_dir)?.len(), 2);
+
+    let target = common::init_repo(&tempdir.join("target"))?;
+    let start = common::commit_files(
+        &target,
+        &[("a.txt", Some("a\n")), ("b.txt", Some("b\n"))],
+        "Initial",
+    )?;
+    let logger = common::logger();
+    let mut bulk = BulkPatchApply::new(&logger, &target, patch_dir.clone());
+    bulk.set_squash(true);
+    bulk.apply_all()?;
+    assert_eq!(target.head()?.target(), Some(start));
+    let workdir = target.workdir().unwrap();
+    assert_eq!(std::fs::read_to_string(workdir.join("a.txt"))?, "a2\n");
+    assert_eq!(std::fs::read_to_string(workdir.join("b.txt"))?, "b1\n");
+    // The combined changes are staged
+    let mut index = target.index()?;
+    let staged = target.find_tree(index.write_tree()?)?;
+    let staged_file = |name: &str| -> anyhow::Result<Vec<u8>> {
+        let entry = staged.get_path(name.as_ref())?;
+        Ok(target.find_blob(entry.id())?.content().to_vec())
+    };
+    assert_eq!(staged_file("a.txt")?, b"a2\n");
+    assert_eq!(staged_file("b.txt")?, b"b1\n");
+    assert!(target
+        .diff_index_to_workdir(None, None)?
+        .deltas()
+        .next()
+        .is_none());
+
+    // A conflict with an earlier patch leaves everything untouched
+    let conflicting = common::init_repo(&tempdir.join("conflicting"))?;
+    let start = common::commit_files(
+        &conflicting,
+        &[("a.txt", Some("a\n")), ("b.txt", Some("conflict\n"))],
+        "Initial",
+    )?;
+    let mut bulk = BulkPatchApply::new(&logger, &conflicting, patch_dir);
+    bulk.set_squash(true);
+    let err = bulk.apply_all().unwrap_err();
+    assert!(err.to_string().contains("0002-Change-both"), "{err}");
+    assert_eq!(conflicting.head()?.target(), Some(start));
+    let workdir = conflicting.workdir().unwrap();
+    assert_eq!(std::fs::read_to_string(workdir.join("a.txt"))?, "a\n");
+    assert!(conflicting
+        .diff_tree_to_index(Some(&conflicting.head()?.peel_to_tree()?), None, None)?
+        .deltas()
+        .next()
+        .is_none());
+    Ok(())
+}
+
+#[test]
+pub fn squash_with_whitespace_fix() -> anyhow::Result<()> {
+    let tempdir = testdir::testdir!();
+    let source = common::init_repo(&tempdir.join("source"))?;
+    let base = common::commit_files(&source, &[("a.txt", Some("a\n"))], "Initial")?;
+    common::commit_files(&source, &[("a.txt", Some("a\nb \t\n"))], "Add b")?;
+    let patch_dir = tempdir.join("patches");
+    assert_eq!(common::format_patches(&source, base, &patch_dir)?.len(), 1);
+
+    // Squashed patches use the same options as committed ones
+    let target = common::init_repo(&tempdir.join("target"))?;
+    common::commit_files(&target, &[("a.txt", Some("a\n"))], "Initial")?;
+    let logger = common::logger();
+    let mut bulk = BulkPatchApply::new(&logger, &target, patch_dir);
+    bulk.set_squash(true);
+    bulk.set_whitespace(WhitespaceAction::Fix);
+    bulk.apply_all()?;
+    let workdir = target.workdir().unwrap();
+    assert_eq!(std::fs::read_to_string(workdir.join("a.txt"))?, "a\nb\n");
+    Ok(())
+}
+
+#[test]
+pub fn sniff_extensionless_patches() -> anyhow::Result<()> {
+    let tempdir = testdir::testdir!();
+    let source = common::init_repo(&tempdir.join("source"))?;
+    let base = common::commit_files(&source, &[("a.txt", Some("a\n"))], "Initial")?;
+    common::commit_files(&source, &[("a.txt", Some("a1\n"))], "Change a")?;
+    common::commit_files(&source, &[("b.txt", Some("b\n"))], "Add b")?;
+    let patch_dir = tempdir.join("patches");
+    assert_eq!(common::format_patches(&source, base, &patch_dir)?.len(), 2);
+    // Saved from an email client, without the extension
+    std::fs::rename(
+        patch_dir.join("0002-Add-b.patch"),
+        patch_dir.join("0002-Add-b"),
+    )?;
+    std::fs::write(patch_dir.join("README"), "Not a patch\n")?;
+    std::fs::create_dir(patch_dir.join("0003-directory"))?;
+
+    let logger = common::logger();
+    let apply = |name: &str, sniff: bool| -> anyhow::Result<git2::Repository> {
+        let target = common::init_repo(&tempdir.join(name))?;
+        common::commit_files(&target, &[("a.txt", Some("a\n"))], "Initial")?;
+        let mut bulk = BulkPatchApply::new(&logger, &target, patch_dir.clone());
+        bulk.set_sniff(sniff);
+        bulk.apply_all()?;
+        Ok(target)
+    };
+    let target = apply("sniffed", true)?;
+    assert_eq!(
+        common::read_head_file(&target, "a.txt")?.as_deref(),
+        Some("a1\n")
+    );
+    assert_eq!(
+        common::read_head_file(&target, "b.txt")?.as_deref(),
+        Some("b\n")
+    );
+    assert_eq!(target.head()?.peel_to_commit()?.summary(), Some("Add b"));
+
+    // Without sniffing, the extensionless patch is skipped
+    let target = apply("skipped", false)?;
+    assert_eq!(target.head()?.peel_to_commit()?.summary(), Some("Change a"));
+    Ok(())
+}
+
+#[test]
+pub fn in_memory() -> anyhow::Result<()> {
+    let tempdir = testdir::testdir!();
+    let source = common::init_repo(&tempdir.join("source"))?;
+    let base = common::commit_files(
+        &source,
+        &[("a.txt", Some("a\n")), ("b.txt", Some("b\n"))],
+        "Initial",
+    )?;
+    common::commit_files(&source, &[("a.txt", Some("a1\n"))], "Change a")?;
+    common::commit_files(
+        &source,
+        &[("b.txt", Some("b1\n")), ("c.txt", Some("c\n"))],
+        "Change b",
+    )?;
+    common::commit_files(
+        &source,
+        &[("a.txt", Some("a2\n")), ("b.txt", None)],
+        "Change a and remove b",
+    )?;
+    let patch_dir = tempdir.join("patches");
+    assert_eq!(common::format_patches(&source, base, &patch_dir)?.len(), 3);
+
+    let logger = common::logger();
+    let apply = |name: &str, in_memory: bool| -> anyhow::Result<git2::Repository> {
+        let target = common::init_repo(&tempdir.join(name))?;
+        common::commit_files(
+            &target,
+            &[("a.txt", Some("a\n")), ("b.txt", Some("b\n"))],
+            "Initial",
+        )?;
+        let mut bulk = BulkPatchApply::new(&logger, &target, patch_dir.clone());
+        bulk.set_in_memory(in_memory);
+        bulk.apply_all()?;
+        Ok(target)
+    };
+    let per_commit = apply("per-commit", false)?;
+    let in_memory = apply("in-memory", true)?;
+    let per_commit_head = per_commit.head()?.peel_to_commit()?;
+    let in_memory_head = in_memory.head()?.peel_to_commit()?;
+    assert_eq!(in_memory_head.tree_id(), per_commit_head.tree_id());
+    // The commits are identical too, including their parents
+    assert_eq!(in_memory_head.id(), per_commit_head.id());
+    let workdir = in_memory.workdir().unwrap();
+    assert_eq!(std::fs::read_to_string(workdir.join("a.txt"))?, "a2\n");
+    assert_eq!(std::fs::read_to_string(workdir.join("c.txt"))?, "c\n");
+    assert!(!workdir.join("b.txt").exists());
+    assert!(in_memory
+        .statuses(None)?
+        .iter()
+        .all(|status| status.status() == git2::Status::CURRENT));
+
+    // The patches before a failure are still checked out
+    let conflicting = common::init_repo(&tempdir.join("conflicting"))?;
+    let start = common::commit_files(
+        &conflicting,
+        &[("a.txt", Some("a\n")), ("b.txt", Some("conflict\n"))],
+        "Initial",
+    )?;
+    let mut bulk = BulkPatchApply::new(&logger, &conflicting, patch_dir.clone());
+    bulk.set_in_memory(true);
+    let err = bulk.apply_all().unwrap_err();
+    assert!(err.to_string().contains("0002-Change-b"), "{err}");
+    let head = conflicting.head()?.peel_to_commit()?;
+    assert_eq!(head.summary(), Some("Change a"));
+    assert_eq!(head.parent_id(0)?, start);
+    let workdir = conflicting.workdir().unwrap();
+    assert_eq!(std::fs::read_to_string(workdir.join("a.txt"))?, "a1\n");
+
+    // Unless the apply is transactional
+    let mut bulk = BulkPatchApply::new(&logger, &conflicting, patch_dir);
+    bulk.set_in_memory(true);
+    bulk.set_transactional(true);
+    let head = conflicting.head()?.target();
+    bulk.apply_all().unwrap_err();
+    assert_eq!(conflicting.head()?.target(), head);
+    Ok(())
+}
+
+#[test]
+pub fn reset_upstream_preview() -> anyhow::Result<()> {
+    let tempdir = testdir::testdir!();
+    let target = common::init_repo(&tempdir.join("target"))?;
+    let upstream = common::commit_files(&target, &[("file.txt", Some("upstream\n"))], "Upstream")?;
+    common::commit_files(&target, &[("file.txt", Some("local\n"))], "Local")?;
+    let workdir = target.workdir().unwrap();
+    std::fs::write(workdir.join("untracked.txt"), "precious\n")?;
+    let patch_dir = tempdir.join("patches");
+    std::fs::create_dir(&patch_dir)?;
+
+    let logger = common::logger();
+    let bulk = BulkPatchApply::new(&logger, &target, patch_dir);
+    let preview = bulk.reset_upstream_preview(&upstream.to_string())?;
+    assert_eq!(preview.changed, [std::path::PathBuf::from("file.txt")]);
+    assert_eq!(
+        preview.removed_untracked,
+        [std::path::PathBuf::from("untracked.txt")]
+    );
+    // Nothing was actually reset
+    assert_eq!(
+        std::fs::read_to_string(workdir.join("file.txt"))?,
+        "local\n"
+    );
+    assert!(workdir.join("untracked.txt").exists());
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "gzip")]
+pub fn apply_gzip_patch() -> anyhow::Result<()> {
+    use std::io::Write;
+    let tempdir = testdir::testdir!();
+    let source = common::init_repo(&tempdir.join("source"))?;
+    let base = common::commit_files(&source, &[("file.txt", Some("a\n"))], "Initial")?;
+    common::commit_files(&source, &[("file.txt", Some("b\n"))], "First")?;
+    common::commit_files(&source, &[("file.txt", Some("c\n"))], "Second")?;
+    let patch_dir = tempdir.join("patches");
+    let patches = common::format_patches(&source, base, &patch_dir)?;
+    // Only compress the first patch, the plain one must still be applied after it
+    let first = patch_dir.join("0001-First.patch");
+    let mut encoder = flate2::write::GzEncoder::new(
+        std::fs::File::create(patch_dir.join("0001-First.patch.gz"))?,
+        flate2::Compression::default(),
+    );
+    encoder.write_all(patches[0].as_bytes())?;
+    encoder.finish()?;
+    std::fs::remove_file(first)?;
+
+    let target = common::init_repo(&tempdir.join("target"))?;
+    common::commit_files(&target, &[("file.txt", Some("a\n"))], "Initial")?;
+    let logger = common::logger();
+    let bulk = BulkPatchApply::new(&logger, &target, patch_dir);
+    bulk.apply_all()?;
+    assert_eq!(
+        common::read_head_file(&target, "file.txt")?.as_deref(),
+        Some("c\n")
+    );
+    let head = target.head()?.peel_to_commit()?;
+    assert_eq!(head.summary(), Some("Second"));
+    assert_eq!(head.parent(0)?.summary(), Some("First"));
+    Ok(())
+}
+
+#[test]
+pub fn round_trip_blank_lines() -> anyhow::Result<()> {
+    const MESSAGE: &str = "Summary\n\nFirst paragraph\n\n\nSecond paragraph\n\n\n\nThird paragraph";
+    let tempdir = testdir::testdir!();
+    let source = common::init_repo(&tempdir.join("source"))?;
+    let base = common::commit_files(&source, &[("file.txt", Some("a\n"))], "Initial")?;
+    common::commit_files(&source, &[("file.txt", Some("b\n"))], MESSAGE)?;
+    let patch_dir = tempdir.join("patches");
+    let patches = common::format_patches(&source, base, &patch_dir)?;
+    assert!(patches[0].contains("First paragraph\n\n\nSecond paragraph\n\n\n\nThird"));
+
+    let target = common::init_repo(&tempdir.join("target"))?;
+    let target_base = common::commit_files(&target, &[("file.txt", Some("a\n"))], "Initial")?;
+    let logger = common::logger();
+    let mut bulk_apply = BulkPatchApply::new(&logger, &target, patch_dir);
+    bulk_apply.set_message_cleanup(CleanupMode::None);
+    bulk_apply.apply_all()?;
+    assert_eq!(target.head()?.peel_to_commit()?.message(), Some(MESSAGE));
+    let regenerated = common::format_patches(&target, target_base, &tempdir.join("regenerated"))?;
+    assert_eq!(regenerated, patches);
+    Ok(())
+}
+
+#[test]
+pub fn apply_first_patches() -> anyhow::Result<()> {
+    let tempdir = testdir::testdir!();
+    let source = common::init_repo(&tempdir.join("source"))?;
+    let base = common::commit_files(&source, &[("file.txt", Some("0\n"))], "Initial")?;
+    for n in 1..=4 {
+        let contents = format!("{n}\n");
+        common::commit_files(
+            &source,
+            &[("file.txt", Some(&contents))],
+            &format!("Patch {n}"),
+        )?;
+    }
+    let patch_dir = tempdir.join("patches");
+    assert_eq!(common::format_patches(&source, base, &patch_dir)?.len(), 4);
+
+    let target = common::init_repo(&tempdir.join("target"))?;
+    common::commit_files(&target, &[("file.txt", Some("0\n"))], "Initial")?;
+    let drain = common::CapturingDrain::default();
+    let mut bulk = BulkPatchApply::new(&drain.logger(), &target, patch_dir);
+    bulk.set_count(Some(2));
+    bulk.apply_all()?;
+    let head = target.head()?.peel_to_commit()?;
+    assert_eq!(head.summary(), Some("Patch 2"));
+    assert_eq!(
+        common::read_head_file(&target, "file.txt")?.as_deref(),
+        Some("2\n")
+    );
+    assert!(drain
+        .messages(slog::Level::Info)
+        .contains(&"Stopping after 2 of 4 patches, before 0003-Patch-3".to_string()));
+    Ok(())
+}
+
+#[test]
+pub fn report_invalid_utf8_position() -> anyhow::Result<()> {
+    let tempdir = testdir::testdir!();
+    let source = common::init_repo(&tempdir.join("source"))?;
+    let base = common::commit_files(&source, &[("file.txt", Some("a\n"))], "Initial")?;
+    common::commit_files(
+        &source,
+        &[("file.txt", Some("b\n"))],
+        "Change\n\nThanks to Rene",
+    )?;
+    let patch_dir = tempdir.join("patches");
+    let patches = common::format_patches(&source, base, &patch_dir)?;
+    // A stray Latin-1 `é`, like an editor with the wrong encoding would write
+    let offset = patches[0].find("Rene").unwrap() + 3;
+    let mut contents = patches[0].as_bytes().to_vec();
+    contents[offset] = 0xE9;
+    std::fs::write(patch_dir.join("0001-Change.patch"), contents)?;
+    let expected_line = patches[0][..offset].lines().count();
+
+    let target = common::init_repo(&tempdir.join("target"))?;
+    common::commit_files(&target, &[("file.txt", Some("a\n"))], "Initial")?;
+    let logger = common::logger();
+    match BulkPatchApply::new(&logger, &target, patch_dir).apply_all() {
+        Err(BulkApplyError::PatchContentsInvalidUtf8 {
+            offset: actual,
+            line,
+            ..
+        }) => {
+            assert_eq!(actual, offset);
+            assert_eq!(line, expected_line);
+        }
+        res => panic!("Expected invalid UTF8, got {res:?}"),
+    }
+    Ok(())
+}
+
+#[test]
+pub fn message_cleanup_from_config() -> anyhow::Result<()> {
+    const MESSAGE: &str = "Summary\n\nFirst paragraph\n\n\nSecond paragraph";
+    let tempdir = testdir::testdir!();
+    let source = common::init_repo(&tempdir.join("source"))?;
+    let base = common::commit_files(&source, &[("file.txt", Some("a\n"))], "Initial")?;
+    common::commit_files(&source, &[("file.txt", Some("b\n"))], MESSAGE)?;
+    let patch_dir = tempdir.join("patches");
+    common::format_patches(&source, base, &patch_dir)?;
+    let logger = common::logger();
+
+    // Like apply-patch, the blank lines are collapsed by default
+    let target = common::init_repo(&tempdir.join("target"))?;
+    common::commit_files(&target, &[("file.txt", Some("a\n"))], "Initial")?;
+    BulkPatchApply::new(&logger, &target, patch_dir.clone()).apply_all()?;
+    assert_eq!(
+        target.head()?.peel_to_commit()?.message(),
+        Some("Summary\n\nFirst paragraph\n\nSecond paragraph")
+    );
+
+    let verbatim = common::init_repo(&tempdir.join("verbatim"))?;
+    common::commit_files(&verbatim, &[("file.txt", Some("a\n"))], "Initial")?;
+    verbatim.config()?.set_str("commit.cleanup", "verbatim")?;
+    let mut bulk_apply = BulkPatchApply::new(&logger, &verbatim, patch_dir);
+    bulk_apply.load_config()?;
+    bulk_apply.apply_all()?;
+    assert_eq!(verbatim.head()?.peel_to_commit()?.message(), Some(MESSAGE));
+    Ok(())
+}
@@ -0,0 +1,61 @@
+//! Tests for checking whether patches still match upstream.
+use gitpatcher::apply_patches::drift::{check_series_drift, DriftStatus};
+use gitpatcher::apply_patches::EmailMessage;
+
+mod common;
+
+const LINES: &str = "1\n2\n3\n4\n5\n6\n7\n8\n9\n";
+
+#[test]
+pub fn clean_fuzzy_and_conflicting() -> anyhow::Result<()> {
+    let tempdir = testdir::testdir!();
+    let repo = common::init_repo(&tempdir.join("repo"))?;
+    let files = ["clean.txt", "fuzzy.txt", "conflict.txt"];
+    let base = common::commit_files(&repo, &files.map(|name| (name, Some(LINES))), "Initial")?;
+    let changed = LINES.replace("5\n", "five\n");
+    for name in files {
+        common::commit_files(&repo, &[(name, Some(&changed))], &format!("Change {name}"))?;
+    }
+    let patches = common::format_patches(&repo, base, &tempdir.join("patches"))?
+        .iter()
+        .map(|patch| EmailMessage::parse(patch))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // Change upstream independently of the patches
+    repo.reset(&repo.find_object(base, None)?, git2::ResetType::Hard, None)?;
+    let upstream = common::commit_files(
+        &repo,
+        &[
+            ("fuzzy.txt", Some(&format!("0\n0\n{LINES}"))),
+            ("conflict.txt", Some(&LINES.replace("4\n", "four\n"))),
+        ],
+        "Upstream changes",
+    )?;
+    let upstream_tree = repo.find_commit(upstream)?.tree()?;
+    let count_objects = || -> anyhow::Result<usize> {
+        let mut count = 0;
+        repo.odb()?.foreach(|_| {
+            count += 1;
+            true
+        })?;
+        Ok(count)
+    };
+    let num_objects = count_objects()?;
+    let statuses = check_series_drift(&repo, &upstream_tree, &patches)?;
+    assert_eq!(
+        statuses,
+        [
+            DriftStatus::Clean,
+            DriftStatus::Fuzzy,
+            DriftStatus::Conflicting
+        ]
+    );
+    // Nothing was modified
+    assert_eq!(count_objects()?, num_objects);
+    assert_eq!(repo.head()?.target(), Some(upstream));
+    assert_eq!(
+        common::read_head_file(&repo, "clean.txt")?.as_deref(),
+        Some(LINES)
+    );
+    Ok(())
+}
@@ -0,0 +1,218 @@
+//! Utilities shared between the integration tests.
+#![allow(dead_code)]
+use std::path::Path;
+
+use git2::{Commit, Oid, Repository, ResetType, Signature};
+
+/// A logger that discards everything
+pub fn logger() -> slog::Logger {
+    slog::Logger::root(slog::Discard, slog::o!())
+}
+
+/// The signature used for all test commits
+pub fn signature() -> Signature<'static> {
+    Signature::new(
+        "dummy",
+        "dummy@dumb.gov",
+        &git2::Time::new(1_692_944_000, 0),
+    )
+    .unwrap()
+}
+
+/// Initialize a fresh (non-bare) repository at the specified path
+pub fn init_repo(path: &Path) -> anyhow::Result<Repository> {
+    let repo = Repository::init_opts(path, git2::RepositoryInitOptions::new().no_reinit(true))?;
+    assert!(repo.is_empty()?, "Repo should be empty");
+    Ok(repo)
+}
+
+/// Write the specified files to the working directory,
+/// then commit them (along with any other changes) on top of HEAD.
+///
+/// A file with contents of `None` is deleted.
+pub fn commit_files(
+    repo: &Repository,
+    files: &[(&str, Option<&str>)],
+    message: &str,
+) -> anyhow::Result<Oid> {
+    let workdir = repo.workdir().expect("Repo should have workdir");
+    let mut index = repo.index()?;
+    for &(name, contents) in files {
+        let path = workdir.join(name);
+        match contents {
+            Some(contents) => {
+                if let Some(parent) = path.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                std::fs::write(&path, contents)?;
+                index.add_path(Path::new(name))?;
+            }
+            None => {
+                std::fs::remove_file(&path)?;
+                index.remove_path(Path::new(name))?;
+            }
+        }
+    }
+    index.write()?;
+    let tree = repo.find_tree(index.write_tree()?)?;
+    let sig = signature();
+    let parent = match repo.head() {
+        Ok(head) => Some(head.peel_to_commit()?),
+        Err(_) => None,
+    };
+    let parents = parent.iter().collect::<Vec<&Commit>>();
+    let commit_id = repo.commit(Some("HEAD"), &sig, &sig, message, &tree, &parents)?;
+    let commit = repo.find_commit(commit_id)?;
+    repo.reset(commit.as_object(), ResetType::Hard, None)?;
+    Ok(commit_id)
+}
+
+/// Read a file from the tree of the HEAD commit
+pub fn read_head_file(repo: &Repository, name: &str) -> anyhow::Result<Option<String>> {
+    let tree = repo.head()?.peel_to_tree()?;
+    let entry = match tree.get_path(Path::new(name)) {
+        Ok(entry) => entry,
+        Err(e) if e.code() == git2::ErrorCode::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let blob = repo.find_blob(entry.id())?;
+    Ok(Some(String::from_utf8(blob.content().to_vec())?))
+}
+
+/// Format all the commits since `base` into patches in the specified directory,
+/// returning their contents in order.
+pub fn format_patches(repo: &Repository, base: Oid, out_dir: &Path) -> anyhow::Result<Vec<String>> {
+    use gitpatcher::format_patches::{FormatOptions, PatchFormatter};
+    let out_dir = camino::Utf8PathBuf::try_from(out_dir.to_path_buf())?;
+    let mut formatter = PatchFormatter::new(
+        logger(),
+        out_dir,
+        repo,
+        repo.find_commit(base)?,
+        FormatOptions::default(),
+    )?;
+    formatter.generate_all()?;
+    formatter
+        .generated_patches()
+        .iter()
+        .map(|patch| Ok(std::fs::read_to_string(&patch.path)?))
+        .collect()
+}
+
+/// A [slog::Drain] that remembers the messages of all the records it receives.
+#[derive(Clone, Default)]
+pub struct CapturingDrain {
+    pub records: std::sync::Arc<std::sync::Mutex<Vec<(slog::Level, String)>>>,
+}
+impl CapturingDrain {
+    pub fn logger(&self) -> slog::Logger {
+        use slog::Drain;
+        slog::Logger::root(self.clone().fuse(), slog::o!())
+    }
+    pub fn messages(&self, level: slog::Level) -> Vec<String> {
+        self.records
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(record_level, _)| *record_level == level)
+            .map(|(_, message)| message.clone())
+            .collect()
+    }
+}
+impl slog::Drain for CapturingDrain {
+    type Ok = ();
+    type Err = slog::Never;
+    fn log(
+        &self,
+        record: &slog::Record,
+        _values: &slog::OwnedKVList,
+    ) -> Result<Self::Ok, Self::Err> {
+        self.records
+            .lock()
+            .unwrap()
+            .push((record.level(), record.msg().to_string()));
+        Ok(())
+    }
+}
+
+/// A root repository containing a `patches` directory,
+/// along with a separate repository that is being patched.
+pub struct RegenerateFixture {
+    pub root_dir: std::path::PathBuf,
+    pub root: Repository,
+    pub patched: Repository,
+    pub base: Oid,
+}
+impl RegenerateFixture {
+    pub fn new(dir: &Path) -> anyhow::Result<Self> {
+        let root_dir = dir.join("root");
+        let root = init_repo(&root_dir)?;
+        commit_files(&root, &[("patches/.keep", Some(""))], "Add patch dir")?;
+        let patched = init_repo(&dir.join("patched"))?;
+        let base = commit_files(&patched, &[("file.txt", Some("base\n"))], "Initial")?;
+        Ok(RegenerateFixture {
+            root_dir,
+            root,
+            patched,
+            base,
+        })
+    }
+    pub fn regenerate(
+        &self,
+        options: gitpatcher::regenerate_patches::RegenerateOptions,
+    ) -> anyhow::Result<()> {
+        self.regenerate_with_logger(options, logger())
+    }
+    pub fn regenerate_with_logger(
+        &self,
+        options: gitpatcher::regenerate_patches::RegenerateOptions,
+        logger: slog::Logger,
+    ) -> anyhow::Result<()> {
+        let mut patch_set = self.load_patches()?;
+        gitpatcher::regenerate_patches::regenerate_patches(
+            &self.patched.find_commit(self.base)?,
+            &mut patch_set,
+            &self.patched,
+            logger,
+            options,
+        )?;
+        Ok(())
+    }
+    /// Load the patches from the patch directory of the root repository
+    pub fn load_patches(&self) -> anyhow::Result<gitpatcher::regenerate_patches::PatchFileSet<'_>> {
+        let patch_dir = camino::Utf8PathBuf::try_from(self.root_dir.join("patches"))?;
+        Ok(gitpatcher::regenerate_patches::PatchFileSet::load(
+            &self.root, &patch_dir,
+        )?)
+    }
+    pub fn patch_path(&self, name: &str) -> std::path::PathBuf {
+        self.root_dir.join("patches").join(name)
+    }
+    /// List the names of the patch files, in sorted order
+    pub fn patch_names(&self) -> anyhow::Result<Vec<String>> {
+        let mut names = std::fs::read_dir(self.root_dir.join("patches"))?
+            .map(|entry| Ok(entry?.file_name().into_string().unwrap()))
+            .filter(|name| !matches!(name, Ok(name) if !name.ends_with(".patch")))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        names.sort();
+        Ok(names)
+    }
+    /// Commit all changes to the patch directory
+    pub fn commit_patches(&self) -> anyhow::Result<Oid> {
+        let mut index = self.root.index()?;
+        index.add_all(["patches"], git2::IndexAddOption::DEFAULT, None)?;
+        index.update_all(["patches"], None)?;
+        index.write()?;
+        let tree = self.root.find_tree(index.write_tree()?)?;
+        let sig = signature();
+        let parent = self.root.head()?.peel_to_commit()?;
+        Ok(self.root.commit(
+            Some("HEAD"),
+            &sig,
+            &sig,
+            "Update patches",
+            &tree,
+            &[&parent],
+        )?)
+    }
+}
@@ -0,0 +1,61 @@
+//! Tests for finding patches that make the same change.
+use gitpatcher::apply_patches::dedup::find_duplicates;
+use gitpatcher::apply_patches::EmailMessage;
+
+mod common;
+
+#[test]
+pub fn identical_patches() -> anyhow::Result<()> {
+    let tempdir = testdir::testdir!();
+    let repo = common::init_repo(&tempdir.join("repo"))?;
+    let base = common::commit_files(&repo, &[("hello.txt", Some("Hello\n"))], "Initial")?;
+    common::commit_files(&repo, &[("hello.txt", Some("Hello World\n"))], "Greet")?;
+    common::commit_files(&repo, &[("hello.txt", Some("Hello\n"))], "Revert greeting")?;
+    // A cherry-pick of the first commit, with a different message
+    common::commit_files(
+        &repo,
+        &[("hello.txt", Some("Hello World\n"))],
+        "Greet again",
+    )?;
+    common::commit_files(&repo, &[("other.txt", Some("Other\n"))], "Unrelated")?;
+    let patches = common::format_patches(&repo, base, &tempdir.join("patches"))?
+        .iter()
+        .map(|patch| EmailMessage::parse(patch))
+        .collect::<Result<Vec<_>, _>>()?;
+    assert_eq!(find_duplicates(&patches)?, [vec![0, 2]]);
+    assert_ne!(patches[0].content_id()?, patches[1].content_id()?);
+    Ok(())
+}
+
+#[test]
+pub fn ignores_offsets_and_whitespace() -> anyhow::Result<()> {
+    let patch = |hunk_header: &str, added: &str| {
+        EmailMessage::parse(&format!(
+            "\
+From 0000000000000000000000000000000000000000 Mon Sep 17 00:00:00 2001
+From: dummy <dummy@dumb.gov>
+Date: Fri, 25 Aug 2023 06:13:20 +0000
+Subject: [PATCH] Change
+
+
+diff --git a/file.txt b/file.txt
+index e965047..557db03 100644
+--- a/file.txt
++++ b/file.txt
+{hunk_header}
+ context
+-old
++{added}
+"
+        ))
+    };
+    let original = patch("@@ -1,2 +1,2 @@", "new")?;
+    let moved = patch("@@ -10,2 +10,2 @@", "new ")?;
+    let different = patch("@@ -1,2 +1,2 @@", "newer")?;
+    assert_eq!(original.content_id()?, moved.content_id()?);
+    assert_eq!(
+        find_duplicates([&original, &different, &moved])?,
+        [vec![0, 2]]
+    );
+    Ok(())
+}
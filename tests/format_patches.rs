@@ -0,0 +1,1078 @@
+//! Tests for formatting a series of commits into patch files.
+use camino::Utf8PathBuf;
+use gitpatcher::apply_patches::EmailMessage;
+use gitpatcher::format_patches::{FormatOptions, PatchFormatError, PatchFormatter};
+
+mod common;
+
+#[test]
+pub fn creates_missing_out_dir() -> anyhow::Result<()> {
+    let tempdir = testdir::testdir!();
+    let repo = common::init_repo(&tempdir.join("repo"))?;
+    let base = common::commit_files(&repo, &[("hello.txt", Some("Hello\n"))], "Initial commit")?;
+    common::commit_files(
+        &repo,
+        &[("hello.txt", Some("Hello World\n"))],
+        "Greet the world",
+    )?;
+    let out_dir = Utf8PathBuf::try_from(tempdir.join("nested/patch/dir"))?;
+    assert!(!out_dir.exists());
+    PatchFormatter::new(
+        common::logger(),
+        out_dir.clone(),
+        &repo,
+        repo.find_commit(base)?,
+        FormatOptions::default(),
+    )?
+    .generate_all()?;
+    assert!(out_dir.is_dir());
+    assert!(out_dir.join("0001-Greet-the-world.patch").is_file());
+    Ok(())
+}
+
+#[test]
+pub fn custom_name_fn() -> anyhow::Result<()> {
+    let tempdir = testdir::testdir!();
+    let repo = common::init_repo(&tempdir.join("repo"))?;
+    let base = common::commit_files(&repo, &[("hello.txt", Some("Hello\n"))], "Initial commit")?;
+    let commit = common::commit_files(
+        &repo,
+        &[("hello.txt", Some("Hello World\n"))],
+        "Greet the world\n\nTicket: GP-42\n",
+    )?;
+    let out_dir = Utf8PathBuf::try_from(tempdir.join("patches"))?;
+    let mut opts = FormatOptions::default();
+    opts.name_fn = Some(Box::new(|ctx| {
+        let ticket = ctx
+            .trailers
+            .iter()
+            .find(|(key, _)| key == "Ticket")
+            .map_or("none", |(_, value)| value.as_str());
+        format!(
+            "{:04}-{}-{ticket}.patch",
+            ctx.index,
+            &ctx.commit_id.to_string()[..7]
+        )
+    }));
+    let mut formatter = PatchFormatter::new(
+        common::logger(),
+        out_dir.clone(),
+        &repo,
+        repo.find_commit(base)?,
+        opts,
+    )?;
+    formatter.generate_all()?;
+    let expected_name = format!("0001-{}-GP-42.patch", &commit.to_string()[..7]);
+    assert!(out_dir.join(&expected_name).is_file());
+    assert_eq!(formatter.generated_patches().len(), 1);
+    Ok(())
+}
+
+#[test]
+pub fn name_fn_outside_out_dir() -> anyhow::Result<()> {
+    let tempdir = testdir::testdir!();
+    let repo = common::init_repo(&tempdir.join("repo"))?;
+    let base = common::commit_files(&repo, &[("hello.txt", Some("Hello\n"))], "Initial commit")?;
+    common::commit_files(
+        &repo,
+        &[("hello.txt", Some("Hello World\n"))],
+        "Greet the world",
+    )?;
+    let out_dir = Utf8PathBuf::try_from(tempdir.join("patches"))?;
+    for name in ["../escaped.patch", "..", "nested/0001.patch", ""] {
+        let mut opts = FormatOptions::default();
+        opts.name_fn = Some(Box::new(move |_ctx| name.to_string()));
+        let mut formatter = PatchFormatter::new(
+            common::logger(),
+            out_dir.clone(),
+            &repo,
+            repo.find_commit(base)?,
+            opts,
+        )?;
+        let err = formatter.generate_all().unwrap_err();
+        assert!(
+            matches!(err, PatchFormatError::InvalidPatchName { name: ref invalid, .. } if invalid == name),
+            "{err:?}"
+        );
+    }
+    assert!(!tempdir.join("escaped.patch").exists());
+    assert_eq!(std::fs::read_dir(&out_dir)?.count(), 0);
+    Ok(())
+}
+
+#[test]
+pub fn gitpatcherignore() -> anyhow::Result<()> {
+    let tempdir = testdir::testdir!();
+    let repo = common::init_repo(&tempdir.join("repo"))?;
+    let base = common::commit_files(
+        &repo,
+        &[
+            ("src.txt", Some("source\n")),
+            ("generated/out.txt", Some("generated\n")),
+        ],
+        "Initial commit",
+    )?;
+    common::commit_files(
+        &repo,
+        &[
+            (".gitpatcherignore", Some("generated/\n.gitpatcherignore\n")),
+            ("src.txt", Some("changed source\n")),
+            ("generated/out.txt", Some("regenerated\n")),
+            ("generated/new.txt", Some("new\n")),
+        ],
+        "Change source",
+    )?;
+    let patches = common::format_patches(&repo, base, &tempdir.join("patches"))?;
+    assert_eq!(patches.len(), 1);
+    assert!(patches[0].contains("diff --git a/src.txt b/src.txt"));
+    assert!(!patches[0].contains("generated"), "{}", patches[0]);
+    assert!(!patches[0].contains(".gitpatcherignore"), "{}", patches[0]);
+    Ok(())
+}
+
+#[test]
+pub fn empty_commit() -> anyhow::Result<()> {
+    let tempdir = testdir::testdir!();
+    let repo = common::init_repo(&tempdir.join("repo"))?;
+    let base = common::commit_files(&repo, &[("hello.txt", Some("Hello\n"))], "Initial commit")?;
+    common::commit_files(&repo, &[], "Empty commit\n\nWith a body\n")?;
+    let patches = common::format_patches(&repo, base, &tempdir.join("patches"))?;
+    assert_eq!(patches.len(), 1);
+    assert!(patches[0].contains("Subject: [PATCH] Empty commit\n\nWith a body\n"));
+    assert!(!patches[0].contains("diff --git"));
+    Ok(())
+}
+
+#[test]
+pub fn owned_formatter() -> anyhow::Result<()> {
+    use gitpatcher::format_patches::OwnedPatchFormatter;
+    struct Exporter {
+        formatter: OwnedPatchFormatter,
+    }
+    fn make_exporter(
+        repo_path: &std::path::Path,
+        base: git2::Oid,
+        out_dir: Utf8PathBuf,
+    ) -> Exporter {
+        Exporter {
+            formatter: OwnedPatchFormatter::new(
+                common::logger(),
+                out_dir,
+                repo_path,
+                base,
+                FormatOptions::default(),
+            ),
+        }
+    }
+    let tempdir = testdir::testdir!();
+    let repo_path = tempdir.join("repo");
+    let repo = common::init_repo(&repo_path)?;
+    let base = common::commit_files(&repo, &[("hello.txt", Some("Hello\n"))], "Initial commit")?;
+    common::commit_files(
+        &repo,
+        &[("hello.txt", Some("Hello World\n"))],
+        "Greet the world",
+    )?;
+    drop(repo);
+    let out_dir = Utf8PathBuf::try_from(tempdir.join("patches"))?;
+    let mut exporter = make_exporter(&repo_path, base, out_dir.clone());
+    let generated = exporter.formatter.generate_all()?;
+    assert_eq!(generated.len(), 1);
+    assert_eq!(
+        generated[0].path,
+        out_dir.join("0001-Greet-the-world.patch")
+    );
+    // Can be reused after generating
+    assert_eq!(exporter.formatter.generate_all()?.len(), 1);
+    Ok(())
+}
+
+#[test]
+pub fn custom_from_line_id() -> anyhow::Result<()> {
+    let tempdir = testdir::testdir!();
+    let repo = common::init_repo(&tempdir.join("repo"))?;
+    let base = common::commit_files(&repo, &[("hello.txt", Some("Hello\n"))], "Initial commit")?;
+    let commit = common::commit_files(
+        &repo,
+        &[("hello.txt", Some("Hello World\n"))],
+        "Greet the world",
+    )?;
+    let out_dir = Utf8PathBuf::try_from(tempdir.join("patches"))?;
+    let custom_id = git2::Oid::from_str("1234567890abcdef1234567890abcdef12345678")?;
+    let mut opts = FormatOptions::default();
+    opts.from_line_id = Some(custom_id);
+    let mut formatter = PatchFormatter::new(
+        common::logger(),
+        out_dir,
+        &repo,
+        repo.find_commit(base)?,
+        opts,
+    )?;
+    formatter.generate_all()?;
+    let patch = std::fs::read_to_string(&formatter.generated_patches()[0].path)?;
+    assert!(patch.starts_with(&format!("From {custom_id} Mon Sep 17 00:00:00 2001\n")));
+    assert!(!patch.contains(&commit.to_string()));
+    Ok(())
+}
+
+#[test]
+pub fn reroll_count() -> anyhow::Result<()> {
+    use gitpatcher::apply_patches::EmailMessage;
+    let tempdir = testdir::testdir!();
+    let repo = common::init_repo(&tempdir.join("repo"))?;
+    let base = common::commit_files(&repo, &[("hello.txt", Some("Hello\n"))], "Initial commit")?;
+    common::commit_files(
+        &repo,
+        &[("hello.txt", Some("Hello World\n"))],
+        "Greet the world",
+    )?;
+    let out_dir = Utf8PathBuf::try_from(tempdir.join("patches"))?;
+    let mut opts = FormatOptions::default();
+    opts.reroll_count = Some(3);
+    let mut formatter = PatchFormatter::new(
+        common::logger(),
+        out_dir,
+        &repo,
+        repo.find_commit(base)?,
+        opts,
+    )?;
+    formatter.generate_all()?;
+    let patch = std::fs::read_to_string(&formatter.generated_patches()[0].path)?;
+    assert!(
+        patch.contains("\nSubject: [PATCH v3] Greet the world\n"),
+        "{patch}"
+    );
+    // The version prefix isn't part of the message
+    let email = EmailMessage::parse(&patch)?;
+    assert_eq!(email.full_message(), "Greet the world");
+    Ok(())
+}
+
+#[test]
+pub fn custom_subject_prefix() -> anyhow::Result<()> {
+    use gitpatcher::apply_patches::EmailMessage;
+    let tempdir = testdir::testdir!();
+    let repo = common::init_repo(&tempdir.join("repo"))?;
+    let base = common::commit_files(&repo, &[("hello.txt", Some("Hello\n"))], "Initial commit")?;
+    common::commit_files(
+        &repo,
+        &[("hello.txt", Some("Hello World\n"))],
+        "Greet the world",
+    )?;
+    repo.config()?
+        .set_str("format.subjectPrefix", "my-project PATCH")?;
+    let out_dir = Utf8PathBuf::try_from(tempdir.join("patches"))?;
+    let opts = FormatOptions::from_config(&repo.config()?)?;
+    assert_eq!(opts.subject_prefix, "my-project PATCH");
+    let mut formatter = PatchFormatter::new(
+        common::logger(),
+        out_dir,
+        &repo,
+        repo.find_commit(base)?,
+        opts,
+    )?;
+    formatter.generate_all()?;
+    let patch = std::fs::read_to_string(&formatter.generated_patches()[0].path)?;
+    assert!(
+        patch.contains("\nSubject: [my-project PATCH] Greet the world\n"),
+        "{patch}"
+    );
+    let email = EmailMessage::parse(&patch)?;
+    assert_eq!(email.full_message(), "Greet the world");
+    Ok(())
+}
+
+#[test]
+pub fn atomic_writes() -> anyhow::Result<()> {
+    let tempdir = testdir::testdir!();
+    let repo = common::init_repo(&tempdir.join("repo"))?;
+    let base = common::commit_files(&repo, &[("hello.txt", Some("Hello\n"))], "Initial commit")?;
+    common::commit_files(
+        &repo,
+        &[("hello.txt", Some("Hello World\n"))],
+        "Greet the world",
+    )?;
+    let out_dir = tempdir.join("patches");
+    std::fs::create_dir(&out_dir)?;
+    let patch_path = out_dir.join("0001-Greet-the-world.patch");
+    std::fs::write(&patch_path, "stale")?;
+    // A hard link keeps the old contents if the patch is replaced instead of overwritten
+    let link = tempdir.join("stale-link");
+    std::fs::hard_link(&patch_path, &link)?;
+    let patches = common::format_patches(&repo, base, &out_dir)?;
+    assert!(patches[0].contains("Subject: [PATCH] Greet the world"));
+    assert_eq!(std::fs::read_to_string(&link)?, "stale");
+    let names = std::fs::read_dir(&out_dir)?
+        .map(|entry| Ok(entry?.file_name().into_string().unwrap()))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    assert_eq!(names, ["0001-Greet-the-world.patch"]);
+    Ok(())
+}
+
+#[test]
+pub fn ignore_merges() -> anyhow::Result<()> {
+    let tempdir = testdir::testdir!();
+    let repo = common::init_repo(&tempdir.join("repo"))?;
+    let base = common::commit_files(&repo, &[("fork.txt", Some("base\n"))], "Initial commit")?;
+    common::commit_files(
+        &repo,
+        &[("fork.txt", Some("fork 1\n"))],
+        "First fork change",
+    )?;
+    let sig = common::signature();
+    let merge = |upstream_file: &str| -> anyhow::Result<git2::Oid> {
+        // Commit an upstream change on top of the previous upstream, then merge it
+        let head = repo.head()?.peel_to_commit()?;
+        let upstream_parent = match repo.find_reference("refs/heads/upstream") {
+            Ok(reference) => reference.peel_to_commit()?,
+            Err(_) => repo.find_commit(base)?,
+        };
+        let mut builder = repo.treebuilder(Some(&upstream_parent.tree()?))?;
+        builder.insert(upstream_file, repo.blob(b"upstream\n")?, 0o100644)?;
+        let upstream_tree = repo.find_tree(builder.write()?)?;
+        let upstream = repo.commit(
+            Some("refs/heads/upstream"),
+            &sig,
+            &sig,
+            "Upstream change",
+            &upstream_tree,
+            &[&upstream_parent],
+        )?;
+        let mut builder = repo.treebuilder(Some(&head.tree()?))?;
+        for entry in upstream_tree.iter() {
+            builder.insert(entry.name().unwrap(), entry.id(), entry.filemode())?;
+        }
+        builder.insert(
+            "fork.txt",
+            head.tree()?.get_name("fork.txt").unwrap().id(),
+            0o100644,
+        )?;
+        let merged_tree = repo.find_tree(builder.write()?)?;
+        repo.commit(
+            Some("HEAD"),
+            &sig,
+            &sig,
+            "Merge upstream",
+            &merged_tree,
+            &[&head, &repo.find_commit(upstream)?],
+        )?;
+        repo.reset(
+            &repo.head()?.peel(git2::ObjectType::Commit)?,
+            git2::ResetType::Hard,
+            None,
+        )?;
+        Ok(upstream)
+    };
+    merge("upstream1.txt")?;
+    common::commit_files(
+        &repo,
+        &[("fork.txt", Some("fork 2\n"))],
+        "Second fork change",
+    )?;
+    let upstream = merge("upstream2.txt")?;
+    common::commit_files(
+        &repo,
+        &[("fork.txt", Some("fork 3\n"))],
+        "Third fork change",
+    )?;
+
+    let out_dir = Utf8PathBuf::try_from(tempdir.join("patches"))?;
+    let mut opts = FormatOptions::default();
+    opts.ignore_merges = true;
+    let mut formatter = PatchFormatter::new(
+        common::logger(),
+        out_dir,
+        &repo,
+        repo.find_commit(upstream)?,
+        opts,
+    )?;
+    formatter.generate_all()?;
+    let patches = formatter
+        .generated_patches()
+        .iter()
+        .map(|patch| std::fs::read_to_string(&patch.path))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    let names = formatter
+        .generated_patches()
+        .iter()
+        .map(|patch| patch.path.file_name().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        names,
+        [
+            "0001-First-fork-change.patch",
+            "0002-Second-fork-change.patch",
+            "0003-Third-fork-change.patch"
+        ]
+    );
+    for patch in &patches {
+        assert!(!patch.contains("upstream"), "{patch}");
+        assert!(patch.contains("diff --git a/fork.txt b/fork.txt"));
+    }
+    Ok(())
+}
+
+#[test]
+pub fn wrap_headers() -> anyhow::Result<()> {
+    use gitpatcher::apply_patches::EmailMessage;
+    let tempdir = testdir::testdir!();
+    let repo = common::init_repo(&tempdir.join("repo"))?;
+    let base = common::commit_files(&repo, &[("hello.txt", Some("Hello\n"))], "Initial commit")?;
+    let summary = "Greet the entire world with a subject that is much longer than usual, \
+                   to make sure it gets folded";
+    common::commit_files(
+        &repo,
+        &[("hello.txt", Some("Hello World\n"))],
+        &format!("{summary}\n\nWith a body\n"),
+    )?;
+    let out_dir = Utf8PathBuf::try_from(tempdir.join("patches"))?;
+    let mut opts = FormatOptions::default();
+    opts.wrap_headers = Some(40);
+    let mut formatter = PatchFormatter::new(
+        common::logger(),
+        out_dir,
+        &repo,
+        repo.find_commit(base)?,
+        opts,
+    )?;
+    formatter.generate_all()?;
+    let patch = std::fs::read_to_string(&formatter.generated_patches()[0].path)?;
+    let subject_lines = patch
+        .lines()
+        .skip_while(|line| !line.starts_with("Subject: "))
+        .take_while(|line| !line.is_empty())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        subject_lines,
+        [
+            "Subject: [PATCH] Greet the entire world",
+            " with a subject that is much longer than",
+            " usual, to make sure it gets folded",
+        ]
+    );
+    let email = EmailMessage::parse(&patch)?;
+    assert_eq!(email.full_message(), format!("{summary}\n\nWith a body"));
+    Ok(())
+}
+
+#[test]
+pub fn diff_context_from_config() -> anyhow::Result<()> {
+    let tempdir = testdir::testdir!();
+    let repo = common::init_repo(&tempdir.join("repo"))?;
+    let lines = (1..=10).map(|i| format!("{i}\n")).collect::<String>();
+    let base = common::commit_files(&repo, &[("numbers.txt", Some(&lines))], "Initial commit")?;
+    common::commit_files(
+        &repo,
+        &[("numbers.txt", Some(&lines.replace("5\n", "five\n")))],
+        "Spell out five",
+    )?;
+    repo.config()?.set_i32("diff.context", 1)?;
+    let out_dir = Utf8PathBuf::try_from(tempdir.join("patches"))?;
+    let mut formatter = PatchFormatter::new(
+        common::logger(),
+        out_dir,
+        &repo,
+        repo.find_commit(base)?,
+        FormatOptions::from_config(&repo.config()?)?,
+    )?;
+    formatter.generate_all()?;
+    let patch = std::fs::read_to_string(&formatter.generated_patches()[0].path)?;
+    assert!(
+        patch.contains("@@ -4,3 +4,3 @@\n 4\n-5\n+five\n 6\n"),
+        "Expected a single line of context: {patch}"
+    );
+    Ok(())
+}
+
+#[test]
+pub fn post_process() -> anyhow::Result<()> {
+    use bstr::ByteSlice;
+    let tempdir = testdir::testdir!();
+    let repo = common::init_repo(&tempdir.join("repo"))?;
+    let base = common::commit_files(&repo, &[("hello.txt", Some("Hello\n"))], "Initial commit")?;
+    common::commit_files(
+        &repo,
+        &[("hello.txt", Some("Hello World\n"))],
+        "Greet the world",
+    )?;
+    let out_dir = Utf8PathBuf::try_from(tempdir.join("patches"))?;
+    let mut opts = FormatOptions::default();
+    opts.post_process = Some(Box::new(|patch| {
+        Ok(patch
+            .lines_with_terminator()
+            .map(|line| match line.strip_prefix(b"Subject: ") {
+                Some(subject) => [&b"Subject: "[..], &subject.to_ascii_uppercase()].concat(),
+                None => line.to_vec(),
+            })
+            .collect::<Vec<_>>()
+            .concat()
+            .into())
+    }));
+    let mut formatter = PatchFormatter::new(
+        common::logger(),
+        out_dir.clone(),
+        &repo,
+        repo.find_commit(base)?,
+        opts,
+    )?;
+    formatter.generate_all()?;
+    let patch = std::fs::read_to_string(&formatter.generated_patches()[0].path)?;
+    assert!(
+        patch.contains("\nSubject: [PATCH] GREET THE WORLD\n"),
+        "{patch}"
+    );
+
+    let mut opts = FormatOptions::default();
+    opts.post_process = Some(Box::new(|_| Err("house style violated".into())));
+    let mut formatter = PatchFormatter::new(
+        common::logger(),
+        out_dir,
+        &repo,
+        repo.find_commit(base)?,
+        opts,
+    )?;
+    let err = formatter.generate_all().unwrap_err();
+    assert!(
+        err.to_string().contains("house style violated"),
+        "Unexpected error: {err}"
+    );
+    Ok(())
+}
+
+#[test]
+pub fn date_range() -> anyhow::Result<()> {
+    let tempdir = testdir::testdir!();
+    let repo = common::init_repo(&tempdir.join("repo"))?;
+    let base = common::commit_files(&repo, &[("log.txt", Some(""))], "Initial commit")?;
+    let day = 24 * 60 * 60;
+    let mut log = String::new();
+    for (day_no, message) in [
+        (1, "Monday"),
+        (2, "Tuesday"),
+        (3, "Wednesday"),
+        (4, "Thursday"),
+    ] {
+        log.push_str(message);
+        log.push('\n');
+        common::commit_files(&repo, &[("log.txt", Some(&log))], message)?;
+        // Backdate the commit
+        let head = repo.head()?.peel_to_commit()?;
+        let sig = git2::Signature::new(
+            "dummy",
+            "dummy@dumb.gov",
+            &git2::Time::new(1_692_944_000 + day_no * day, 0),
+        )?;
+        // The tree is unchanged, so the worktree is still up to date
+        head.amend(Some("HEAD"), Some(&sig), Some(&sig), None, None, None)?;
+    }
+    let out_dir = Utf8PathBuf::try_from(tempdir.join("patches"))?;
+    let mut opts = FormatOptions::default();
+    opts.since = Some(time::OffsetDateTime::from_unix_timestamp(
+        1_692_944_000 + 2 * day,
+    )?);
+    opts.until = Some(time::OffsetDateTime::from_unix_timestamp(
+        1_692_944_000 + 3 * day + 1,
+    )?);
+    let drain = common::CapturingDrain::default();
+    let mut formatter = PatchFormatter::new(
+        drain.logger(),
+        out_dir,
+        &repo,
+        repo.find_commit(base)?,
+        opts,
+    )?;
+    // No other test in this file depends on the abbreviation
+    gitpatcher::set_abbrev_len(12);
+    formatter.generate_all()?;
+    let names = formatter
+        .generated_patches()
+        .iter()
+        .map(|patch| patch.path.file_name().unwrap().to_string())
+        .collect::<Vec<_>>();
+    assert_eq!(names, ["0001-Tuesday.patch", "0002-Wednesday.patch"]);
+    let skipped = drain.messages(slog::Level::Debug);
+    assert_eq!(skipped.len(), 2, "{skipped:?}");
+    for message in skipped {
+        let id = message
+            .strip_prefix("Skipping commit outside date range: ")
+            .unwrap();
+        assert_eq!(id.len(), 12, "{message}");
+    }
+    let tuesday = std::fs::read_to_string(&formatter.generated_patches()[0].path)?;
+    assert!(tuesday.contains(" Monday\n+Tuesday\n"), "{tuesday}");
+    Ok(())
+}
+
+#[test]
+pub fn generate_mbox() -> anyhow::Result<()> {
+    use gitpatcher::apply_patches::EmailMessage;
+    let tempdir = testdir::testdir!();
+    let repo = common::init_repo(&tempdir.join("repo"))?;
+    let base = common::commit_files(&repo, &[("hello.txt", Some("Hello\n"))], "Initial commit")?;
+    let first = common::commit_files(&repo, &[("hello.txt", Some("Hello World\n"))], "Greet")?;
+    let second = common::commit_files(&repo, &[("bye.txt", Some("Bye\n"))], "Say goodbye")?;
+    let out_dir = Utf8PathBuf::try_from(tempdir.join("patches"))?;
+    let mut formatter = PatchFormatter::new(
+        common::logger(),
+        out_dir.clone(),
+        &repo,
+        repo.find_commit(base)?,
+        FormatOptions::default(),
+    )?;
+    let mut mbox = Vec::new();
+    formatter.generate_mbox(&mut mbox)?;
+    assert!(!out_dir.exists());
+    assert_eq!(formatter.generated_patches().len(), 2);
+    let mbox = String::from_utf8(mbox)?;
+    let separator = format!("\n\nFrom {second} Mon Sep 17 00:00:00 2001\n");
+    let (first_message, second_message) = mbox
+        .split_once(&separator)
+        .expect("Missing separator before second patch");
+    assert!(first_message.starts_with(&format!("From {first} ")));
+    let second_message = format!("{}{second_message}", &separator[2..]);
+    assert_eq!(EmailMessage::parse(first_message)?.summary(), "Greet");
+    assert_eq!(
+        EmailMessage::parse(&second_message)?.summary(),
+        "Say goodbye"
+    );
+    Ok(())
+}
+
+#[test]
+pub fn normalize_patch() -> anyhow::Result<()> {
+    use gitpatcher::format_patches::normalize_patch;
+    const PATCH: &str = "\
+From 0000000000000000000000000000000000000000 Mon Sep 17 00:00:00 2001
+From: dummy <dummy@dumb.gov>
+Date: Fri, 25 Aug 2023 06:13:20 +0000
+Subject: [PATCH] Greet the world
+
+Say hello to everyone.
+---
+ hello.txt | 2 +-
+ 1 file changed, 1 insertion(+), 1 deletion(-)
+
+diff --git a/hello.txt b/hello.txt
+index e965047..557db03 100644
+--- a/hello.txt
++++ b/hello.txt
+@@ -1 +1 @@
+-Hello
++Hello World
+--
+libgit2 1.8.1
+
+
+";
+    let normalized = normalize_patch(PATCH.as_bytes())?;
+    assert_eq!(
+        normalized,
+        "\
+From 0000000000000000000000000000000000000000 Mon Sep 17 00:00:00 2001
+From: dummy <dummy@dumb.gov>
+Date: Fri, 25 Aug 2023 06:13:20 +0000
+Subject: [PATCH] Greet the world
+
+Say hello to everyone.
+
+diff --git a/hello.txt b/hello.txt
+index e965047..557db03 100644
+--- a/hello.txt
++++ b/hello.txt
+@@ -1 +1 @@
+-Hello
++Hello World
+--
+libgit2 1.8.1
+"
+    );
+    assert_eq!(normalize_patch(&normalized)?, normalized);
+    Ok(())
+}
+
+#[test]
+pub fn header_order() -> anyhow::Result<()> {
+    use gitpatcher::apply_patches::EmailMessage;
+    use gitpatcher::format_patches::HeaderOrder;
+    let tempdir = testdir::testdir!();
+    let repo = common::init_repo(&tempdir.join("repo"))?;
+    let base = common::commit_files(&repo, &[("hello.txt", Some("Hello\n"))], "Initial commit")?;
+    common::commit_files(
+        &repo,
+        &[("hello.txt", Some("Hello World\n"))],
+        "Greet the world",
+    )?;
+    let format = |header_order| -> anyhow::Result<String> {
+        let mut opts = FormatOptions::default();
+        opts.from_line_id = Some(git2::Oid::zero());
+        opts.header_order = header_order;
+        let out_dir = Utf8PathBuf::try_from(tempdir.join(format!("{header_order:?}")))?;
+        let mut formatter = PatchFormatter::new(
+            common::logger(),
+            out_dir,
+            &repo,
+            repo.find_commit(base)?,
+            opts,
+        )?;
+        formatter.generate_all()?;
+        Ok(std::fs::read_to_string(
+            &formatter.generated_patches()[0].path,
+        )?)
+    };
+    const DIFF: &str = "\
+diff --git a/hello.txt b/hello.txt
+index e965047..557db03 100644
+--- a/hello.txt
++++ b/hello.txt
+@@ -1 +1 @@
+-Hello
++Hello World
+";
+    let default = format(HeaderOrder::FromDate)?;
+    assert!(
+        default.starts_with(&format!(
+            "\
+From 0000000000000000000000000000000000000000 Mon Sep 17 00:00:00 2001
+From: dummy <dummy@dumb.gov>
+Date: Fri, 25 Aug 2023 06:13:20 +0000
+Subject: [PATCH] Greet the world
+
+
+{DIFF}"
+        )),
+        "{default}"
+    );
+    let date_first = format(HeaderOrder::DateFrom)?;
+    assert!(
+        date_first.starts_with(&format!(
+            "\
+From 0000000000000000000000000000000000000000 Mon Sep 17 00:00:00 2001
+Date: Fri, 25 Aug 2023 06:13:20 +0000
+From: dummy <dummy@dumb.gov>
+Subject: [PATCH] Greet the world
+
+
+{DIFF}"
+        )),
+        "{date_first}"
+    );
+    let (default, date_first) = (
+        EmailMessage::parse(&default)?,
+        EmailMessage::parse(&date_first)?,
+    );
+    assert_eq!(default.to_patch_string()?, date_first.to_patch_string()?);
+    Ok(())
+}
+
+#[test]
+pub fn plain_output_style() -> anyhow::Result<()> {
+    use gitpatcher::format_patches::OutputStyle;
+    let tempdir = testdir::testdir!();
+    let initial: &[(&str, Option<&str>)] = &[
+        ("hello.txt", Some("Hello\n")),
+        ("no-newline.txt", Some("a\nb")),
+    ];
+    let repo = common::init_repo(&tempdir.join("repo"))?;
+    let base = common::commit_files(&repo, initial, "Initial commit")?;
+    common::commit_files(
+        &repo,
+        &[
+            ("hello.txt", Some("Hello World\n")),
+            ("no-newline.txt", Some("a\nc")),
+            ("added.txt", Some("Added\n")),
+        ],
+        "Change everything\n\nWith a body",
+    )?;
+    let out_dir = Utf8PathBuf::try_from(tempdir.join("patches"))?;
+    let mut opts = FormatOptions::default();
+    opts.style = OutputStyle::Plain;
+    let mut formatter = PatchFormatter::new(
+        common::logger(),
+        out_dir,
+        &repo,
+        repo.find_commit(base)?,
+        opts,
+    )?;
+    formatter.generate_all()?;
+    let patch = std::fs::read(&formatter.generated_patches()[0].path)?;
+    let text = String::from_utf8(patch.clone())?;
+    assert!(
+        text.starts_with("diff --git a/added.txt b/added.txt\n"),
+        "{text}"
+    );
+    for header in ["From ", "Subject:", "Date:", "With a body"] {
+        assert!(!text.contains(header), "{text}");
+    }
+
+    // Applies to the worktree like `git apply`
+    let target = common::init_repo(&tempdir.join("target"))?;
+    common::commit_files(&target, initial, "Initial commit")?;
+    let diff = git2::Diff::from_buffer(&patch)?;
+    target.apply(&diff, git2::ApplyLocation::WorkDir, None)?;
+    let workdir = target.workdir().unwrap();
+    assert_eq!(
+        std::fs::read_to_string(workdir.join("hello.txt"))?,
+        "Hello World\n"
+    );
+    assert_eq!(
+        std::fs::read_to_string(workdir.join("no-newline.txt"))?,
+        "a\nc"
+    );
+    assert_eq!(
+        std::fs::read_to_string(workdir.join("added.txt"))?,
+        "Added\n"
+    );
+    Ok(())
+}
+
+#[test]
+pub fn reuse_with_new_base() -> anyhow::Result<()> {
+    let tempdir = testdir::testdir!();
+    let repo = common::init_repo(&tempdir.join("repo"))?;
+    let base = common::commit_files(&repo, &[("a.txt", Some("a\n"))], "Initial commit")?;
+    let change_a = common::commit_files(&repo, &[("a.txt", Some("a1\n"))], "Change a")?;
+    common::commit_files(&repo, &[("b.txt", Some("b\n"))], "Add b")?;
+    let out_dir = Utf8PathBuf::try_from(tempdir.join("patches"))?;
+    let mut formatter = PatchFormatter::new(
+        common::logger(),
+        out_dir.clone(),
+        &repo,
+        repo.find_commit(change_a)?,
+        FormatOptions::default(),
+    )?;
+    formatter.generate_all()?;
+    assert_eq!(formatter.generated_patches().len(), 1);
+    assert!(out_dir.join("0001-Add-b.patch").is_file());
+
+    // A disjoint range, from an earlier base to an earlier HEAD
+    repo.reset(
+        &repo.find_object(change_a, None)?,
+        git2::ResetType::Hard,
+        None,
+    )?;
+    formatter.set_base(repo.find_commit(base)?);
+    formatter.generate_all()?;
+    assert_eq!(formatter.generated_patches().len(), 1);
+    let patch = std::fs::read_to_string(out_dir.join("0001-Change-a.patch"))?;
+    assert!(patch.contains("-a\n+a1\n"), "{patch}");
+    // The diff is against the new base, not the last commit of the previous range
+    assert!(!patch.contains("b.txt"), "{patch}");
+    Ok(())
+}
+
+#[test]
+pub fn format_other_branch() -> anyhow::Result<()> {
+    let tempdir = testdir::testdir!();
+    let repo = common::init_repo(&tempdir.join("repo"))?;
+    let base = common::commit_files(&repo, &[("a.txt", Some("a\n"))], "Initial commit")?;
+    common::commit_files(&repo, &[("a.txt", Some("a1\n"))], "Change a")?;
+    let feature = common::commit_files(&repo, &[("b.txt", Some("b\n"))], "Add b")?;
+    repo.branch("feature", &repo.find_commit(feature)?, false)?;
+    // HEAD moves on without the branch
+    repo.reset(&repo.find_object(base, None)?, git2::ResetType::Hard, None)?;
+    common::commit_files(&repo, &[("c.txt", Some("c\n"))], "Add c")?;
+
+    let out_dir = Utf8PathBuf::try_from(tempdir.join("patches"))?;
+    let mut opts = FormatOptions::default();
+    opts.tip = Some(repo.revparse_single("feature")?.id());
+    let mut formatter = PatchFormatter::new(
+        common::logger(),
+        out_dir.clone(),
+        &repo,
+        repo.find_commit(base)?,
+        opts,
+    )?;
+    formatter.generate_all()?;
+    let names = formatter
+        .generated_patches()
+        .iter()
+        .map(|patch| patch.path.file_name().unwrap().to_string())
+        .collect::<Vec<_>>();
+    assert_eq!(names, ["0001-Change-a.patch", "0002-Add-b.patch"]);
+    // HEAD is left alone
+    assert_eq!(
+        common::read_head_file(&repo, "c.txt")?.as_deref(),
+        Some("c\n")
+    );
+    Ok(())
+}
+
+/// Create an executable file, so the next commit adds it with mode `100755`
+#[cfg(unix)]
+fn create_executable(repo: &git2::Repository, name: &str) -> anyhow::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let path = repo.workdir().unwrap().join(name);
+    std::fs::write(&path, "")?;
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755))?;
+    Ok(())
+}
+
+/// The diff of a patch, from the first `diff --git` line up to the signature
+fn diff_section(patch: &str) -> &str {
+    let start = patch.find("diff --git ").expect("Patch should have a diff");
+    let end = patch
+        .rfind("\n--\n")
+        .expect("Patch should have a signature");
+    &patch[start..=end]
+}
+
+#[test]
+#[cfg(unix)]
+pub fn golden_added_files() -> anyhow::Result<()> {
+    let tempdir = testdir::testdir!();
+    let repo = common::init_repo(&tempdir.join("repo"))?;
+    let base = common::commit_files(&repo, &[("existing.txt", Some("existing\n"))], "Initial")?;
+    create_executable(&repo, "run.sh")?;
+    common::commit_files(
+        &repo,
+        &[("new.txt", Some("new\n")), ("run.sh", Some("#!/bin/sh\n"))],
+        "Add files",
+    )?;
+    let patches = common::format_patches(&repo, base, &tempdir.join("patches"))?;
+    // Identical to `git format-patch`
+    assert_eq!(
+        diff_section(&patches[0]),
+        "\
+diff --git a/new.txt b/new.txt
+new file mode 100644
+index 0000000..3e75765
+--- /dev/null
++++ b/new.txt
+@@ -0,0 +1 @@
++new
+diff --git a/run.sh b/run.sh
+new file mode 100755
+index 0000000..1a24852
+--- /dev/null
++++ b/run.sh
+@@ -0,0 +1 @@
++#!/bin/sh
+"
+    );
+
+    let target = common::init_repo(&tempdir.join("target"))?;
+    common::commit_files(&target, &[("existing.txt", Some("existing\n"))], "Initial")?;
+    EmailMessage::parse(&patches[0])?.apply_commit(&target)?;
+    let tree = target.head()?.peel_to_tree()?;
+    let mode = |name: &str| -> anyhow::Result<i32> { Ok(tree.get_path(name.as_ref())?.filemode()) };
+    assert_eq!(mode("new.txt")?, 0o100644);
+    assert_eq!(mode("run.sh")?, 0o100755);
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+pub fn golden_deleted_files() -> anyhow::Result<()> {
+    let tempdir = testdir::testdir!();
+    let repo = common::init_repo(&tempdir.join("repo"))?;
+    create_executable(&repo, "tool.sh")?;
+    let base = common::commit_files(
+        &repo,
+        &[("old.txt", Some("old\n")), ("tool.sh", Some("tool\n"))],
+        "Initial",
+    )?;
+    common::commit_files(
+        &repo,
+        &[("old.txt", None), ("tool.sh", None)],
+        "Delete files",
+    )?;
+    let patches = common::format_patches(&repo, base, &tempdir.join("patches"))?;
+    // Identical to `git format-patch`
+    assert_eq!(
+        diff_section(&patches[0]),
+        "\
+diff --git a/old.txt b/old.txt
+deleted file mode 100644
+index 3367afd..0000000
+--- a/old.txt
++++ /dev/null
+@@ -1 +0,0 @@
+-old
+diff --git a/tool.sh b/tool.sh
+deleted file mode 100755
+index 94027da..0000000
+--- a/tool.sh
++++ /dev/null
+@@ -1 +0,0 @@
+-tool
+"
+    );
+
+    let target = common::init_repo(&tempdir.join("target"))?;
+    create_executable(&target, "tool.sh")?;
+    common::commit_files(
+        &target,
+        &[("old.txt", Some("old\n")), ("tool.sh", Some("tool\n"))],
+        "Initial",
+    )?;
+    EmailMessage::parse(&patches[0])?.apply_commit(&target)?;
+    assert!(target.head()?.peel_to_tree()?.is_empty());
+    Ok(())
+}
+
+#[test]
+pub fn rename_threshold() -> anyhow::Result<()> {
+    let tempdir = testdir::testdir!();
+    let repo = common::init_repo(&tempdir.join("repo"))?;
+    let lines = (1..=10).map(|n| format!("line {n}\n")).collect::<String>();
+    let base = common::commit_files(&repo, &[("old.txt", Some(&lines))], "Initial")?;
+    // Moved with a single line changed, so the files are about 90% similar
+    common::commit_files(
+        &repo,
+        &[
+            ("old.txt", None),
+            ("new.txt", Some(&lines.replace("line 5\n", "line five\n"))),
+        ],
+        "Move and edit",
+    )?;
+    let format = |threshold: Option<u16>| -> anyhow::Result<String> {
+        let out_dir = Utf8PathBuf::try_from(tempdir.join(format!("patches-{threshold:?}")))?;
+        let mut opts = FormatOptions::default();
+        opts.rename_threshold = threshold;
+        let mut formatter = PatchFormatter::new(
+            common::logger(),
+            out_dir,
+            &repo,
+            repo.find_commit(base)?,
+            opts,
+        )?;
+        formatter.generate_all()?;
+        Ok(std::fs::read_to_string(
+            &formatter.generated_patches()[0].path,
+        )?)
+    };
+
+    let renamed = format(Some(50))?;
+    assert!(
+        renamed.contains("rename from old.txt\nrename to new.txt\n"),
+        "{renamed}"
+    );
+    assert!(renamed.contains("-line 5\n+line five\n"), "{renamed}");
+    let target = common::init_repo(&tempdir.join("target"))?;
+    common::commit_files(&target, &[("old.txt", Some(&lines))], "Initial")?;
+    EmailMessage::parse(&renamed)?.apply_commit(&target)?;
+    assert_eq!(common::read_head_file(&target, "old.txt")?, None);
+    assert_eq!(
+        common::read_head_file(&target, "new.txt")?,
+        Some(lines.replace("line 5\n", "line five\n"))
+    );
+
+    for threshold in [Some(95), None] {
+        let patch = format(threshold)?;
+        assert!(!patch.contains("rename from"), "{patch}");
+        assert!(patch.contains("deleted file mode 100644\n"), "{patch}");
+        assert!(patch.contains("new file mode 100644\n"), "{patch}");
+    }
+
+    let mut opts = FormatOptions::default();
+    opts.rename_threshold = Some(101);
+    assert!(PatchFormatter::new(
+        common::logger(),
+        Utf8PathBuf::try_from(tempdir.join("invalid"))?,
+        &repo,
+        repo.find_commit(base)?,
+        opts,
+    )
+    .is_err());
+    Ok(())
+}
@@ -0,0 +1,27 @@
+//! Check that the lightweight parsing still works without the `git` feature.
+use std::process::Command;
+
+#[test]
+pub fn build_without_git() {
+    let manifest_dir = env!("CARGO_MANIFEST_DIR");
+    // Use a separate target directory, to avoid invalidating the regular build
+    let target_dir = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("no-default-features");
+    let output = Command::new(env!("CARGO"))
+        .current_dir(manifest_dir)
+        .args([
+            "test",
+            "--lib",
+            "--no-default-features",
+            "--package",
+            "gitpatcher",
+        ])
+        .arg("--target-dir")
+        .arg(&target_dir)
+        .output()
+        .expect("Failed to run cargo");
+    assert!(
+        output.status.success(),
+        "Failed to test without default features:\n{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
@@ -0,0 +1,549 @@
+//! Tests for regenerating a set of patches.
+use gitpatcher::apply_patches::bulk::ExcludePatterns;
+use gitpatcher::regenerate_patches::RegenerateOptions;
+
+mod common;
+
+#[test]
+pub fn incremental_regeneration() -> anyhow::Result<()> {
+    use gitpatcher::format_patches::LineEnding;
+    let tempdir = testdir::testdir!();
+    let fixture = common::RegenerateFixture::new(&tempdir)?;
+    common::commit_files(&fixture.patched, &[("one.txt", Some("1\n"))], "Add one")?;
+    common::commit_files(&fixture.patched, &[("two.txt", Some("2\n"))], "Add two")?;
+    let three = common::commit_files(&fixture.patched, &[("three.txt", Some("3\n"))], "Add three")?;
+    fixture.regenerate(RegenerateOptions::default())?;
+    assert_eq!(
+        fixture.patch_names()?,
+        [
+            "0001-Add-one.patch",
+            "0002-Add-two.patch",
+            "0003-Add-three.patch"
+        ]
+    );
+    fixture.commit_patches()?;
+
+    // Mark the existing patches, so we can tell if they are rewritten
+    for name in fixture.patch_names()? {
+        let path = fixture.patch_path(&name);
+        let contents = std::fs::read_to_string(&path)?;
+        std::fs::write(&path, contents.replacen("\n", "\nX-Marker: yes\n", 1))?;
+    }
+    // Amend the last commit
+    let parent = fixture.patched.find_commit(three)?.parent(0)?;
+    fixture
+        .patched
+        .reset(parent.as_object(), git2::ResetType::Hard, None)?;
+    common::commit_files(
+        &fixture.patched,
+        &[("three.txt", Some("3 amended\n"))],
+        "Add three",
+    )?;
+    fixture.regenerate(RegenerateOptions {
+        incremental: true,
+        ..Default::default()
+    })?;
+    assert_eq!(
+        fixture.patch_names()?,
+        [
+            "0001-Add-one.patch",
+            "0002-Add-two.patch",
+            "0003-Add-three.patch"
+        ]
+    );
+    for unchanged in ["0001-Add-one.patch", "0002-Add-two.patch"] {
+        assert!(std::fs::read_to_string(fixture.patch_path(unchanged))?.contains("X-Marker"));
+    }
+    let rewritten = std::fs::read_to_string(fixture.patch_path("0003-Add-three.patch"))?;
+    assert!(!rewritten.contains("X-Marker"));
+    assert!(rewritten.contains("+3 amended"));
+
+    // Changing the line endings rewrites every patch
+    fixture.regenerate(RegenerateOptions {
+        incremental: true,
+        patch_file_eol: LineEnding::CrLf,
+        ..Default::default()
+    })?;
+    for name in fixture.patch_names()? {
+        let contents = std::fs::read_to_string(fixture.patch_path(&name))?;
+        assert!(!contents.contains("X-Marker"), "{name}");
+        assert!(
+            contents.starts_with("From ") && contents.contains(" 2001\r\n"),
+            "{name}"
+        );
+    }
+
+    // Other format options can't be checked, so every patch is rewritten
+    let mut format_opts = gitpatcher::format_patches::FormatOptions::default();
+    format_opts.subject_prefix = "RFC PATCH".into();
+    fixture.regenerate(RegenerateOptions {
+        incremental: true,
+        patch_file_eol: LineEnding::CrLf,
+        format_opts,
+        ..Default::default()
+    })?;
+    for name in fixture.patch_names()? {
+        let contents = std::fs::read_to_string(fixture.patch_path(&name))?;
+        assert!(contents.contains("Subject: [RFC PATCH]"), "{name}");
+    }
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+pub fn bare_repo_with_non_utf8_path() -> anyhow::Result<()> {
+    use gitpatcher::regenerate_patches::regenerate_patches;
+    use std::os::unix::ffi::OsStrExt;
+    let tempdir = testdir::testdir!();
+    let fixture = common::RegenerateFixture::new(&tempdir)?;
+    common::commit_files(&fixture.patched, &[("one.txt", Some("1\n"))], "Add one")?;
+    let bare_dir = tempdir.join(std::ffi::OsStr::from_bytes(b"bare-\xff.git"));
+    let bare = git2::build::RepoBuilder::new()
+        .bare(true)
+        .clone(fixture.patched.path().to_str().unwrap(), &bare_dir)?;
+    let mut patch_set = fixture.load_patches()?;
+    regenerate_patches(
+        &bare.find_commit(fixture.base)?,
+        &mut patch_set,
+        &bare,
+        common::logger(),
+        RegenerateOptions::default(),
+    )?;
+    assert_eq!(fixture.patch_names()?, ["0001-Add-one.patch"]);
+    Ok(())
+}
+
+#[test]
+pub fn patch_dir_outside_repo() -> anyhow::Result<()> {
+    use gitpatcher::regenerate_patches::patch_file::{PatchError, PatchFileSet};
+    let tempdir = testdir::testdir!();
+    let fixture = common::RegenerateFixture::new(&tempdir)?;
+    let outside = camino::Utf8PathBuf::try_from(tempdir.join("outside"))?;
+    std::fs::create_dir(&outside)?;
+    assert!(matches!(
+        PatchFileSet::load(&fixture.root, &outside),
+        Err(PatchError::InvalidPatchDir { .. })
+    ));
+    Ok(())
+}
+
+#[test]
+pub fn apply_loaded_patch_set() -> anyhow::Result<()> {
+    let tempdir = testdir::testdir!();
+    let fixture = common::RegenerateFixture::new(&tempdir)?;
+    common::commit_files(&fixture.patched, &[("file.txt", Some("one\n"))], "First")?;
+    common::commit_files(&fixture.patched, &[("file.txt", Some("two\n"))], "Second")?;
+    fixture.regenerate(RegenerateOptions::default())?;
+
+    let target = common::init_repo(&tempdir.join("target"))?;
+    common::commit_files(&target, &[("file.txt", Some("base\n"))], "Initial")?;
+    fixture.load_patches()?.apply_all(&target)?;
+    assert_eq!(
+        common::read_head_file(&target, "file.txt")?.as_deref(),
+        Some("two\n")
+    );
+    let head = target.head()?.peel_to_commit()?;
+    assert_eq!(head.summary(), Some("Second"));
+    assert_eq!(head.parent(0)?.summary(), Some("First"));
+    Ok(())
+}
+
+#[test]
+pub fn patch_file_eol() -> anyhow::Result<()> {
+    use gitpatcher::apply_patches::EmailMessage;
+    use gitpatcher::format_patches::LineEnding;
+    let tempdir = testdir::testdir!();
+    let fixture = common::RegenerateFixture::new(&tempdir)?;
+    common::commit_files(
+        &fixture.patched,
+        &[("file.txt", Some("changed\n"))],
+        "Change",
+    )?;
+    fixture.regenerate(RegenerateOptions::default())?;
+    let lf = std::fs::read_to_string(fixture.patch_path("0001-Change.patch"))?;
+    assert!(lf.ends_with('\n') && !lf.ends_with("\n\n"), "{lf:?}");
+    assert!(!lf.contains('\r'));
+
+    fixture.regenerate(RegenerateOptions {
+        patch_file_eol: LineEnding::CrLf,
+        ..Default::default()
+    })?;
+    let crlf = std::fs::read_to_string(fixture.patch_path("0001-Change.patch"))?;
+    assert!(
+        crlf.ends_with("\r\n") && !crlf.ends_with("\r\n\r\n"),
+        "{crlf:?}"
+    );
+    assert_eq!(crlf.replace("\r\n", "\n"), lf);
+
+    let target = common::init_repo(&tempdir.join("target"))?;
+    common::commit_files(&target, &[("file.txt", Some("base\n"))], "Initial")?;
+    EmailMessage::parse(&crlf)?.apply_commit(&target)?;
+    assert_eq!(
+        common::read_head_file(&target, "file.txt")?.as_deref(),
+        Some("changed\n")
+    );
+    Ok(())
+}
+
+#[test]
+pub fn exclude_existing_patches() -> anyhow::Result<()> {
+    let tempdir = testdir::testdir!();
+    let fixture = common::RegenerateFixture::new(&tempdir)?;
+    common::commit_files(
+        &fixture.patched,
+        &[("file.txt", Some("changed\n"))],
+        "Change",
+    )?;
+    // A broken patch that was skipped when applying, so it isn't in the patched repo
+    std::fs::write(fixture.patch_path("0002-Broken.patch"), "broken\n")?;
+
+    fixture.regenerate(RegenerateOptions {
+        exclude: ExcludePatterns::new(["0002-*"])?,
+        ..Default::default()
+    })?;
+    assert_eq!(
+        fixture.patch_names()?,
+        ["0001-Change.patch", "0002-Broken.patch"]
+    );
+    assert_eq!(
+        std::fs::read_to_string(fixture.patch_path("0002-Broken.patch"))?,
+        "broken\n"
+    );
+
+    fixture.regenerate(RegenerateOptions::default())?;
+    assert_eq!(fixture.patch_names()?, ["0001-Change.patch"]);
+    Ok(())
+}
+
+#[test]
+pub fn exclude_middle_patch() -> anyhow::Result<()> {
+    let tempdir = testdir::testdir!();
+    let fixture = common::RegenerateFixture::new(&tempdir)?;
+    common::commit_files(&fixture.patched, &[("one.txt", Some("1\n"))], "Add one")?;
+    common::commit_files(&fixture.patched, &[("three.txt", Some("3\n"))], "Add three")?;
+    // The second patch was skipped when applying, so the third commit follows the first
+    std::fs::write(fixture.patch_path("0001-Add-one.patch"), "old\n")?;
+    std::fs::write(fixture.patch_path("0002-Broken.patch"), "broken\n")?;
+    std::fs::write(fixture.patch_path("0003-Add-three.patch"), "old\n")?;
+
+    fixture.regenerate(RegenerateOptions {
+        exclude: ExcludePatterns::new(["0002-*"])?,
+        ..Default::default()
+    })?;
+    assert_eq!(
+        fixture.patch_names()?,
+        [
+            "0001-Add-one.patch",
+            "0002-Broken.patch",
+            "0003-Add-three.patch"
+        ]
+    );
+    assert_eq!(
+        std::fs::read_to_string(fixture.patch_path("0002-Broken.patch"))?,
+        "broken\n"
+    );
+    assert!(
+        std::fs::read_to_string(fixture.patch_path("0003-Add-three.patch"))?
+            .contains("Subject: [PATCH] Add three")
+    );
+    Ok(())
+}
+
+#[test]
+pub fn total_stats() -> anyhow::Result<()> {
+    use gitpatcher::regenerate_patches::DiffSummary;
+    let tempdir = testdir::testdir!();
+    let fixture = common::RegenerateFixture::new(&tempdir)?;
+    common::commit_files(
+        &fixture.patched,
+        &[("file.txt", Some("one\n")), ("new.txt", Some("a\nb\n"))],
+        "First",
+    )?;
+    common::commit_files(&fixture.patched, &[("new.txt", None)], "Second")?;
+    fixture.regenerate(RegenerateOptions::default())?;
+
+    let patch_set = fixture.load_patches()?;
+    let expected = DiffSummary {
+        files_changed: 3,
+        insertions: 3,
+        deletions: 3,
+    };
+    assert_eq!(patch_set.total_stats()?, expected);
+    // Cached results are the same
+    assert_eq!(patch_set.total_stats()?, expected);
+    Ok(())
+}
+
+#[test]
+pub fn empty_range() -> anyhow::Result<()> {
+    let tempdir = testdir::testdir!();
+    let fixture = common::RegenerateFixture::new(&tempdir)?;
+    std::fs::write(fixture.patch_path("0001-Existing.patch"), "existing\n")?;
+
+    // `base` is still HEAD of the patched repo
+    let err = fixture
+        .regenerate(RegenerateOptions::default())
+        .unwrap_err();
+    assert!(err.to_string().contains("No commits after"), "{err}");
+    assert_eq!(fixture.patch_names()?, ["0001-Existing.patch"]);
+
+    fixture.regenerate(RegenerateOptions {
+        allow_empty: true,
+        ..Default::default()
+    })?;
+    assert!(fixture.patch_names()?.is_empty());
+
+    // HEAD has moved on, but the tip is still the base
+    std::fs::write(fixture.patch_path("0001-Existing.patch"), "existing\n")?;
+    common::commit_files(&fixture.patched, &[("one.txt", Some("1\n"))], "Add one")?;
+    let mut opts = RegenerateOptions::default();
+    opts.format_opts.tip = Some(fixture.base);
+    let err = fixture.regenerate(opts).unwrap_err();
+    assert!(err.to_string().contains("No commits after"), "{err}");
+    assert_eq!(fixture.patch_names()?, ["0001-Existing.patch"]);
+    Ok(())
+}
+
+#[test]
+pub fn date_filter_keeps_series() -> anyhow::Result<()> {
+    let tempdir = testdir::testdir!();
+    let fixture = common::RegenerateFixture::new(&tempdir)?;
+    common::commit_files(&fixture.patched, &[("one.txt", Some("1\n"))], "Add one")?;
+    common::commit_files(&fixture.patched, &[("two.txt", Some("2\n"))], "Add two")?;
+    fixture.regenerate(RegenerateOptions::default())?;
+    let names = fixture.patch_names()?;
+    assert_eq!(names, ["0001-Add-one.patch", "0002-Add-two.patch"]);
+
+    // Every commit is authored at the same time, so this would only keep an empty series
+    let mut options = RegenerateOptions::default();
+    options.format_opts.since = Some(time::macros::datetime!(2030-01-01 0:00 UTC));
+    let err = fixture.regenerate(options).unwrap_err();
+    assert!(err.to_string().contains("date filter"), "{err}");
+    assert_eq!(fixture.patch_names()?, names);
+    Ok(())
+}
+
+#[test]
+pub fn uncommitted_changes() -> anyhow::Result<()> {
+    let tempdir = testdir::testdir!();
+    let fixture = common::RegenerateFixture::new(&tempdir)?;
+    common::commit_files(&fixture.patched, &[("one.txt", Some("1\n"))], "Add one")?;
+    let workdir = fixture.patched.workdir().unwrap();
+    std::fs::write(workdir.join("one.txt"), "forgot to commit\n")?;
+    // Untracked files don't count
+    std::fs::write(workdir.join("untracked.txt"), "scratch\n")?;
+
+    let err = fixture
+        .regenerate(RegenerateOptions {
+            strict: true,
+            ..Default::default()
+        })
+        .unwrap_err();
+    assert!(err.to_string().contains("uncommitted changes"), "{err}");
+    assert!(fixture.patch_names()?.is_empty());
+
+    let drain = common::CapturingDrain::default();
+    fixture.regenerate_with_logger(RegenerateOptions::default(), drain.logger())?;
+    assert_eq!(
+        drain.messages(slog::Level::Warning),
+        ["Patched repo has uncommitted changes, which won't be included in the patches"]
+    );
+    assert_eq!(fixture.patch_names()?, ["0001-Add-one.patch"]);
+    let patch = std::fs::read_to_string(fixture.patch_path("0001-Add-one.patch"))?;
+    assert!(!patch.contains("forgot to commit"));
+
+    std::fs::write(workdir.join("one.txt"), "1\n")?;
+    let drain = common::CapturingDrain::default();
+    fixture.regenerate_with_logger(RegenerateOptions::default(), drain.logger())?;
+    assert!(drain.messages(slog::Level::Warning).is_empty());
+    Ok(())
+}
+
+#[test]
+pub fn timings() -> anyhow::Result<()> {
+    let tempdir = testdir::testdir!();
+    let fixture = common::RegenerateFixture::new(&tempdir)?;
+    common::commit_files(&fixture.patched, &[("one.txt", Some("1\n"))], "Add one")?;
+    let phases = |options| -> anyhow::Result<Vec<String>> {
+        let drain = common::CapturingDrain::default();
+        fixture.regenerate_with_logger(options, drain.logger())?;
+        Ok(drain
+            .messages(slog::Level::Info)
+            .into_iter()
+            .filter_map(|message| {
+                let phase = message.strip_prefix("Finished ")?.rsplit_once(" in ")?.0;
+                Some(phase.to_string())
+            })
+            .collect())
+    };
+    assert!(phases(RegenerateOptions::default())?.is_empty());
+    let timed = phases(RegenerateOptions {
+        timings: true,
+        ..Default::default()
+    })?;
+    assert_eq!(
+        timed,
+        [
+            "removing old patches",
+            "formatting",
+            "staging",
+            "pruning trivial changes"
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+pub fn read_only_patch() -> anyhow::Result<()> {
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+    let tempdir = testdir::testdir!();
+    // Root can write to read-only files
+    if std::fs::metadata(&tempdir)?.uid() == 0 {
+        return Ok(());
+    }
+    let fixture = common::RegenerateFixture::new(&tempdir)?;
+    common::commit_files(&fixture.patched, &[("one.txt", Some("1\n"))], "Add one")?;
+    common::commit_files(&fixture.patched, &[("two.txt", Some("2\n"))], "Add two")?;
+    fixture.regenerate(RegenerateOptions::default())?;
+    let names = fixture.patch_names()?;
+    assert_eq!(names, ["0001-Add-one.patch", "0002-Add-two.patch"]);
+
+    let read_only = fixture.patch_path("0002-Add-two.patch");
+    std::fs::set_permissions(&read_only, std::fs::Permissions::from_mode(0o444))?;
+    common::commit_files(
+        &fixture.patched,
+        &[("one.txt", Some("one\n"))],
+        "Change one",
+    )?;
+    let err = fixture
+        .regenerate(RegenerateOptions::default())
+        .unwrap_err();
+    assert!(
+        err.to_string()
+            .ends_with("patches/0002-Add-two.patch is read-only"),
+        "{err}"
+    );
+    // Nothing was deleted
+    assert_eq!(fixture.patch_names()?, names);
+
+    std::fs::set_permissions(&read_only, std::fs::Permissions::from_mode(0o644))?;
+    fixture.regenerate(RegenerateOptions::default())?;
+    assert_eq!(fixture.patch_names()?.len(), 3);
+    Ok(())
+}
+
+#[test]
+pub fn single_repo_layout() -> anyhow::Result<()> {
+    use gitpatcher::regenerate_patches::{regenerate_patches, PatchFileSet};
+    let tempdir = testdir::testdir!();
+    let repo_dir = tempdir.join("repo");
+    let repo = common::init_repo(&repo_dir)?;
+    // Code that isn't valid UTF-8 must not be considered by the trivial pruning
+    std::fs::write(repo_dir.join("legacy.txt"), b"caf\xe9\n")?;
+    let mut index = repo.index()?;
+    index.add_path(std::path::Path::new("legacy.txt"))?;
+    index.write()?;
+    let base = common::commit_files(
+        &repo,
+        &[("patches/.keep", Some("")), ("file.txt", Some("base\n"))],
+        "Initial",
+    )?;
+    common::commit_files(&repo, &[("one.txt", Some("1\n"))], "Add one")?;
+
+    let regenerate = || {
+        let drain = common::CapturingDrain::default();
+        let patch_dir = camino::Utf8PathBuf::try_from(repo_dir.join("patches"))?;
+        let mut patch_set = PatchFileSet::load(&repo, &patch_dir)?;
+        regenerate_patches(
+            &repo.find_commit(base)?,
+            &mut patch_set,
+            &repo,
+            drain.logger(),
+            RegenerateOptions {
+                strict: true,
+                ..Default::default()
+            },
+        )?;
+        assert_eq!(drain.messages(slog::Level::Warning), Vec::<String>::new());
+        anyhow::Ok(())
+    };
+    regenerate()?;
+    let patch = repo_dir.join("patches/0001-Add-one.patch");
+    let contents = std::fs::read_to_string(&patch)?;
+    assert!(contents.contains("+++ b/one.txt"), "{contents}");
+    assert!(repo
+        .index()?
+        .get_path(std::path::Path::new("patches/0001-Add-one.patch"), 0)
+        .is_some());
+
+    // The staged patches aren't uncommitted changes to the code
+    regenerate()?;
+    assert_eq!(std::fs::read_to_string(&patch)?, contents);
+    Ok(())
+}
+
+#[test]
+pub fn lf_patches_with_autocrlf() -> anyhow::Result<()> {
+    let tempdir = testdir::testdir!();
+    let fixture = common::RegenerateFixture::new(&tempdir)?;
+    // Simulate a Windows checkout
+    fixture.root.config()?.set_bool("core.autocrlf", true)?;
+    common::commit_files(&fixture.patched, &[("one.txt", Some("1\n"))], "Add one")?;
+    fixture.regenerate(RegenerateOptions::default())?;
+    let path = fixture.patch_path("0001-Add-one.patch");
+    let generated = std::fs::read_to_string(&path)?;
+    assert!(!generated.contains('\r'), "{generated:?}");
+
+    // A patch that only differs in its trailer is restored from HEAD
+    let (contents, _trailer) = generated.rsplit_once("--\n").unwrap();
+    std::fs::write(&path, format!("{contents}--\n9.9.9\n"))?;
+    fixture.commit_patches()?;
+    fixture.regenerate(RegenerateOptions::default())?;
+    let restored = std::fs::read_to_string(&path)?;
+    assert!(restored.ends_with("--\n9.9.9\n"), "{restored:?}");
+    assert!(!restored.contains('\r'), "{restored:?}");
+    Ok(())
+}
+
+#[test]
+pub fn touches_binary() -> anyhow::Result<()> {
+    let tempdir = testdir::testdir!();
+    let fixture = common::RegenerateFixture::new(&tempdir)?;
+    common::commit_files(
+        &fixture.patched,
+        &[("text.txt", Some("text\n"))],
+        "Add text",
+    )?;
+    common::commit_files(
+        &fixture.patched,
+        &[("image.bin", Some("\0binary\0"))],
+        "Add binary",
+    )?;
+    fixture.regenerate(RegenerateOptions::default())?;
+    let binary = fixture
+        .load_patches()?
+        .patches()
+        .iter()
+        .map(|patch| patch.touches_binary())
+        .collect::<Result<Vec<_>, _>>()?;
+    assert_eq!(binary, [false, true]);
+    Ok(())
+}
+
+#[test]
+pub fn base_override() -> anyhow::Result<()> {
+    let tempdir = testdir::testdir!();
+    let fixture = common::RegenerateFixture::new(&tempdir)?;
+    let one = common::commit_files(&fixture.patched, &[("one.txt", Some("1\n"))], "Add one")?;
+    common::commit_files(&fixture.patched, &[("two.txt", Some("2\n"))], "Add two")?;
+    // The fixture always passes its own base, from before "Add one"
+    fixture.regenerate(RegenerateOptions {
+        base_override: Some(one),
+        ..Default::default()
+    })?;
+    assert_eq!(fixture.patch_names()?, ["0001-Add-two.patch"]);
+    let patch = std::fs::read_to_string(fixture.patch_path("0001-Add-two.patch"))?;
+    assert!(!patch.contains("one.txt"), "{patch}");
+    Ok(())
+}
@@ -0,0 +1,108 @@
+//! Tests for resolving revisions given on the command line.
+use gitpatcher::resolve::resolve_commitish;
+
+mod common;
+
+#[test]
+pub fn resolve_revisions() -> anyhow::Result<()> {
+    let tempdir = testdir::testdir!();
+    let repo = common::init_repo(&tempdir.join("repo"))?;
+    let first = common::commit_files(&repo, &[("file.txt", Some("a\n"))], "First")?;
+    let second = common::commit_files(&repo, &[("file.txt", Some("b\n"))], "Second")?;
+    repo.branch("old", &repo.find_commit(first)?, false)?;
+    repo.tag_lightweight("v1", repo.find_commit(first)?.as_object(), false)?;
+
+    // A bare oid that isn't the target of any reference
+    let unreferenced = {
+        let parent = repo.find_commit(second)?;
+        let sig = parent.author();
+        repo.commit(
+            None,
+            &sig,
+            &sig,
+            "Unreferenced",
+            &parent.tree()?,
+            &[&parent],
+        )?
+    };
+    assert_eq!(
+        resolve_commitish(&repo, &unreferenced.to_string())?.id(),
+        unreferenced
+    );
+    // The tip of the current branch
+    assert_eq!(resolve_commitish(&repo, &second.to_string())?.id(), second);
+    assert_eq!(
+        resolve_commitish(&repo, &first.to_string()[..8])?.id(),
+        first
+    );
+    assert_eq!(resolve_commitish(&repo, "old")?.id(), first);
+    assert_eq!(resolve_commitish(&repo, "v1")?.id(), first);
+
+    let err = resolve_commitish(&repo, "missing").unwrap_err().to_string();
+    assert!(
+        err.starts_with("Unable to resolve \"missing\" to a commit"),
+        "{err}"
+    );
+    for kind in ["revision", "reference", "tag"] {
+        assert!(err.contains(&format!("as {kind}:")), "{err}");
+    }
+    Ok(())
+}
+
+#[test]
+pub fn resolve_revspecs_and_symbolic_refs() -> anyhow::Result<()> {
+    let tempdir = testdir::testdir!();
+    let repo = common::init_repo(&tempdir.join("repo"))?;
+    let first = common::commit_files(&repo, &[("file.txt", Some("a\n"))], "First")?;
+    let second = common::commit_files(&repo, &[("file.txt", Some("b\n"))], "Second")?;
+    let third = common::commit_files(&repo, &[("file.txt", Some("c\n"))], "Third")?;
+    let branch = repo.head()?.shorthand().unwrap().to_string();
+
+    assert_eq!(resolve_commitish(&repo, "HEAD~2")?.id(), first);
+    assert_eq!(resolve_commitish(&repo, "@")?.id(), third);
+    assert_eq!(resolve_commitish(&repo, "@~1")?.id(), second);
+    assert_eq!(resolve_commitish(&repo, "@^")?.id(), second);
+    // Only a bare `@` is a shorthand for `HEAD`, not the start of a branch name
+    repo.branch("@foo", &repo.find_commit(second)?, false)?;
+    assert_eq!(resolve_commitish(&repo, "@foo")?.id(), second);
+    assert_eq!(resolve_commitish(&repo, "@foo~1")?.id(), first);
+
+    // The branch tracks a remote-tracking branch
+    repo.remote("origin", "https://example.com/upstream.git")?;
+    repo.reference("refs/remotes/origin/main", second, false, "Fake fetch")?;
+    let mut config = repo.config()?;
+    config.set_str(&format!("branch.{branch}.remote"), "origin")?;
+    config.set_str(&format!("branch.{branch}.merge"), "refs/heads/main")?;
+    assert_eq!(resolve_commitish(&repo, "@{upstream}")?.id(), second);
+    assert_eq!(
+        resolve_commitish(&repo, &format!("{branch}@{{u}}"))?.id(),
+        second
+    );
+
+    // Symbolic refs, including the default branch of a remote
+    repo.reference_symbolic(
+        "refs/remotes/origin/HEAD",
+        "refs/remotes/origin/main",
+        false,
+        "",
+    )?;
+    assert_eq!(resolve_commitish(&repo, "origin")?.id(), second);
+    repo.reference_symbolic(
+        "refs/heads/alias",
+        &format!("refs/heads/{branch}"),
+        false,
+        "",
+    )?;
+    assert_eq!(resolve_commitish(&repo, "alias")?.id(), third);
+    assert_eq!(resolve_commitish(&repo, "alias~1")?.id(), second);
+
+    // Refs that only exist in `packed-refs`
+    std::fs::write(
+        repo.path().join("packed-refs"),
+        format!("# pack-refs with: peeled fully-peeled sorted \n{first} refs/heads/packed\n"),
+    )?;
+    assert_eq!(resolve_commitish(&repo, "packed")?.id(), first);
+    repo.reference_symbolic("refs/heads/packed-alias", "refs/heads/packed", false, "")?;
+    assert_eq!(resolve_commitish(&repo, "packed-alias")?.id(), first);
+    Ok(())
+}